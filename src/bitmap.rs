@@ -0,0 +1,282 @@
+//! exFAT allocation bitmap
+//!
+//! This module implements the in-memory allocation bitmap, which records
+//! one bit per cluster of the cluster heap. Changes are tracked per sector
+//! so only the affected sectors need writing back.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use crate::error::{ExFatError, Result};
+
+/// Cluster allocation bitmap
+///
+/// Bit `n` tracks cluster `n + 2`, since cluster numbering starts at 2.
+#[derive(Debug, Clone)]
+pub struct AllocationBitmap {
+    /// First cluster of the bitmap on disk
+    first_cluster: u32,
+    /// Bitmap contents
+    bits: Vec<u8>,
+    /// Number of clusters tracked
+    cluster_count: u32,
+    /// Size of an on-disk sector, for dirty tracking
+    sector_size: usize,
+    /// Sectors changed since they were last written back
+    dirty: BTreeSet<usize>,
+}
+
+impl AllocationBitmap {
+    /// Create a bitmap from its on-disk contents
+    ///
+    /// Dirty sectors are tracked in 512-byte units unless
+    /// `with_sector_size` says otherwise.
+    pub fn new(first_cluster: u32, bits: Vec<u8>, cluster_count: u32) -> Self {
+        Self {
+            first_cluster,
+            bits,
+            cluster_count,
+            sector_size: 512,
+            dirty: BTreeSet::new(),
+        }
+    }
+    
+    /// Set the sector size used for dirty tracking
+    pub fn with_sector_size(mut self, sector_size: usize) -> Self {
+        self.sector_size = sector_size.max(1);
+        self
+    }
+    
+    /// Get the first cluster of the bitmap on disk
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+    
+    /// Get the raw bitmap bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+    
+    /// Get the number of clusters tracked
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+    
+    /// Check if a cluster is allocated
+    ///
+    /// Clusters outside the cluster heap are reported as allocated so they
+    /// are never handed out.
+    pub fn is_allocated(&self, cluster: u32) -> bool {
+        match self.bit_index(cluster) {
+            Some(index) => self.bits.get(index / 8).is_none_or(|byte| byte & (1 << (index % 8)) != 0),
+            None => true,
+        }
+    }
+    
+    /// Mark a cluster as allocated or free
+    pub fn set_allocated(&mut self, cluster: u32, allocated: bool) -> Result<()> {
+        let index = self.bit_index(cluster).ok_or(ExFatError::ClusterOutOfRange)?;
+        let byte = self.bits.get_mut(index / 8).ok_or(ExFatError::ClusterOutOfRange)?;
+        
+        if allocated {
+            *byte |= 1 << (index % 8);
+        } else {
+            *byte &= !(1 << (index % 8));
+        }
+        self.dirty.insert(index / 8 / self.sector_size);
+        Ok(())
+    }
+    
+    /// Mark a run of `count` clusters starting at `start` as allocated or
+    /// free
+    ///
+    /// Whole bytes are updated at once, and each affected sector is marked
+    /// dirty once. Nothing is changed if any cluster of the run is out of
+    /// range.
+    pub fn set_range(&mut self, start: u32, count: u32, allocated: bool) -> Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        
+        let end = start.checked_add(count - 1).ok_or(ExFatError::ClusterOutOfRange)?;
+        let first = self.bit_index(start).ok_or(ExFatError::ClusterOutOfRange)?;
+        let last = self.bit_index(end).ok_or(ExFatError::ClusterOutOfRange)?;
+        if last / 8 >= self.bits.len() {
+            return Err(ExFatError::ClusterOutOfRange);
+        }
+        
+        let mut index = first;
+        while index <= last {
+            if index % 8 == 0 && last - index >= 7 {
+                self.bits[index / 8] = if allocated { 0xFF } else { 0 };
+                index += 8;
+            } else if allocated {
+                self.bits[index / 8] |= 1 << (index % 8);
+                index += 1;
+            } else {
+                self.bits[index / 8] &= !(1 << (index % 8));
+                index += 1;
+            }
+        }
+        
+        self.dirty.extend(first / 8 / self.sector_size..=last / 8 / self.sector_size);
+        Ok(())
+    }
+    
+    /// Check if any sector changed since the last `clear_dirty`
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+    
+    /// Iterate over the indices of the changed sectors, in order
+    pub fn dirty_sectors(&self) -> impl Iterator<Item = usize> + '_ {
+        self.dirty.iter().copied()
+    }
+    
+    /// Forget the changed sectors once they have been written back
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+    
+    /// Get the bytes of a sector
+    ///
+    /// The last sector may be short, and sectors past the end are empty.
+    pub fn sector(&self, index: usize) -> &[u8] {
+        let start = index.saturating_mul(self.sector_size).min(self.bits.len());
+        let end = start.saturating_add(self.sector_size).min(self.bits.len());
+        &self.bits[start..end]
+    }
+    
+    /// Count the free clusters
+    ///
+    /// Padding bits past `cluster_count` in the last byte are ignored.
+    pub fn free_cluster_count(&self) -> u32 {
+        let full_bytes = (self.cluster_count / 8) as usize;
+        let tail_bits = self.cluster_count % 8;
+        
+        let mut allocated: u32 = self.bits.iter().take(full_bytes).map(|byte| byte.count_ones()).sum();
+        if tail_bits > 0 {
+            let mask = (1u8 << tail_bits) - 1;
+            allocated += self.bits.get(full_bytes).map_or(tail_bits, |byte| (byte & mask).count_ones());
+        }
+        
+        // Clusters missing from a short bitmap count as allocated
+        let missing = full_bytes.saturating_sub(self.bits.len()) as u32 * 8;
+        self.cluster_count - (allocated + missing).min(self.cluster_count)
+    }
+    
+    /// Find the first run of `count` free clusters
+    pub fn find_free_run(&self, count: u32) -> Option<u32> {
+        if count == 0 {
+            return None;
+        }
+        
+        let mut run_start = 0;
+        let mut run_len = 0;
+        
+        for cluster in 2..self.cluster_count + 2 {
+            if self.is_allocated(cluster) {
+                run_len = 0;
+                continue;
+            }
+            
+            if run_len == 0 {
+                run_start = cluster;
+            }
+            run_len += 1;
+            
+            if run_len == count {
+                return Some(run_start);
+            }
+        }
+        
+        None
+    }
+    
+    /// Allocate a run of `count` contiguous clusters
+    ///
+    /// Returns the first cluster of the run, or `None` if no free run is
+    /// long enough.
+    pub fn allocate_contiguous(&mut self, count: u32) -> Option<u32> {
+        let first = self.find_free_run(count)?;
+        self.set_range(first, count, true).ok()?;
+        Some(first)
+    }
+    
+    /// Find the first free cluster at or after `start`
+    pub fn find_free(&self, start: u32) -> Option<u32> {
+        (start.max(2)..self.cluster_count + 2).find(|&cluster| !self.is_allocated(cluster))
+    }
+    
+    /// Get the bit index for a cluster
+    fn bit_index(&self, cluster: u32) -> Option<usize> {
+        if cluster < 2 || cluster - 2 >= self.cluster_count {
+            return None;
+        }
+        Some((cluster - 2) as usize)
+    }
+}
+
+#[cfg(all(test, not(target_os = "uefi")))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    
+    #[test]
+    fn test_allocation_bits() {
+        let mut bitmap = AllocationBitmap::new(2, vec![0b0000_0101, 0], 16);
+        assert!(bitmap.is_allocated(2));
+        assert!(!bitmap.is_allocated(3));
+        assert!(bitmap.is_allocated(4));
+        
+        // Out of range clusters are never free
+        assert!(bitmap.is_allocated(1));
+        assert!(bitmap.is_allocated(18));
+        assert_eq!(bitmap.set_allocated(18, true), Err(ExFatError::ClusterOutOfRange));
+        
+        bitmap.set_allocated(3, true).unwrap();
+        bitmap.set_allocated(2, false).unwrap();
+        assert_eq!(bitmap.as_bytes()[0], 0b0000_0110);
+    }
+    
+    #[test]
+    fn test_free_cluster_count() {
+        // Padding bits past the twelfth cluster are set but don't count
+        let bitmap = AllocationBitmap::new(2, vec![0xFF, 0b1111_0001], 12);
+        assert_eq!(bitmap.free_cluster_count(), 3);
+        
+        let bitmap = AllocationBitmap::new(2, vec![0b1010_1010; 4], 32);
+        assert_eq!(bitmap.free_cluster_count(), 16);
+    }
+    
+    #[test]
+    fn test_allocate_contiguous() {
+        let mut bitmap = AllocationBitmap::new(2, vec![0b0010_0111, 0], 16);
+        assert_eq!(bitmap.find_free_run(2), Some(5));
+        assert_eq!(bitmap.find_free_run(3), Some(8));
+        
+        assert_eq!(bitmap.allocate_contiguous(3), Some(8));
+        assert!((8..11).all(|cluster| bitmap.is_allocated(cluster)));
+        assert_eq!(bitmap.allocate_contiguous(16), None);
+        assert_eq!(bitmap.find_free(6), Some(6));
+    }
+    
+    #[test]
+    fn test_set_range_dirty_sectors() {
+        // 10,000 clusters need 1,250 bytes, or three 512-byte sectors
+        let mut bitmap = AllocationBitmap::new(2, vec![0; 1250], 10_000);
+        assert!(!bitmap.is_dirty());
+        assert_eq!(bitmap.allocate_contiguous(10_000), Some(2));
+        assert_eq!(bitmap.dirty_sectors().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(bitmap.free_cluster_count(), 0);
+        assert_eq!(bitmap.sector(2).len(), 226);
+        
+        bitmap.clear_dirty();
+        bitmap.set_range(5000, 9, false).unwrap();
+        assert_eq!(bitmap.dirty_sectors().collect::<Vec<_>>(), [1]);
+        assert_eq!(bitmap.find_free_run(9), Some(5000));
+        assert!(bitmap.is_allocated(4999) && bitmap.is_allocated(5009));
+        
+        assert_eq!(bitmap.set_range(9000, 2000, true), Err(ExFatError::ClusterOutOfRange));
+        assert!(!bitmap.is_allocated(5000));
+    }
+}
@@ -0,0 +1,241 @@
+//! Block device abstraction
+//!
+//! This module defines the block device interface the exFAT volume reads
+//! and writes through, plus a RAM-backed device for tests and tooling and,
+//! with the `std` feature, a file-backed device for host-side tools.
+
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use uefi::Status;
+use crate::error::Result;
+
+/// Block device used as the backing store of an exFAT volume
+///
+/// Logical block addresses are expressed in units of `block_size` bytes,
+/// which must match the volume's bytes per sector.
+pub trait BlockDevice {
+    /// Size of a single block in bytes
+    fn block_size(&self) -> u32;
+    
+    /// Read consecutive blocks starting at `lba` into `buffer`
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<()>;
+    
+    /// Write consecutive blocks starting at `lba` from `buffer`
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<()>;
+    
+    /// Flush any cached writes to the underlying medium
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// RAM-backed block device
+///
+/// Clones share the same backing storage, so a test can keep a handle to
+/// inspect the image after handing the device to a volume.
+#[derive(Debug, Clone)]
+pub struct MemBlockDevice {
+    /// Backing storage
+    data: Rc<RefCell<Vec<u8>>>,
+    /// Block size in bytes
+    block_size: u32,
+}
+
+impl MemBlockDevice {
+    /// Create a zero-filled device of `size` bytes
+    pub fn new(size: usize, block_size: u32) -> Self {
+        Self::from_vec(vec![0; size], block_size)
+    }
+    
+    /// Create a device over an existing image
+    pub fn from_vec(data: Vec<u8>, block_size: u32) -> Self {
+        Self {
+            data: Rc::new(RefCell::new(data)),
+            block_size,
+        }
+    }
+    
+    /// Get device size in bytes
+    pub fn len(&self) -> usize {
+        self.data.borrow().len()
+    }
+    
+    /// Check if the device has no storage
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    /// Copy the current image contents
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.borrow().clone()
+    }
+    
+    /// Get the byte range covered by a block transfer
+    fn range(&self, lba: u64, len: usize) -> Result<core::ops::Range<usize>> {
+        if !len.is_multiple_of(self.block_size as usize) {
+            return Err(Status::BAD_BUFFER_SIZE.into());
+        }
+        
+        let start = lba
+            .checked_mul(self.block_size as u64)
+            .and_then(|start| usize::try_from(start).ok())
+            .ok_or(Status::INVALID_PARAMETER)?;
+        let end = start.checked_add(len).ok_or(Status::INVALID_PARAMETER)?;
+        
+        if end > self.len() {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        
+        Ok(start..end)
+    }
+}
+
+impl BlockDevice for MemBlockDevice {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+    
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+        let range = self.range(lba, buffer.len())?;
+        buffer.copy_from_slice(&self.data.borrow()[range]);
+        Ok(())
+    }
+    
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<()> {
+        let range = self.range(lba, buffer.len())?;
+        self.data.borrow_mut()[range].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+/// Block device backed by a host file, such as a disk image
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct FileBlockDevice {
+    /// Backing file
+    file: std::fs::File,
+    /// Block size in bytes
+    block_size: u32,
+}
+
+#[cfg(feature = "std")]
+impl FileBlockDevice {
+    /// Create a device over an open file
+    ///
+    /// Writes fail unless the file was opened for writing.
+    pub fn new(file: std::fs::File, block_size: u32) -> Self {
+        Self { file, block_size }
+    }
+    
+    /// Open an image file for reading and writing
+    pub fn open(path: impl AsRef<std::path::Path>, block_size: u32) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(file, block_size))
+    }
+    
+    /// Move the file position to the start of a block transfer
+    fn seek(&mut self, lba: u64, len: usize) -> Result<()> {
+        use std::io::Seek;
+        
+        if !len.is_multiple_of(self.block_size as usize) {
+            return Err(Status::BAD_BUFFER_SIZE.into());
+        }
+        
+        let offset = lba.checked_mul(self.block_size as u64).ok_or(Status::INVALID_PARAMETER)?;
+        self.file.seek(std::io::SeekFrom::Start(offset)).map_err(io_status)?;
+        Ok(())
+    }
+}
+
+/// Map a host I/O error to the status a firmware block device would return
+///
+/// Transfers past the end of the file are reported like out-of-range
+/// transfers on `MemBlockDevice`.
+#[cfg(feature = "std")]
+fn io_status(error: std::io::Error) -> Status {
+    match error.kind() {
+        std::io::ErrorKind::UnexpectedEof => Status::INVALID_PARAMETER,
+        std::io::ErrorKind::PermissionDenied => Status::WRITE_PROTECTED,
+        _ => Status::DEVICE_ERROR,
+    }
+}
+
+#[cfg(feature = "std")]
+impl BlockDevice for FileBlockDevice {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+    
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+        use std::io::Read;
+        
+        self.seek(lba, buffer.len())?;
+        self.file.read_exact(buffer).map_err(io_status)?;
+        Ok(())
+    }
+    
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<()> {
+        use std::io::Write;
+        
+        self.seek(lba, buffer.len())?;
+        self.file.write_all(buffer).map_err(io_status)?;
+        Ok(())
+    }
+    
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data().map_err(io_status)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_os = "uefi")))]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_mem_device_round_trip() {
+        let mut device = MemBlockDevice::new(4096, 512);
+        let data = [0xA5u8; 1024];
+        device.write_blocks(2, &data).unwrap();
+        
+        let mut buffer = [0u8; 1024];
+        device.read_blocks(2, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+        
+        // Clones share storage
+        let clone = device.clone();
+        assert_eq!(clone.to_vec()[1024..2048], data);
+    }
+    
+    #[test]
+    fn test_mem_device_bounds() {
+        let mut device = MemBlockDevice::new(4096, 512);
+        let mut buffer = [0u8; 512];
+        assert!(device.read_blocks(8, &mut buffer).is_err());
+        assert!(device.read_blocks(0, &mut buffer[..100]).is_err());
+    }
+    
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_file_device_round_trip() {
+        let path = std::env::temp_dir().join(std::format!("uefi-exfat-block-{}.img", std::process::id()));
+        std::fs::write(&path, [0u8; 2048]).unwrap();
+        
+        let mut device = FileBlockDevice::open(&path, 512).unwrap();
+        device.write_blocks(1, &[0x5A; 1024]).unwrap();
+        device.flush().unwrap();
+        
+        let mut buffer = [0u8; 512];
+        device.read_blocks(2, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x5A; 512]);
+        assert_eq!(device.read_blocks(3, &mut [0; 1024]), Err(Status::INVALID_PARAMETER.into()));
+        assert_eq!(device.read_blocks(0, &mut [0; 100]), Err(Status::BAD_BUFFER_SIZE.into()));
+        
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(image[512..1536].iter().all(|&b| b == 0x5A));
+        assert!(image[1536..].iter().all(|&b| b == 0));
+    }
+}
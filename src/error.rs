@@ -0,0 +1,173 @@
+//! exFAT driver errors
+//!
+//! This module defines the error type used by the filesystem core, which
+//! keeps more detail than a bare `uefi::Status`. Errors are converted to
+//! the closest EFI status only at the UEFI protocol boundary.
+
+use uefi::Status;
+
+/// Result type used throughout the filesystem core
+pub type Result<T> = core::result::Result<T, ExFatError>;
+
+/// exFAT driver error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExFatError {
+    /// Boot sector signature or filesystem name is wrong
+    BadSignature,
+    /// A stored checksum doesn't match the computed one
+    ChecksumMismatch,
+    /// A cluster number lies outside the cluster heap
+    ClusterOutOfRange,
+    /// A FAT cluster chain loops back on itself
+    CyclicChain,
+    /// On-disk metadata is inconsistent
+    Corrupted,
+    /// An entry set's secondary entries don't match its secondary count
+    InvalidEntrySequence,
+    /// Directory iteration exceeded its entry budget
+    DirectoryTooLarge,
+    /// File name exceeds 255 UTF-16 code units
+    NameTooLong,
+    /// File name is empty or contains illegal characters
+    InvalidName,
+    /// Directory still contains entries
+    DirectoryNotEmpty,
+    /// File or directory doesn't exist
+    NotFound,
+    /// An entry with the same name already exists
+    AlreadyExists,
+    /// An argument is out of range or otherwise invalid
+    InvalidParameter,
+    /// Caller-supplied buffer is too small
+    BufferTooSmall,
+    /// Raw bytes are too short for the structure being decoded
+    BadLength,
+    /// A directory entry has a different type than expected, given by its
+    /// type code
+    UnexpectedEntryType(u8),
+    /// No free space is left for the operation
+    VolumeFull,
+    /// Free space exists but not in the form the operation requires
+    OutOfResources,
+    /// The volume is mounted read-only
+    WriteProtected,
+    /// The volume dirty flag is set, so it wasn't cleanly unmounted
+    VolumeDirty,
+    /// The root directory has no allocation bitmap entry
+    MissingAllocationBitmap,
+    /// The root directory has no up-case table entry
+    MissingUpCaseTable,
+    /// Error reported by the block device
+    Io(Status),
+}
+
+impl From<Status> for ExFatError {
+    fn from(status: Status) -> Self {
+        ExFatError::Io(status)
+    }
+}
+
+impl From<uefi::Error> for ExFatError {
+    fn from(error: uefi::Error) -> Self {
+        ExFatError::Io(error.status())
+    }
+}
+
+impl From<ExFatError> for Status {
+    fn from(error: ExFatError) -> Self {
+        match error {
+            ExFatError::BadSignature => Status::VOLUME_CORRUPTED,
+            ExFatError::ChecksumMismatch => Status::CRC_ERROR,
+            ExFatError::ClusterOutOfRange => Status::VOLUME_CORRUPTED,
+            ExFatError::CyclicChain => Status::VOLUME_CORRUPTED,
+            ExFatError::Corrupted => Status::VOLUME_CORRUPTED,
+            ExFatError::InvalidEntrySequence => Status::VOLUME_CORRUPTED,
+            ExFatError::DirectoryTooLarge => Status::VOLUME_CORRUPTED,
+            ExFatError::NameTooLong => Status::INVALID_PARAMETER,
+            ExFatError::InvalidName => Status::INVALID_PARAMETER,
+            ExFatError::DirectoryNotEmpty => Status::ACCESS_DENIED,
+            ExFatError::NotFound => Status::NOT_FOUND,
+            ExFatError::AlreadyExists => Status::ACCESS_DENIED,
+            ExFatError::InvalidParameter => Status::INVALID_PARAMETER,
+            ExFatError::BufferTooSmall => Status::BUFFER_TOO_SMALL,
+            ExFatError::BadLength => Status::BAD_BUFFER_SIZE,
+            ExFatError::UnexpectedEntryType(_) => Status::VOLUME_CORRUPTED,
+            ExFatError::VolumeFull => Status::VOLUME_FULL,
+            ExFatError::OutOfResources => Status::OUT_OF_RESOURCES,
+            ExFatError::WriteProtected => Status::WRITE_PROTECTED,
+            ExFatError::VolumeDirty => Status::VOLUME_CORRUPTED,
+            ExFatError::MissingAllocationBitmap => Status::VOLUME_CORRUPTED,
+            ExFatError::MissingUpCaseTable => Status::VOLUME_CORRUPTED,
+            ExFatError::Io(status) => status,
+        }
+    }
+}
+
+impl From<ExFatError> for uefi::Error {
+    fn from(error: ExFatError) -> Self {
+        Status::from(error).into()
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ExFatError> for std::io::Error {
+    fn from(error: ExFatError) -> Self {
+        use std::io::ErrorKind;
+        
+        let kind = match error {
+            ExFatError::NotFound => ErrorKind::NotFound,
+            ExFatError::AlreadyExists => ErrorKind::AlreadyExists,
+            ExFatError::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            ExFatError::InvalidParameter | ExFatError::NameTooLong | ExFatError::InvalidName => ErrorKind::InvalidInput,
+            ExFatError::WriteProtected => ErrorKind::ReadOnlyFilesystem,
+            ExFatError::VolumeFull => ErrorKind::StorageFull,
+            ExFatError::OutOfResources => ErrorKind::OutOfMemory,
+            ExFatError::BufferTooSmall | ExFatError::BadLength => ErrorKind::InvalidInput,
+            ExFatError::Io(_) => ErrorKind::Other,
+            _ => ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, std::format!("{error:?}"))
+    }
+}
+
+#[cfg(all(test, not(target_os = "uefi")))]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_status_mapping() {
+        assert_eq!(Status::from(ExFatError::BadSignature), Status::VOLUME_CORRUPTED);
+        assert_eq!(Status::from(ExFatError::ChecksumMismatch), Status::CRC_ERROR);
+        assert_eq!(Status::from(ExFatError::ClusterOutOfRange), Status::VOLUME_CORRUPTED);
+        assert_eq!(Status::from(ExFatError::CyclicChain), Status::VOLUME_CORRUPTED);
+        assert_eq!(Status::from(ExFatError::NameTooLong), Status::INVALID_PARAMETER);
+        assert_eq!(Status::from(ExFatError::DirectoryNotEmpty), Status::ACCESS_DENIED);
+        assert_eq!(Status::from(ExFatError::NotFound), Status::NOT_FOUND);
+        assert_eq!(Status::from(ExFatError::BadLength), Status::BAD_BUFFER_SIZE);
+        assert_eq!(Status::from(ExFatError::UnexpectedEntryType(0xC0)), Status::VOLUME_CORRUPTED);
+        assert_eq!(Status::from(ExFatError::VolumeFull), Status::VOLUME_FULL);
+        assert_eq!(Status::from(ExFatError::OutOfResources), Status::OUT_OF_RESOURCES);
+        assert_eq!(Status::from(ExFatError::WriteProtected), Status::WRITE_PROTECTED);
+        assert_eq!(Status::from(ExFatError::VolumeDirty), Status::VOLUME_CORRUPTED);
+        assert_eq!(Status::from(ExFatError::MissingUpCaseTable), Status::VOLUME_CORRUPTED);
+        assert_eq!(Status::from(ExFatError::Io(Status::DEVICE_ERROR)), Status::DEVICE_ERROR);
+    }
+    
+    #[test]
+    fn test_uefi_error_round_trip() {
+        let error: uefi::Error = ExFatError::CyclicChain.into();
+        assert_eq!(error.status(), Status::VOLUME_CORRUPTED);
+        assert_eq!(ExFatError::from(error), ExFatError::Io(Status::VOLUME_CORRUPTED));
+    }
+    
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_io_error_kind() {
+        use std::io::ErrorKind;
+        
+        assert_eq!(std::io::Error::from(ExFatError::NotFound).kind(), ErrorKind::NotFound);
+        assert_eq!(std::io::Error::from(ExFatError::InvalidParameter).kind(), ErrorKind::InvalidInput);
+        assert_eq!(std::io::Error::from(ExFatError::WriteProtected).kind(), ErrorKind::ReadOnlyFilesystem);
+        assert_eq!(std::io::Error::from(ExFatError::CyclicChain).kind(), ErrorKind::InvalidData);
+    }
+}
@@ -3,9 +3,15 @@
 //! This module implements the core exFAT filesystem structures including
 //! the boot sector, file allocation table, and directory entries.
 
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::error::{ExFatError, Result};
+use crate::time::DateTime;
+use crate::upcase::UpCaseTable;
+
 /// exFAT Boot Sector
 #[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BootSector {
     /// Jump boot code
     pub jump_boot: [u8; 3],
@@ -55,10 +61,135 @@ impl BootSector {
     /// Size of boot sector in bytes
     pub const SIZE: usize = 512;
     
-    /// Verify boot sector signature and filesystem name
+    /// Sector of the backup boot sector
+    pub const BACKUP_SECTOR: u64 = 12;
+    
+    /// Sector of the boot checksum, relative to the start of a boot region
+    pub const CHECKSUM_SECTOR: u64 = 11;
+    
+    /// Byte offset of `volume_flags`
+    pub const VOLUME_FLAGS_OFFSET: usize = 106;
+    
+    /// Byte offset of `percent_in_use`
+    pub const PERCENT_IN_USE_OFFSET: usize = 112;
+    
+    /// Bytes of the boot region left out of the boot checksum
+    ///
+    /// These are `volume_flags` and `percent_in_use`, which change while
+    /// the volume is mounted.
+    pub const CHECKSUM_SKIP: [usize; 3] = [
+        Self::VOLUME_FLAGS_OFFSET,
+        Self::VOLUME_FLAGS_OFFSET + 1,
+        Self::PERCENT_IN_USE_OFFSET,
+    ];
+    
+    /// Smallest `bytes_per_sector_shift`, for 512-byte sectors
+    pub const MIN_BYTES_PER_SECTOR_SHIFT: u8 = 9;
+    
+    /// Largest `bytes_per_sector_shift`, for 4096-byte sectors
+    pub const MAX_BYTES_PER_SECTOR_SHIFT: u8 = 12;
+    
+    /// Largest sum of `bytes_per_sector_shift` and
+    /// `sectors_per_cluster_shift`, for 32 MiB clusters
+    pub const MAX_BYTES_PER_CLUSTER_SHIFT: u8 = 25;
+    
+    /// Parse a boot sector from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 512]) -> Self {
+        let mut jump_boot = [0u8; 3];
+        jump_boot.copy_from_slice(&bytes[0..3]);
+        let mut fs_name = [0u8; 8];
+        fs_name.copy_from_slice(&bytes[3..11]);
+        let mut must_be_zero = [0u8; 53];
+        must_be_zero.copy_from_slice(&bytes[11..64]);
+        let mut reserved = [0u8; 7];
+        reserved.copy_from_slice(&bytes[113..120]);
+        let mut boot_code = [0u8; 390];
+        boot_code.copy_from_slice(&bytes[120..510]);
+        
+        Self {
+            jump_boot,
+            fs_name,
+            must_be_zero,
+            partition_offset: le_u64(bytes, 64),
+            volume_length: le_u64(bytes, 72),
+            fat_offset: le_u32(bytes, 80),
+            fat_length: le_u32(bytes, 84),
+            cluster_heap_offset: le_u32(bytes, 88),
+            cluster_count: le_u32(bytes, 92),
+            root_dir_cluster: le_u32(bytes, 96),
+            volume_serial: le_u32(bytes, 100),
+            fs_revision: le_u16(bytes, 104),
+            volume_flags: le_u16(bytes, 106),
+            bytes_per_sector_shift: bytes[108],
+            sectors_per_cluster_shift: bytes[109],
+            num_fats: bytes[110],
+            drive_select: bytes[111],
+            percent_in_use: bytes[112],
+            reserved,
+            boot_code,
+            boot_signature: le_u16(bytes, 510),
+        }
+    }
+    
+    /// Parse a boot sector from the start of a buffer
+    ///
+    /// Fails with `ExFatError::BadLength` if `buffer` is shorter than
+    /// `BootSector::SIZE`; any bytes past that are ignored. The fields are
+    /// decoded but not validated, so follow with `is_valid`.
+    pub fn try_from_bytes(buffer: &[u8]) -> Result<Self> {
+        let bytes = buffer
+            .get(..Self::SIZE)
+            .and_then(|bytes| <&[u8; 512]>::try_from(bytes).ok())
+            .ok_or(ExFatError::BadLength)?;
+        Ok(Self::from_bytes(bytes))
+    }
+    
+    /// Serialize the boot sector to its on-disk bytes
+    ///
+    /// Multi-byte fields are written little-endian whatever the host's
+    /// byte order, so this is the exact inverse of `from_bytes`.
+    pub fn to_bytes(&self) -> [u8; 512] {
+        let mut bytes = [0u8; 512];
+        bytes[0..3].copy_from_slice(&self.jump_boot);
+        bytes[3..11].copy_from_slice(&self.fs_name);
+        bytes[11..64].copy_from_slice(&self.must_be_zero);
+        bytes[64..72].copy_from_slice(&{ self.partition_offset }.to_le_bytes());
+        bytes[72..80].copy_from_slice(&{ self.volume_length }.to_le_bytes());
+        bytes[80..84].copy_from_slice(&{ self.fat_offset }.to_le_bytes());
+        bytes[84..88].copy_from_slice(&{ self.fat_length }.to_le_bytes());
+        bytes[88..92].copy_from_slice(&{ self.cluster_heap_offset }.to_le_bytes());
+        bytes[92..96].copy_from_slice(&{ self.cluster_count }.to_le_bytes());
+        bytes[96..100].copy_from_slice(&{ self.root_dir_cluster }.to_le_bytes());
+        bytes[100..104].copy_from_slice(&{ self.volume_serial }.to_le_bytes());
+        bytes[104..106].copy_from_slice(&{ self.fs_revision }.to_le_bytes());
+        bytes[106..108].copy_from_slice(&{ self.volume_flags }.to_le_bytes());
+        bytes[108] = self.bytes_per_sector_shift;
+        bytes[109] = self.sectors_per_cluster_shift;
+        bytes[110] = self.num_fats;
+        bytes[111] = self.drive_select;
+        bytes[112] = self.percent_in_use;
+        bytes[113..120].copy_from_slice(&self.reserved);
+        bytes[120..510].copy_from_slice(&self.boot_code);
+        bytes[510..512].copy_from_slice(&{ self.boot_signature }.to_le_bytes());
+        bytes
+    }
+    
+    /// Verify boot sector signature, filesystem name, sector size and
+    /// cluster size
     pub fn is_valid(&self) -> bool {
         self.boot_signature == 0xAA55 && 
-        &self.fs_name == b"EXFAT   "
+        &self.fs_name == b"EXFAT   " &&
+        (Self::MIN_BYTES_PER_SECTOR_SHIFT..=Self::MAX_BYTES_PER_SECTOR_SHIFT).contains(&self.bytes_per_sector_shift) &&
+        self.sectors_per_cluster_shift <= Self::MAX_BYTES_PER_CLUSTER_SHIFT - self.bytes_per_sector_shift
+    }
+    
+    /// Verify as `is_valid`, and also that the must-be-zero region and the
+    /// reserved bytes are all zero
+    ///
+    /// Some tools leave stray bytes in these fields, so mounting only
+    /// checks them when asked to be strict.
+    pub fn is_valid_strict(&self) -> bool {
+        self.is_valid() && self.must_be_zero.iter().chain(&self.reserved).all(|&b| b == 0)
     }
     
     /// Get bytes per sector
@@ -75,6 +206,127 @@ impl BootSector {
     pub fn bytes_per_cluster(&self) -> u32 {
         self.bytes_per_sector() * self.sectors_per_cluster()
     }
+    
+    /// Get the number of sectors a FAT needs
+    ///
+    /// A FAT holds a 32-bit entry for every cluster of the heap plus the
+    /// two reserved entries, rounded up to whole sectors.
+    pub fn required_fat_sectors(&self) -> u32 {
+        let bytes = (self.cluster_count as u64 + 2) * 4;
+        bytes.div_ceil(self.bytes_per_sector() as u64) as u32
+    }
+    
+    /// Check if a cluster number lies within the cluster heap
+    ///
+    /// Clusters 0 and 1 are reserved; the heap runs from cluster 2 to
+    /// `cluster_count + 1`.
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count
+    }
+    
+    /// Get the length of the checksummed part of a boot region
+    ///
+    /// This is the eleven sectors preceding the checksum sector.
+    pub fn boot_region_len(&self) -> usize {
+        Self::CHECKSUM_SECTOR as usize * self.bytes_per_sector() as usize
+    }
+    
+    /// Get the index of the active FAT
+    ///
+    /// Only TexFAT volumes have a second FAT; `volume_flags` selects which
+    /// one is in use.
+    pub fn active_fat(&self) -> u8 {
+        if self.num_fats == 2 && (self.volume_flags & volume_flags::ACTIVE_FAT) != 0 {
+            1
+        } else {
+            0
+        }
+    }
+    
+    /// Get the active allocation bitmap
+    ///
+    /// The bitmap follows the FAT: the second bitmap is active exactly when
+    /// the second FAT is.
+    pub fn active_bitmap(&self) -> BitmapId {
+        if self.active_fat() == 1 {
+            BitmapId::Second
+        } else {
+            BitmapId::First
+        }
+    }
+    
+    /// Get the sector offset of the active FAT
+    pub fn active_fat_offset(&self) -> u64 {
+        self.fat_offset as u64 + self.active_fat() as u64 * self.fat_length as u64
+    }
+    
+    /// Summarize the volume geometry with sizes and offsets in bytes
+    ///
+    /// Every value is computed with checked 64-bit arithmetic; a boot
+    /// sector whose fields overflow fails with `ExFatError::Corrupted`.
+    pub fn describe(&self) -> Result<GeometrySummary> {
+        let shift = |value: u8| 1u64.checked_shl(value as u32).ok_or(ExFatError::Corrupted);
+        let bytes_per_sector = shift(self.bytes_per_sector_shift)?;
+        let sectors_per_cluster = shift(self.sectors_per_cluster_shift)?;
+        let bytes_per_cluster = bytes_per_sector.checked_mul(sectors_per_cluster).ok_or(ExFatError::Corrupted)?;
+        let bytes = |sectors: u64| sectors.checked_mul(bytes_per_sector).ok_or(ExFatError::Corrupted);
+        
+        Ok(GeometrySummary {
+            bytes_per_sector,
+            sectors_per_cluster,
+            bytes_per_cluster,
+            volume_bytes: bytes(self.volume_length)?,
+            cluster_count: self.cluster_count,
+            fat_offset: bytes(self.fat_offset as u64)?,
+            fat_length: bytes(self.fat_length as u64)?,
+            num_fats: self.num_fats,
+            cluster_heap_offset: bytes(self.cluster_heap_offset as u64)?,
+            cluster_heap_length: (self.cluster_count as u64).checked_mul(bytes_per_cluster).ok_or(ExFatError::Corrupted)?,
+            revision: ((self.fs_revision >> 8) as u8, self.fs_revision as u8),
+        })
+    }
+}
+
+/// Volume geometry derived from a boot sector
+///
+/// Returned by `BootSector::describe`. Unlike the packed boot sector, the
+/// fields can be borrowed and printed directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeometrySummary {
+    /// Bytes per sector
+    pub bytes_per_sector: u64,
+    /// Sectors per cluster
+    pub sectors_per_cluster: u64,
+    /// Bytes per cluster
+    pub bytes_per_cluster: u64,
+    /// Size of the volume in bytes
+    pub volume_bytes: u64,
+    /// Number of clusters in the heap
+    pub cluster_count: u32,
+    /// Byte offset of the first FAT from the start of the volume
+    pub fat_offset: u64,
+    /// Length of each FAT in bytes
+    pub fat_length: u64,
+    /// Number of FATs
+    pub num_fats: u8,
+    /// Byte offset of the cluster heap from the start of the volume
+    pub cluster_heap_offset: u64,
+    /// Length of the cluster heap in bytes
+    pub cluster_heap_length: u64,
+    /// File system revision as (major, minor)
+    pub revision: (u8, u8),
+}
+
+/// Boot sector volume flags
+pub mod volume_flags {
+    /// Second FAT and allocation bitmap are active
+    pub const ACTIVE_FAT: u16 = 0x0001;
+    /// Volume was not cleanly unmounted
+    pub const VOLUME_DIRTY: u16 = 0x0002;
+    /// Media has reported failures
+    pub const MEDIA_FAILURE: u16 = 0x0004;
+    /// Must be cleared to zero
+    pub const CLEAR_TO_ZERO: u16 = 0x0008;
 }
 
 /// exFAT Directory Entry Type
@@ -99,6 +351,8 @@ pub enum EntryType {
     FileName = 0xC1,
     /// Vendor extension
     VendorExtension = 0xE0,
+    /// Vendor allocation
+    VendorAllocation = 0xE1,
     /// Unknown type
     Unknown = 0xFF,
 }
@@ -115,6 +369,7 @@ impl From<u8> for EntryType {
             0xC0 => EntryType::StreamExtension,
             0xC1 => EntryType::FileName,
             0xE0 => EntryType::VendorExtension,
+            0xE1 => EntryType::VendorAllocation,
             _ => EntryType::Unknown,
         }
     }
@@ -134,20 +389,79 @@ impl DirectoryEntry {
     /// Size of directory entry in bytes
     pub const SIZE: usize = 32;
     
+    /// Wrap the raw bytes of an entry
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut data = [0u8; 31];
+        data.copy_from_slice(&bytes[1..]);
+        Self {
+            entry_type: bytes[0],
+            data,
+        }
+    }
+    
+    /// Wrap the first entry of a buffer
+    ///
+    /// Fails with `ExFatError::BadLength` if `buffer` is shorter than
+    /// `DirectoryEntry::SIZE`; any bytes past that are ignored.
+    pub fn try_from_bytes(buffer: &[u8]) -> Result<Self> {
+        let bytes = buffer
+            .get(..Self::SIZE)
+            .and_then(|bytes| <&[u8; 32]>::try_from(bytes).ok())
+            .ok_or(ExFatError::BadLength)?;
+        Ok(Self::from_bytes(bytes))
+    }
+    
+    /// Check that the entry has the given type
+    ///
+    /// Fails with `ExFatError::UnexpectedEntryType` otherwise. Deleted
+    /// entries have their own type codes, so never match.
+    fn expect_type(&self, entry_type: EntryType) -> Result<[u8; 32]> {
+        if self.get_type() != entry_type {
+            return Err(ExFatError::UnexpectedEntryType(self.entry_type));
+        }
+        Ok(self.to_bytes())
+    }
+    
+    /// Get the raw bytes of the entry
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1..].copy_from_slice(&self.data);
+        bytes
+    }
+    
     /// Get entry type
     pub fn get_type(&self) -> EntryType {
         EntryType::from(self.entry_type)
     }
     
+    /// Get the entry type and whether the entry is deleted
+    ///
+    /// A deleted entry keeps its type with the in-use bit (0x80) cleared,
+    /// so `0x05` is a deleted `File` entry. `get_type` reports such
+    /// entries as `Unknown`; this masks the bit first. The end of
+    /// directory marker is never deleted.
+    pub fn classify(&self) -> (EntryType, bool) {
+        if self.entry_type == EntryType::EndOfDirectory as u8 {
+            return (EntryType::EndOfDirectory, false);
+        }
+        (EntryType::from(self.entry_type | 0x80), (self.entry_type & 0x80) == 0)
+    }
+    
     /// Check if entry is in use
     pub fn is_in_use(&self) -> bool {
         self.entry_type != 0x00 && (self.entry_type & 0x80) != 0
     }
+    
+    /// Check if entry is an in-use secondary entry
+    pub fn is_secondary(&self) -> bool {
+        (self.entry_type & 0xC0) == 0xC0
+    }
 }
 
 /// File Directory Entry
 #[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct FileEntry {
     /// Entry type (0x85)
     pub entry_type: u8,
@@ -179,6 +493,149 @@ pub struct FileEntry {
     pub reserved2: [u8; 7],
 }
 
+impl FileEntry {
+    /// Byte offset of `set_checksum`
+    pub const SET_CHECKSUM_OFFSET: usize = 2;
+    
+    /// Bytes of the primary entry left out of the entry set checksum
+    ///
+    /// These are `set_checksum` itself.
+    pub const CHECKSUM_SKIP: [usize; 2] = [Self::SET_CHECKSUM_OFFSET, Self::SET_CHECKSUM_OFFSET + 1];
+    
+    /// Convert a generic entry, checking that it is a File entry (0x85)
+    pub fn from_entry(entry: &DirectoryEntry) -> Result<Self> {
+        Ok(Self::from_bytes(&entry.expect_type(EntryType::File)?))
+    }
+    
+    /// Parse a file entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut reserved2 = [0u8; 7];
+        reserved2.copy_from_slice(&bytes[25..32]);
+        
+        Self {
+            entry_type: bytes[0],
+            secondary_count: bytes[1],
+            set_checksum: le_u16(bytes, 2),
+            file_attributes: le_u16(bytes, 4),
+            reserved1: le_u16(bytes, 6),
+            create_timestamp: le_u32(bytes, 8),
+            last_modified_timestamp: le_u32(bytes, 12),
+            last_accessed_timestamp: le_u32(bytes, 16),
+            create_10ms: bytes[20],
+            last_modified_10ms: bytes[21],
+            create_utc_offset: bytes[22],
+            last_modified_utc_offset: bytes[23],
+            last_accessed_utc_offset: bytes[24],
+            reserved2,
+        }
+    }
+    
+    /// Serialize the file entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.secondary_count;
+        bytes[2..4].copy_from_slice(&{ self.set_checksum }.to_le_bytes());
+        bytes[4..6].copy_from_slice(&{ self.file_attributes }.to_le_bytes());
+        bytes[6..8].copy_from_slice(&{ self.reserved1 }.to_le_bytes());
+        bytes[8..12].copy_from_slice(&{ self.create_timestamp }.to_le_bytes());
+        bytes[12..16].copy_from_slice(&{ self.last_modified_timestamp }.to_le_bytes());
+        bytes[16..20].copy_from_slice(&{ self.last_accessed_timestamp }.to_le_bytes());
+        bytes[20] = self.create_10ms;
+        bytes[21] = self.last_modified_10ms;
+        bytes[22] = self.create_utc_offset;
+        bytes[23] = self.last_modified_utc_offset;
+        bytes[24] = self.last_accessed_utc_offset;
+        bytes[25..32].copy_from_slice(&self.reserved2);
+        bytes
+    }
+}
+
+/// Raw timestamps of a file entry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timestamps {
+    /// Create timestamp
+    pub create: u32,
+    /// Create 10ms increment
+    pub create_10ms: u8,
+    /// Create UTC offset
+    pub create_utc_offset: u8,
+    /// Last modified timestamp
+    pub modified: u32,
+    /// Last modified 10ms increment
+    pub modified_10ms: u8,
+    /// Last modified UTC offset
+    pub modified_utc_offset: u8,
+    /// Last accessed timestamp
+    pub accessed: u32,
+    /// Last accessed UTC offset
+    pub accessed_utc_offset: u8,
+}
+
+impl Timestamps {
+    /// Create timestamps with every field set to `time`
+    pub fn at(time: &DateTime) -> Result<Self> {
+        let (timestamp, increment_10ms, utc_offset) = time.to_exfat()?;
+        Ok(Self {
+            create: timestamp,
+            create_10ms: increment_10ms,
+            create_utc_offset: utc_offset,
+            modified: timestamp,
+            modified_10ms: increment_10ms,
+            modified_utc_offset: utc_offset,
+            accessed: timestamp,
+            accessed_utc_offset: utc_offset,
+        })
+    }
+    
+    /// Set the last modified and last accessed times to `time`
+    pub fn touch(&mut self, time: &DateTime) -> Result<()> {
+        let now = Self::at(time)?;
+        self.modified = now.modified;
+        self.modified_10ms = now.modified_10ms;
+        self.modified_utc_offset = now.modified_utc_offset;
+        self.accessed = now.accessed;
+        self.accessed_utc_offset = now.accessed_utc_offset;
+        Ok(())
+    }
+    
+    /// Set only the last accessed time to `time`
+    pub fn touch_accessed(&mut self, time: &DateTime) -> Result<()> {
+        let (timestamp, _, utc_offset) = time.to_exfat()?;
+        self.accessed = timestamp;
+        self.accessed_utc_offset = utc_offset;
+        Ok(())
+    }
+}
+
+impl FileEntry {
+    /// Get the entry's timestamps
+    pub fn timestamps(&self) -> Timestamps {
+        Timestamps {
+            create: self.create_timestamp,
+            create_10ms: self.create_10ms,
+            create_utc_offset: self.create_utc_offset,
+            modified: self.last_modified_timestamp,
+            modified_10ms: self.last_modified_10ms,
+            modified_utc_offset: self.last_modified_utc_offset,
+            accessed: self.last_accessed_timestamp,
+            accessed_utc_offset: self.last_accessed_utc_offset,
+        }
+    }
+    
+    /// Replace the entry's timestamps
+    pub fn set_timestamps(&mut self, timestamps: &Timestamps) {
+        self.create_timestamp = timestamps.create;
+        self.create_10ms = timestamps.create_10ms;
+        self.create_utc_offset = timestamps.create_utc_offset;
+        self.last_modified_timestamp = timestamps.modified;
+        self.last_modified_10ms = timestamps.modified_10ms;
+        self.last_modified_utc_offset = timestamps.modified_utc_offset;
+        self.last_accessed_timestamp = timestamps.accessed;
+        self.last_accessed_utc_offset = timestamps.accessed_utc_offset;
+    }
+}
+
 /// File attributes
 pub mod file_attributes {
     /// Read-only
@@ -195,7 +652,7 @@ pub mod file_attributes {
 
 /// Stream Extension Directory Entry
 #[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct StreamExtensionEntry {
     /// Entry type (0xC0)
     pub entry_type: u8,
@@ -219,6 +676,59 @@ pub struct StreamExtensionEntry {
     pub data_length: u64,
 }
 
+/// Stream extension general secondary flags
+pub mod stream_flags {
+    /// Allocation possible
+    pub const ALLOCATION_POSSIBLE: u8 = 0x01;
+    /// Clusters are contiguous and the FAT chain is not used
+    pub const NO_FAT_CHAIN: u8 = 0x02;
+}
+
+impl StreamExtensionEntry {
+    /// Convert a generic entry, checking that it is a Stream Extension
+    /// entry (0xC0)
+    pub fn from_entry(entry: &DirectoryEntry) -> Result<Self> {
+        Ok(Self::from_bytes(&entry.expect_type(EntryType::StreamExtension)?))
+    }
+    
+    /// Parse a stream extension entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        Self {
+            entry_type: bytes[0],
+            flags: bytes[1],
+            reserved1: bytes[2],
+            name_length: bytes[3],
+            name_hash: le_u16(bytes, 4),
+            reserved2: le_u16(bytes, 6),
+            valid_data_length: le_u64(bytes, 8),
+            reserved3: le_u32(bytes, 16),
+            first_cluster: le_u32(bytes, 20),
+            data_length: le_u64(bytes, 24),
+        }
+    }
+    
+    /// Serialize the stream extension entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.flags;
+        bytes[2] = self.reserved1;
+        bytes[3] = self.name_length;
+        bytes[4..6].copy_from_slice(&{ self.name_hash }.to_le_bytes());
+        bytes[6..8].copy_from_slice(&{ self.reserved2 }.to_le_bytes());
+        bytes[8..16].copy_from_slice(&{ self.valid_data_length }.to_le_bytes());
+        bytes[16..20].copy_from_slice(&{ self.reserved3 }.to_le_bytes());
+        bytes[20..24].copy_from_slice(&{ self.first_cluster }.to_le_bytes());
+        bytes[24..32].copy_from_slice(&{ self.data_length }.to_le_bytes());
+        bytes
+    }
+    
+    /// Check if the stream's clusters are contiguous (no FAT chain)
+    pub fn is_contiguous(&self) -> bool {
+        (self.flags & stream_flags::NO_FAT_CHAIN) != 0
+    }
+}
+
 /// File Name Directory Entry
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -231,6 +741,888 @@ pub struct FileNameEntry {
     pub file_name: [u16; 15],
 }
 
+impl FileNameEntry {
+    /// Number of name characters held by one entry
+    pub const CHARS_PER_ENTRY: usize = 15;
+    
+    /// Convert a generic entry, checking that it is a File Name entry
+    /// (0xC1)
+    pub fn from_entry(entry: &DirectoryEntry) -> Result<Self> {
+        Ok(Self::from_bytes(&entry.expect_type(EntryType::FileName)?))
+    }
+    
+    /// Parse a file name entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut file_name = [0u16; 15];
+        for (i, c) in file_name.iter_mut().enumerate() {
+            *c = le_u16(bytes, 2 + i * 2);
+        }
+        
+        Self {
+            entry_type: bytes[0],
+            flags: bytes[1],
+            file_name,
+        }
+    }
+    
+    /// Serialize the file name entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.flags;
+        let file_name = self.file_name;
+        for (i, c) in file_name.iter().enumerate() {
+            bytes[2 + i * 2..4 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Which of a volume's allocation bitmaps an entry describes
+///
+/// Only TexFAT volumes, which have two FATs, have a second bitmap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapId {
+    /// First allocation bitmap
+    First,
+    /// Second allocation bitmap (TexFAT only)
+    Second,
+}
+
+/// Allocation Bitmap Directory Entry
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationBitmapEntry {
+    /// Entry type (0x81)
+    pub entry_type: u8,
+    /// Bitmap flags (bit 0 selects the second bitmap)
+    pub bitmap_flags: u8,
+    /// Reserved
+    pub reserved: [u8; 18],
+    /// First cluster of the bitmap
+    pub first_cluster: u32,
+    /// Bitmap length in bytes
+    pub data_length: u64,
+}
+
+impl AllocationBitmapEntry {
+    /// Parse an allocation bitmap entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut reserved = [0u8; 18];
+        reserved.copy_from_slice(&bytes[2..20]);
+        
+        Self {
+            entry_type: bytes[0],
+            bitmap_flags: bytes[1],
+            reserved,
+            first_cluster: le_u32(bytes, 20),
+            data_length: le_u64(bytes, 24),
+        }
+    }
+    
+    /// Get which bitmap the entry describes, from bit 0 of the flags
+    pub fn bitmap_id(&self) -> BitmapId {
+        if (self.bitmap_flags & 1) != 0 {
+            BitmapId::Second
+        } else {
+            BitmapId::First
+        }
+    }
+    
+    /// Get the index of the bitmap the entry describes, 0 or 1
+    ///
+    /// Matches the numbering of `BootSector::active_fat`.
+    pub fn bitmap_number(&self) -> u8 {
+        self.bitmap_flags & 1
+    }
+    
+    /// Serialize the allocation bitmap entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.bitmap_flags;
+        bytes[2..20].copy_from_slice(&self.reserved);
+        bytes[20..24].copy_from_slice(&{ self.first_cluster }.to_le_bytes());
+        bytes[24..32].copy_from_slice(&{ self.data_length }.to_le_bytes());
+        bytes
+    }
+}
+
+/// Up-case Table Directory Entry
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct UpCaseTableEntry {
+    /// Entry type (0x82)
+    pub entry_type: u8,
+    /// Reserved
+    pub reserved1: [u8; 3],
+    /// Table checksum
+    pub table_checksum: u32,
+    /// Reserved
+    pub reserved2: [u8; 12],
+    /// First cluster of the table
+    pub first_cluster: u32,
+    /// Table length in bytes
+    pub data_length: u64,
+}
+
+impl UpCaseTableEntry {
+    /// Parse an up-case table entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut reserved1 = [0u8; 3];
+        reserved1.copy_from_slice(&bytes[1..4]);
+        let mut reserved2 = [0u8; 12];
+        reserved2.copy_from_slice(&bytes[8..20]);
+        
+        Self {
+            entry_type: bytes[0],
+            reserved1,
+            table_checksum: le_u32(bytes, 4),
+            reserved2,
+            first_cluster: le_u32(bytes, 20),
+            data_length: le_u64(bytes, 24),
+        }
+    }
+    
+    /// Serialize the up-case table entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1..4].copy_from_slice(&self.reserved1);
+        bytes[4..8].copy_from_slice(&{ self.table_checksum }.to_le_bytes());
+        bytes[8..20].copy_from_slice(&self.reserved2);
+        bytes[20..24].copy_from_slice(&{ self.first_cluster }.to_le_bytes());
+        bytes[24..32].copy_from_slice(&{ self.data_length }.to_le_bytes());
+        bytes
+    }
+}
+
+/// Volume Label Directory Entry
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeLabelEntry {
+    /// Entry type (0x83)
+    pub entry_type: u8,
+    /// Label length in UTF-16 code units
+    pub character_count: u8,
+    /// Volume label (UTF-16)
+    pub volume_label: [u16; 11],
+    /// Reserved
+    pub reserved: [u8; 8],
+}
+
+impl VolumeLabelEntry {
+    /// Maximum label length in UTF-16 code units
+    pub const MAX_CHARS: usize = 11;
+    
+    /// Parse a volume label entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut volume_label = [0u16; 11];
+        for (i, c) in volume_label.iter_mut().enumerate() {
+            *c = le_u16(bytes, 2 + i * 2);
+        }
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&bytes[24..32]);
+        
+        Self {
+            entry_type: bytes[0],
+            character_count: bytes[1],
+            volume_label,
+            reserved,
+        }
+    }
+    
+    /// Serialize the volume label entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.character_count;
+        let volume_label = self.volume_label;
+        for (i, c) in volume_label.iter().enumerate() {
+            bytes[2 + i * 2..4 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        bytes[24..32].copy_from_slice(&self.reserved);
+        bytes
+    }
+    
+    /// Get the label as a string
+    ///
+    /// A character count past the 11-unit limit is clamped.
+    pub fn label(&self) -> String {
+        let volume_label = self.volume_label;
+        let len = (self.character_count as usize).min(Self::MAX_CHARS);
+        String::from_utf16_lossy(&volume_label[..len])
+    }
+}
+
+/// Volume GUID Directory Entry
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeGuidEntry {
+    /// Entry type (0xA0)
+    pub entry_type: u8,
+    /// Secondary count (always 0)
+    pub secondary_count: u8,
+    /// Set checksum
+    pub set_checksum: u16,
+    /// General primary flags
+    pub flags: u16,
+    /// Volume GUID
+    pub volume_guid: [u8; 16],
+    /// Reserved
+    pub reserved: [u8; 10],
+}
+
+impl VolumeGuidEntry {
+    /// Create a volume GUID entry with a valid checksum
+    pub fn new(guid: [u8; 16]) -> Self {
+        let mut entry = Self {
+            entry_type: EntryType::VolumeGuid as u8,
+            secondary_count: 0,
+            set_checksum: 0,
+            flags: 0,
+            volume_guid: guid,
+            reserved: [0; 10],
+        };
+        entry.set_checksum = entry_set_checksum(&[entry.to_bytes()]);
+        entry
+    }
+    
+    /// Parse a volume GUID entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut volume_guid = [0u8; 16];
+        volume_guid.copy_from_slice(&bytes[6..22]);
+        let mut reserved = [0u8; 10];
+        reserved.copy_from_slice(&bytes[22..32]);
+        
+        Self {
+            entry_type: bytes[0],
+            secondary_count: bytes[1],
+            set_checksum: le_u16(bytes, 2),
+            flags: le_u16(bytes, 4),
+            volume_guid,
+            reserved,
+        }
+    }
+    
+    /// Serialize the volume GUID entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.secondary_count;
+        bytes[2..4].copy_from_slice(&{ self.set_checksum }.to_le_bytes());
+        bytes[4..6].copy_from_slice(&{ self.flags }.to_le_bytes());
+        bytes[6..22].copy_from_slice(&self.volume_guid);
+        bytes[22..32].copy_from_slice(&self.reserved);
+        bytes
+    }
+    
+    /// Check the stored checksum against the entry contents
+    pub fn checksum_valid(&self) -> bool {
+        self.set_checksum == entry_set_checksum(&[self.to_bytes()])
+    }
+}
+
+/// Vendor Extension Directory Entry
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VendorExtensionEntry {
+    /// Entry type (0xE0)
+    pub entry_type: u8,
+    /// General secondary flags
+    pub flags: u8,
+    /// Vendor GUID
+    pub vendor_guid: [u8; 16],
+    /// Vendor-defined data
+    pub vendor_defined: [u8; 14],
+}
+
+impl VendorExtensionEntry {
+    /// Parse a vendor extension entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut vendor_guid = [0u8; 16];
+        vendor_guid.copy_from_slice(&bytes[2..18]);
+        let mut vendor_defined = [0u8; 14];
+        vendor_defined.copy_from_slice(&bytes[18..32]);
+        
+        Self {
+            entry_type: bytes[0],
+            flags: bytes[1],
+            vendor_guid,
+            vendor_defined,
+        }
+    }
+    
+    /// Serialize the vendor extension entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.flags;
+        bytes[2..18].copy_from_slice(&self.vendor_guid);
+        bytes[18..32].copy_from_slice(&self.vendor_defined);
+        bytes
+    }
+}
+
+/// Vendor Allocation Directory Entry
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VendorAllocationEntry {
+    /// Entry type (0xE1)
+    pub entry_type: u8,
+    /// General secondary flags
+    pub flags: u8,
+    /// Vendor GUID
+    pub vendor_guid: [u8; 16],
+    /// Vendor-defined data
+    pub vendor_defined: u16,
+    /// First cluster of the vendor data
+    pub first_cluster: u32,
+    /// Vendor data length
+    pub data_length: u64,
+}
+
+impl VendorAllocationEntry {
+    /// Parse a vendor allocation entry from its on-disk bytes
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut vendor_guid = [0u8; 16];
+        vendor_guid.copy_from_slice(&bytes[2..18]);
+        
+        Self {
+            entry_type: bytes[0],
+            flags: bytes[1],
+            vendor_guid,
+            vendor_defined: le_u16(bytes, 18),
+            first_cluster: le_u32(bytes, 20),
+            data_length: le_u64(bytes, 24),
+        }
+    }
+    
+    /// Serialize the vendor allocation entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0] = self.entry_type;
+        bytes[1] = self.flags;
+        bytes[2..18].copy_from_slice(&self.vendor_guid);
+        bytes[18..20].copy_from_slice(&{ self.vendor_defined }.to_le_bytes());
+        bytes[20..24].copy_from_slice(&{ self.first_cluster }.to_le_bytes());
+        bytes[24..32].copy_from_slice(&{ self.data_length }.to_le_bytes());
+        bytes
+    }
+}
+
+/// Vendor secondary entry of a file entry set
+///
+/// The vendor GUID is kept whether or not it is recognised, so the entry
+/// survives being rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VendorEntry {
+    /// Vendor extension entry
+    Extension(VendorExtensionEntry),
+    /// Vendor allocation entry
+    Allocation(VendorAllocationEntry),
+}
+
+impl VendorEntry {
+    /// Parse a vendor entry, or `None` if the bytes hold another type
+    pub fn from_bytes(bytes: &[u8; 32]) -> Option<Self> {
+        match EntryType::from(bytes[0]) {
+            EntryType::VendorExtension => Some(VendorEntry::Extension(VendorExtensionEntry::from_bytes(bytes))),
+            EntryType::VendorAllocation => Some(VendorEntry::Allocation(VendorAllocationEntry::from_bytes(bytes))),
+            _ => None,
+        }
+    }
+    
+    /// Serialize the vendor entry to its on-disk bytes
+    pub fn to_bytes(&self) -> [u8; 32] {
+        match self {
+            VendorEntry::Extension(entry) => entry.to_bytes(),
+            VendorEntry::Allocation(entry) => entry.to_bytes(),
+        }
+    }
+    
+    /// Get the vendor GUID
+    pub fn vendor_guid(&self) -> [u8; 16] {
+        match self {
+            VendorEntry::Extension(entry) => entry.vendor_guid,
+            VendorEntry::Allocation(entry) => entry.vendor_guid,
+        }
+    }
+}
+
+/// Directory entry decoded according to its type code
+#[derive(Debug, Clone, Copy)]
+pub enum ParsedEntry {
+    /// File directory entry
+    File(FileEntry),
+    /// Stream extension entry
+    Stream(StreamExtensionEntry),
+    /// File name entry
+    Name(FileNameEntry),
+    /// Allocation bitmap entry
+    Bitmap(AllocationBitmapEntry),
+    /// Up-case table entry
+    UpCase(UpCaseTableEntry),
+    /// Volume label entry
+    Label(VolumeLabelEntry),
+    /// Volume GUID entry
+    Guid(VolumeGuidEntry),
+    /// Vendor extension or allocation entry
+    Vendor(VendorEntry),
+    /// End of directory marker
+    EndOfDirectory,
+    /// Deleted, unused or unrecognised entry, with its type code
+    Unknown(u8),
+}
+
+impl DirectoryEntry {
+    /// Decode the entry according to its type code
+    pub fn parse(&self) -> ParsedEntry {
+        let bytes = self.to_bytes();
+        match self.get_type() {
+            EntryType::EndOfDirectory => ParsedEntry::EndOfDirectory,
+            EntryType::AllocationBitmap => ParsedEntry::Bitmap(AllocationBitmapEntry::from_bytes(&bytes)),
+            EntryType::UpCaseTable => ParsedEntry::UpCase(UpCaseTableEntry::from_bytes(&bytes)),
+            EntryType::VolumeLabel => ParsedEntry::Label(VolumeLabelEntry::from_bytes(&bytes)),
+            EntryType::File => ParsedEntry::File(FileEntry::from_bytes(&bytes)),
+            EntryType::VolumeGuid => ParsedEntry::Guid(VolumeGuidEntry::from_bytes(&bytes)),
+            EntryType::StreamExtension => ParsedEntry::Stream(StreamExtensionEntry::from_bytes(&bytes)),
+            EntryType::FileName => ParsedEntry::Name(FileNameEntry::from_bytes(&bytes)),
+            EntryType::VendorExtension | EntryType::VendorAllocation => {
+                VendorEntry::from_bytes(&bytes).map_or(ParsedEntry::Unknown(self.entry_type), ParsedEntry::Vendor)
+            }
+            EntryType::Unknown => ParsedEntry::Unknown(self.entry_type),
+        }
+    }
+}
+
+/// Iterator over the raw 32-byte entries of a directory buffer
+///
+/// Trailing bytes that don't make up a whole entry are ignored. Entries
+/// are returned whatever their type, including system, deleted and
+/// unrecognised entries.
+#[derive(Debug, Clone)]
+pub struct RawEntryIter<'a> {
+    /// Remaining whole entries
+    chunks: core::slice::ChunksExact<'a, u8>,
+    /// End iteration at the first end of directory marker
+    stop_at_end: bool,
+}
+
+impl<'a> RawEntryIter<'a> {
+    /// Iterate up to, but not including, the first end of directory marker
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self {
+            chunks: buffer.chunks_exact(DirectoryEntry::SIZE),
+            stop_at_end: true,
+        }
+    }
+    
+    /// Iterate every whole entry, including end of directory markers and
+    /// anything after them
+    pub fn full(buffer: &'a [u8]) -> Self {
+        Self {
+            chunks: buffer.chunks_exact(DirectoryEntry::SIZE),
+            stop_at_end: false,
+        }
+    }
+}
+
+impl<'a> Iterator for RawEntryIter<'a> {
+    type Item = &'a [u8; 32];
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry: &[u8; 32] = self.chunks.next()?.try_into().ok()?;
+        if self.stop_at_end && entry[0] == EntryType::EndOfDirectory as u8 {
+            self.chunks = [].chunks_exact(DirectoryEntry::SIZE);
+            return None;
+        }
+        Some(entry)
+    }
+}
+
+/// Maximum file name length in UTF-16 code units
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Check that a file name is legal on exFAT
+///
+/// Names must be non-empty and must not contain control characters or any
+/// of `" * / : < > ? \ |`.
+pub fn validate_file_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(ExFatError::InvalidName);
+    }
+    
+    let illegal = |c: char| {
+        (c as u32) < 0x20 || matches!(c, '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|')
+    };
+    if name.chars().any(illegal) {
+        return Err(ExFatError::InvalidName);
+    }
+    
+    Ok(())
+}
+
+/// Validated exFAT file name
+///
+/// The name is held as UTF-16, since that's how it is stored on disk and
+/// how the length limit is defined. Characters outside the BMP take two
+/// code units each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileName {
+    /// UTF-16 code units
+    units: Vec<u16>,
+}
+
+impl FileName {
+    /// Validate and encode a file name
+    ///
+    /// Fails with `NameTooLong` if the name needs more than
+    /// `MAX_NAME_LENGTH` UTF-16 code units, regardless of its char count.
+    pub fn new(name: &str) -> Result<Self> {
+        validate_file_name(name)?;
+        
+        let units: Vec<u16> = name.encode_utf16().collect();
+        if units.len() > MAX_NAME_LENGTH {
+            return Err(ExFatError::NameTooLong);
+        }
+        
+        Ok(Self { units })
+    }
+    
+    /// Get the name length in UTF-16 code units
+    ///
+    /// This is the value stored in the stream extension `name_length` field.
+    pub fn utf16_len(&self) -> usize {
+        self.units.len()
+    }
+    
+    /// Get the UTF-16 code units
+    pub fn as_utf16(&self) -> &[u16] {
+        &self.units
+    }
+    
+    /// Take the UTF-16 code units
+    pub fn into_utf16(self) -> Vec<u16> {
+        self.units
+    }
+}
+
+/// Encode a file name to UTF-16, validating it along the way
+///
+/// Fails if `validate_file_name` rejects the name or it needs more than
+/// `MAX_NAME_LENGTH` code units; a surrogate pair counts as two.
+pub fn to_utf16_name(name: &str) -> Result<Vec<u16>> {
+    FileName::new(name).map(FileName::into_utf16)
+}
+
+/// Count the directory entries a file named `name` takes
+///
+/// This is the file and stream extension entries plus one name entry per
+/// 15 UTF-16 code units. The name isn't validated.
+pub fn entries_needed_for_name(name: &str) -> usize {
+    2 + name.encode_utf16().count().div_ceil(FileNameEntry::CHARS_PER_ENTRY)
+}
+
+/// Decode a file name read from disk
+///
+/// Unpaired surrogates, which only a corrupt or foreign entry set can
+/// hold, are replaced with U+FFFD rather than failing the lookup.
+pub fn from_utf16_name(units: &[u16]) -> String {
+    String::from_utf16_lossy(units)
+}
+
+/// Up-case a single UTF-16 code unit
+///
+/// Used when no up-case table is available. Characters whose upper case
+/// form is not a single BMP character are left unchanged.
+pub fn upcase_char(c: u16) -> u16 {
+    let Some(ch) = char::from_u32(c as u32) else {
+        return c;
+    };
+    
+    let mut upper = ch.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(u), None) if (u as u32) <= 0xFFFF => u as u16,
+        _ => c,
+    }
+}
+
+/// Compare two UTF-16 names case-insensitively
+pub fn names_equal(a: &[u16], b: &[u16]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| upcase_char(x) == upcase_char(y))
+}
+
+/// Compute the name hash stored in the stream extension entry
+pub fn name_hash(name: &[u16]) -> u16 {
+    name_hash_with(name, upcase_char)
+}
+
+/// Compute the name hash using `upcase` to map each code unit
+pub fn name_hash_with(name: &[u16], upcase: impl Fn(u16) -> u16) -> u16 {
+    let mut hash: u16 = 0;
+    for &c in name {
+        let c = upcase(c);
+        hash = hash.rotate_right(1).wrapping_add(c & 0xFF);
+        hash = hash.rotate_right(1).wrapping_add(c >> 8);
+    }
+    hash
+}
+
+/// Compute the boot region checksum
+///
+/// `region` holds the first eleven sectors of a boot region, which is
+/// `BootSector::boot_region_len` bytes long. The `volume_flags` and
+/// `percent_in_use` fields are skipped, so they can be updated without
+/// rewriting the checksum sector.
+pub fn boot_checksum(region: &[u8]) -> u32 {
+    boot_checksum_update(0, 0, region)
+}
+
+/// Fold part of a boot region into a running boot checksum
+///
+/// `chunk` starts at byte `offset` of the boot region. Feeding the region
+/// a sector at a time from a checksum of 0 gives the same result as
+/// `boot_checksum`.
+pub fn boot_checksum_update(checksum: u32, offset: usize, chunk: &[u8]) -> u32 {
+    rotate_checksum_update(checksum, offset, chunk, &BootSector::CHECKSUM_SKIP)
+}
+
+/// Fold bytes into a 32-bit rotate-and-add checksum, leaving some out
+///
+/// `chunk` starts at byte `offset` of the checksummed data, and `skip`
+/// lists the offsets within that data whose bytes don't count. This is
+/// the algorithm behind both the boot checksum and the up-case table
+/// checksum.
+pub fn rotate_checksum_update(mut checksum: u32, offset: usize, chunk: &[u8], skip: &[usize]) -> u32 {
+    for (i, &byte) in chunk.iter().enumerate() {
+        if skip.contains(&(offset + i)) {
+            continue;
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(byte as u32);
+    }
+    checksum
+}
+
+/// Compute the checksum of an up-case table's on-disk contents
+///
+/// Stored in the up-case table entry's `table_checksum` field.
+pub fn table_checksum(data: &[u8]) -> u32 {
+    rotate_checksum_update(0, 0, data, &[])
+}
+
+/// Compute the CRC-32 (IEEE 802.3) of `data`
+///
+/// Not part of exFAT; used to verify file contents.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Fold bytes into a running CRC-32
+///
+/// Feeding data in pieces, starting from 0, gives the same result as
+/// `crc32` over all of it.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Lookup table for the reflected CRC-32 polynomial
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Compute the checksum of a directory entry set
+///
+/// The set checksum field (bytes 2 and 3 of the primary entry) is skipped.
+pub fn entry_set_checksum(entries: &[[u8; 32]]) -> u16 {
+    let mut checksum: u16 = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        for (j, &byte) in entry.iter().enumerate() {
+            if i == 0 && FileEntry::CHECKSUM_SKIP.contains(&j) {
+                continue;
+            }
+            checksum = checksum.rotate_right(1).wrapping_add(byte as u16);
+        }
+    }
+    checksum
+}
+
+/// Build a complete file entry set for `name`
+///
+/// The primary and stream entries are taken as templates; the secondary
+/// count, name length, name hash, name entries and set checksum are filled
+/// in from `name`. The name is hashed through `upcase`, falling back to the
+/// built-in case mapping when no table is given.
+pub fn build_entry_set(
+    file: &FileEntry,
+    stream: &StreamExtensionEntry,
+    name: &[u16],
+    upcase: Option<&UpCaseTable>,
+) -> Vec<[u8; 32]> {
+    let name_entries = name.len().div_ceil(FileNameEntry::CHARS_PER_ENTRY);
+    
+    let mut file = *file;
+    file.entry_type = EntryType::File as u8;
+    file.secondary_count = (1 + name_entries) as u8;
+    
+    let mut stream = *stream;
+    stream.entry_type = EntryType::StreamExtension as u8;
+    stream.name_length = name.len() as u8;
+    stream.name_hash = upcase.map_or_else(|| name_hash(name), |upcase| upcase.name_hash(name));
+    
+    let mut entries = Vec::with_capacity(2 + name_entries);
+    entries.push(file.to_bytes());
+    entries.push(stream.to_bytes());
+    
+    for chunk in name.chunks(FileNameEntry::CHARS_PER_ENTRY) {
+        let mut file_name = [0u16; 15];
+        file_name[..chunk.len()].copy_from_slice(chunk);
+        entries.push(FileNameEntry {
+            entry_type: EntryType::FileName as u8,
+            flags: 0,
+            file_name,
+        }.to_bytes());
+    }
+    
+    let checksum = entry_set_checksum(&entries);
+    entries[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+    entries
+}
+
+/// Builder for a complete file entry set
+///
+/// Collects the fields of a new file or directory and emits the primary,
+/// stream and name entries with the name hash and set checksum filled in.
+#[derive(Debug, Clone)]
+pub struct EntrySetBuilder {
+    /// File attributes
+    attributes: u16,
+    /// File name
+    name: String,
+    /// First cluster of the data
+    first_cluster: u32,
+    /// Data length in bytes
+    size: u64,
+    /// Valid data length, defaulting to the data length
+    valid_data_length: Option<u64>,
+    /// Stream flags
+    flags: u8,
+    /// Timestamps
+    timestamps: Timestamps,
+}
+
+impl EntrySetBuilder {
+    /// Start a new entry set with the given attributes
+    pub fn new(attributes: u16) -> Self {
+        Self {
+            attributes,
+            name: String::new(),
+            first_cluster: 0,
+            size: 0,
+            valid_data_length: None,
+            flags: stream_flags::ALLOCATION_POSSIBLE,
+            timestamps: Timestamps::default(),
+        }
+    }
+    
+    /// Set the file name
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = String::from(name);
+        self
+    }
+    
+    /// Set the first cluster of the data
+    pub fn first_cluster(mut self, first_cluster: u32) -> Self {
+        self.first_cluster = first_cluster;
+        self
+    }
+    
+    /// Set the data length
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+    
+    /// Set the valid data length
+    pub fn valid_data_length(mut self, valid_data_length: u64) -> Self {
+        self.valid_data_length = Some(valid_data_length);
+        self
+    }
+    
+    /// Mark the data as one contiguous run with no FAT chain
+    pub fn contiguous(mut self, contiguous: bool) -> Self {
+        if contiguous {
+            self.flags |= stream_flags::NO_FAT_CHAIN;
+        } else {
+            self.flags &= !stream_flags::NO_FAT_CHAIN;
+        }
+        self
+    }
+    
+    /// Set the timestamps
+    pub fn timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+    
+    /// Emit the entry set, hashing the name through `upcase`
+    ///
+    /// Fails if the name is invalid or longer than `MAX_NAME_LENGTH` UTF-16
+    /// code units.
+    pub fn build(&self, upcase: Option<&UpCaseTable>) -> Result<Vec<[u8; 32]>> {
+        let name = FileName::new(&self.name)?;
+        
+        let mut file = FileEntry {
+            file_attributes: self.attributes,
+            ..Default::default()
+        };
+        file.set_timestamps(&self.timestamps);
+        
+        let stream = StreamExtensionEntry {
+            flags: self.flags,
+            first_cluster: self.first_cluster,
+            data_length: self.size,
+            valid_data_length: self.valid_data_length.unwrap_or(self.size),
+            ..Default::default()
+        };
+        
+        Ok(build_entry_set(&file, &stream, name.as_utf16(), upcase))
+    }
+}
+
+/// Read a little-endian u16 at `offset`
+pub(crate) fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Read a little-endian u32 at `offset`
+pub(crate) fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+/// Read a little-endian u64 at `offset`
+pub(crate) fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
 /// exFAT File Allocation Table Entry
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FatEntry {
@@ -240,21 +1632,36 @@ pub enum FatEntry {
     Next(u32),
     /// Bad cluster
     Bad,
-    /// End of cluster chain
+    /// End of cluster chain, stored as `0xFFFFFFFF`
     EndOfChain,
+    /// End of cluster chain stored as another marker in
+    /// `0xFFFFFFF8..=0xFFFFFFFE`, kept so it is written back unchanged
+    EndOfChainValue(u32),
 }
 
 impl FatEntry {
+    /// Fixed values of FAT entries 0 and 1
+    ///
+    /// Entry 0 holds the media descriptor and entry 1 is unused; neither
+    /// describes a cluster.
+    pub const RESERVED_VALUES: [u32; 2] = [0xFFFF_FFF8, 0xFFFF_FFFF];
+    
     /// Parse FAT entry from u32 value
     pub fn from_u32(value: u32) -> Self {
         match value {
             0x00000000 => FatEntry::Free,
             0xFFFFFFF7 => FatEntry::Bad,
-            0xFFFFFFF8..=0xFFFFFFFF => FatEntry::EndOfChain,
+            0xFFFFFFFF => FatEntry::EndOfChain,
+            0xFFFFFFF8..=0xFFFFFFFE => FatEntry::EndOfChainValue(value),
             cluster => FatEntry::Next(cluster),
         }
     }
     
+    /// Check if the entry ends a chain, whichever marker it uses
+    pub fn is_end_of_chain(&self) -> bool {
+        matches!(self, FatEntry::EndOfChain | FatEntry::EndOfChainValue(_))
+    }
+    
     /// Convert FAT entry to u32 value
     pub fn to_u32(&self) -> u32 {
         match self {
@@ -262,6 +1669,7 @@ impl FatEntry {
             FatEntry::Next(cluster) => *cluster,
             FatEntry::Bad => 0xFFFFFFF7,
             FatEntry::EndOfChain => 0xFFFFFFFF,
+            FatEntry::EndOfChainValue(value) => *value,
         }
     }
 }
@@ -270,12 +1678,226 @@ impl FatEntry {
 mod tests {
     use super::*;
     use core::mem;
+    use alloc::string::String;
     
     #[test]
     fn test_boot_sector_size() {
         assert_eq!(mem::size_of::<BootSector>(), BootSector::SIZE);
     }
     
+    #[test]
+    fn test_boot_checksum_skips_flags() {
+        let mut region = [0x5Au8; 11 * 512];
+        let checksum = boot_checksum(&region);
+        
+        region[BootSector::VOLUME_FLAGS_OFFSET] = 0x02;
+        region[BootSector::PERCENT_IN_USE_OFFSET] = 50;
+        assert_eq!(boot_checksum(&region), checksum);
+        
+        region[BootSector::VOLUME_FLAGS_OFFSET - 1] = 0;
+        assert_ne!(boot_checksum(&region), checksum);
+        
+        let sectors = region.chunks(512).enumerate();
+        let incremental = sectors.fold(0, |sum, (i, sector)| boot_checksum_update(sum, i * 512, sector));
+        assert_eq!(incremental, boot_checksum(&region));
+    }
+    
+    #[test]
+    fn test_checksum_skip_offsets() {
+        assert_eq!(BootSector::VOLUME_FLAGS_OFFSET, mem::offset_of!(BootSector, volume_flags));
+        assert_eq!(BootSector::PERCENT_IN_USE_OFFSET, mem::offset_of!(BootSector, percent_in_use));
+        assert_eq!(BootSector::CHECKSUM_SKIP, [106, 107, 112]);
+        
+        assert_eq!(FileEntry::SET_CHECKSUM_OFFSET, mem::offset_of!(FileEntry, set_checksum));
+        assert_eq!(FileEntry::CHECKSUM_SKIP, [2, 3]);
+        
+        // Changing the skipped bytes of the primary leaves the checksum alone
+        let mut entries = [[0x33u8; 32]; 3];
+        let checksum = entry_set_checksum(&entries);
+        entries[0][2] = 0;
+        entries[0][3] = 0;
+        assert_eq!(entry_set_checksum(&entries), checksum);
+        entries[1][2] = 0;
+        assert_ne!(entry_set_checksum(&entries), checksum);
+    }
+    
+    #[test]
+    fn test_describe() {
+        let mut boot_sector = BootSector::from_bytes(&[0; 512]);
+        boot_sector.volume_length = 1 << 21;
+        boot_sector.fat_offset = 128;
+        boot_sector.fat_length = 64;
+        boot_sector.cluster_heap_offset = 256;
+        boot_sector.cluster_count = 8160;
+        boot_sector.fs_revision = 0x0100;
+        boot_sector.bytes_per_sector_shift = 12;
+        boot_sector.sectors_per_cluster_shift = 8;
+        boot_sector.num_fats = 1;
+        
+        assert_eq!(boot_sector.describe(), Ok(GeometrySummary {
+            bytes_per_sector: 4096,
+            sectors_per_cluster: 256,
+            bytes_per_cluster: 1 << 20,
+            volume_bytes: 8 << 30,
+            cluster_count: 8160,
+            fat_offset: 512 * 1024,
+            fat_length: 256 * 1024,
+            num_fats: 1,
+            cluster_heap_offset: 1 << 20,
+            cluster_heap_length: 8160 << 20,
+            revision: (1, 0),
+        }));
+        
+        boot_sector.volume_length = u64::MAX / 2;
+        assert_eq!(boot_sector.describe(), Err(ExFatError::Corrupted));
+        boot_sector.volume_length = 1 << 21;
+        boot_sector.sectors_per_cluster_shift = 64;
+        assert_eq!(boot_sector.describe(), Err(ExFatError::Corrupted));
+    }
+    
+    #[test]
+    fn test_required_fat_sectors() {
+        let mut boot_sector = BootSector::from_bytes(&[0; 512]);
+        boot_sector.bytes_per_sector_shift = 9;
+        boot_sector.cluster_count = 126;
+        assert_eq!(boot_sector.required_fat_sectors(), 1);
+        // One more entry spills into a second sector
+        boot_sector.cluster_count = 127;
+        assert_eq!(boot_sector.required_fat_sectors(), 2);
+        
+        boot_sector.bytes_per_sector_shift = 12;
+        boot_sector.cluster_count = 1023;
+        assert_eq!(boot_sector.required_fat_sectors(), 2);
+        boot_sector.cluster_count = u32::MAX;
+        assert_eq!(boot_sector.required_fat_sectors(), (1 << 22) + 1);
+    }
+    
+    #[test]
+    fn test_is_valid_cluster() {
+        let mut boot_sector = BootSector::from_bytes(&[0; 512]);
+        boot_sector.cluster_count = 64;
+        assert!(!boot_sector.is_valid_cluster(0));
+        assert!(!boot_sector.is_valid_cluster(1));
+        assert!(boot_sector.is_valid_cluster(2));
+        assert!(boot_sector.is_valid_cluster(65));
+        assert!(!boot_sector.is_valid_cluster(66));
+        
+        boot_sector.cluster_count = u32::MAX - 1;
+        assert!(boot_sector.is_valid_cluster(u32::MAX));
+        boot_sector.cluster_count = 0;
+        assert!(!boot_sector.is_valid_cluster(2));
+    }
+    
+    #[test]
+    fn test_boot_checksum_skipped_bytes() {
+        let mut region = [0u8; 11 * 512];
+        for (i, byte) in region.iter_mut().enumerate() {
+            *byte = (i * 7 + 3) as u8;
+        }
+        let checksum = boot_checksum(&region);
+        
+        for offset in [106, 107, 112] {
+            let mut changed = region;
+            changed[offset] ^= 0xFF;
+            assert_eq!(boot_checksum(&changed), checksum, "byte {offset}");
+        }
+        
+        // The byte between the skipped fields does count
+        region[108] ^= 0xFF;
+        assert_ne!(boot_checksum(&region), checksum);
+        
+        // Skipping nothing is the plain checksum, and skipping a byte is
+        // the same as removing it
+        assert_eq!(rotate_checksum_update(0, 0, &region, &[]), table_checksum(&region));
+        let mut without = region.to_vec();
+        without.remove(108);
+        assert_eq!(rotate_checksum_update(0, 0, &region, &[108]), table_checksum(&without));
+    }
+    
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31) as u8).collect();
+        let pieces = data.chunks(77).fold(0, crc32_update);
+        assert_eq!(pieces, crc32(&data));
+    }
+    
+    #[test]
+    fn test_boot_sector_from_bytes() {
+        let mut bytes = [0u8; 512];
+        bytes[3..11].copy_from_slice(b"EXFAT   ");
+        bytes[80..84].copy_from_slice(&24u32.to_le_bytes());
+        bytes[92..96].copy_from_slice(&64u32.to_le_bytes());
+        bytes[106..108].copy_from_slice(&volume_flags::VOLUME_DIRTY.to_le_bytes());
+        bytes[108] = 9;
+        bytes[109] = 3;
+        bytes[510..512].copy_from_slice(&0xAA55u16.to_le_bytes());
+        
+        let boot_sector = BootSector::from_bytes(&bytes);
+        assert!(boot_sector.is_valid());
+        assert!(boot_sector.is_valid_strict());
+        assert_eq!({ boot_sector.fat_offset }, 24);
+        assert_eq!({ boot_sector.cluster_count }, 64);
+        assert_eq!({ boot_sector.volume_flags }, volume_flags::VOLUME_DIRTY);
+        assert_eq!(boot_sector.bytes_per_cluster(), 4096);
+        assert_eq!(boot_sector.to_bytes(), bytes);
+        
+        // Clusters may be at most 32 MiB
+        let mut large = boot_sector;
+        large.sectors_per_cluster_shift = 16;
+        assert!(large.is_valid());
+        large.sectors_per_cluster_shift = 17;
+        assert!(!large.is_valid());
+        
+        // Whole sectors of any size are accepted, short buffers are not
+        let mut sector = [0xEEu8; 4096];
+        sector[..512].copy_from_slice(&bytes);
+        assert_eq!(BootSector::try_from_bytes(&sector).unwrap().to_bytes(), bytes);
+        assert_eq!(BootSector::try_from_bytes(&bytes[..511]).err(), Some(ExFatError::BadLength));
+        
+        // Stray bytes in the must-be-zero region or the reserved field
+        for offset in [11, 63, 113, 119] {
+            let mut sloppy = bytes;
+            sloppy[offset] = 1;
+            let boot_sector = BootSector::from_bytes(&sloppy);
+            assert!(boot_sector.is_valid());
+            assert!(!boot_sector.is_valid_strict());
+        }
+    }
+    
+    #[test]
+    fn test_boot_sector_round_trip() {
+        // Every byte distinct from its neighbours, so a misplaced or
+        // byte-swapped field shows up
+        let mut bytes = [0u8; 512];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i * 7 + 3) as u8;
+        }
+        let boot_sector = BootSector::from_bytes(&bytes);
+        assert_eq!(boot_sector.to_bytes(), bytes);
+        assert_eq!(BootSector::from_bytes(&boot_sector.to_bytes()), boot_sector);
+        
+        let mut boot_sector = boot_sector;
+        boot_sector.volume_length = 0x0102_0304_0506_0708;
+        boot_sector.cluster_count = 0x1122_3344;
+        boot_sector.boot_signature = 0xAA55;
+        let bytes = boot_sector.to_bytes();
+        assert_eq!(bytes[72..80], [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(bytes[92..96], [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(bytes[510..512], [0x55, 0xAA]);
+        assert_eq!(BootSector::from_bytes(&bytes), boot_sector);
+        
+        // The checksum of a region built from the serialized sector
+        // survives a round trip
+        let mut region = [0u8; 11 * 512];
+        region[..512].copy_from_slice(&bytes);
+        let checksum = boot_checksum(&region);
+        region[..512].copy_from_slice(&BootSector::from_bytes(&bytes).to_bytes());
+        assert_eq!(boot_checksum(&region), checksum);
+    }
+    
     #[test]
     fn test_directory_entry_size() {
         assert_eq!(mem::size_of::<DirectoryEntry>(), DirectoryEntry::SIZE);
@@ -287,5 +1909,343 @@ mod tests {
         assert_eq!(FatEntry::from_u32(0xFFFFFFF7), FatEntry::Bad);
         assert_eq!(FatEntry::from_u32(0xFFFFFFFF), FatEntry::EndOfChain);
         assert_eq!(FatEntry::from_u32(0x12345), FatEntry::Next(0x12345));
+        
+        // Every end of chain marker is recognised and written back as read
+        for value in [0xFFFFFFF8, 0xFFFFFFFC, 0xFFFFFFFF] {
+            let entry = FatEntry::from_u32(value);
+            assert!(entry.is_end_of_chain());
+            assert_eq!(entry.to_u32(), value);
+        }
+        assert_eq!(FatEntry::from_u32(0xFFFFFFF8), FatEntry::EndOfChainValue(0xFFFFFFF8));
+        assert!(!FatEntry::Bad.is_end_of_chain());
+    }
+    
+    #[test]
+    fn test_entry_round_trip() {
+        let mut stream = unsafe { mem::zeroed::<StreamExtensionEntry>() };
+        stream.entry_type = 0xC0;
+        stream.flags = stream_flags::NO_FAT_CHAIN;
+        stream.first_cluster = 0x1234;
+        stream.data_length = 0x1_0000_0001;
+        
+        let parsed = StreamExtensionEntry::from_bytes(&stream.to_bytes());
+        assert_eq!({ parsed.first_cluster }, 0x1234);
+        assert_eq!({ parsed.data_length }, 0x1_0000_0001);
+        assert!(parsed.is_contiguous());
+    }
+    
+    #[test]
+    fn test_typed_entries_from_generic() {
+        let set = EntrySetBuilder::new(file_attributes::ARCHIVE)
+            .name("a.txt")
+            .first_cluster(9)
+            .size(5)
+            .build(None)
+            .unwrap();
+        let mut bytes = set.concat();
+        bytes.push(0xFF);
+        let entries: Vec<DirectoryEntry> = bytes
+            .chunks(DirectoryEntry::SIZE)
+            .take(3)
+            .map(|chunk| DirectoryEntry::try_from_bytes(chunk).unwrap())
+            .collect();
+        assert_eq!(DirectoryEntry::try_from_bytes(&bytes[..31]).err(), Some(ExFatError::BadLength));
+        assert_eq!(DirectoryEntry::try_from_bytes(&bytes[96..]).err(), Some(ExFatError::BadLength));
+        
+        assert_eq!({ FileEntry::from_entry(&entries[0]).unwrap().secondary_count }, 2);
+        assert_eq!({ StreamExtensionEntry::from_entry(&entries[1]).unwrap().first_cluster }, 9);
+        assert_eq!({ FileNameEntry::from_entry(&entries[2]).unwrap().file_name[0] }, b'a' as u16);
+        
+        assert_eq!(FileEntry::from_entry(&entries[1]).err(), Some(ExFatError::UnexpectedEntryType(0xC0)));
+        assert_eq!(StreamExtensionEntry::from_entry(&entries[2]).err(), Some(ExFatError::UnexpectedEntryType(0xC1)));
+        assert_eq!(FileNameEntry::from_entry(&entries[0]).err(), Some(ExFatError::UnexpectedEntryType(0x85)));
+        
+        // A deleted File entry is not a File entry
+        let deleted = DirectoryEntry { entry_type: 0x05, ..entries[0] };
+        assert_eq!(FileEntry::from_entry(&deleted).err(), Some(ExFatError::UnexpectedEntryType(0x05)));
+    }
+    
+    #[test]
+    fn test_classify_entry() {
+        let entry = |entry_type| DirectoryEntry { entry_type, data: [0; 31] };
+        
+        assert_eq!(entry(0x85).classify(), (EntryType::File, false));
+        assert_eq!(entry(0x05).classify(), (EntryType::File, true));
+        assert_eq!(entry(0x05).get_type(), EntryType::Unknown);
+        assert_eq!(entry(0x00).classify(), (EntryType::EndOfDirectory, false));
+        assert_eq!(entry(0x41).classify(), (EntryType::FileName, true));
+        assert_eq!(entry(0x40).classify(), (EntryType::StreamExtension, true));
+        assert_eq!(entry(0x86).classify(), (EntryType::Unknown, false));
+        assert_eq!(entry(0x06).classify(), (EntryType::Unknown, true));
+    }
+    
+    #[test]
+    fn test_allocation_bitmap_entry() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = EntryType::AllocationBitmap as u8;
+        bytes[20..24].copy_from_slice(&5u32.to_le_bytes());
+        bytes[24..32].copy_from_slice(&8u64.to_le_bytes());
+        
+        let first = AllocationBitmapEntry::from_bytes(&bytes);
+        assert_eq!(({ first.first_cluster }, { first.data_length }), (5, 8));
+        assert_eq!((first.bitmap_number(), first.bitmap_id()), (0, BitmapId::First));
+        
+        // Only bit 0 of the flags selects the bitmap
+        bytes[1] = 0x03;
+        bytes[20..24].copy_from_slice(&7u32.to_le_bytes());
+        let second = AllocationBitmapEntry::from_bytes(&bytes);
+        assert_eq!({ second.first_cluster }, 7);
+        assert_eq!((second.bitmap_number(), second.bitmap_id()), (1, BitmapId::Second));
+        assert_eq!(second.to_bytes(), bytes);
+    }
+    
+    #[test]
+    fn test_volume_guid_entry() {
+        assert_eq!(mem::size_of::<VolumeGuidEntry>(), DirectoryEntry::SIZE);
+        
+        let guid = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00];
+        let entry = VolumeGuidEntry::new(guid);
+        assert_eq!(entry.entry_type, 0xA0);
+        assert!(entry.checksum_valid());
+        
+        let mut bytes = entry.to_bytes();
+        let parsed = VolumeGuidEntry::from_bytes(&bytes);
+        assert_eq!(parsed.volume_guid, guid);
+        assert!(parsed.checksum_valid());
+        
+        bytes[10] ^= 0xFF;
+        assert!(!VolumeGuidEntry::from_bytes(&bytes).checksum_valid());
+    }
+    
+    #[test]
+    fn test_file_name_utf16_len() {
+        // 'é' is in the BMP, '😀' and '𝄞' need surrogate pairs
+        let name = FileName::new("é😀a𝄞").unwrap();
+        assert_eq!(name.utf16_len(), 6);
+        assert_eq!(name.as_utf16()[1..3], [0xD83D, 0xDE00]);
+        
+        // 128 emoji are 128 chars but 256 code units
+        let emoji: String = core::iter::repeat_n('😀', 128).collect();
+        assert_eq!(emoji.chars().count(), 128);
+        assert_eq!(FileName::new(&emoji), Err(ExFatError::NameTooLong));
+        
+        // 127 emoji plus one BMP char land exactly on the limit
+        let mut name: String = core::iter::repeat_n('😀', 127).collect();
+        name.push('é');
+        assert_eq!(FileName::new(&name).unwrap().utf16_len(), MAX_NAME_LENGTH);
+        
+        name.push('x');
+        assert_eq!(to_utf16_name(&name), Err(ExFatError::NameTooLong));
+    }
+    
+    #[test]
+    fn test_entries_needed_for_name() {
+        assert_eq!(entries_needed_for_name("a"), 3);
+        assert_eq!(entries_needed_for_name(&"a".repeat(15)), 3);
+        assert_eq!(entries_needed_for_name(&"a".repeat(16)), 4);
+        // Eight surrogate pairs are 16 code units
+        assert_eq!(entries_needed_for_name(&"😀".repeat(8)), 4);
+        assert_eq!(entries_needed_for_name(&"a".repeat(MAX_NAME_LENGTH)), 19);
+        
+        let name = "a_rather_long_file_name.txt";
+        assert_eq!(EntrySetBuilder::new(0).name(name).build(None).unwrap().len(), entries_needed_for_name(name));
+    }
+    
+    #[test]
+    fn test_utf16_name_round_trip() {
+        // 127 emoji and a final BMP char, one code unit short of the limit
+        let mut name: String = core::iter::repeat_n('😀', 127).collect();
+        let units = to_utf16_name(&name).unwrap();
+        assert_eq!(units.len(), MAX_NAME_LENGTH - 1);
+        assert_eq!(from_utf16_name(&units), name);
+        
+        // A further emoji would split across the limit
+        name.push('😀');
+        assert_eq!(to_utf16_name(&name), Err(ExFatError::NameTooLong));
+        name.pop();
+        name.push('é');
+        assert_eq!(from_utf16_name(&to_utf16_name(&name).unwrap()), name);
+        
+        assert_eq!(to_utf16_name("😀/x"), Err(ExFatError::InvalidName));
+        assert_eq!(from_utf16_name(&[0x61, 0xD83D, 0x62]), "a\u{FFFD}b");
+    }
+    
+    #[test]
+    fn test_vendor_entries() {
+        assert_eq!(mem::size_of::<VendorExtensionEntry>(), DirectoryEntry::SIZE);
+        assert_eq!(mem::size_of::<VendorAllocationEntry>(), DirectoryEntry::SIZE);
+        
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0xE1;
+        bytes[2..18].copy_from_slice(b"vendor-guid-0001");
+        bytes[20..24].copy_from_slice(&9u32.to_le_bytes());
+        bytes[24..32].copy_from_slice(&1024u64.to_le_bytes());
+        
+        let Some(VendorEntry::Allocation(entry)) = VendorEntry::from_bytes(&bytes) else {
+            panic!("expected a vendor allocation entry");
+        };
+        assert_eq!({ entry.first_cluster }, 9);
+        assert_eq!({ entry.data_length }, 1024);
+        assert_eq!(VendorEntry::Allocation(entry).vendor_guid(), *b"vendor-guid-0001");
+        assert_eq!(entry.to_bytes(), bytes);
+        
+        bytes[0] = 0xE0;
+        let entry = VendorEntry::from_bytes(&bytes).unwrap();
+        assert!(matches!(entry, VendorEntry::Extension(_)));
+        assert_eq!(entry.to_bytes(), bytes);
+        
+        bytes[0] = 0xC1;
+        assert_eq!(VendorEntry::from_bytes(&bytes), None);
+    }
+    
+    #[test]
+    fn test_raw_entry_iter() {
+        let mut buffer = [0u8; 32 * 4 + 10];
+        buffer[0] = EntryType::AllocationBitmap as u8;
+        buffer[32] = 0x05;
+        // End of directory at entry 2, then a stale entry
+        buffer[96] = EntryType::File as u8;
+        buffer[128..].fill(0xEE);
+        
+        let types: Vec<u8> = RawEntryIter::new(&buffer).map(|entry| entry[0]).collect();
+        assert_eq!(types, [0x81, 0x05]);
+        let types: Vec<u8> = RawEntryIter::full(&buffer).map(|entry| entry[0]).collect();
+        assert_eq!(types, [0x81, 0x05, 0x00, 0x85]);
+        
+        assert_eq!(RawEntryIter::new(&buffer[..31]).count(), 0);
+        assert_eq!(RawEntryIter::full(&buffer[64..]).count(), 2);
+    }
+    
+    #[test]
+    fn test_parse_entries() {
+        for size in [
+            mem::size_of::<AllocationBitmapEntry>(),
+            mem::size_of::<UpCaseTableEntry>(),
+            mem::size_of::<VolumeLabelEntry>(),
+        ] {
+            assert_eq!(size, DirectoryEntry::SIZE);
+        }
+        
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0x81;
+        bytes[20..24].copy_from_slice(&5u32.to_le_bytes());
+        bytes[24..32].copy_from_slice(&8u64.to_le_bytes());
+        let ParsedEntry::Bitmap(bitmap) = DirectoryEntry::from_bytes(&bytes).parse() else {
+            panic!("expected a bitmap entry");
+        };
+        assert_eq!(({ bitmap.first_cluster }, { bitmap.data_length }), (5, 8));
+        assert_eq!(bitmap.to_bytes(), bytes);
+        
+        bytes[0] = 0x82;
+        bytes[4..8].copy_from_slice(&0xE619_D30Du32.to_le_bytes());
+        let ParsedEntry::UpCase(upcase) = DirectoryEntry::from_bytes(&bytes).parse() else {
+            panic!("expected an up-case table entry");
+        };
+        assert_eq!({ upcase.table_checksum }, 0xE619_D30D);
+        assert_eq!(upcase.to_bytes(), bytes);
+        
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0x83;
+        bytes[1] = 4;
+        for (i, c) in "BOOT".encode_utf16().enumerate() {
+            bytes[2 + i * 2..4 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        let ParsedEntry::Label(label) = DirectoryEntry::from_bytes(&bytes).parse() else {
+            panic!("expected a volume label entry");
+        };
+        assert_eq!(label.label(), "BOOT");
+        assert_eq!(label.to_bytes(), bytes);
+        
+        let guid = VolumeGuidEntry::new([7; 16]).to_bytes();
+        assert!(matches!(DirectoryEntry::from_bytes(&guid).parse(), ParsedEntry::Guid(entry) if entry.volume_guid == [7; 16]));
+        
+        let set = build_entry_set(&FileEntry::default(), &StreamExtensionEntry::default(), &[0x41], None);
+        assert!(matches!(DirectoryEntry::from_bytes(&set[0]).parse(), ParsedEntry::File(_)));
+        assert!(matches!(DirectoryEntry::from_bytes(&set[1]).parse(), ParsedEntry::Stream(entry) if entry.name_length == 1));
+        assert!(matches!(DirectoryEntry::from_bytes(&set[2]).parse(), ParsedEntry::Name(_)));
+        
+        bytes[0] = 0xE0;
+        assert!(matches!(DirectoryEntry::from_bytes(&bytes).parse(), ParsedEntry::Vendor(VendorEntry::Extension(_))));
+        
+        // Deleted entries keep their type code
+        bytes[0] = 0x05;
+        assert!(matches!(DirectoryEntry::from_bytes(&bytes).parse(), ParsedEntry::Unknown(0x05)));
+        assert!(matches!(DirectoryEntry::from_bytes(&[0; 32]).parse(), ParsedEntry::EndOfDirectory));
+    }
+    
+    #[test]
+    fn test_validate_file_name() {
+        assert!(validate_file_name("hello.txt").is_ok());
+        assert!(validate_file_name("").is_err());
+        assert!(validate_file_name("a/b").is_err());
+        assert!(validate_file_name("what?").is_err());
+        assert_eq!(validate_file_name("a:b"), Err(ExFatError::InvalidName));
+        assert_eq!(to_utf16_name(&"x".repeat(256)), Err(ExFatError::NameTooLong));
+    }
+    
+    #[test]
+    fn test_build_entry_set() {
+        let file = unsafe { mem::zeroed::<FileEntry>() };
+        let stream = unsafe { mem::zeroed::<StreamExtensionEntry>() };
+        let name: Vec<u16> = "a_rather_long_file_name.txt".encode_utf16().collect();
+        
+        let entries = build_entry_set(&file, &stream, &name, None);
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0][1], 3);
+        assert_eq!(entries[1][3] as usize, name.len());
+        assert_eq!(le_u16(&entries[0], 2), entry_set_checksum(&entries));
+        
+        // Name hash is case-insensitive
+        let upper: Vec<u16> = "A_RATHER_LONG_FILE_NAME.TXT".encode_utf16().collect();
+        assert_eq!(name_hash(&name), name_hash(&upper));
+        assert!(names_equal(&name, &upper));
+        
+        // An up-case table replaces the built-in mapping in the hash
+        let identity = UpCaseTable::new(Vec::new());
+        let entries = build_entry_set(&file, &stream, &name, Some(&identity));
+        assert_eq!(le_u16(&entries[1], 4), identity.name_hash(&name));
+        assert_ne!(le_u16(&entries[1], 4), name_hash(&name));
+    }
+    
+    #[test]
+    fn test_entry_set_builder() {
+        let timestamps = Timestamps {
+            create: 0x5A21_6C00,
+            modified: 0x5A21_6C01,
+            ..Default::default()
+        };
+        let entries = EntrySetBuilder::new(file_attributes::ARCHIVE)
+            .name("a_name_spanning_two_name_entries.txt")
+            .first_cluster(7)
+            .size(4096)
+            .valid_data_length(100)
+            .contiguous(true)
+            .timestamps(timestamps)
+            .build(None)
+            .unwrap();
+        
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0][1] as usize, entries.len() - 1);
+        assert_eq!(le_u16(&entries[0], 2), entry_set_checksum(&entries));
+        
+        let file = FileEntry::from_bytes(&entries[0]);
+        assert_eq!({ file.file_attributes }, file_attributes::ARCHIVE);
+        assert_eq!(file.timestamps(), timestamps);
+        
+        let stream = StreamExtensionEntry::from_bytes(&entries[1]);
+        assert!(stream.is_contiguous());
+        assert_eq!({ stream.first_cluster }, 7);
+        assert_eq!({ stream.data_length }, 4096);
+        assert_eq!({ stream.valid_data_length }, 100);
+        assert_eq!(stream.name_length, 36);
+        
+        // The longest name fills all 17 name entries
+        let entries = EntrySetBuilder::new(0).name(&"n".repeat(MAX_NAME_LENGTH)).build(None).unwrap();
+        assert_eq!(entries.len(), 19);
+        assert_eq!(entries[0][1], 18);
+        
+        let builder = EntrySetBuilder::new(0).name(&"n".repeat(MAX_NAME_LENGTH + 1));
+        assert_eq!(builder.build(None), Err(ExFatError::NameTooLong));
+        assert_eq!(EntrySetBuilder::new(0).build(None), Err(ExFatError::InvalidName));
     }
 }
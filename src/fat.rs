@@ -0,0 +1,182 @@
+//! exFAT FAT sector cache
+//!
+//! This module implements a bounded cache of FAT sectors, so chain walks
+//! read each sector from the device once. Entries are updated in memory and
+//! changed sectors are written back when evicted or flushed.
+
+use alloc::vec::Vec;
+use crate::exfat::le_u32;
+
+/// FAT sector cached in memory
+#[derive(Debug, Clone)]
+struct CachedSector {
+    /// Sector index within the FAT
+    index: u64,
+    /// Sector contents
+    data: Vec<u8>,
+    /// Changed since it was read or last written back
+    dirty: bool,
+}
+
+/// Least recently used cache of FAT sectors
+///
+/// Sectors are identified by their index within the FAT, so the same cache
+/// serves whichever FAT is active.
+#[derive(Debug, Clone)]
+pub struct FatCache {
+    /// Most sectors held at once
+    capacity: usize,
+    /// Size of a sector in bytes
+    sector_size: usize,
+    /// Cached sectors, least recently used first
+    sectors: Vec<CachedSector>,
+}
+
+impl FatCache {
+    /// Number of sectors cached by a new volume
+    pub const DEFAULT_CAPACITY: usize = 16;
+    
+    /// Create an empty cache holding up to `capacity` sectors
+    pub fn new(capacity: usize, sector_size: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            sector_size: sector_size.max(4),
+            sectors: Vec::new(),
+        }
+    }
+    
+    /// Get the most sectors held at once
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    
+    /// Get the size of a sector in bytes
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+    
+    /// Get the sector index and byte offset of a cluster's FAT entry
+    pub fn locate(&self, cluster: u32) -> (u64, usize) {
+        let byte_offset = cluster as u64 * 4;
+        let sector_size = self.sector_size as u64;
+        (byte_offset / sector_size, (byte_offset % sector_size) as usize)
+    }
+    
+    /// Check if a sector is cached
+    pub fn contains(&self, index: u64) -> bool {
+        self.sectors.iter().any(|sector| sector.index == index)
+    }
+    
+    /// Read a cluster's FAT entry, or `None` if its sector isn't cached
+    pub fn read_fat_entry(&mut self, cluster: u32) -> Option<u32> {
+        let (index, offset) = self.locate(cluster);
+        let sector = self.touch(index)?;
+        Some(le_u32(&sector.data, offset))
+    }
+    
+    /// Update a cluster's FAT entry and mark its sector dirty
+    ///
+    /// Returns false, changing nothing, if the sector isn't cached.
+    pub fn write_fat_entry(&mut self, cluster: u32, value: u32) -> bool {
+        let (index, offset) = self.locate(cluster);
+        let Some(sector) = self.touch(index) else {
+            return false;
+        };
+        sector.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        sector.dirty = true;
+        true
+    }
+    
+    /// Add a sector read from disk
+    ///
+    /// When the cache is full the least recently used sector is dropped;
+    /// if it was dirty its index and contents are returned so the caller
+    /// can write it back.
+    pub fn insert(&mut self, index: u64, mut data: Vec<u8>) -> Option<(u64, Vec<u8>)> {
+        data.resize(self.sector_size, 0);
+        self.sectors.retain(|sector| sector.index != index);
+        
+        let evicted = if self.sectors.len() >= self.capacity {
+            Some(self.sectors.remove(0))
+        } else {
+            None
+        };
+        self.sectors.push(CachedSector { index, data, dirty: false });
+        
+        evicted.filter(|sector| sector.dirty).map(|sector| (sector.index, sector.data))
+    }
+    
+    /// Check if any cached sector needs writing back
+    pub fn is_dirty(&self) -> bool {
+        self.sectors.iter().any(|sector| sector.dirty)
+    }
+    
+    /// Iterate over the dirty sectors as `(index, contents)`
+    pub fn dirty_sectors(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.sectors
+            .iter()
+            .filter(|sector| sector.dirty)
+            .map(|sector| (sector.index, sector.data.as_slice()))
+    }
+    
+    /// Mark every cached sector as written back
+    pub fn clear_dirty(&mut self) {
+        for sector in &mut self.sectors {
+            sector.dirty = false;
+        }
+    }
+    
+    /// Find a cached sector and mark it most recently used
+    fn touch(&mut self, index: u64) -> Option<&mut CachedSector> {
+        let position = self.sectors.iter().position(|sector| sector.index == index)?;
+        let sector = self.sectors.remove(position);
+        self.sectors.push(sector);
+        self.sectors.last_mut()
+    }
+}
+
+#[cfg(all(test, not(target_os = "uefi")))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    
+    #[test]
+    fn test_read_and_write_entries() {
+        let mut cache = FatCache::new(2, 512);
+        assert_eq!(cache.locate(130), (1, 8));
+        assert_eq!(cache.read_fat_entry(3), None);
+        assert!(!cache.write_fat_entry(3, 4));
+        
+        let mut sector = vec![0u8; 512];
+        sector[12..16].copy_from_slice(&4u32.to_le_bytes());
+        assert!(cache.insert(0, sector).is_none());
+        assert_eq!(cache.read_fat_entry(3), Some(4));
+        assert!(!cache.is_dirty());
+        
+        assert!(cache.write_fat_entry(4, 0xFFFF_FFFF));
+        assert_eq!(cache.read_fat_entry(4), Some(0xFFFF_FFFF));
+        let dirty: Vec<u64> = cache.dirty_sectors().map(|(index, _)| index).collect();
+        assert_eq!(dirty, [0]);
+        
+        cache.clear_dirty();
+        assert!(!cache.is_dirty());
+    }
+    
+    #[test]
+    fn test_eviction() {
+        let mut cache = FatCache::new(2, 512);
+        cache.insert(0, vec![0; 512]);
+        cache.insert(1, vec![0; 512]);
+        assert!(cache.write_fat_entry(0, 1));
+        
+        // Sector 1 is least recently used, and clean
+        assert!(cache.insert(2, vec![0; 512]).is_none());
+        assert!(!cache.contains(1));
+        
+        // Sector 0 goes next, and its change is handed back
+        let (index, data) = cache.insert(3, vec![0; 512]).unwrap();
+        assert_eq!((index, le_u32(&data, 0)), (0, 1));
+        assert!(cache.contains(2) && cache.contains(3));
+        assert_eq!(cache.capacity(), 2);
+    }
+}
@@ -1,23 +1,37 @@
 #![no_std]
-#![cfg_attr(target_os = "uefi", no_main)]
+#![cfg_attr(all(target_os = "uefi", not(feature = "std")), no_main)]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
+mod bitmap;
+mod block;
+mod error;
 mod exfat;
+mod fat;
 mod protocol;
+mod time;
+mod upcase;
 
+pub use bitmap::*;
+pub use block::*;
+pub use error::*;
 pub use exfat::*;
+pub use fat::*;
 pub use protocol::*;
+pub use time::*;
+pub use upcase::*;
 
-// Global allocator for UEFI
-#[cfg(target_os = "uefi")]
+// Global allocator for UEFI; host builds with `std` use the system allocator
+#[cfg(all(target_os = "uefi", not(feature = "std")))]
 use uefi::allocator::Allocator;
 
-#[cfg(target_os = "uefi")]
+#[cfg(all(target_os = "uefi", not(feature = "std")))]
 #[global_allocator]
 static ALLOCATOR: Allocator = Allocator;
 
-#[cfg(target_os = "uefi")]
+#[cfg(all(target_os = "uefi", not(feature = "std")))]
 #[no_mangle]
 pub extern "efiapi" fn efi_main(_image_handle: uefi::Handle, _system_table: *const ()) -> uefi::Status {
     // Initialize UEFI environment
@@ -29,7 +43,7 @@ pub extern "efiapi" fn efi_main(_image_handle: uefi::Handle, _system_table: *con
     uefi::Status::SUCCESS
 }
 
-#[cfg(target_os = "uefi")]
+#[cfg(all(target_os = "uefi", not(feature = "std")))]
 #[panic_handler]
 fn panic_handler(_info: &core::panic::PanicInfo) -> ! {
     loop {}
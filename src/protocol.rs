@@ -3,32 +3,607 @@
 //! This module provides the UEFI Simple File System Protocol implementation
 //! for the exFAT driver.
 
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
 use alloc::string::String;
-use uefi::{Result, Status};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use crate::bitmap::AllocationBitmap;
+use crate::block::BlockDevice;
+use crate::error::{ExFatError, Result};
 use crate::exfat::*;
+use crate::fat::FatCache;
+use crate::time::TimeSource;
+use crate::upcase::UpCaseTable;
+use uefi::Status;
+
+/// Outcome of probing a device for an exFAT volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResult {
+    /// A boot region has a valid signature and checksum
+    Valid,
+    /// A boot sector has a valid signature, but no boot region's checksum
+    /// matches
+    ChecksumBad,
+    /// No usable exFAT boot sector was found
+    NotExFat,
+    /// The device failed to read the boot region
+    IoError(Status),
+}
+
+/// Volume-wide structures recorded in the root directory
+#[derive(Debug, Clone)]
+pub struct SystemEntries {
+    /// First cluster and length in bytes of the first allocation bitmap
+    pub bitmap: (u32, u64),
+    /// First cluster and length in bytes of the second allocation bitmap,
+    /// on TexFAT volumes
+    pub second_bitmap: Option<(u32, u64)>,
+    /// Up-case table
+    pub upcase: UpCaseTable,
+    /// Volume label, if one is set
+    pub label: Option<String>,
+    /// Volume GUID, if one is set
+    pub guid: Option<[u8; 16]>,
+}
+
+/// Layout of a data stream's clusters on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FragReport {
+    /// Clusters allocated to the stream
+    pub clusters: u32,
+    /// Runs of physically consecutive clusters
+    pub extents: u32,
+}
+
+impl FragReport {
+    /// Check if the stream is split into more than one extent
+    pub fn is_fragmented(&self) -> bool {
+        self.extents > 1
+    }
+    
+    /// Count the breaks between extents
+    ///
+    /// A break is a link to any cluster other than the next one on disk.
+    pub fn fragments(&self) -> u32 {
+        self.extents.saturating_sub(1)
+    }
+    
+    /// Get the percentage of cluster links that are breaks, rounded down
+    pub fn percent_fragmented(&self) -> u8 {
+        percent(self.fragments() as u64, self.clusters.saturating_sub(1) as u64)
+    }
+}
+
+/// Fragmentation of every file and directory on a volume
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FragmentationReport {
+    /// Path and layout of each entry with clusters allocated, most
+    /// fragmented first
+    pub files: Vec<(String, FragReport)>,
+    /// Clusters allocated to all entries
+    pub clusters: u64,
+    /// Breaks between extents across all entries
+    pub fragments: u64,
+}
+
+impl FragmentationReport {
+    /// Get the percentage of cluster links on the volume that are breaks,
+    /// rounded down
+    pub fn percent_fragmented(&self) -> u8 {
+        let links: u64 = self.files.iter().map(|(_, report)| report.clusters.saturating_sub(1) as u64).sum();
+        percent(self.fragments, links)
+    }
+}
+
+/// Problems found by `ExFatVolume::check`
+///
+/// The allocation bitmap and up-case table are listed under the paths
+/// `$Bitmap` and `$UpCase`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Path of each data stream whose FAT chain loops back on itself, and
+    /// the cluster whose link closes the loop
+    pub cycles: Vec<(String, u32)>,
+    /// Path of each data stream whose clusters couldn't be traced, and why
+    pub broken_chains: Vec<(String, ExFatError)>,
+    /// Clusters used by more than one data stream
+    pub cross_linked: Vec<u32>,
+    /// Clusters in use but marked free in the allocation bitmap
+    pub unallocated: Vec<u32>,
+    /// Clusters marked allocated but used by nothing
+    ///
+    /// Left empty when a chain is broken, since the clusters it would have
+    /// reached can't be known.
+    pub leaked: Vec<u32>,
+    /// Whether FAT entries 0 and 1 don't hold their reserved values
+    pub bad_reserved_entries: bool,
+}
+
+impl CheckReport {
+    /// Check if no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.cycles.is_empty()
+            && self.broken_chains.is_empty()
+            && self.cross_linked.is_empty()
+            && self.unallocated.is_empty()
+            && self.leaked.is_empty()
+            && !self.bad_reserved_entries
+    }
+}
+
+/// Changes made by `ExFatVolume::repair`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairResult {
+    /// Clusters marked allocated in the allocation bitmap
+    pub allocated: Vec<u32>,
+    /// Clusters marked free in the allocation bitmap
+    pub freed: Vec<u32>,
+    /// Path of each data stream cut at a cycle, and its new last cluster
+    pub truncated: Vec<(String, u32)>,
+    /// Whether the volume dirty flag was cleared
+    pub marked_clean: bool,
+}
+
+/// Compute `part` as a percentage of `whole`, rounded down
+fn percent(part: u64, whole: u64) -> u8 {
+    (part.min(whole) * 100).checked_div(whole).unwrap_or(0) as u8
+}
+
+/// Snapshot of a volume's geometry and usage
+///
+/// Owns all of its data, so it can be kept and reported on after the
+/// volume and its device are gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeMetadata {
+    /// Bytes per sector
+    pub bytes_per_sector: u32,
+    /// Bytes per cluster
+    pub bytes_per_cluster: u32,
+    /// Number of clusters in the cluster heap
+    pub cluster_count: u32,
+    /// First cluster of the root directory
+    pub root_dir_cluster: u32,
+    /// Volume serial number
+    pub volume_serial: u32,
+    /// Volume label, if one is set
+    pub label: Option<String>,
+    /// Free clusters at the time of the snapshot
+    pub free_clusters: u32,
+}
+
+/// Options for `ExFatVolume::mount_options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MountOptions {
+    /// Reject every write with `ExFatError::WriteProtected`
+    pub read_only: bool,
+    /// Mount read-write even if the volume dirty flag is set
+    pub ignore_dirty: bool,
+    /// Refuse a boot sector with stray bytes in its must-be-zero region or
+    /// reserved field, per `BootSector::is_valid_strict`
+    pub strict: bool,
+}
+
+/// Volume structures located through the root directory
+#[derive(Debug, Clone)]
+pub struct RootMetadata {
+    /// First allocation bitmap, as read from disk
+    pub bitmap: AllocationBitmap,
+    /// Up-case table
+    pub upcase: UpCaseTable,
+    /// Volume label, if one is set
+    pub label: Option<String>,
+    /// Volume GUID, if one is set
+    pub guid: Option<[u8; 16]>,
+}
+
+/// System entries found by a single pass over the root directory
+#[derive(Default)]
+struct RootScan {
+    /// First cluster and length of the first allocation bitmap
+    bitmap: Option<(u32, u64)>,
+    /// First cluster and length of the second allocation bitmap
+    second_bitmap: Option<(u32, u64)>,
+    /// Up-case table entry
+    upcase: Option<UpCaseTableEntry>,
+    /// Volume label
+    label: Option<String>,
+    /// Volume GUID with a valid checksum
+    guid: Option<[u8; 16]>,
+}
 
 /// exFAT Volume
 pub struct ExFatVolume {
     /// Boot sector
     pub boot_sector: BootSector,
-    /// Block device handle (for future use)
-    #[allow(dead_code)]
-    block_device: usize,
+    /// Underlying block device
+    device: RefCell<Box<dyn BlockDevice>>,
+    /// Allocation bitmap, loaded on first use
+    bitmap: RefCell<Option<AllocationBitmap>>,
+    /// Recently used FAT sectors, with changes not yet written back
+    fat_cache: RefCell<FatCache>,
+    /// Reject all writes to the device
+    read_only: bool,
+    /// Clock for stamping created and modified files
+    time_source: Option<Box<dyn TimeSource>>,
+    /// Device LBA of the volume's first sector
+    partition_lba_base: u64,
+    /// Root directory system entries, once loaded
+    system: Option<SystemEntries>,
 }
 
 impl ExFatVolume {
+    /// Deepest directory level `walk` descends into
+    pub const MAX_WALK_DEPTH: usize = 64;
+    
     /// Create a new exFAT volume from boot sector
-    pub fn new(boot_sector: BootSector, block_device: usize) -> Result<Self> {
+    ///
+    /// The volume is assumed to start at the boot sector's
+    /// `partition_offset` on the device, as in a whole-disk image. Use
+    /// `set_partition_lba_base` if the device holds just the partition.
+    pub fn new(boot_sector: BootSector, device: impl BlockDevice + 'static) -> Result<Self> {
         if !boot_sector.is_valid() {
-            return Err(Status::VOLUME_CORRUPTED.into());
+            return Err(ExFatError::BadSignature);
+        }
+        // Sector numbers are used as LBAs directly
+        if device.block_size() != boot_sector.bytes_per_sector() {
+            return Err(ExFatError::InvalidParameter);
+        }
+        if boot_sector.fat_length < boot_sector.required_fat_sectors() {
+            log::warn!(
+                "FAT is {} sectors but {} clusters need {}",
+                { boot_sector.fat_length },
+                { boot_sector.cluster_count },
+                boot_sector.required_fat_sectors(),
+            );
         }
         
         Ok(Self {
             boot_sector,
-            block_device,
+            device: RefCell::new(Box::new(device)),
+            bitmap: RefCell::new(None),
+            fat_cache: RefCell::new(FatCache::new(FatCache::DEFAULT_CAPACITY, boot_sector.bytes_per_sector() as usize)),
+            read_only: false,
+            time_source: None,
+            partition_lba_base: boot_sector.partition_offset,
+            system: None,
+        })
+    }
+    
+    /// Mount the volume on a device
+    ///
+    /// The boot sector is read from the main boot region, falling back to
+    /// the backup if the main one has a bad signature or checksum, and the
+    /// system entries are loaded from the root directory. A volume
+    /// that was left dirty fails with `ExFatError::VolumeDirty`, since it
+    /// may need repair before it is safe to write, and one with the media
+    /// failure flag set fails with `ExFatError::WriteProtected`; both can
+    /// still be mounted with `mount_read_only`.
+    pub fn mount(device: impl BlockDevice + 'static) -> Result<Self> {
+        Self::mount_at(device, 0)
+    }
+    
+    /// Mount a volume that starts at `partition_lba_base` on the device
+    ///
+    /// Used for whole-disk images, where the caller has located the
+    /// partition from the partition table. Otherwise the same as `mount`.
+    pub fn mount_at(device: impl BlockDevice + 'static, partition_lba_base: u64) -> Result<Self> {
+        Self::mount_with(device, partition_lba_base, MountOptions::default())
+    }
+    
+    /// Mount a volume that never writes to the device
+    ///
+    /// The boot sector is read as by `mount`, but a dirty volume is
+    /// accepted. Every operation that would modify the volume, including
+    /// setting the dirty flag, fails with `ExFatError::WriteProtected`.
+    pub fn mount_read_only(device: impl BlockDevice + 'static) -> Result<Self> {
+        Self::mount_options(device, MountOptions { read_only: true, ..Default::default() })
+    }
+    
+    /// Mount the volume on a device with explicit options
+    ///
+    /// With default options this is `mount`. `read_only` makes it
+    /// `mount_read_only`, and `ignore_dirty` mounts a dirty volume
+    /// read-write. A volume with the media failure flag set is only ever
+    /// mounted read-only. With `strict`, a boot sector that fails
+    /// `BootSector::is_valid_strict` is refused with
+    /// `ExFatError::BadSignature`; otherwise those fields are ignored.
+    pub fn mount_options(device: impl BlockDevice + 'static, options: MountOptions) -> Result<Self> {
+        Self::mount_with(device, 0, options)
+    }
+    
+    /// Format a RAM-backed device of `size_bytes` and mount it
+    ///
+    /// A helper for tests and host tooling: the device has 512-byte sectors
+    /// and is laid out by the same formatter as a real volume, with an
+    /// empty root directory, then mounted with `mount`. Panics if the size
+    /// or cluster size can't hold a volume.
+    #[cfg(any(test, feature = "std"))]
+    pub fn new_in_memory(size_bytes: u64, cluster_size: u32) -> Self {
+        let size = usize::try_from(size_bytes).expect("volume too large for memory");
+        let mut device = crate::block::MemBlockDevice::new(size, 512);
+        Self::format(&mut device, size_bytes / 512, cluster_size).expect("volume can't be formatted");
+        Self::mount(device).expect("formatted volume can't be mounted")
+    }
+    
+    /// Lay out an empty volume of `volume_length` sectors on a device
+    ///
+    /// The FAT follows the main and backup boot regions and is sized for
+    /// the whole device; the cluster heap starts at the next cluster
+    /// boundary. Clusters 2 onwards hold the allocation bitmap, the
+    /// compressed up-case table and the root directory, in that order,
+    /// each linked in the FAT. Fails with `ExFatError::InvalidParameter`
+    /// if the cluster size isn't a power of two at least the sector size,
+    /// or if the volume is too small for its own structures.
+    #[cfg(any(test, feature = "std"))]
+    fn format(device: &mut dyn BlockDevice, volume_length: u64, cluster_size: u32) -> Result<()> {
+        let sector_size = device.block_size();
+        if !sector_size.is_power_of_two() || !cluster_size.is_power_of_two() || cluster_size < sector_size {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        let mut boot_sector = BootSector::from_bytes(&[0; 512]);
+        boot_sector.jump_boot = [0xEB, 0x76, 0x90];
+        boot_sector.fs_name.copy_from_slice(b"EXFAT   ");
+        boot_sector.volume_length = volume_length;
+        boot_sector.fs_revision = 0x0100;
+        boot_sector.bytes_per_sector_shift = sector_size.trailing_zeros() as u8;
+        boot_sector.sectors_per_cluster_shift = (cluster_size / sector_size).trailing_zeros() as u8;
+        boot_sector.num_fats = 1;
+        boot_sector.drive_select = 0x80;
+        boot_sector.boot_signature = 0xAA55;
+        if !boot_sector.is_valid() {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        // Size the FAT for every cluster the device could hold, then fit
+        // the heap after it
+        let sectors_per_cluster = boot_sector.sectors_per_cluster() as u64;
+        let fat_offset = 2 * BootSector::BACKUP_SECTOR;
+        let clusters = |start: u64| {
+            u32::try_from(volume_length.saturating_sub(start) / sectors_per_cluster).map_err(|_| ExFatError::InvalidParameter)
+        };
+        boot_sector.fat_offset = fat_offset as u32;
+        boot_sector.cluster_count = clusters(fat_offset)?;
+        boot_sector.fat_length = boot_sector.required_fat_sectors();
+        let heap_offset = (fat_offset + boot_sector.fat_length as u64).next_multiple_of(sectors_per_cluster);
+        boot_sector.cluster_heap_offset = u32::try_from(heap_offset).map_err(|_| ExFatError::InvalidParameter)?;
+        boot_sector.cluster_count = clusters(heap_offset)?;
+        
+        let upcase = UpCaseTable::generate().to_bytes();
+        let bitmap_len = (boot_sector.cluster_count as u64).div_ceil(8);
+        let cluster_size = cluster_size as u64;
+        let streams = [bitmap_len, upcase.len() as u64, cluster_size];
+        let mut first_clusters = [0u32; 3];
+        let mut next = 2u32;
+        for (first, len) in first_clusters.iter_mut().zip(streams) {
+            *first = next;
+            next += len.div_ceil(cluster_size) as u32;
+        }
+        let used = next - 2;
+        if used > boot_sector.cluster_count {
+            return Err(ExFatError::InvalidParameter);
+        }
+        let [bitmap_cluster, upcase_cluster, root_cluster] = first_clusters;
+        boot_sector.root_dir_cluster = root_cluster;
+        boot_sector.percent_in_use = (used as u64 * 100 / boot_sector.cluster_count as u64) as u8;
+        
+        // Media descriptor and reserved entries, then one chain per stream
+        let mut fat = vec![0u8; boot_sector.fat_length as usize * sector_size as usize];
+        let mut set_fat = |cluster: u32, value: u32| {
+            let offset = cluster as usize * 4;
+            fat[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        set_fat(0, FatEntry::RESERVED_VALUES[0]);
+        set_fat(1, FatEntry::RESERVED_VALUES[1]);
+        for (first, end) in [(bitmap_cluster, upcase_cluster), (upcase_cluster, root_cluster), (root_cluster, next)] {
+            for cluster in first..end - 1 {
+                set_fat(cluster, cluster + 1);
+            }
+            set_fat(end - 1, 0xFFFF_FFFF);
+        }
+        device.write_blocks(fat_offset, &fat)?;
+        
+        let cluster_lba = |cluster: u32| heap_offset + (cluster - 2) as u64 * sectors_per_cluster;
+        let write_stream = |device: &mut dyn BlockDevice, first: u32, mut data: Vec<u8>| {
+            data.resize((data.len() as u64).div_ceil(cluster_size).max(1) as usize * cluster_size as usize, 0);
+            device.write_blocks(cluster_lba(first), &data)
+        };
+        
+        let mut bitmap = vec![0u8; bitmap_len as usize];
+        for index in 0..used as usize {
+            bitmap[index / 8] |= 1 << (index % 8);
+        }
+        write_stream(device, bitmap_cluster, bitmap)?;
+        
+        let upcase_entry = UpCaseTableEntry {
+            entry_type: EntryType::UpCaseTable as u8,
+            reserved1: [0; 3],
+            table_checksum: table_checksum(&upcase),
+            reserved2: [0; 12],
+            first_cluster: upcase_cluster,
+            data_length: upcase.len() as u64,
+        };
+        write_stream(device, upcase_cluster, upcase)?;
+        
+        let bitmap_entry = AllocationBitmapEntry {
+            entry_type: EntryType::AllocationBitmap as u8,
+            bitmap_flags: 0,
+            reserved: [0; 18],
+            first_cluster: bitmap_cluster,
+            data_length: bitmap_len,
+        };
+        let mut root = Vec::new();
+        root.extend_from_slice(&bitmap_entry.to_bytes());
+        root.extend_from_slice(&upcase_entry.to_bytes());
+        write_stream(device, root_cluster, root)?;
+        
+        // Boot regions go last, so a partly formatted device doesn't mount
+        let mut region = vec![0u8; boot_sector.boot_region_len()];
+        region[..BootSector::SIZE].copy_from_slice(&boot_sector.to_bytes());
+        let checksum = boot_checksum(&region).to_le_bytes();
+        let checksum_sector: Vec<u8> = checksum.iter().copied().cycle().take(sector_size as usize).collect();
+        for base in [0, BootSector::BACKUP_SECTOR] {
+            device.write_blocks(base, &region)?;
+            device.write_blocks(base + BootSector::CHECKSUM_SECTOR, &checksum_sector)?;
+        }
+        device.flush()
+    }
+    
+    /// Mount the volume at `partition_lba_base` with `options`
+    fn mount_with(mut device: impl BlockDevice + 'static, partition_lba_base: u64, options: MountOptions) -> Result<Self> {
+        let boot_sector = Self::read_boot_sector(&mut device, partition_lba_base)?;
+        if options.strict && !boot_sector.is_valid_strict() {
+            return Err(ExFatError::BadSignature);
+        }
+        if !options.read_only {
+            if (boot_sector.volume_flags & volume_flags::VOLUME_DIRTY) != 0 && !options.ignore_dirty {
+                return Err(ExFatError::VolumeDirty);
+            }
+            if (boot_sector.volume_flags & volume_flags::MEDIA_FAILURE) != 0 {
+                return Err(ExFatError::WriteProtected);
+            }
+        }
+        
+        let mut volume = Self::new(boot_sector, device)?;
+        volume.partition_lba_base = partition_lba_base;
+        volume.read_only = options.read_only;
+        volume.load_system_entries()?;
+        Ok(volume)
+    }
+    
+    /// Check a device for an exFAT volume without mounting it
+    ///
+    /// Both the main and backup boot regions are checked, and the better
+    /// outcome is returned, so `Valid` means `mount` would find a boot
+    /// sector. Only one sector is held in memory at a time.
+    pub fn probe(device: &mut dyn BlockDevice) -> ProbeResult {
+        let probe = |device: &mut dyn BlockDevice, base| match Self::read_boot_region(device, base) {
+            Ok(_) => ProbeResult::Valid,
+            Err(ExFatError::ChecksumMismatch) => ProbeResult::ChecksumBad,
+            Err(ExFatError::Io(status)) => ProbeResult::IoError(status),
+            Err(_) => ProbeResult::NotExFat,
+        };
+        
+        match probe(device, 0) {
+            ProbeResult::Valid => ProbeResult::Valid,
+            main => match probe(device, BootSector::BACKUP_SECTOR) {
+                ProbeResult::Valid => ProbeResult::Valid,
+                ProbeResult::ChecksumBad => ProbeResult::ChecksumBad,
+                _ => main,
+            },
+        }
+    }
+    
+    /// Check if the volume is mounted read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    
+    /// Scan the root directory once and cache the system entries
+    ///
+    /// The allocation bitmap, up-case table, volume label and volume GUID
+    /// entries are accepted in any order among the other root entries. On
+    /// TexFAT volumes both bitmaps are recorded, and allocation uses the one
+    /// that goes with the active FAT. Fails with
+    /// `ExFatError::MissingAllocationBitmap` or
+    /// `ExFatError::MissingUpCaseTable` if a mandatory entry is absent, and
+    /// with `ExFatError::ChecksumMismatch` if the up-case table doesn't
+    /// match its checksum. A GUID entry with a bad checksum is ignored.
+    pub fn load_system_entries(&mut self) -> Result<()> {
+        self.system = Some(self.read_system_entries()?);
+        Ok(())
+    }
+    
+    /// Read the system entries and the allocation bitmap in one pass
+    ///
+    /// The root directory is scanned once, as by `load_system_entries`,
+    /// and the active allocation bitmap is read from disk. Nothing is
+    /// cached on the volume, so the bitmap doesn't include allocations
+    /// not yet written back.
+    pub fn load_root_metadata(&self) -> Result<RootMetadata> {
+        let system = self.read_system_entries()?;
+        let (first_cluster, data_length) = match self.boot_sector.active_bitmap() {
+            BitmapId::First => system.bitmap,
+            BitmapId::Second => system.second_bitmap.ok_or(ExFatError::MissingAllocationBitmap)?,
+        };
+        
+        Ok(RootMetadata {
+            bitmap: self.read_bitmap(first_cluster, data_length)?,
+            upcase: system.upcase,
+            label: system.label,
+            guid: system.guid,
+        })
+    }
+    
+    /// Scan the root directory and read the up-case table it refers to
+    fn read_system_entries(&self) -> Result<SystemEntries> {
+        let scan = self.scan_root()?;
+        let bitmap = scan.bitmap.ok_or(ExFatError::MissingAllocationBitmap)?;
+        let entry = scan.upcase.ok_or(ExFatError::MissingUpCaseTable)?;
+        
+        let data = self.read_system_stream(entry.first_cluster, entry.data_length)?;
+        if table_checksum(&data) != entry.table_checksum {
+            return Err(ExFatError::ChecksumMismatch);
+        }
+        
+        Ok(SystemEntries {
+            bitmap,
+            second_bitmap: scan.second_bitmap,
+            upcase: UpCaseTable::from_bytes(&data)?,
+            label: scan.label,
+            guid: scan.guid,
         })
     }
     
+    /// Get the system entries cached by `load_system_entries`
+    pub fn system_entries(&self) -> Option<&SystemEntries> {
+        self.system.as_ref()
+    }
+    
+    /// Get the volume's up-case table, if it has been loaded
+    pub fn upcase_table(&self) -> Option<&UpCaseTable> {
+        self.system.as_ref().map(|system| &system.upcase)
+    }
+    
+    /// Get a copy of one of the volume's allocation bitmaps
+    ///
+    /// The active bitmap, chosen by `BootSector::active_bitmap`, is the one
+    /// used for allocation and includes changes not yet written back. The
+    /// other bitmap of a TexFAT volume is read from disk for inspection
+    /// only; it is never updated. Fails with
+    /// `ExFatError::MissingAllocationBitmap` if the volume has no such
+    /// bitmap.
+    pub fn allocation_bitmap(&self, which: BitmapId) -> Result<AllocationBitmap> {
+        if which == self.boot_sector.active_bitmap() {
+            return self.with_bitmap(|bitmap| bitmap.clone());
+        }
+        
+        let (first_cluster, data_length) = self.bitmap_location(which)?;
+        self.read_bitmap(first_cluster, data_length)
+    }
+    
+    /// Set the clock used to stamp created and modified files
+    ///
+    /// Without a time source, new files get zero timestamps and existing
+    /// timestamps are left alone.
+    pub fn set_time_source(&mut self, time_source: impl TimeSource + 'static) {
+        self.time_source = Some(Box::new(time_source));
+    }
+    
+    /// Get the device LBA of the volume's first sector
+    pub fn partition_lba_base(&self) -> u64 {
+        self.partition_lba_base
+    }
+    
+    /// Override where the volume starts on the device
+    ///
+    /// Set to 0 when the device already represents just the partition.
+    pub fn set_partition_lba_base(&mut self, partition_lba_base: u64) {
+        self.partition_lba_base = partition_lba_base;
+    }
+    
     /// Get bytes per sector
     pub fn bytes_per_sector(&self) -> u32 {
         self.boot_sector.bytes_per_sector()
@@ -40,7 +615,20 @@ impl ExFatVolume {
     }
     
     /// Convert cluster number to LBA (Logical Block Address)
-    pub fn cluster_to_lba(&self, cluster: u32) -> u64 {
+    ///
+    /// Fails with `ExFatError::ClusterOutOfRange` for clusters outside the
+    /// cluster heap, as checked by `BootSector::is_valid_cluster`.
+    pub fn cluster_to_lba(&self, cluster: u32) -> Result<u64> {
+        self.validate_cluster(cluster)?;
+        Ok(self.sector_to_lba(self.cluster_to_sector(cluster)))
+    }
+    
+    /// Convert cluster number to a sector number within the volume
+    ///
+    /// The cluster isn't range checked here; callers validate it first
+    /// with `BootSector::is_valid_cluster`.
+    fn cluster_to_sector(&self, cluster: u32) -> u64 {
+        debug_assert!(cluster >= 2);
         let cluster_heap_offset = self.boot_sector.cluster_heap_offset as u64;
         let sectors_per_cluster = self.boot_sector.sectors_per_cluster() as u64;
         
@@ -48,152 +636,6529 @@ impl ExFatVolume {
         cluster_heap_offset + ((cluster - 2) as u64 * sectors_per_cluster)
     }
     
+    /// Convert a sector number within the volume to a device LBA
+    ///
+    /// The result wraps rather than overflowing; a corrupt
+    /// `partition_offset` then yields an LBA the device rejects.
+    fn sector_to_lba(&self, sector: u64) -> u64 {
+        self.partition_lba_base.wrapping_add(sector)
+    }
+    
     /// Read a cluster from the volume
-    pub fn read_cluster(&self, _cluster: u32, buffer: &mut [u8]) -> Result<()> {
+    pub fn read_cluster(&self, cluster: u32, buffer: &mut [u8]) -> Result<()> {
         let bytes_per_cluster = self.bytes_per_cluster() as usize;
         
         if buffer.len() < bytes_per_cluster {
-            return Err(Status::BUFFER_TOO_SMALL.into());
+            return Err(ExFatError::BufferTooSmall);
+        }
+        
+        let lba = self.cluster_to_lba(cluster)?;
+        self.device.borrow_mut().read_blocks(lba, &mut buffer[..bytes_per_cluster])
+    }
+    
+    /// Read `cluster_count` consecutive clusters with a single device read
+    ///
+    /// Used for contiguous (`NoFatChain`) streams, where the clusters are
+    /// also consecutive on disk.
+    pub fn read_contiguous(&self, first_cluster: u32, cluster_count: u32, buffer: &mut [u8]) -> Result<()> {
+        let length = cluster_count as u64 * self.bytes_per_cluster() as u64;
+        let length = usize::try_from(length).map_err(|_| ExFatError::BufferTooSmall)?;
+        if buffer.len() < length {
+            return Err(ExFatError::BufferTooSmall);
+        }
+        if cluster_count == 0 {
+            return Ok(());
         }
         
-        // In a real implementation, this would read from the block device
-        // For now, this is a placeholder
+        let last = first_cluster.checked_add(cluster_count - 1).ok_or(ExFatError::ClusterOutOfRange)?;
+        self.validate_cluster(last)?;
+        let lba = self.cluster_to_lba(first_cluster)?;
+        self.device.borrow_mut().read_blocks(lba, &mut buffer[..length])
+    }
+    
+    /// Write a cluster to the volume
+    pub fn write_cluster(&self, cluster: u32, buffer: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let bytes_per_cluster = self.bytes_per_cluster() as usize;
         
-        Ok(())
+        if buffer.len() < bytes_per_cluster {
+            return Err(ExFatError::BufferTooSmall);
+        }
+        
+        let lba = self.cluster_to_lba(cluster)?;
+        self.device.borrow_mut().write_blocks(lba, &buffer[..bytes_per_cluster])
     }
     
     /// Get the root directory cluster
     pub fn root_dir_cluster(&self) -> u32 {
         self.boot_sector.root_dir_cluster
     }
-}
-
-/// exFAT File Handle
-pub struct ExFatFile {
-    /// File name
-    pub name: String,
-    /// File attributes
-    pub attributes: u16,
-    /// First cluster
-    pub first_cluster: u32,
-    /// File size
-    pub size: u64,
-    /// Current position in file
-    pub position: u64,
-    /// Associated volume (for future use)
-    #[allow(dead_code)]
-    volume: usize,
-}
-
-impl ExFatFile {
-    /// Create a new file handle
-    pub fn new(name: String, attributes: u16, first_cluster: u32, size: u64, volume: usize) -> Self {
-        Self {
-            name,
-            attributes,
-            first_cluster,
-            size,
-            position: 0,
-            volume,
+    
+    /// Read the FAT entry for a cluster
+    ///
+    /// The entry is served from the FAT cache, reading its sector from the
+    /// active FAT on a miss. The reserved entries 0 and 1 aren't clusters
+    /// and fail with `ExFatError::ClusterOutOfRange`, as does any other
+    /// cluster outside the heap.
+    pub fn fat_entry(&self, cluster: u32) -> Result<FatEntry> {
+        let (lba, _) = self.fat_entry_location(cluster)?;
+        
+        let mut cache = self.fat_cache.borrow_mut();
+        self.load_fat_sector(&mut cache, cluster, lba)?;
+        cache.read_fat_entry(cluster).map(FatEntry::from_u32).ok_or(ExFatError::Corrupted)
+    }
+    
+    /// Locate a cluster's entry in the active FAT
+    ///
+    /// Returns the device LBA of the FAT sector holding the entry and the
+    /// entry's byte offset within that sector. Fails with
+    /// `ExFatError::ClusterOutOfRange` for clusters outside the heap.
+    pub fn fat_entry_location(&self, cluster: u32) -> Result<(u64, usize)> {
+        self.validate_cluster(cluster)?;
+        
+        let byte_offset = cluster as u64 * 4;
+        let bytes_per_sector = self.bytes_per_sector() as u64;
+        let sector = self.boot_sector.active_fat_offset() + byte_offset / bytes_per_sector;
+        Ok((self.sector_to_lba(sector), (byte_offset % bytes_per_sector) as usize))
+    }
+    
+    /// Check that FAT entries 0 and 1 hold their reserved values
+    ///
+    /// `fat_entry` refuses these entries, so they are read here directly
+    /// from the active FAT. Fails with `ExFatError::Corrupted` if either
+    /// differs from `FatEntry::RESERVED_VALUES`.
+    pub fn check_reserved_fat_entries(&self) -> Result<()> {
+        let lba = self.sector_to_lba(self.boot_sector.active_fat_offset());
+        let mut cache = self.fat_cache.borrow_mut();
+        self.load_fat_sector(&mut cache, 0, lba)?;
+        
+        let entries = [0, 1].map(|cluster| cache.read_fat_entry(cluster));
+        if entries != FatEntry::RESERVED_VALUES.map(Some) {
+            return Err(ExFatError::Corrupted);
         }
+        Ok(())
     }
     
-    /// Check if this is a directory
-    pub fn is_directory(&self) -> bool {
-        (self.attributes & file_attributes::DIRECTORY) != 0
+    /// Write the changed sectors of the FAT cache to disk
+    ///
+    /// Each sector goes to the active FAT and, on TexFAT volumes, to the
+    /// other FAT as well.
+    pub fn flush_fat(&self) -> Result<()> {
+        let mut cache = self.fat_cache.borrow_mut();
+        if !cache.is_dirty() {
+            return Ok(());
+        }
+        
+        self.check_writable()?;
+        for (index, sector) in cache.dirty_sectors() {
+            self.write_fat_sector(index, sector)?;
+        }
+        cache.clear_dirty();
+        Ok(())
     }
     
-    /// Read from the file
-    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
-        if self.position >= self.size {
-            return Ok(0);
+    /// Change how many FAT sectors are cached
+    ///
+    /// Outstanding changes are written back first.
+    pub fn set_fat_cache_capacity(&mut self, capacity: usize) -> Result<()> {
+        self.flush_fat()?;
+        self.fat_cache = RefCell::new(FatCache::new(capacity, self.bytes_per_sector() as usize));
+        Ok(())
+    }
+    
+    /// Count the free clusters in the allocation bitmap
+    pub fn free_clusters(&self) -> Result<u32> {
+        self.with_bitmap(|bitmap| bitmap.free_cluster_count())
+    }
+    
+    /// Compute the percentage of allocated clusters
+    ///
+    /// The result is rounded down. Returns `0xFF`, the on-disk value for
+    /// "not available", if the allocation bitmap can't be read.
+    pub fn recompute_percent_in_use(&self) -> u8 {
+        let cluster_count = self.total_clusters() as u64;
+        match self.free_clusters() {
+            Ok(free) if cluster_count > 0 => {
+                let used = cluster_count - free as u64;
+                (used * 100 / cluster_count) as u8
+            }
+            _ => 0xFF,
+        }
+    }
+    
+    /// Check if the volume dirty flag is set
+    pub fn is_dirty(&self) -> bool {
+        (self.boot_sector.volume_flags & volume_flags::VOLUME_DIRTY) != 0
+    }
+    
+    /// Check if the media failure flag is set
+    pub fn has_media_failure(&self) -> bool {
+        (self.boot_sector.volume_flags & volume_flags::MEDIA_FAILURE) != 0
+    }
+    
+    /// Verify the checksum of the boot region starting at `base`
+    ///
+    /// `base` is 0 for the main boot region or `BootSector::BACKUP_SECTOR`
+    /// for the backup, relative to the start of the volume.
+    pub fn verify_boot_region(&self, base: u64) -> Result<()> {
+        Self::read_boot_region(&mut **self.device.borrow_mut(), self.sector_to_lba(base)).map(|_| ())
+    }
+    
+    /// Set the volume dirty flag
+    ///
+    /// Should be called before the first write to the volume.
+    pub fn mark_dirty(&mut self) -> Result<()> {
+        self.set_volume_flag(volume_flags::VOLUME_DIRTY, true)
+    }
+    
+    /// Clear the volume dirty flag
+    pub fn mark_clean(&mut self) -> Result<()> {
+        self.set_volume_flag(volume_flags::VOLUME_DIRTY, false)
+    }
+    
+    /// Write back the cached FAT sectors and allocation bitmap and flush
+    /// the block device
+    ///
+    /// Does nothing for a read-only volume.
+    pub fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
         }
         
-        let remaining = (self.size - self.position) as usize;
-        let to_read = buffer.len().min(remaining);
+        self.commit()?;
+        self.device.borrow_mut().flush()
+    }
+    
+    /// Write every outstanding change to disk in crash-safe order
+    ///
+    /// The allocation bitmap and FAT are written as by `commit`, then
+    /// `percent_in_use` is refreshed in both boot regions and the block
+    /// device is flushed. Does nothing for a read-only volume.
+    pub fn sync_all(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
         
-        // In a real implementation, this would read from the clusters
-        // For now, this is a placeholder
+        self.commit()?;
+        let percent_in_use = self.recompute_percent_in_use();
+        if percent_in_use != self.boot_sector.percent_in_use {
+            self.write_boot_bytes(BootSector::PERCENT_IN_USE_OFFSET, &[percent_in_use])?;
+            self.boot_sector.percent_in_use = percent_in_use;
+        }
+        self.device.get_mut().flush()
+    }
+    
+    /// Flush outstanding state and release the volume
+    ///
+    /// The cached FAT sectors and allocation bitmap are written back, the
+    /// dirty flag is cleared and the block device is flushed.
+    ///
+    /// Nothing is written for a read-only volume, which never sets the
+    /// dirty flag in the first place.
+    pub fn unmount(mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
         
-        self.position += to_read as u64;
-        Ok(to_read)
+        self.commit()?;
+        self.mark_clean()?;
+        self.device.get_mut().flush()
     }
     
-    /// Seek to a position in the file
-    pub fn seek(&mut self, position: u64) -> Result<()> {
-        if position > self.size {
-            return Err(Status::INVALID_PARAMETER.into());
+    /// Get the sectors holding the FATs as `(start_lba, sector_count)`
+    ///
+    /// Covers every FAT, including the second FAT of TexFAT volumes.
+    pub fn fat_region(&self) -> Result<(u64, u64)> {
+        let length = (self.boot_sector.fat_length as u64)
+            .checked_mul(self.boot_sector.num_fats as u64)
+            .ok_or(ExFatError::Corrupted)?;
+        self.region(self.boot_sector.fat_offset as u64, length)
+    }
+    
+    /// Get the sectors holding the allocation bitmap as
+    /// `(start_lba, sector_count)`
+    ///
+    /// Fails with `ExFatError::Corrupted` if the bitmap's clusters aren't
+    /// consecutive, since the region couldn't be described by one range.
+    pub fn bitmap_region(&self) -> Result<(u64, u64)> {
+        let (first_cluster, length) = self.with_bitmap(|bitmap| (bitmap.first_cluster(), bitmap.as_bytes().len() as u64))?;
+        let clusters = self.cluster_chain(first_cluster, false, length)?;
+        if clusters.windows(2).any(|pair| pair[1] != pair[0] + 1) {
+            return Err(ExFatError::Corrupted);
         }
         
-        self.position = position;
-        Ok(())
+        let sectors = length.div_ceil(self.bytes_per_sector() as u64);
+        self.region(self.cluster_to_sector(first_cluster), sectors)
     }
     
-    /// Get file size
-    pub fn size(&self) -> u64 {
-        self.size
+    /// Get the sectors holding the cluster heap as
+    /// `(start_lba, sector_count)`
+    pub fn cluster_heap_region(&self) -> Result<(u64, u64)> {
+        let sectors_per_cluster = 1u64
+            .checked_shl(self.boot_sector.sectors_per_cluster_shift as u32)
+            .ok_or(ExFatError::Corrupted)?;
+        let length = (self.boot_sector.cluster_count as u64)
+            .checked_mul(sectors_per_cluster)
+            .ok_or(ExFatError::Corrupted)?;
+        self.region(self.boot_sector.cluster_heap_offset as u64, length)
     }
     
-    /// Get file name
-    pub fn name(&self) -> &str {
-        &self.name
+    /// Build a region from a sector within the volume, checking that its
+    /// end is addressable on the device
+    fn region(&self, start: u64, length: u64) -> Result<(u64, u64)> {
+        let start = self.partition_lba_base.checked_add(start).ok_or(ExFatError::Corrupted)?;
+        start.checked_add(length).ok_or(ExFatError::Corrupted)?;
+        Ok((start, length))
     }
-}
-
-/// exFAT Directory Handle
-pub struct ExFatDirectory {
-    /// Directory cluster
-    pub cluster: u32,
-    /// Current entry index
-    pub current_entry: usize,
-    /// Associated volume (for future use)
-    #[allow(dead_code)]
-    volume: usize,
-}
-
-impl ExFatDirectory {
-    /// Create a new directory handle
-    pub fn new(cluster: u32, volume: usize) -> Self {
-        Self {
-            cluster,
-            current_entry: 0,
-            volume,
+    
+    /// Read raw sectors from the volume, for inspection
+    ///
+    /// `start_lba` is a sector number within the volume; the partition base
+    /// is added before reading. Fails with `ExFatError::InvalidParameter`
+    /// if the sectors run past the end of the volume, and with
+    /// `ExFatError::BufferTooSmall` if `out` can't hold them. FAT changes
+    /// still held in the FAT cache aren't visible until flushed.
+    pub fn dump_region(&self, start_lba: u64, sectors: u32, out: &mut [u8]) -> Result<()> {
+        let end = start_lba.checked_add(sectors as u64).ok_or(ExFatError::InvalidParameter)?;
+        if end > self.boot_sector.volume_length {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        let length = sectors as u64 * self.bytes_per_sector() as u64;
+        let length = usize::try_from(length).map_err(|_| ExFatError::BufferTooSmall)?;
+        if out.len() < length {
+            return Err(ExFatError::BufferTooSmall);
+        }
+        if sectors == 0 {
+            return Ok(());
         }
+        
+        let (lba, _) = self.region(start_lba, sectors as u64)?;
+        self.device.borrow_mut().read_blocks(lba, &mut out[..length])
     }
     
-    /// Read next directory entry
-    pub fn read_entry(&mut self) -> Result<Option<ExFatFile>> {
-        // In a real implementation, this would read directory entries
-        // from the clusters and parse them into file structures
-        // For now, this is a placeholder
-        
-        Ok(None)
+    /// Get the number of clusters in the cluster heap
+    pub fn total_clusters(&self) -> u32 {
+        self.boot_sector.cluster_count
     }
     
-    /// Reset directory iteration
-    pub fn reset(&mut self) {
-        self.current_entry = 0;
+    /// Get the size of the cluster heap in bytes
+    pub fn usable_bytes(&self) -> u64 {
+        self.total_clusters() as u64 * self.bytes_per_cluster() as u64
     }
-}
-
-#[cfg(all(test, not(target_os = "uefi")))]
-mod tests {
-    use super::*;
     
-    #[test]
-    fn test_cluster_to_lba() {
-        let mut boot_sector = unsafe { core::mem::zeroed::<BootSector>() };
-        boot_sector.cluster_heap_offset = 1024;
-        boot_sector.sectors_per_cluster_shift = 3; // 8 sectors per cluster
-        boot_sector.boot_signature = 0xAA55;
-        boot_sector.fs_name.copy_from_slice(b"EXFAT   ");
+    /// Get the largest file size the volume can hold
+    ///
+    /// The 64-bit data length field never binds before the cluster heap
+    /// does, so this is the size of the heap.
+    pub fn max_file_size(&self) -> u64 {
+        self.usable_bytes()
+    }
+    
+    /// Get the free space in bytes
+    pub fn free_bytes(&self) -> Result<u64> {
+        Ok(self.free_clusters()? as u64 * self.bytes_per_cluster() as u64)
+    }
+    
+    /// Check if `bytes` more bytes of clusters can be allocated
+    ///
+    /// The byte count is rounded up to whole clusters and compared with
+    /// the free count in the allocation bitmap, including allocations not
+    /// yet written back.
+    pub fn can_allocate(&self, bytes: u64) -> Result<bool> {
+        let needed = bytes.div_ceil(self.bytes_per_cluster() as u64);
+        Ok(needed <= self.free_clusters()? as u64)
+    }
+    
+    /// Take a snapshot of the volume's geometry and usage
+    ///
+    /// The label comes from the cached system entries, or from a scan of
+    /// the root directory if they haven't been loaded. The free count
+    /// includes allocations not yet written back.
+    pub fn metadata(&self) -> Result<VolumeMetadata> {
+        let label = match &self.system {
+            Some(system) => system.label.clone(),
+            None => self.scan_root()?.label,
+        };
+        
+        Ok(VolumeMetadata {
+            bytes_per_sector: self.bytes_per_sector(),
+            bytes_per_cluster: self.bytes_per_cluster(),
+            cluster_count: self.boot_sector.cluster_count,
+            root_dir_cluster: self.boot_sector.root_dir_cluster,
+            volume_serial: self.boot_sector.volume_serial,
+            label,
+            free_clusters: self.free_clusters()?,
+        })
+    }
+    
+    /// Read the volume GUID from the root directory
+    ///
+    /// Returns `None` if the volume has no GUID entry.
+    pub fn volume_guid(&self) -> Result<Option<[u8; 16]>> {
+        let root = self.load_directory(self.root_stream())?;
+        let Some(index) = root.find_entry(EntryType::VolumeGuid) else {
+            return Ok(None);
+        };
         
-        let volume = ExFatVolume::new(boot_sector, 0).unwrap();
+        let entry = VolumeGuidEntry::from_bytes(&root.entry(index));
+        if !entry.checksum_valid() {
+            return Err(ExFatError::ChecksumMismatch);
+        }
+        Ok(Some(entry.volume_guid))
+    }
+    
+    /// Set or remove the volume GUID
+    pub fn set_volume_guid(&mut self, guid: Option<[u8; 16]>) -> Result<()> {
+        self.check_writable()?;
+        let mut root = self.load_directory(self.root_stream())?;
+        let existing = root.find_entry(EntryType::VolumeGuid);
         
-        // Cluster 2 should be at cluster_heap_offset
-        assert_eq!(volume.cluster_to_lba(2), 1024);
+        match (guid, existing) {
+            (Some(guid), Some(index)) => root.write_entries(index, &[VolumeGuidEntry::new(guid).to_bytes()]),
+            (Some(guid), None) => {
+                let index = root.find_free_run(1).ok_or(ExFatError::VolumeFull)?;
+                root.write_entries(index, &[VolumeGuidEntry::new(guid).to_bytes()]);
+            }
+            (None, Some(index)) => {
+                let mut entry = root.entry(index);
+                entry[0] &= 0x7F;
+                root.write_entries(index, &[entry]);
+            }
+            (None, None) => return Ok(()),
+        }
         
-        // Cluster 3 should be at cluster_heap_offset + sectors_per_cluster
-        assert_eq!(volume.cluster_to_lba(3), 1032);
+        self.store_directory(&root)?;
+        if let Some(system) = &mut self.system {
+            system.guid = guid;
+        }
+        Ok(())
+    }
+    
+    /// Write the FAT entry for a cluster
+    ///
+    /// The change is made in the FAT cache and reaches the disk when its
+    /// sector is evicted or the FAT is flushed. As with `fat_entry`, the
+    /// reserved entries 0 and 1 are refused.
+    pub fn set_fat_entry(&self, cluster: u32, entry: FatEntry) -> Result<()> {
+        self.check_writable()?;
+        let (lba, _) = self.fat_entry_location(cluster)?;
+        
+        let mut cache = self.fat_cache.borrow_mut();
+        self.load_fat_sector(&mut cache, cluster, lba)?;
+        if !cache.write_fat_entry(cluster, entry.to_u32()) {
+            return Err(ExFatError::Corrupted);
+        }
+        Ok(())
+    }
+    
+    /// List the clusters of a data stream in order
+    ///
+    /// Contiguous streams are computed from `data_length`; all others follow
+    /// the FAT chain to its end.
+    pub fn cluster_chain(&self, first_cluster: u32, contiguous: bool, data_length: u64) -> Result<Vec<u32>> {
+        if first_cluster == 0 {
+            return Ok(Vec::new());
+        }
+        
+        if contiguous {
+            self.check_contiguous(first_cluster, data_length)?;
+            let count = data_length.div_ceil(self.bytes_per_cluster() as u64) as u32;
+            return Ok((first_cluster..first_cluster + count).collect());
+        }
+        
+        self.chain(first_cluster).collect()
+    }
+    
+    /// Check that a cluster number lies within the cluster heap
+    ///
+    /// Valid clusters run from 2 to `cluster_count + 1`; anything else
+    /// fails with `ExFatError::ClusterOutOfRange`.
+    pub fn validate_cluster(&self, cluster: u32) -> Result<()> {
+        if !self.boot_sector.is_valid_cluster(cluster) {
+            return Err(ExFatError::ClusterOutOfRange);
+        }
+        Ok(())
+    }
+    
+    /// Check the first cluster of a data stream read from an entry set
+    ///
+    /// Only an empty stream may have no clusters; a nonzero length with a
+    /// first cluster of 0 fails with `ExFatError::Corrupted`. Any other
+    /// first cluster must pass `validate_cluster`. Applied whenever a file
+    /// or directory is opened from its entry set.
+    fn validate_stream(&self, first_cluster: u32, data_length: u64) -> Result<()> {
+        match (first_cluster, data_length) {
+            (0, 0) => Ok(()),
+            (0, _) => Err(ExFatError::Corrupted),
+            (cluster, _) => self.validate_cluster(cluster),
+        }
+    }
+    
+    /// Check that a contiguous stream lies within the cluster heap
+    ///
+    /// A stream marked `NoFatChain` occupies every cluster from
+    /// `first_cluster` on, so one whose last cluster would fall past the
+    /// end of the heap is corrupt. Fails with
+    /// `ExFatError::ClusterOutOfRange` in that case.
+    pub fn check_contiguous(&self, first_cluster: u32, data_length: u64) -> Result<()> {
+        let count = data_length.div_ceil(self.bytes_per_cluster() as u64).max(1);
+        let last = u32::try_from(first_cluster as u64 + count - 1).map_err(|_| ExFatError::ClusterOutOfRange)?;
+        self.validate_cluster(first_cluster)?;
+        self.validate_cluster(last)
+    }
+    
+    /// Map a byte offset within a data stream to a cluster
+    ///
+    /// Returns the cluster holding `offset` and the byte offset within that
+    /// cluster. Contiguous streams are computed directly; all others walk
+    /// the FAT chain. Fails with `ExFatError::InvalidParameter` if the
+    /// offset lies past the end of the chain.
+    pub fn offset_to_cluster(&self, first_cluster: u32, contiguous: bool, offset: u64) -> Result<(u32, u32)> {
+        if first_cluster == 0 {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        let bytes_per_cluster = self.bytes_per_cluster() as u64;
+        let hops = offset / bytes_per_cluster;
+        let within = (offset % bytes_per_cluster) as u32;
+        
+        let cluster = if contiguous {
+            let cluster = u32::try_from(first_cluster as u64 + hops).map_err(|_| ExFatError::ClusterOutOfRange)?;
+            self.validate_cluster(cluster)?;
+            cluster
+        } else {
+            let hops = usize::try_from(hops).map_err(|_| ExFatError::InvalidParameter)?;
+            self.chain(first_cluster).nth(hops).ok_or(ExFatError::InvalidParameter)??
+        };
+        
+        Ok((cluster, within))
+    }
+    
+    /// Count the clusters and extents of a data stream
+    ///
+    /// A contiguous stream is a single extent by definition; all others
+    /// walk the FAT chain, starting a new extent wherever a link doesn't
+    /// lead to the next cluster on disk. The volume is not modified.
+    pub fn fragmentation(&self, first_cluster: u32, contiguous: bool, data_length: u64) -> Result<FragReport> {
+        let clusters = self.cluster_chain(first_cluster, contiguous, data_length)?;
+        let count = u32::try_from(clusters.len()).map_err(|_| ExFatError::Corrupted)?;
+        if count == 0 {
+            return Ok(FragReport::default());
+        }
+        
+        let breaks = clusters.windows(2).filter(|pair| pair[0].checked_add(1) != Some(pair[1])).count() as u32;
+        Ok(FragReport {
+            clusters: count,
+            extents: if contiguous { 1 } else { breaks + 1 },
+        })
+    }
+    
+    /// Report the fragmentation of every file and directory on the volume
+    ///
+    /// Each entry below the root with clusters allocated is measured with
+    /// `fragmentation`. Entries are listed with the most breaks first,
+    /// keeping directory order among equals. The volume is not modified.
+    pub fn fragmentation_report(&self) -> Result<FragmentationReport> {
+        let mut streams = Vec::new();
+        self.walk(self.root_dir_cluster(), &mut |path, file| {
+            if file.first_cluster != 0 {
+                streams.push((String::from(path), file.first_cluster, file.is_contiguous(), file.size));
+            }
+        })?;
+        
+        let mut report = FragmentationReport::default();
+        for (path, first_cluster, contiguous, data_length) in streams {
+            let file = self.fragmentation(first_cluster, contiguous, data_length)?;
+            report.clusters += file.clusters as u64;
+            report.fragments += file.fragments() as u64;
+            report.files.push((path, file));
+        }
+        report.files.sort_by_key(|(_, file)| core::cmp::Reverse(file.fragments()));
+        Ok(report)
+    }
+    
+    /// Find the path of the file or directory whose data includes `cluster`
+    ///
+    /// Every entry on the volume is visited and its clusters listed, so
+    /// this is slow on large volumes. The root directory's own clusters
+    /// report `/`. Returns `None` for a cluster no file or directory owns,
+    /// including those of the allocation bitmap and up-case table. Fails
+    /// with `ExFatError::ClusterOutOfRange` if the cluster lies outside the
+    /// cluster heap.
+    pub fn find_file_owning_cluster(&self, cluster: u32) -> Result<Option<String>> {
+        self.validate_cluster(cluster)?;
+        
+        let mut streams = vec![(String::from("/"), self.root_dir_cluster(), false, 0)];
+        self.walk(self.root_dir_cluster(), &mut |path, file| {
+            if file.first_cluster != 0 {
+                streams.push((String::from(path), file.first_cluster, file.is_contiguous(), file.size));
+            }
+        })?;
+        
+        for (path, first_cluster, contiguous, data_length) in streams {
+            if self.cluster_chain(first_cluster, contiguous, data_length)?.contains(&cluster) {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+    
+    /// Check the volume's cluster allocation for consistency
+    ///
+    /// The clusters of every file and directory, the root directory and
+    /// the system structures are traced and compared with the allocation
+    /// bitmap, including allocations not yet written back. Entry sets are
+    /// read straight from each directory, so an entry with a bad first
+    /// cluster or a subdirectory that can't be read is listed in
+    /// `broken_chains` rather than failing the check. FAT entries 0 and 1
+    /// are checked as by `check_reserved_fat_entries`. Fails only if the
+    /// root directory can't be read. The volume is not modified.
+    pub fn check(&self) -> Result<CheckReport> {
+        let bad_reserved_entries = match self.check_reserved_fat_entries() {
+            Ok(()) => false,
+            Err(ExFatError::Corrupted) => true,
+            Err(err) => return Err(err),
+        };
+        let mut report = CheckReport { bad_reserved_entries, ..Default::default() };
+        
+        let scan = self.scan_root()?;
+        let mut streams = vec![(String::from("/"), self.root_dir_cluster(), false, 0)];
+        for &(first_cluster, data_length) in scan.bitmap.iter().chain(&scan.second_bitmap) {
+            streams.push((String::from("$Bitmap"), first_cluster, false, data_length));
+        }
+        if let Some(entry) = scan.upcase {
+            streams.push((String::from("$UpCase"), entry.first_cluster, false, entry.data_length));
+        }
+        
+        let mut visited = BTreeSet::from([self.root_dir_cluster()]);
+        let mut directories = vec![(String::new(), self.root_stream())];
+        while let Some((prefix, stream)) = directories.pop() {
+            let sets = match self.load_directory(stream).and_then(|dir| dir.entry_sets(|_| true)) {
+                Ok(sets) => sets,
+                Err(err) if !prefix.is_empty() => {
+                    report.broken_chains.push((prefix, err));
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            
+            for set in sets {
+                let name = from_utf16_name(&set.name);
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let mut path = prefix.clone();
+                path.push('/');
+                path.push_str(&name);
+                
+                let (first_cluster, data_length) = (set.stream.first_cluster, set.stream.data_length);
+                if let Err(err) = self.validate_stream(first_cluster, data_length) {
+                    report.broken_chains.push((path, err));
+                    continue;
+                }
+                if first_cluster == 0 {
+                    continue;
+                }
+                
+                let contiguous = set.stream.is_contiguous();
+                let is_directory = set.file.file_attributes & file_attributes::DIRECTORY != 0;
+                if is_directory && visited.insert(first_cluster) {
+                    directories.push((path.clone(), DirStream { first_cluster, contiguous, data_length }));
+                }
+                streams.push((path, first_cluster, contiguous, data_length));
+            }
+        }
+        
+        let mut used = BTreeSet::new();
+        for (path, first_cluster, contiguous, data_length) in streams {
+            let clusters = if contiguous {
+                self.cluster_chain(first_cluster, true, data_length)
+            } else {
+                self.trace_chain(first_cluster).map(|(clusters, cycle)| {
+                    if let Some(last) = cycle {
+                        report.cycles.push((path.clone(), last));
+                    }
+                    clusters
+                })
+            };
+            match clusters {
+                Ok(clusters) => {
+                    for cluster in clusters {
+                        if !used.insert(cluster) {
+                            report.cross_linked.push(cluster);
+                        }
+                    }
+                }
+                Err(err) => report.broken_chains.push((path, err)),
+            }
+        }
+        report.cross_linked.sort_unstable();
+        report.cross_linked.dedup();
+        // An unreadable directory is usually also a broken chain
+        report.broken_chains.sort_by(|a, b| a.0.cmp(&b.0));
+        report.broken_chains.dedup_by(|a, b| a.0 == b.0);
+        
+        let clusters = 2..self.total_clusters() + 2;
+        let complete = report.broken_chains.is_empty();
+        self.with_bitmap(|bitmap| {
+            for cluster in clusters {
+                match (used.contains(&cluster), bitmap.is_allocated(cluster)) {
+                    (true, false) => report.unallocated.push(cluster),
+                    (false, true) if complete => report.leaked.push(cluster),
+                    _ => {}
+                }
+            }
+        })?;
+        Ok(report)
+    }
+    
+    /// Fix the problems in `report` that can be fixed safely
+    ///
+    /// Chains are cut at their cycles, and the allocation bitmap is
+    /// reconciled with the clusters in use. Broken and cross-linked chains
+    /// and bad reserved FAT entries are left alone. If nothing was left
+    /// alone the volume dirty flag is
+    /// then cleared. The report must match a fresh `check`, or this fails
+    /// with `ExFatError::InvalidParameter` without changing anything.
+    pub fn repair(&mut self, report: &CheckReport) -> Result<RepairResult> {
+        self.check_writable()?;
+        if self.check()? != *report {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        let mut result = RepairResult::default();
+        for (path, last) in &report.cycles {
+            self.set_fat_entry(*last, FatEntry::EndOfChain)?;
+            result.truncated.push((path.clone(), *last));
+        }
+        self.with_bitmap(|bitmap| {
+            for &cluster in &report.unallocated {
+                bitmap.set_allocated(cluster, true)?;
+            }
+            for &cluster in &report.leaked {
+                bitmap.set_allocated(cluster, false)?;
+            }
+            Ok::<_, ExFatError>(())
+        })??;
+        result.allocated.clone_from(&report.unallocated);
+        result.freed.clone_from(&report.leaked);
+        self.commit()?;
+        
+        if report.broken_chains.is_empty() && report.cross_linked.is_empty() && !report.bad_reserved_entries {
+            self.mark_clean()?;
+            result.marked_clean = true;
+        }
+        Ok(result)
+    }
+    
+    /// List the clusters of a FAT chain, stopping at a cycle
+    ///
+    /// Returns the clusters in order and, for a chain that loops back on
+    /// itself, the cluster whose link closes the loop.
+    fn trace_chain(&self, first_cluster: u32) -> Result<(Vec<u32>, Option<u32>)> {
+        let mut clusters = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut cluster = first_cluster;
+        loop {
+            clusters.push(cluster);
+            seen.insert(cluster);
+            match self.fat_entry(cluster)? {
+                FatEntry::Next(next) if seen.contains(&next) => return Ok((clusters, Some(cluster))),
+                FatEntry::Next(next) => cluster = next,
+                FatEntry::EndOfChain | FatEntry::EndOfChainValue(_) => return Ok((clusters, None)),
+                FatEntry::Free | FatEntry::Bad => return Err(ExFatError::Corrupted),
+            }
+        }
+    }
+    
+    /// Walk the FAT chain starting at `first_cluster`
+    pub fn chain(&self, first_cluster: u32) -> ClusterChain<'_> {
+        ClusterChain {
+            volume: self,
+            next: Some(first_cluster),
+            visited: 0,
+        }
+    }
+    
+    /// Move a file or directory to a new path
+    ///
+    /// Only the directory entry set moves; the data clusters, size and
+    /// timestamps are preserved. The destination's final component is the
+    /// new name, so a move within one directory is a rename. A destination
+    /// directory with no room for the entry set is extended.
+    pub fn move_entry(&self, from_path: &str, to_path: &str) -> Result<()> {
+        self.check_writable()?;
+        let (from_parent, from_name) = split_path(from_path)?;
+        let (to_parent, to_name) = split_path(to_path)?;
+        let old_name = to_utf16_name(from_name)?;
+        let new_name = to_utf16_name(to_name)?;
+        
+        let (src_stream, _) = self.resolve_directory(from_parent)?;
+        let (dst_stream, dst_ancestors) = self.resolve_directory(to_parent)?;
+        
+        let mut src = self.load_directory(src_stream)?;
+        let set = src.find(&old_name, self.upcase_table())?.ok_or(ExFatError::NotFound)?;
+        
+        // A directory can't be moved into itself or one of its descendants
+        if set.is_directory() {
+            let cluster = set.stream.first_cluster;
+            if dst_stream.first_cluster == cluster || dst_ancestors.contains(&cluster) {
+                return Err(ExFatError::InvalidParameter);
+            }
+        }
+        
+        let entries = set.with_name(&new_name, self.upcase_table());
+        
+        if src_stream.first_cluster == dst_stream.first_cluster {
+            if let Some(existing) = src.find(&new_name, self.upcase_table())? {
+                if existing.index != set.index {
+                    return Err(ExFatError::AlreadyExists);
+                }
+            }
+            
+            src.delete_set(&set);
+            let slot = self.reserve_slots(to_parent, &mut src, entries.len(), |dir| dir.delete_set(&set))?;
+            src.write_entries(slot, &entries);
+            return self.store_directory(&src);
+        }
+        
+        let mut dst = self.load_directory(dst_stream)?;
+        if dst.find(&new_name, self.upcase_table())?.is_some() {
+            return Err(ExFatError::AlreadyExists);
+        }
+        
+        let slot = self.reserve_slots(to_parent, &mut dst, entries.len(), |_| {})?;
+        dst.write_entries(slot, &entries);
+        
+        // Write the new entry set before removing the old one so a failure
+        // in between leaves the file reachable. The source is reloaded, as
+        // growing the destination may have rewritten its entry set there.
+        self.store_directory(&dst)?;
+        let mut src = self.load_directory(src_stream)?;
+        src.delete_set(&set);
+        self.store_directory(&src)
+    }
+    
+    /// Find a run of `count` free slots in `dir`, the directory at `path`
+    ///
+    /// A full directory is extended a cluster at a time, as
+    /// `ExFatDirectory::create_file` does. `dir` is reloaded after each
+    /// extension and `update` reapplied, so its pending changes are kept.
+    fn reserve_slots(
+        &self,
+        path: &str,
+        dir: &mut DirBuffer,
+        count: usize,
+        update: impl Fn(&mut DirBuffer),
+    ) -> Result<usize> {
+        let mut handle = None;
+        loop {
+            if let Some(slot) = dir.find_free_run(count) {
+                return Ok(slot);
+            }
+            
+            let handle = match &mut handle {
+                Some(handle) => handle,
+                None => handle.insert(self.open_dir(path)?),
+            };
+            handle.grow()?;
+            *dir = self.load_directory(handle.stream)?;
+            update(dir);
+        }
+    }
+    
+    /// Delete a file or an empty directory
+    ///
+    /// The entry set is marked unused first and the data clusters are
+    /// released afterwards, so an interruption leaks clusters rather than
+    /// leaving an entry that points at free space.
+    pub fn delete(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let (parent, name) = split_path(path)?;
+        let name = to_utf16_name(name)?;
+        
+        let (stream, _) = self.resolve_directory(parent)?;
+        let mut dir = self.load_directory(stream)?;
+        let set = dir.find(&name, self.upcase_table())?.ok_or(ExFatError::NotFound)?;
+        
+        if set.is_directory() && !ExFatDirectory::from_stream(DirStream::from_entry(&set.stream), self).is_empty()? {
+            return Err(ExFatError::DirectoryNotEmpty);
+        }
+        
+        dir.delete_set(&set);
+        self.store_directory(&dir)?;
+        self.free_chain(&set.stream)
+    }
+    
+    /// Rename a file or directory within its parent directory
+    pub fn rename(&self, path: &str, new_name: &str) -> Result<()> {
+        validate_file_name(new_name)?;
+        let (parent, _) = split_path(path)?;
+        
+        let mut to_path = String::from(parent);
+        to_path.push('/');
+        to_path.push_str(new_name);
+        self.move_entry(path, &to_path)
+    }
+    
+    /// Open the directory at `path` for iteration
+    ///
+    /// `/` opens the root directory. A handle to any other directory
+    /// records the position of its entry set, as one from
+    /// `ExFatDirectory::from_file` does. Fails with `ExFatError::NotFound`
+    /// if any component is missing or names a regular file.
+    pub fn open_dir(&self, path: &str) -> Result<ExFatDirectory<'_>> {
+        let name = match path_components(path).last() {
+            None | Some(".") | Some("..") => {
+                let (stream, _) = self.resolve_directory(path)?;
+                return Ok(ExFatDirectory::from_stream(stream, self));
+            }
+            Some(name) => to_utf16_name(name)?,
+        };
+        
+        let (parent, _) = split_path(path)?;
+        let (parent, _) = self.resolve_directory(parent)?;
+        let (set, position) = ExFatDirectory::from_stream(parent, self)
+            .find_set(&name, self.upcase_table())?
+            .ok_or(ExFatError::NotFound)?;
+        if !set.is_directory() {
+            return Err(ExFatError::NotFound);
+        }
+        
+        self.validate_stream(set.stream.first_cluster, set.stream.data_length)?;
+        let mut dir = ExFatDirectory::from_stream(DirStream::from_entry(&set.stream), self);
+        dir.entry_position = position;
+        Ok(dir)
+    }
+    
+    /// Open a file or directory from its metadata, without looking it up
+    ///
+    /// The handle is built from the metadata as-is; if `entry_position` is
+    /// set, metadata changes can be flushed back to that entry set,
+    /// otherwise flushing fails with `ExFatError::NotFound`. Fails with
+    /// `ExFatError::ClusterOutOfRange` if the first cluster lies outside the
+    /// cluster heap.
+    pub fn open_metadata(&self, metadata: &FileMetadata) -> Result<ExFatFile<'_>> {
+        self.validate_stream(metadata.first_cluster, metadata.data_length)?;
+        if metadata.valid_data_length > metadata.data_length {
+            return Err(ExFatError::Corrupted);
+        }
+        
+        let mut file = ExFatFile::new(
+            metadata.name.clone(),
+            metadata.attributes,
+            metadata.first_cluster,
+            metadata.data_length,
+            self,
+        );
+        file.valid_data_length = metadata.valid_data_length;
+        if metadata.contiguous {
+            file.flags |= stream_flags::NO_FAT_CHAIN;
+        }
+        file.timestamps = metadata.timestamps;
+        file.entry_position = metadata.entry_position;
+        Ok(file)
+    }
+    
+    /// Visit every file and directory below the directory at `root`
+    ///
+    /// The visitor gets each entry's full path, starting with `/`, and its
+    /// handle. A directory is reported before its contents. Directories
+    /// deeper than `MAX_WALK_DEPTH` are reported but not descended into,
+    /// and a directory whose cluster was already visited fails with
+    /// `ExFatError::Corrupted`, so a corrupt volume can't loop forever.
+    pub fn walk(&self, root: u32, visitor: &mut dyn FnMut(&str, &ExFatFile)) -> Result<()> {
+        let stream = DirStream {
+            first_cluster: root,
+            contiguous: false,
+            data_length: 0,
+        };
+        self.walk_stream(stream, String::new(), Self::MAX_WALK_DEPTH, visitor)
+    }
+    
+    /// Visit every file and directory below the directory at `path`
+    ///
+    /// The visitor gets each entry's metadata and its path, which is `path`
+    /// with `.` and `..` resolved, joined with the entry's path below it.
+    /// Only `max_depth` levels of subdirectories are descended into, so 0
+    /// visits just the entries of `path` itself. Loops are rejected as by
+    /// `walk`.
+    pub fn walk_path(&self, path: &str, max_depth: usize, mut visitor: impl FnMut(&str, &FileMetadata)) -> Result<()> {
+        let (stream, _) = self.resolve_directory(path)?;
+        
+        let mut components: Vec<&str> = Vec::new();
+        for component in path_components(path) {
+            match component {
+                "." => {}
+                ".." => {
+                    components.pop();
+                }
+                name => components.push(name),
+            }
+        }
+        let prefix: String = components.iter().flat_map(|name| ["/", name]).collect();
+        
+        self.walk_stream(stream, prefix, max_depth, &mut |path, file| visitor(path, &file.metadata()))
+    }
+    
+    /// Walk the directory `stream`, whose path is `prefix`
+    ///
+    /// Entries named `.` or `..`, which exFAT doesn't have but a corrupt or
+    /// foreign directory might, are neither visited nor descended into.
+    fn walk_stream(
+        &self,
+        stream: DirStream,
+        prefix: String,
+        max_depth: usize,
+        visitor: &mut dyn FnMut(&str, &ExFatFile),
+    ) -> Result<()> {
+        let mut visited = BTreeSet::new();
+        visited.insert(stream.first_cluster);
+        
+        // Explicit stack of open directories, to keep recursion off the
+        // firmware stack
+        let mut stack = vec![(ExFatDirectory::from_stream(stream, self), prefix)];
+        while let Some((dir, path)) = stack.last_mut() {
+            let Some(file) = dir.read_entry()? else {
+                stack.pop();
+                continue;
+            };
+            if file.name == "." || file.name == ".." {
+                continue;
+            }
+            
+            let mut file_path = path.clone();
+            file_path.push('/');
+            file_path.push_str(&file.name);
+            visitor(&file_path, &file);
+            
+            if file.is_directory() && file.first_cluster != 0 && stack.len() <= max_depth {
+                if !visited.insert(file.first_cluster) {
+                    return Err(ExFatError::Corrupted);
+                }
+                
+                let stream = DirStream {
+                    first_cluster: file.first_cluster,
+                    contiguous: file.is_contiguous(),
+                    data_length: file.size,
+                };
+                stack.push((ExFatDirectory::from_stream(stream, self), file_path));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Set or clear a volume flag in the main and backup boot sectors
+    ///
+    /// The boot checksum excludes `volume_flags`, so the checksum sectors
+    /// stay valid without being rewritten.
+    fn set_volume_flag(&mut self, flag: u16, set: bool) -> Result<()> {
+        self.check_writable()?;
+        let flags = if set {
+            self.boot_sector.volume_flags | flag
+        } else {
+            self.boot_sector.volume_flags & !flag
+        };
+        if flags == self.boot_sector.volume_flags {
+            return Ok(());
+        }
+        
+        self.write_boot_bytes(BootSector::VOLUME_FLAGS_OFFSET, &flags.to_le_bytes())?;
+        self.device.get_mut().flush()?;
+        
+        self.boot_sector.volume_flags = flags;
+        Ok(())
+    }
+    
+    /// Overwrite bytes of the main and backup boot sectors
+    ///
+    /// Only for fields the boot checksum skips, so the checksum sectors
+    /// stay valid.
+    fn write_boot_bytes(&self, offset: usize, bytes: &[u8]) -> Result<()> {
+        let mut device = self.device.borrow_mut();
+        let mut sector = vec![0u8; self.bytes_per_sector() as usize];
+        for base in [0, BootSector::BACKUP_SECTOR] {
+            let lba = self.sector_to_lba(base);
+            device.read_blocks(lba, &mut sector)?;
+            sector[offset..offset + bytes.len()].copy_from_slice(bytes);
+            device.write_blocks(lba, &sector)?;
+        }
+        Ok(())
+    }
+    
+    /// Write back the allocation bitmap, then the cached FAT sectors
+    ///
+    /// Called before a directory entry set that refers to new clusters is
+    /// written, so the entry never points at clusters the disk doesn't yet
+    /// record as allocated and linked. The bitmap goes first: a cluster
+    /// marked allocated but not yet linked is only leaked by a crash, while
+    /// one linked but still marked free could be handed out twice.
+    fn commit(&self) -> Result<()> {
+        self.store_bitmap()?;
+        self.flush_fat()
+    }
+    
+    /// Get timestamps for a newly created file
+    ///
+    /// Zero if there is no time source or its time can't be stored.
+    fn new_timestamps(&self) -> Timestamps {
+        self.time_source
+            .as_ref()
+            .and_then(|source| Timestamps::at(&source.now()).ok())
+            .unwrap_or_default()
+    }
+    
+    /// Read the boot sector, falling back to the backup boot region
+    ///
+    /// `partition_lba_base` is the device LBA of the volume's first sector.
+    /// If both regions are bad, the error from the main region is returned.
+    fn read_boot_sector(device: &mut dyn BlockDevice, partition_lba_base: u64) -> Result<BootSector> {
+        let backup = partition_lba_base.checked_add(BootSector::BACKUP_SECTOR).ok_or(ExFatError::InvalidParameter)?;
+        Self::read_boot_region(device, partition_lba_base)
+            .or_else(|err| Self::read_boot_region(device, backup).map_err(|_| err))
+    }
+    
+    /// Read and validate the boot region starting at device LBA `base`
+    ///
+    /// Every word of the checksum sector must match the checksum of the
+    /// eleven sectors before it.
+    fn read_boot_region(device: &mut dyn BlockDevice, base: u64) -> Result<BootSector> {
+        let mut sector = vec![0u8; (device.block_size() as usize).max(BootSector::SIZE)];
+        device.read_blocks(base, &mut sector)?;
+        
+        let boot_sector = BootSector::try_from_bytes(&sector)?;
+        if !boot_sector.is_valid() {
+            return Err(ExFatError::BadSignature);
+        }
+        if device.block_size() != boot_sector.bytes_per_sector() {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        // One sector at a time, so a 4K-sector region needs no large buffer
+        let mut checksum = boot_checksum_update(0, 0, &sector);
+        for index in 1..BootSector::CHECKSUM_SECTOR {
+            device.read_blocks(base + index, &mut sector)?;
+            checksum = boot_checksum_update(checksum, index as usize * sector.len(), &sector);
+        }
+        
+        device.read_blocks(base + BootSector::CHECKSUM_SECTOR, &mut sector)?;
+        if sector.chunks_exact(4).any(|word| le_u32(word, 0) != checksum) {
+            return Err(ExFatError::ChecksumMismatch);
+        }
+        Ok(boot_sector)
+    }
+    
+    /// Release the clusters of a data stream
+    ///
+    /// The clusters are cleared in the allocation bitmap, and their FAT
+    /// entries are freed unless the stream is contiguous. Both changes are
+    /// written back before returning.
+    fn free_chain(&self, stream: &StreamExtensionEntry) -> Result<()> {
+        if stream.first_cluster == 0 {
+            return Ok(());
+        }
+        
+        let clusters = self.cluster_chain(stream.first_cluster, stream.is_contiguous(), stream.data_length)?;
+        if stream.is_contiguous() {
+            let count = u32::try_from(clusters.len()).map_err(|_| ExFatError::Corrupted)?;
+            self.with_bitmap(|bitmap| bitmap.set_range(stream.first_cluster, count, false))??;
+        } else {
+            for &cluster in &clusters {
+                self.with_bitmap(|bitmap| bitmap.set_allocated(cluster, false))??;
+                self.set_fat_entry(cluster, FatEntry::Free)?;
+            }
+        }
+        self.commit()
+    }
+    
+    /// Release the clusters cut from the end of a data stream
+    ///
+    /// `kept` are the clusters the stream keeps and `freed` those that
+    /// followed them. The last kept cluster becomes the end of the FAT
+    /// chain; a contiguous stream leaves the FAT untouched. The bitmap and
+    /// FAT changes are written back before returning, so the chain on disk
+    /// never runs into clusters that may be handed out again.
+    fn release_tail(&self, kept: &[u32], freed: &[u32], contiguous: bool) -> Result<()> {
+        let Some(&first_freed) = freed.first() else {
+            return Ok(());
+        };
+        
+        if contiguous {
+            let count = u32::try_from(freed.len()).map_err(|_| ExFatError::Corrupted)?;
+            self.with_bitmap(|bitmap| bitmap.set_range(first_freed, count, false))??;
+        } else {
+            if let Some(&last) = kept.last() {
+                self.set_fat_entry(last, FatEntry::EndOfChain)?;
+            }
+            for &cluster in freed {
+                self.with_bitmap(|bitmap| bitmap.set_allocated(cluster, false))??;
+                self.set_fat_entry(cluster, FatEntry::Free)?;
+            }
+        }
+        self.commit()
+    }
+    
+    /// Allocate `count` contiguous clusters without touching the FAT
+    ///
+    /// Returns `None` if no free run is long enough.
+    fn allocate_contiguous(&self, count: u32) -> Result<Option<u32>> {
+        let first = self.with_bitmap(|bitmap| bitmap.allocate_contiguous(count))?;
+        if first.is_some() {
+            self.store_bitmap()?;
+        }
+        Ok(first)
+    }
+    
+    /// Allocate the `count` clusters starting at `first` if all are free
+    fn allocate_run_at(&self, first: u32, count: u32) -> Result<bool> {
+        let Some(end) = first.checked_add(count) else {
+            return Ok(false);
+        };
+        
+        let allocated = self.with_bitmap(|bitmap| {
+            if (first..end).any(|cluster| bitmap.is_allocated(cluster)) {
+                return false;
+            }
+            (first..end).all(|cluster| bitmap.set_allocated(cluster, true).is_ok())
+        })?;
+        if allocated {
+            self.store_bitmap()?;
+        }
+        Ok(allocated)
+    }
+    
+    /// Allocate `count` clusters anywhere and link them in the FAT
+    ///
+    /// Returns the first cluster of the new chain.
+    fn allocate_chain(&self, count: u32) -> Result<u32> {
+        let clusters = self.with_bitmap(|bitmap| {
+            let mut clusters = Vec::with_capacity(count as usize);
+            let mut next = 2;
+            while clusters.len() < count as usize {
+                let cluster = bitmap.find_free(next)?;
+                clusters.push(cluster);
+                next = cluster + 1;
+            }
+            
+            for &cluster in &clusters {
+                bitmap.set_allocated(cluster, true).ok()?;
+            }
+            Some(clusters)
+        })?.ok_or(ExFatError::VolumeFull)?;
+        
+        for pair in clusters.windows(2) {
+            self.set_fat_entry(pair[0], FatEntry::Next(pair[1]))?;
+        }
+        if let Some(&last) = clusters.last() {
+            self.set_fat_entry(last, FatEntry::EndOfChain)?;
+        }
+        
+        self.store_bitmap()?;
+        clusters.first().copied().ok_or(ExFatError::InvalidParameter)
+    }
+    
+    /// Run `f` against the allocation bitmap, loading it if necessary
+    fn with_bitmap<R>(&self, f: impl FnOnce(&mut AllocationBitmap) -> R) -> Result<R> {
+        let mut cached = self.bitmap.borrow_mut();
+        let bitmap = match &mut *cached {
+            Some(bitmap) => bitmap,
+            None => cached.insert(self.load_bitmap()?),
+        };
+        Ok(f(bitmap))
+    }
+    
+    /// Locate and read the active allocation bitmap
+    ///
+    /// A TexFAT volume whose second FAT is active allocates from its
+    /// second bitmap; every other volume uses the first.
+    fn load_bitmap(&self) -> Result<AllocationBitmap> {
+        let (first_cluster, data_length) = self.bitmap_location(self.boot_sector.active_bitmap())?;
+        self.read_bitmap(first_cluster, data_length)
+    }
+    
+    /// Get the first cluster and length of an allocation bitmap
+    ///
+    /// The locations cached by `load_system_entries` are used if present;
+    /// otherwise the root directory is scanned for them.
+    fn bitmap_location(&self, which: BitmapId) -> Result<(u32, u64)> {
+        let (first, second) = match &self.system {
+            Some(system) => (Some(system.bitmap), system.second_bitmap),
+            None => {
+                let scan = self.scan_root()?;
+                (scan.bitmap, scan.second_bitmap)
+            }
+        };
+        
+        match which {
+            BitmapId::First => first,
+            BitmapId::Second => second,
+        }
+        .ok_or(ExFatError::MissingAllocationBitmap)
+    }
+    
+    /// Read an allocation bitmap from its clusters
+    ///
+    /// The clusters are followed through the FAT and joined in chain order,
+    /// so a bitmap needn't be contiguous on disk. `store_bitmap` maps dirty
+    /// sectors back through the same chain.
+    fn read_bitmap(&self, first_cluster: u32, data_length: u64) -> Result<AllocationBitmap> {
+        let bits = self.read_system_stream(first_cluster, data_length)?;
+        let bitmap = AllocationBitmap::new(first_cluster, bits, self.boot_sector.cluster_count);
+        Ok(bitmap.with_sector_size(self.bytes_per_sector() as usize))
+    }
+    
+    /// Find the system entries in the root directory
+    fn scan_root(&self) -> Result<RootScan> {
+        let root = self.load_directory(self.root_stream())?;
+        let mut scan = RootScan::default();
+        
+        for index in 0..root.entry_count() {
+            match DirectoryEntry::from_bytes(&root.entry(index)).parse() {
+                ParsedEntry::EndOfDirectory => break,
+                ParsedEntry::Bitmap(entry) => {
+                    let slot = match entry.bitmap_id() {
+                        BitmapId::First => &mut scan.bitmap,
+                        BitmapId::Second => &mut scan.second_bitmap,
+                    };
+                    slot.get_or_insert((entry.first_cluster, entry.data_length));
+                }
+                ParsedEntry::UpCase(entry) => {
+                    scan.upcase.get_or_insert(entry);
+                }
+                ParsedEntry::Label(entry) if scan.label.is_none() => {
+                    scan.label = Some(entry.label()).filter(|label| !label.is_empty());
+                }
+                ParsedEntry::Guid(entry) if entry.checksum_valid() => {
+                    scan.guid.get_or_insert(entry.volume_guid);
+                }
+                _ => {}
+            }
+        }
+        
+        Ok(scan)
+    }
+    
+    /// Read the contents of a system structure stored as a FAT chain
+    fn read_system_stream(&self, first_cluster: u32, data_length: u64) -> Result<Vec<u8>> {
+        let bytes_per_cluster = self.bytes_per_cluster() as usize;
+        let clusters = self.cluster_chain(first_cluster, false, data_length)?;
+        let mut data = vec![0u8; clusters.len() * bytes_per_cluster];
+        for (chunk, &cluster) in data.chunks_mut(bytes_per_cluster).zip(&clusters) {
+            self.read_cluster(cluster, chunk)?;
+        }
+        
+        if (data.len() as u64) < data_length {
+            return Err(ExFatError::Corrupted);
+        }
+        data.truncate(data_length as usize);
+        Ok(data)
+    }
+    
+    /// Write the changed sectors of the cached allocation bitmap to disk
+    fn store_bitmap(&self) -> Result<()> {
+        let mut cached = self.bitmap.borrow_mut();
+        let Some(bitmap) = cached.as_mut() else {
+            return Ok(());
+        };
+        if !bitmap.is_dirty() {
+            return Ok(());
+        }
+        
+        self.check_writable()?;
+        let bytes_per_sector = self.bytes_per_sector() as usize;
+        let sectors_per_cluster = self.boot_sector.sectors_per_cluster() as usize;
+        let clusters = self.cluster_chain(bitmap.first_cluster(), false, bitmap.as_bytes().len() as u64)?;
+        let mut buffer = vec![0u8; bytes_per_sector];
+        
+        for index in bitmap.dirty_sectors() {
+            let cluster = *clusters.get(index / sectors_per_cluster).ok_or(ExFatError::Corrupted)?;
+            let lba = self.sector_to_lba(self.cluster_to_sector(cluster) + (index % sectors_per_cluster) as u64);
+            let sector = bitmap.sector(index);
+            
+            buffer.fill(0);
+            buffer[..sector.len()].copy_from_slice(sector);
+            self.device.borrow_mut().write_blocks(lba, &buffer)?;
+        }
+        
+        bitmap.clear_dirty();
+        Ok(())
+    }
+    
+    /// Read the entry set whose primary entry is at `position`
+    fn read_entry_set(&self, position: EntryPosition) -> Result<Vec<[u8; 32]>> {
+        let bytes_per_cluster = self.bytes_per_cluster() as usize;
+        let mut buffer = vec![0u8; bytes_per_cluster];
+        let mut slot = position;
+        let mut loaded = None;
+        let mut entries = Vec::new();
+        
+        loop {
+            if loaded != Some(slot.cluster) {
+                self.read_cluster(slot.cluster, &mut buffer)?;
+                loaded = Some(slot.cluster);
+            }
+            
+            let offset = slot.offset as usize;
+            let mut entry = [0u8; 32];
+            entry.copy_from_slice(&buffer[offset..offset + DirectoryEntry::SIZE]);
+            entries.push(entry);
+            
+            let expected = 1 + entries[0][1] as usize;
+            if EntryType::from(entries[0][0]) != EntryType::File {
+                return Err(ExFatError::Corrupted);
+            }
+            if entries.len() == expected {
+                return Ok(entries);
+            }
+            
+            slot = self.next_entry_position(slot)?;
+        }
+    }
+    
+    /// Overwrite the entry set whose primary entry is at `position`
+    fn write_entry_set(&self, position: EntryPosition, entries: &[[u8; 32]]) -> Result<()> {
+        let bytes_per_cluster = self.bytes_per_cluster() as usize;
+        let mut buffer = vec![0u8; bytes_per_cluster];
+        let mut slot = position;
+        
+        self.read_cluster(slot.cluster, &mut buffer)?;
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                let next = self.next_entry_position(slot)?;
+                if next.cluster != slot.cluster {
+                    self.write_cluster(slot.cluster, &buffer)?;
+                    self.read_cluster(next.cluster, &mut buffer)?;
+                }
+                slot = next;
+            }
+            
+            let offset = slot.offset as usize;
+            buffer[offset..offset + DirectoryEntry::SIZE].copy_from_slice(entry);
+        }
+        
+        self.write_cluster(slot.cluster, &buffer)
+    }
+    
+    /// Get the position of the directory entry following `position`
+    fn next_entry_position(&self, position: EntryPosition) -> Result<EntryPosition> {
+        let offset = position.offset + DirectoryEntry::SIZE as u32;
+        if offset < self.bytes_per_cluster() {
+            return Ok(EntryPosition { offset, ..position });
+        }
+        
+        let cluster = if position.contiguous {
+            position.cluster + 1
+        } else {
+            match self.fat_entry(position.cluster)? {
+                FatEntry::Next(next) => next,
+                _ => return Err(ExFatError::Corrupted),
+            }
+        };
+        
+        Ok(EntryPosition { cluster, offset: 0, ..position })
+    }
+    
+    /// Make sure the FAT sector holding a cluster's entry is cached
+    ///
+    /// A dirty sector evicted to make room is written back.
+    ///
+    /// `lba` is where the sector lies in the active FAT, as found by
+    /// `fat_entry_location`.
+    fn load_fat_sector(&self, cache: &mut FatCache, cluster: u32, lba: u64) -> Result<()> {
+        let (index, _) = cache.locate(cluster);
+        if cache.contains(index) {
+            return Ok(());
+        }
+        
+        let mut sector = vec![0u8; cache.sector_size()];
+        self.device.borrow_mut().read_blocks(lba, &mut sector)?;
+        if let Some((evicted, data)) = cache.insert(index, sector) {
+            self.write_fat_sector(evicted, &data)?;
+        }
+        Ok(())
+    }
+    
+    /// Write a sector of the FAT to every FAT on the volume
+    fn write_fat_sector(&self, index: u64, sector: &[u8]) -> Result<()> {
+        let mut device = self.device.borrow_mut();
+        for fat in 0..self.boot_sector.num_fats.max(1) as u64 {
+            let start = self.boot_sector.fat_offset as u64 + fat * self.boot_sector.fat_length as u64;
+            device.write_blocks(self.sector_to_lba(start + index), sector)?;
+        }
+        Ok(())
+    }
+    
+    /// Get the data stream of the root directory
+    fn root_stream(&self) -> DirStream {
+        DirStream {
+            first_cluster: self.root_dir_cluster(),
+            contiguous: false,
+            data_length: 0,
+        }
+    }
+    
+    /// Resolve a directory path to its data stream
+    ///
+    /// Also returns the first clusters of every ancestor of the resolved
+    /// directory, root first. exFAT stores no `.` or `..` entries, so those
+    /// components are handled here: `.` is skipped and `..` returns to the
+    /// previous directory, stopping at the root. Each directory is searched
+    /// a cluster at a time rather than loaded whole.
+    fn resolve_directory(&self, path: &str) -> Result<(DirStream, Vec<u32>)> {
+        let mut stream = self.root_stream();
+        let mut parents: Vec<DirStream> = Vec::new();
+        
+        for component in path_components(path) {
+            match component {
+                "." => continue,
+                ".." => {
+                    if let Some(parent) = parents.pop() {
+                        stream = parent;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            
+            let name = to_utf16_name(component)?;
+            let (set, _) = ExFatDirectory::from_stream(stream, self)
+                .find_set(&name, self.upcase_table())?
+                .ok_or(ExFatError::NotFound)?;
+            if !set.is_directory() {
+                return Err(ExFatError::NotFound);
+            }
+            
+            self.validate_stream(set.stream.first_cluster, set.stream.data_length)?;
+            parents.push(stream);
+            stream = DirStream::from_entry(&set.stream);
+        }
+        
+        Ok((stream, parents.iter().map(|parent| parent.first_cluster).collect()))
+    }
+    
+    /// Read an entire directory into memory
+    fn load_directory(&self, stream: DirStream) -> Result<DirBuffer> {
+        let clusters = self.cluster_chain(stream.first_cluster, stream.contiguous, stream.data_length)?;
+        let bytes_per_cluster = self.bytes_per_cluster() as usize;
+        
+        let mut data = vec![0u8; clusters.len() * bytes_per_cluster];
+        for (chunk, &cluster) in data.chunks_mut(bytes_per_cluster).zip(&clusters) {
+            self.read_cluster(cluster, chunk)?;
+        }
+        
+        Ok(DirBuffer {
+            dirty: vec![false; clusters.len()],
+            clusters,
+            data,
+            bytes_per_cluster,
+        })
+    }
+    
+    /// Fail with `WriteProtected` on a read-only volume
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(ExFatError::WriteProtected);
+        }
+        Ok(())
+    }
+    
+    /// Write back the modified clusters of a directory
+    fn store_directory(&self, dir: &DirBuffer) -> Result<()> {
+        for (i, &cluster) in dir.clusters.iter().enumerate() {
+            if dir.dirty[i] {
+                let start = i * dir.bytes_per_cluster;
+                self.write_cluster(cluster, &dir.data[start..start + dir.bytes_per_cluster])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the clusters of a FAT chain
+///
+/// A chain can't visit more clusters than the volume has, so walking past
+/// `cluster_count` clusters means the chain loops and yields
+/// `ExFatError::CyclicChain` instead of iterating forever.
+pub struct ClusterChain<'a> {
+    /// Volume whose FAT is walked
+    volume: &'a ExFatVolume,
+    /// Next cluster to yield
+    next: Option<u32>,
+    /// Number of clusters yielded so far
+    visited: u32,
+}
+
+impl Iterator for ClusterChain<'_> {
+    type Item = Result<u32>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        let cluster = self.next.take()?;
+        
+        if self.visited >= self.volume.boot_sector.cluster_count {
+            return Some(Err(ExFatError::CyclicChain));
+        }
+        self.visited += 1;
+        
+        match self.volume.fat_entry(cluster) {
+            Ok(FatEntry::Next(next)) if self.volume.boot_sector.is_valid_cluster(next) => self.next = Some(next),
+            Ok(FatEntry::Next(_)) => return Some(Err(ExFatError::ClusterOutOfRange)),
+            Ok(FatEntry::EndOfChain | FatEntry::EndOfChainValue(_)) => {}
+            Ok(FatEntry::Free | FatEntry::Bad) => return Some(Err(ExFatError::Corrupted)),
+            Err(err) => return Some(Err(err)),
+        }
+        
+        Some(Ok(cluster))
+    }
+}
+
+/// Iterator reading a file's data one cluster at a time
+///
+/// Yields an owned buffer per cluster in file order; the final buffer is
+/// trimmed to the file's data length.
+pub struct ClusterReadIter<'a> {
+    /// Volume the file lives on
+    volume: &'a ExFatVolume,
+    /// Source of cluster numbers
+    clusters: ClusterSource<'a>,
+    /// Bytes of file data not yet yielded
+    remaining: u64,
+}
+
+/// Source of cluster numbers for a file's data
+enum ClusterSource<'a> {
+    /// Next cluster of a contiguous run
+    Contiguous(u32),
+    /// FAT chain walker
+    Chain(ClusterChain<'a>),
+}
+
+impl Iterator for ClusterReadIter<'_> {
+    type Item = Result<Vec<u8>>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        
+        let cluster = match &mut self.clusters {
+            ClusterSource::Contiguous(next) => {
+                let cluster = *next;
+                *next = next.saturating_add(1);
+                Ok(cluster)
+            }
+            ClusterSource::Chain(chain) => chain.next().unwrap_or(Err(ExFatError::Corrupted)),
+        };
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as usize;
+        let mut buffer = vec![0u8; bytes_per_cluster];
+        if let Err(err) = cluster.and_then(|cluster| self.volume.read_cluster(cluster, &mut buffer)) {
+            self.remaining = 0;
+            return Some(Err(err));
+        }
+        
+        buffer.truncate(self.remaining.min(bytes_per_cluster as u64) as usize);
+        self.remaining -= buffer.len() as u64;
+        Some(Ok(buffer))
+    }
+}
+
+/// Location of a file's entry set on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryPosition {
+    /// Directory cluster holding the primary entry
+    pub cluster: u32,
+    /// Byte offset of the primary entry within the cluster
+    pub offset: u32,
+    /// Whether the directory's clusters are contiguous (no FAT chain)
+    pub contiguous: bool,
+}
+
+/// Location of a directory's data stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DirStream {
+    /// First cluster of the directory
+    first_cluster: u32,
+    /// Whether the clusters are contiguous (no FAT chain)
+    contiguous: bool,
+    /// Data length in bytes (zero if unknown, as for the root)
+    data_length: u64,
+}
+
+impl DirStream {
+    /// Get the stream described by a stream extension entry
+    fn from_entry(stream: &StreamExtensionEntry) -> Self {
+        Self {
+            first_cluster: stream.first_cluster,
+            contiguous: stream.is_contiguous(),
+            data_length: stream.data_length,
+        }
+    }
+}
+
+/// A file entry set located within a directory
+#[derive(Debug, Clone)]
+struct EntrySet {
+    /// Entry index of the primary entry within the directory
+    index: usize,
+    /// Total number of entries in the set
+    len: usize,
+    /// File directory entry
+    file: FileEntry,
+    /// Stream extension entry
+    stream: StreamExtensionEntry,
+    /// File name (UTF-16)
+    name: Vec<u16>,
+    /// Vendor secondary entries, in on-disk order
+    vendor: Vec<VendorEntry>,
+}
+
+impl EntrySet {
+    /// Check the structure of an entry set (primary entry first)
+    ///
+    /// Every entry after the primary must be a secondary entry, the count
+    /// must match `secondary_count`, and the stream extension must be the
+    /// first secondary entry and appear only once.
+    fn check_sequence(entries: &[[u8; 32]]) -> Result<()> {
+        let secondary_count = entries[0][1] as usize;
+        if secondary_count < 2 || entries.len() != 1 + secondary_count {
+            return Err(ExFatError::InvalidEntrySequence);
+        }
+        
+        let is_stream = |entry: &[u8; 32]| EntryType::from(entry[0]) == EntryType::StreamExtension;
+        let secondaries = &entries[1..];
+        if !secondaries.iter().all(|entry| DirectoryEntry::from_bytes(entry).is_secondary())
+            || !is_stream(&secondaries[0])
+            || secondaries[1..].iter().any(is_stream)
+        {
+            return Err(ExFatError::InvalidEntrySequence);
+        }
+        
+        Ok(())
+    }
+    
+    /// Parse a complete entry set (primary entry first)
+    fn parse(index: usize, entries: &[[u8; 32]]) -> Result<Self> {
+        Self::check_sequence(entries)?;
+        let file = FileEntry::from_bytes(&entries[0]);
+        let stream = StreamExtensionEntry::from_bytes(&entries[1]);
+        
+        let mut name = Vec::with_capacity(stream.name_length as usize);
+        let mut vendor = Vec::new();
+        for bytes in &entries[2..] {
+            if EntryType::from(bytes[0]) == EntryType::FileName {
+                name.extend_from_slice(&{ FileNameEntry::from_bytes(bytes).file_name });
+            } else if let Some(entry) = VendorEntry::from_bytes(bytes) {
+                vendor.push(entry);
+            }
+        }
+        
+        if name.len() < stream.name_length as usize {
+            return Err(ExFatError::Corrupted);
+        }
+        name.truncate(stream.name_length as usize);
+        
+        Ok(Self {
+            index,
+            len: entries.len(),
+            file,
+            stream,
+            name,
+            vendor,
+        })
+    }
+    
+    /// Rebuild the entry set under a new name, hashed through `upcase`
+    ///
+    /// Vendor entries are carried over after the name entries.
+    fn with_name(&self, name: &[u16], upcase: Option<&UpCaseTable>) -> Vec<[u8; 32]> {
+        let mut entries = build_entry_set(&self.file, &self.stream, name, upcase);
+        if self.vendor.is_empty() {
+            return entries;
+        }
+        
+        entries.extend(self.vendor.iter().map(VendorEntry::to_bytes));
+        entries[0][1] = (entries.len() - 1) as u8;
+        let checksum = entry_set_checksum(&entries);
+        entries[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        entries
+    }
+    
+    /// Check if the set describes a directory
+    fn is_directory(&self) -> bool {
+        (self.file.file_attributes & file_attributes::DIRECTORY) != 0
+    }
+}
+
+/// Metadata of a file or directory, parsed from its entry set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// File name
+    pub name: String,
+    /// File attributes
+    pub attributes: u16,
+    /// First cluster of the data, or 0 if none is allocated
+    pub first_cluster: u32,
+    /// Size in bytes
+    pub data_length: u64,
+    /// Bytes of data actually written
+    pub valid_data_length: u64,
+    /// Data is stored in consecutive clusters without a FAT chain
+    pub contiguous: bool,
+    /// Creation, modification and access timestamps
+    pub timestamps: Timestamps,
+    /// Location of the entry set on the volume, if known
+    pub entry_position: Option<EntryPosition>,
+}
+
+impl FileMetadata {
+    /// Parse a complete entry set, checking its structure and set checksum
+    fn from_entries(entries: &[[u8; 32]]) -> Result<Self> {
+        EntrySet::check_sequence(entries)?;
+        if entry_set_checksum(entries) != le_u16(&entries[0], 2) {
+            return Err(ExFatError::ChecksumMismatch);
+        }
+        
+        let set = EntrySet::parse(0, entries)?;
+        Ok(Self {
+            name: from_utf16_name(&set.name),
+            attributes: set.file.file_attributes,
+            first_cluster: set.stream.first_cluster,
+            data_length: set.stream.data_length,
+            valid_data_length: set.stream.valid_data_length,
+            contiguous: set.stream.is_contiguous(),
+            timestamps: set.file.timestamps(),
+            entry_position: None,
+        })
+    }
+    
+    /// Check if this is a directory
+    pub fn is_directory(&self) -> bool {
+        (self.attributes & file_attributes::DIRECTORY) != 0
+    }
+}
+
+/// Parse every file entry set in raw directory contents
+///
+/// Needs no block device, so it suits host tooling and fuzzing, but the
+/// results carry no `entry_position`. Each
+/// malformed entry set yields one `Err` item and parsing resumes at the
+/// next primary entry; secondary entries outside a set are reported the
+/// same way. Parsing stops at the end of directory marker, at a partial
+/// trailing entry, or at an entry set cut short by the end of `bytes`.
+/// Never panics, whatever the input.
+pub fn parse_directory_bytes(bytes: &[u8]) -> Vec<Result<FileMetadata>> {
+    let entries: Vec<[u8; 32]> = RawEntryIter::full(bytes).copied().collect();
+    let is_secondary = |index: usize| {
+        entries.get(index).is_some_and(|entry| DirectoryEntry::from_bytes(entry).is_secondary())
+    };
+    
+    let mut results = Vec::new();
+    let mut index = 0;
+    while let Some(entry) = entries.get(index) {
+        let parsed = match EntryType::from(entry[0]) {
+            EntryType::EndOfDirectory => break,
+            EntryType::File => {
+                let end = index + 1 + entry[1] as usize;
+                let Some(set) = entries.get(index..end) else {
+                    results.push(Err(ExFatError::InvalidEntrySequence));
+                    break;
+                };
+                FileMetadata::from_entries(set).map(|metadata| (metadata, end))
+            }
+            _ if is_secondary(index) => Err(ExFatError::InvalidEntrySequence),
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        
+        match parsed {
+            Ok((metadata, end)) => {
+                results.push(Ok(metadata));
+                index = end;
+            }
+            Err(error) => {
+                results.push(Err(error));
+                index += 1;
+                while is_secondary(index) {
+                    index += 1;
+                }
+            }
+        }
+    }
+    
+    results
+}
+
+/// In-memory copy of a directory's clusters
+struct DirBuffer {
+    /// Clusters backing the directory, in order
+    clusters: Vec<u32>,
+    /// Raw directory contents
+    data: Vec<u8>,
+    /// Clusters modified since loading
+    dirty: Vec<bool>,
+    /// Bytes per cluster
+    bytes_per_cluster: usize,
+}
+
+impl DirBuffer {
+    /// Get the number of entry slots in the directory
+    fn entry_count(&self) -> usize {
+        self.data.len() / DirectoryEntry::SIZE
+    }
+    
+    /// Get the raw bytes of an entry
+    fn entry(&self, index: usize) -> [u8; 32] {
+        let start = index * DirectoryEntry::SIZE;
+        let mut entry = [0u8; 32];
+        entry.copy_from_slice(&self.data[start..start + DirectoryEntry::SIZE]);
+        entry
+    }
+    
+    /// Overwrite consecutive entries starting at `index`
+    fn write_entries(&mut self, index: usize, entries: &[[u8; 32]]) {
+        for (i, entry) in entries.iter().enumerate() {
+            let start = (index + i) * DirectoryEntry::SIZE;
+            self.data[start..start + DirectoryEntry::SIZE].copy_from_slice(entry);
+            self.dirty[start / self.bytes_per_cluster] = true;
+        }
+    }
+    
+    /// Parse the in-use file entry sets whose stream entry passes `filter`
+    ///
+    /// Sets are read up to the end of directory marker. Every set's entry
+    /// sequence is checked, but sets rejected by the filter are skipped
+    /// without assembling their names.
+    fn entry_sets(&self, filter: impl Fn(&StreamExtensionEntry) -> bool) -> Result<Vec<EntrySet>> {
+        let mut sets = Vec::new();
+        let mut index = 0;
+        
+        while index < self.entry_count() {
+            let entry = self.entry(index);
+            match EntryType::from(entry[0]) {
+                EntryType::EndOfDirectory => break,
+                EntryType::File => {
+                    let entries = self.set_entries(index)?;
+                    if filter(&StreamExtensionEntry::from_bytes(&entries[1])) {
+                        sets.push(EntrySet::parse(index, &entries)?);
+                    }
+                    index += entries.len();
+                }
+                _ if DirectoryEntry::from_bytes(&entry).is_secondary() => {
+                    return Err(ExFatError::InvalidEntrySequence);
+                }
+                _ => index += 1,
+            }
+        }
+        
+        Ok(sets)
+    }
+    
+    /// Get the checked entries of the set whose primary entry is at `index`
+    fn set_entries(&self, index: usize) -> Result<Vec<[u8; 32]>> {
+        let secondary_count = self.entry(index)[1] as usize;
+        let next = index + 1 + secondary_count;
+        if next > self.entry_count() {
+            return Err(ExFatError::InvalidEntrySequence);
+        }
+        
+        // A secondary entry right after the set means the count is too small
+        if next < self.entry_count() && DirectoryEntry::from_bytes(&self.entry(next)).is_secondary() {
+            return Err(ExFatError::InvalidEntrySequence);
+        }
+        
+        let entries: Vec<[u8; 32]> = (index..next).map(|i| self.entry(i)).collect();
+        EntrySet::check_sequence(&entries)?;
+        Ok(entries)
+    }
+    
+    /// Find an entry set by name, ignoring case
+    ///
+    /// Names are compared through `upcase`, falling back to the built-in
+    /// case mapping when the volume's table hasn't been loaded. As in
+    /// `ExFatDirectory::find`, only sets whose stored name hash and length
+    /// match are assembled, and a hash match still needs the full name to
+    /// match.
+    fn find(&self, name: &[u16], upcase: Option<&UpCaseTable>) -> Result<Option<EntrySet>> {
+        let hash = upcase.map_or_else(|| name_hash(name), |upcase| upcase.name_hash(name));
+        let matches_hash = |stream: &StreamExtensionEntry| {
+            stream.name_hash == hash && stream.name_length as usize == name.len()
+        };
+        let matches = |set: &EntrySet| match upcase {
+            Some(upcase) => upcase.eq_ignore_case(&set.name, name),
+            None => names_equal(&set.name, name),
+        };
+        Ok(self.entry_sets(matches_hash)?.into_iter().find(matches))
+    }
+    
+    /// Find the first in-use entry of a given type
+    fn find_entry(&self, entry_type: EntryType) -> Option<usize> {
+        (0..self.entry_count())
+            .map(|index| (index, self.entry(index)[0]))
+            .take_while(|&(_, byte)| byte != EntryType::EndOfDirectory as u8)
+            .find(|&(_, byte)| byte == entry_type as u8)
+            .map(|(index, _)| index)
+    }
+    
+    /// Find the first run of `count` unused entry slots
+    fn find_free_run(&self, count: usize) -> Option<usize> {
+        let mut run_start = 0;
+        let mut run_len = 0;
+        
+        for index in 0..self.entry_count() {
+            if self.entry(index)[0] & 0x80 != 0 {
+                run_len = 0;
+                continue;
+            }
+            
+            if run_len == 0 {
+                run_start = index;
+            }
+            run_len += 1;
+            
+            if run_len == count {
+                return Some(run_start);
+            }
+        }
+        
+        None
+    }
+    
+    /// Get the on-disk position of the entry at `index`
+    fn position(&self, index: usize, contiguous: bool) -> EntryPosition {
+        let offset = index * DirectoryEntry::SIZE;
+        EntryPosition {
+            cluster: self.clusters[offset / self.bytes_per_cluster],
+            offset: (offset % self.bytes_per_cluster) as u32,
+            contiguous,
+        }
+    }
+    
+    /// Mark every entry of a set as deleted
+    fn delete_set(&mut self, set: &EntrySet) {
+        for index in set.index..set.index + set.len {
+            let mut entry = self.entry(index);
+            entry[0] &= 0x7F;
+            self.write_entries(index, &[entry]);
+        }
+    }
+}
+
+/// Split a path into its non-empty components
+fn path_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['/', '\\']).filter(|component| !component.is_empty())
+}
+
+/// Split a path into its parent directory and final component
+fn split_path(path: &str) -> Result<(&str, &str)> {
+    let path = path.trim_end_matches(['/', '\\']);
+    let (parent, name) = match path.rfind(['/', '\\']) {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    };
+    
+    if name.is_empty() {
+        return Err(ExFatError::InvalidParameter);
+    }
+    
+    Ok((parent, name))
+}
+
+/// exFAT File Handle
+pub struct ExFatFile<'a> {
+    /// File name
+    pub name: String,
+    /// File attributes
+    pub attributes: u16,
+    /// First cluster
+    pub first_cluster: u32,
+    /// File size
+    pub size: u64,
+    /// Valid data length
+    pub valid_data_length: u64,
+    /// Stream extension flags
+    pub flags: u8,
+    /// Timestamps
+    pub timestamps: Timestamps,
+    /// Vendor entries from the directory entry set
+    pub vendor_entries: Vec<VendorEntry>,
+    /// Current position in file
+    pub position: u64,
+    /// Update the last accessed time when the file is read
+    pub update_atime: bool,
+    /// Associated volume
+    volume: &'a ExFatVolume,
+    /// Location of the directory entry set, if the file exists on disk
+    entry_position: Option<EntryPosition>,
+    /// Metadata changed since the directory entry was last written
+    dirty: bool,
+}
+
+impl<'a> ExFatFile<'a> {
+    /// Create a new file handle
+    pub fn new(name: String, attributes: u16, first_cluster: u32, size: u64, volume: &'a ExFatVolume) -> Self {
+        Self {
+            name,
+            attributes,
+            first_cluster,
+            size,
+            valid_data_length: size,
+            flags: stream_flags::ALLOCATION_POSSIBLE,
+            timestamps: Timestamps::default(),
+            vendor_entries: Vec::new(),
+            position: 0,
+            update_atime: false,
+            volume,
+            entry_position: None,
+            dirty: false,
+        }
+    }
+    
+    /// Check if this is a directory
+    pub fn is_directory(&self) -> bool {
+        (self.attributes & file_attributes::DIRECTORY) != 0
+    }
+    
+    /// Check if the file's clusters are contiguous (no FAT chain)
+    pub fn is_contiguous(&self) -> bool {
+        (self.flags & stream_flags::NO_FAT_CHAIN) != 0
+    }
+    
+    /// Check if the file is read-only
+    pub fn is_read_only(&self) -> bool {
+        (self.attributes & file_attributes::READ_ONLY) != 0
+    }
+    
+    /// Check if the file is hidden
+    pub fn is_hidden(&self) -> bool {
+        (self.attributes & file_attributes::HIDDEN) != 0
+    }
+    
+    /// Check if this is a system file
+    pub fn is_system(&self) -> bool {
+        (self.attributes & file_attributes::SYSTEM) != 0
+    }
+    
+    /// Check if the archive bit is set
+    pub fn is_archive(&self) -> bool {
+        (self.attributes & file_attributes::ARCHIVE) != 0
+    }
+    
+    /// Set or clear the read-only attribute
+    pub fn set_read_only(&mut self, value: bool) {
+        self.set_attribute(file_attributes::READ_ONLY, value);
+    }
+    
+    /// Set or clear the hidden attribute
+    pub fn set_hidden(&mut self, value: bool) {
+        self.set_attribute(file_attributes::HIDDEN, value);
+    }
+    
+    /// Set or clear the system attribute
+    pub fn set_system(&mut self, value: bool) {
+        self.set_attribute(file_attributes::SYSTEM, value);
+    }
+    
+    /// Set or clear the archive attribute
+    pub fn set_archive(&mut self, value: bool) {
+        self.set_attribute(file_attributes::ARCHIVE, value);
+    }
+    
+    /// Allocate clusters for `size` bytes as a single contiguous run
+    ///
+    /// A contiguous run is recorded with the `NoFatChain` flag and leaves
+    /// the FAT untouched. If no run is long enough the clusters are
+    /// allocated individually and chained in the FAT instead. The file must
+    /// not have any clusters allocated yet.
+    pub fn preallocate_contiguous(&mut self, size: u64) -> Result<()> {
+        self.volume.check_writable()?;
+        if self.first_cluster != 0 {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let count = u32::try_from(size.div_ceil(bytes_per_cluster)).map_err(|_| ExFatError::VolumeFull)?;
+        
+        self.allocate_clusters(count)?;
+        
+        self.size = size;
+        self.touch();
+        Ok(())
+    }
+    
+    /// Change the file size
+    ///
+    /// Growing allocates clusters but writes nothing to them; the new
+    /// bytes lie past `valid_data_length` and read as zeros. Shrinking
+    /// clamps `valid_data_length` and the position and frees the clusters
+    /// past the new size, all of them for a size of zero. When the file has
+    /// an entry set, a shrink updates it before the clusters are released,
+    /// so an interruption leaks clusters rather than leaving the entry
+    /// pointing at free space. Otherwise the change is recorded by
+    /// `flush_metadata`.
+    pub fn set_size(&mut self, size: u64) -> Result<()> {
+        self.volume.check_writable()?;
+        if size == self.size {
+            return Ok(());
+        }
+        if size > self.volume.max_file_size() {
+            return Err(ExFatError::VolumeFull);
+        }
+        
+        if size > self.size {
+            self.grow(size)?;
+            self.size = size;
+            self.touch();
+            return Ok(());
+        }
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let clusters = self.volume.cluster_chain(self.first_cluster, self.is_contiguous(), self.size)?;
+        let kept = usize::try_from(size.div_ceil(bytes_per_cluster)).map_err(|_| ExFatError::Corrupted)?;
+        let (kept, freed) = clusters.split_at(kept.min(clusters.len()));
+        let contiguous = self.is_contiguous();
+        
+        self.size = size;
+        self.valid_data_length = self.valid_data_length.min(size);
+        self.position = self.position.min(size);
+        if kept.is_empty() {
+            self.first_cluster = 0;
+            self.flags &= !stream_flags::NO_FAT_CHAIN;
+        }
+        self.touch();
+        if self.entry_position.is_some() {
+            self.flush_metadata()?;
+        }
+        
+        self.volume.release_tail(kept, freed, contiguous)
+    }
+    
+    /// Allocate the first `count` clusters of a file that has none
+    ///
+    /// A contiguous run is preferred, falling back to a FAT chain.
+    fn allocate_clusters(&mut self, count: u32) -> Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        
+        match self.volume.allocate_contiguous(count)? {
+            Some(first_cluster) => {
+                self.first_cluster = first_cluster;
+                self.flags |= stream_flags::NO_FAT_CHAIN;
+            }
+            None => {
+                self.first_cluster = self.volume.allocate_chain(count)?;
+                self.flags &= !stream_flags::NO_FAT_CHAIN;
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+    
+    /// Make sure enough clusters are allocated to hold `size` bytes
+    ///
+    /// A file without clusters gets its first allocation here. A contiguous
+    /// file is extended in place if the following clusters are free, and
+    /// otherwise converted to a FAT chain before new clusters are linked on.
+    fn grow(&mut self, size: u64) -> Result<()> {
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let needed = u32::try_from(size.div_ceil(bytes_per_cluster)).map_err(|_| ExFatError::VolumeFull)?;
+        
+        if self.first_cluster == 0 {
+            return self.allocate_clusters(needed);
+        }
+        
+        if self.is_contiguous() {
+            let have = u32::try_from(self.size.div_ceil(bytes_per_cluster).max(1)).map_err(|_| ExFatError::Corrupted)?;
+            if needed <= have {
+                return Ok(());
+            }
+            
+            let extra = needed - have;
+            let last = self.first_cluster + have - 1;
+            if self.volume.allocate_run_at(last + 1, extra)? {
+                return Ok(());
+            }
+            
+            let tail = self.volume.allocate_chain(extra)?;
+            for cluster in self.first_cluster..last {
+                self.volume.set_fat_entry(cluster, FatEntry::Next(cluster + 1))?;
+            }
+            self.volume.set_fat_entry(last, FatEntry::Next(tail))?;
+            self.flags &= !stream_flags::NO_FAT_CHAIN;
+        } else {
+            let chain = self.volume.chain(self.first_cluster).collect::<Result<Vec<u32>>>()?;
+            let have = chain.len() as u32;
+            if needed <= have {
+                return Ok(());
+            }
+            
+            let tail = self.volume.allocate_chain(needed - have)?;
+            let last = chain.last().copied().ok_or(ExFatError::Corrupted)?;
+            self.volume.set_fat_entry(last, FatEntry::Next(tail))?;
+        }
+        
+        self.dirty = true;
+        Ok(())
+    }
+    
+    /// Get the cluster following `cluster` in the file
+    fn next_cluster(&self, cluster: u32) -> Result<u32> {
+        if self.is_contiguous() {
+            let next = cluster.checked_add(1).ok_or(ExFatError::ClusterOutOfRange)?;
+            self.volume.validate_cluster(next)?;
+            return Ok(next);
+        }
+        
+        match self.volume.fat_entry(cluster)? {
+            FatEntry::Next(next) => Ok(next),
+            _ => Err(ExFatError::Corrupted),
+        }
+    }
+    
+    /// Map a byte range of the file to `(start_lba, byte_length)` spans
+    ///
+    /// Physically consecutive clusters are merged into one span, including
+    /// consecutive links of a FAT chain. `offset` must be sector aligned
+    /// and the range must lie within the file; otherwise this fails with
+    /// `ExFatError::InvalidParameter`. Data past `valid_data_length` is
+    /// undefined on disk, so callers must zero it themselves.
+    pub fn extents(&self, offset: u64, len: u64) -> Result<Vec<(u64, u64)>> {
+        let end = offset.checked_add(len).ok_or(ExFatError::InvalidParameter)?;
+        if end > self.size || !offset.is_multiple_of(self.volume.bytes_per_sector() as u64) {
+            return Err(ExFatError::InvalidParameter);
+        }
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let bytes_per_sector = self.volume.bytes_per_sector() as u64;
+        let (mut cluster, within) = self.volume.offset_to_cluster(self.first_cluster, self.is_contiguous(), offset)?;
+        let mut lba = self.volume.cluster_to_lba(cluster)? + within as u64 / bytes_per_sector;
+        
+        if self.is_contiguous() {
+            self.volume.offset_to_cluster(self.first_cluster, true, end - 1)?;
+            return Ok(vec![(lba, len)]);
+        }
+        
+        let mut extents: Vec<(u64, u64)> = Vec::new();
+        let mut previous: Option<u32> = None;
+        let mut position = offset;
+        loop {
+            let chunk = (bytes_per_cluster - position % bytes_per_cluster).min(end - position);
+            match extents.last_mut() {
+                Some((_, length)) if previous == cluster.checked_sub(1) => *length += chunk,
+                _ => extents.push((lba, chunk)),
+            }
+            
+            position += chunk;
+            if position == end {
+                return Ok(extents);
+            }
+            previous = Some(cluster);
+            cluster = self.next_cluster(cluster)?;
+            lba = self.volume.cluster_to_lba(cluster)?;
+        }
+    }
+    
+    /// Iterate over the file's data one cluster at a time
+    pub fn clusters(&self) -> ClusterReadIter<'a> {
+        let mut clusters = self.clusters_from(self.first_cluster);
+        clusters.remaining = if self.first_cluster == 0 { 0 } else { self.size };
+        clusters
+    }
+    
+    /// Iterate over the file's clusters from `cluster` on
+    ///
+    /// The caller sets how many bytes remain.
+    fn clusters_from(&self, cluster: u32) -> ClusterReadIter<'a> {
+        let clusters = if self.is_contiguous() {
+            ClusterSource::Contiguous(cluster)
+        } else {
+            ClusterSource::Chain(self.volume.chain(cluster))
+        };
+        
+        ClusterReadIter {
+            volume: self.volume,
+            clusters,
+            remaining: 0,
+        }
+    }
+    
+    /// Replace the file's timestamps
+    pub fn set_timestamps(&mut self, timestamps: Timestamps) {
+        if timestamps != self.timestamps {
+            self.timestamps = timestamps;
+            self.dirty = true;
+        }
+    }
+    
+    /// Get the location of the file's directory entry set
+    pub fn entry_position(&self) -> Option<EntryPosition> {
+        self.entry_position
+    }
+    
+    /// Get the file's metadata, as it would be stored in its entry set
+    ///
+    /// Pass the result to `ExFatVolume::open_metadata` to reopen the file.
+    pub fn metadata(&self) -> FileMetadata {
+        FileMetadata {
+            name: self.name.clone(),
+            attributes: self.attributes,
+            first_cluster: self.first_cluster,
+            data_length: self.size,
+            valid_data_length: self.valid_data_length,
+            contiguous: self.is_contiguous(),
+            timestamps: self.timestamps,
+            entry_position: self.entry_position,
+        }
+    }
+    
+    /// Write modified metadata back to the directory entry set
+    ///
+    /// Updates the attributes and timestamps in the file entry and the
+    /// allocation and lengths in the stream extension, then recomputes the
+    /// set checksum. The entry set is located by the position recorded
+    /// when the file was opened. The allocation bitmap and FAT are written
+    /// back first, so the entry is always the last thing to change.
+    pub fn flush_metadata(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        
+        self.volume.check_writable()?;
+        let position = self.entry_position.ok_or(ExFatError::NotFound)?;
+        let mut entries = self.volume.read_entry_set(position)?;
+        let set = EntrySet::parse(0, &entries)?;
+        
+        let mut file = set.file;
+        file.file_attributes = self.attributes;
+        file.set_timestamps(&self.timestamps);
+        
+        let mut stream = set.stream;
+        stream.flags = self.flags;
+        stream.first_cluster = self.first_cluster;
+        stream.data_length = self.size;
+        stream.valid_data_length = self.valid_data_length;
+        
+        entries[0] = file.to_bytes();
+        entries[1] = stream.to_bytes();
+        let checksum = entry_set_checksum(&entries);
+        entries[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        
+        self.volume.commit()?;
+        self.volume.write_entry_set(position, &entries)?;
+        self.dirty = false;
+        Ok(())
+    }
+    
+    /// Check if metadata changes are waiting to be flushed
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+    
+    /// Make the file's changes durable
+    ///
+    /// Data reaches the device as it is written, so this writes back the
+    /// directory entry set with `flush_metadata` and then flushes the
+    /// volume.
+    pub fn flush(&mut self) -> Result<()> {
+        self.flush_metadata()?;
+        self.volume.flush()
+    }
+    
+    /// Flush the file and release the handle
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+    
+    /// Mark the entry dirty after a change to the file's data
+    ///
+    /// The last modified time is updated from the volume's time source, if
+    /// it has one.
+    fn touch(&mut self) {
+        if let Some(source) = &self.volume.time_source {
+            // A clock outside the storable range leaves the old time
+            let _ = self.timestamps.touch(&source.now());
+        }
+        self.dirty = true;
+    }
+    
+    /// Update the last accessed time after a read
+    ///
+    /// Only done if `update_atime` is set and the volume has a time source
+    /// and is writable. The entry is marked dirty only if the stored time
+    /// changes, which it does at most every two seconds.
+    fn touch_accessed(&mut self) {
+        if !self.update_atime || self.volume.read_only {
+            return;
+        }
+        
+        if let Some(source) = &self.volume.time_source {
+            let before = self.timestamps;
+            // A clock outside the storable range leaves the old time
+            let _ = self.timestamps.touch_accessed(&source.now());
+            if self.timestamps != before {
+                self.dirty = true;
+            }
+        }
+    }
+    
+    /// Update an attribute bit, marking the entry dirty if it changed
+    fn set_attribute(&mut self, mask: u16, value: bool) {
+        let attributes = if value {
+            self.attributes | mask
+        } else {
+            self.attributes & !mask
+        };
+        
+        if attributes != self.attributes {
+            self.attributes = attributes;
+            self.dirty = true;
+        }
+    }
+    
+    /// Read from the file
+    ///
+    /// Bytes past `valid_data_length` read as zeros, as does every byte of
+    /// a file with no clusters allocated; no device reads are issued for
+    /// them. The last accessed time is updated if `update_atime` is set.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let read = self.read_at(self.position, buffer)?;
+        if read > 0 {
+            self.position += read as u64;
+            self.touch_accessed();
+        }
+        Ok(read)
+    }
+    
+    /// Read from the file at `offset`, leaving the position alone
+    ///
+    /// Returns the number of bytes read, which is 0 at or past the end of
+    /// the file. Bytes past `valid_data_length` read as zeros, as with
+    /// `read`, but the last accessed time is never updated.
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        
+        let remaining = usize::try_from(self.size - offset).unwrap_or(usize::MAX);
+        let to_read = buffer.len().min(remaining);
+        let buffer = &mut buffer[..to_read];
+        
+        let valid = if self.first_cluster == 0 {
+            0
+        } else {
+            self.valid_data_length.min(self.size)
+        };
+        let stored = usize::try_from(valid.saturating_sub(offset)).unwrap_or(usize::MAX).min(to_read);
+        
+        buffer[stored..].fill(0);
+        if stored > 0 {
+            // A corrupt contiguous file is refused outright rather than
+            // read up to the end of the heap
+            if self.is_contiguous() {
+                self.volume.check_contiguous(self.first_cluster, self.size)?;
+            }
+            self.read_stored(offset, &mut buffer[..stored])?;
+        }
+        Ok(to_read)
+    }
+    
+    /// Read stored data at `offset` from the clusters
+    fn read_stored(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as usize;
+        let (mut cluster, within) = self.volume.offset_to_cluster(self.first_cluster, self.is_contiguous(), offset)?;
+        let mut within = within as usize;
+        let mut data = vec![0u8; bytes_per_cluster];
+        let mut done = 0;
+        
+        loop {
+            // Whole clusters of a contiguous file are read in one request
+            let whole = ((buffer.len() - done) / bytes_per_cluster).min(u32::MAX as usize);
+            if self.is_contiguous() && within == 0 && whole > 0 {
+                let len = whole * bytes_per_cluster;
+                self.volume.read_contiguous(cluster, whole as u32, &mut buffer[done..done + len])?;
+                done += len;
+                
+                if done == buffer.len() {
+                    return Ok(());
+                }
+                cluster += whole as u32;
+                continue;
+            }
+            
+            let len = (bytes_per_cluster - within).min(buffer.len() - done);
+            self.volume.read_cluster(cluster, &mut data)?;
+            buffer[done..done + len].copy_from_slice(&data[within..within + len]);
+            done += len;
+            
+            if done == buffer.len() {
+                return Ok(());
+            }
+            cluster = self.next_cluster(cluster)?;
+            within = 0;
+        }
+    }
+    
+    /// Write to the file at the current position
+    ///
+    /// Clusters are allocated as needed, including the first cluster of a
+    /// file that has none. Writing past `valid_data_length` zeroes the gap
+    /// on disk. The size and allocation are recorded in the directory
+    /// entry by `flush_metadata`, which commits the allocation first, so
+    /// after a crash the entry never refers to unallocated clusters.
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+        self.volume.check_writable()?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        
+        let end = self.position.checked_add(buffer.len() as u64).ok_or(ExFatError::InvalidParameter)?;
+        if end > self.volume.max_file_size() {
+            return Err(ExFatError::VolumeFull);
+        }
+        // Fail before allocating anything if the new clusters won't fit
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let allocated = match self.first_cluster {
+            0 => 0,
+            _ => self.size.div_ceil(bytes_per_cluster).max(1) * bytes_per_cluster,
+        };
+        if !self.volume.can_allocate(end.saturating_sub(allocated))? {
+            return Err(ExFatError::VolumeFull);
+        }
+        // The allocation must cover the whole file, not just this write
+        self.grow(end.max(self.size))?;
+        
+        let start = self.position.min(self.valid_data_length);
+        let (mut cluster, _) = self.volume.offset_to_cluster(self.first_cluster, self.is_contiguous(), start)?;
+        let mut data = vec![0u8; bytes_per_cluster as usize];
+        let mut offset = start;
+        
+        while offset < end {
+            let base = offset - offset % bytes_per_cluster;
+            let chunk_end = (base + bytes_per_cluster).min(end);
+            
+            // Keep valid data before and after the written range
+            let keeps_head = offset > base && base < self.valid_data_length;
+            let keeps_tail = chunk_end < base + bytes_per_cluster && chunk_end < self.valid_data_length;
+            if keeps_head || keeps_tail {
+                self.volume.read_cluster(cluster, &mut data)?;
+            } else {
+                data.fill(0);
+            }
+            
+            let copy_start = offset.max(self.position).min(chunk_end);
+            data[(offset - base) as usize..(copy_start - base) as usize].fill(0);
+            if copy_start < chunk_end {
+                let source = &buffer[(copy_start - self.position) as usize..(chunk_end - self.position) as usize];
+                data[(copy_start - base) as usize..(chunk_end - base) as usize].copy_from_slice(source);
+            }
+            self.volume.write_cluster(cluster, &data)?;
+            
+            offset = chunk_end;
+            if offset < end {
+                cluster = self.next_cluster(cluster)?;
+            }
+        }
+        
+        self.position = end;
+        self.size = self.size.max(end);
+        self.valid_data_length = self.valid_data_length.max(end);
+        self.touch();
+        Ok(buffer.len())
+    }
+    
+    /// Read the rest of the file into memory
+    ///
+    /// Reading starts at the current position and leaves the position at
+    /// the end of the file. Clusters are streamed in order, each read
+    /// once. Bytes past `valid_data_length` are returned as zeros without
+    /// being read. As with `read`, the last accessed time is updated if
+    /// `update_atime` is set. Fails with `ExFatError::Corrupted` if the file
+    /// size exceeds the clusters allocated to it.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let allocated = if self.first_cluster == 0 {
+            0
+        } else if self.is_contiguous() {
+            self.volume.check_contiguous(self.first_cluster, self.size)?;
+            self.size.div_ceil(bytes_per_cluster) * bytes_per_cluster
+        } else {
+            let clusters = self.volume.cluster_chain(self.first_cluster, false, self.size)?;
+            clusters.len() as u64 * bytes_per_cluster
+        };
+        if self.size > allocated {
+            return Err(ExFatError::Corrupted);
+        }
+        
+        let remaining = usize::try_from(self.size.saturating_sub(self.position)).map_err(|_| ExFatError::OutOfResources)?;
+        let mut data = Vec::with_capacity(remaining);
+        let valid = self.valid_data_length.min(self.size);
+        if self.position < valid {
+            // Start from the cluster holding the position
+            let within = (self.position % bytes_per_cluster) as usize;
+            let start = self.position - within as u64;
+            let (cluster, _) = self.volume.offset_to_cluster(self.first_cluster, self.is_contiguous(), start)?;
+            let mut clusters = self.clusters_from(cluster);
+            clusters.remaining = valid - start;
+            for (i, chunk) in clusters.enumerate() {
+                let chunk = chunk?;
+                data.extend_from_slice(if i == 0 { &chunk[within..] } else { &chunk });
+            }
+        }
+        data.resize(remaining, 0);
+        
+        self.position = self.size;
+        if !data.is_empty() {
+            self.touch_accessed();
+        }
+        Ok(data)
+    }
+    
+    /// Feed the file's contents to `f` one cluster at a time
+    ///
+    /// The whole file is passed from the start, in pieces no larger than a
+    /// cluster, and the position isn't moved. As with `read_at`, bytes past
+    /// `valid_data_length`, or every byte of a file with no first cluster,
+    /// are passed as zeros without being read.
+    pub fn hash_with(&self, mut f: impl FnMut(&[u8])) -> Result<()> {
+        let valid = if self.first_cluster == 0 {
+            0
+        } else {
+            self.valid_data_length.min(self.size)
+        };
+        let mut clusters = self.clusters();
+        clusters.remaining = clusters.remaining.min(valid);
+        for chunk in clusters {
+            f(&chunk?);
+        }
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let zeros = vec![0u8; bytes_per_cluster as usize];
+        let mut remaining = self.size - valid;
+        while remaining > 0 {
+            let chunk = remaining.min(bytes_per_cluster);
+            f(&zeros[..chunk as usize]);
+            remaining -= chunk;
+        }
+        Ok(())
+    }
+    
+    /// Compute the CRC-32 of the file's contents
+    ///
+    /// Streamed through `hash_with`, so memory use is bounded by the
+    /// cluster size.
+    pub fn crc32(&self) -> Result<u32> {
+        let mut crc = 0;
+        self.hash_with(|chunk| crc = crc32_update(crc, chunk))?;
+        Ok(crc)
+    }
+    
+    /// Seek to a position in the file
+    pub fn seek(&mut self, position: u64) -> Result<()> {
+        if position > self.size {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        self.position = position;
+        Ok(())
+    }
+    
+    /// Get file size
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    
+    /// Get file name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Byte source for parsing code that shouldn't depend on `std::io`
+pub trait ExfatRead {
+    /// Read up to `buffer.len()` bytes, returning how many were read
+    ///
+    /// A return value of 0 means the end of the data was reached.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
+}
+
+impl<R: ExfatRead + ?Sized> ExfatRead for &mut R {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read(buffer)
+    }
+}
+
+impl ExfatRead for ExFatFile<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        ExFatFile::read(self, buffer)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Read for ExFatFile<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        Ok(ExFatFile::read(self, buffer)?)
+    }
+}
+
+/// Seeking past the end of the file fails with `ErrorKind::InvalidInput`
+#[cfg(feature = "std")]
+impl std::io::Seek for ExFatFile<'_> {
+    fn seek(&mut self, from: std::io::SeekFrom) -> std::io::Result<u64> {
+        let position = match from {
+            std::io::SeekFrom::Start(position) => Some(position),
+            std::io::SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            std::io::SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        
+        ExFatFile::seek(self, position.ok_or(ExFatError::InvalidParameter)?)?;
+        Ok(self.position)
+    }
+}
+
+/// exFAT Directory Handle
+///
+/// Entries are read one cluster at a time into a buffer reused for the
+/// whole directory, so iterating needs `bytes_per_cluster` bytes however
+/// large the directory is. An entry set that crosses into the next cluster
+/// is copied out entry by entry as the buffer advances.
+pub struct ExFatDirectory<'a> {
+    /// Directory cluster
+    pub cluster: u32,
+    /// Current entry index
+    pub current_entry: usize,
+    /// Associated volume
+    volume: &'a ExFatVolume,
+    /// Data stream of the directory
+    stream: DirStream,
+    /// Location of the directory's own entry set, if known
+    entry_position: Option<EntryPosition>,
+    /// Maximum number of entries read before iteration gives up
+    max_entries: usize,
+    /// Index and number of the cluster held in `buffer`
+    loaded: Option<(usize, u32)>,
+    /// Contents of the most recently read cluster
+    buffer: Vec<u8>,
+}
+
+impl<'a> ExFatDirectory<'a> {
+    /// Largest directory size allowed by the specification (256 MiB)
+    pub const MAX_DIRECTORY_SIZE: u64 = 256 * 1024 * 1024;
+    
+    /// Create a new directory handle
+    pub fn new(cluster: u32, volume: &'a ExFatVolume) -> Self {
+        Self::from_stream(DirStream {
+            first_cluster: cluster,
+            contiguous: false,
+            data_length: 0,
+        }, volume)
+    }
+    
+    /// Open a directory handle from its file handle
+    ///
+    /// Unlike `new`, the handle knows the directory's size and where its
+    /// entry set lives, so the directory can be compacted. Fails with
+    /// `ExFatError::InvalidParameter` if `file` isn't a directory.
+    pub fn from_file(file: &ExFatFile<'a>) -> Result<Self> {
+        if !file.is_directory() {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        let mut dir = Self::from_stream(DirStream {
+            first_cluster: file.first_cluster,
+            contiguous: file.is_contiguous(),
+            data_length: file.size,
+        }, file.volume);
+        dir.entry_position = file.entry_position;
+        Ok(dir)
+    }
+    
+    /// Create a directory handle over a directory's data stream
+    fn from_stream(stream: DirStream, volume: &'a ExFatVolume) -> Self {
+        // A directory can't hold more entries than its allocation, and no
+        // directory may exceed the specification's size limit
+        let allocated = match stream.data_length {
+            0 => volume.boot_sector.cluster_count as u64 * volume.bytes_per_cluster() as u64,
+            data_length => data_length,
+        };
+        let max_entries = allocated.min(Self::MAX_DIRECTORY_SIZE) / DirectoryEntry::SIZE as u64;
+        
+        Self {
+            cluster: stream.first_cluster,
+            current_entry: 0,
+            volume,
+            stream,
+            entry_position: None,
+            max_entries: max_entries as usize,
+            loaded: None,
+            buffer: Vec::new(),
+        }
+    }
+    
+    /// Get the maximum number of entries iteration will examine
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+    
+    /// Set the maximum number of entries iteration will examine
+    ///
+    /// Reading past this many entries without reaching the end of directory
+    /// marker fails with `ExFatError::DirectoryTooLarge`, which bounds the
+    /// work done on a directory whose cluster chain loops.
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+    }
+    
+    /// Read next directory entry
+    pub fn read_entry(&mut self) -> Result<Option<ExFatFile<'a>>> {
+        self.next_entry_set(|_| true)?.map(|(set, position)| self.file_from_set(set, position)).transpose()
+    }
+    
+    /// List every file and directory in the directory
+    ///
+    /// Iteration restarts from the beginning of the directory and is left
+    /// at its end. Deleted entries are skipped and listing stops at the end
+    /// of directory marker.
+    pub fn entries(&mut self) -> Result<Vec<ExFatFile<'a>>> {
+        self.entries_filtered(|_| true)
+    }
+    
+    /// List the files and directories that pass `pred`
+    ///
+    /// Iterates as `entries` does.
+    pub fn entries_filtered(&mut self, pred: impl Fn(&ExFatFile<'a>) -> bool) -> Result<Vec<ExFatFile<'a>>> {
+        self.reset();
+        let mut entries = Vec::new();
+        while let Some(file) = self.read_entry()? {
+            if pred(&file) {
+                entries.push(file);
+            }
+        }
+        Ok(entries)
+    }
+    
+    /// List only the subdirectories
+    pub fn subdirectories(&mut self) -> Result<Vec<ExFatFile<'a>>> {
+        self.entries_filtered(ExFatFile::is_directory)
+    }
+    
+    /// List only the files that aren't directories
+    pub fn regular_files(&mut self) -> Result<Vec<ExFatFile<'a>>> {
+        self.entries_filtered(|file| !file.is_directory())
+    }
+    
+    /// Find an entry by name, ignoring case
+    ///
+    /// Iteration restarts from the beginning of the directory. Each entry
+    /// set's stored name hash is checked before its name is assembled, so
+    /// only hash matches pay for the full comparison.
+    pub fn find(&mut self, name: &str, upcase: &UpCaseTable) -> Result<Option<ExFatFile<'a>>> {
+        let name = FileName::new(name)?;
+        let found = self.find_set(name.as_utf16(), Some(upcase))?;
+        found.map(|(set, position)| self.file_from_set(set, position)).transpose()
+    }
+    
+    /// Find an entry set by UTF-16 name, ignoring case
+    ///
+    /// Names are compared through `upcase`, falling back to the built-in
+    /// case mapping when the volume's table hasn't been loaded.
+    fn find_set(
+        &mut self,
+        name: &[u16],
+        upcase: Option<&UpCaseTable>,
+    ) -> Result<Option<(EntrySet, Option<EntryPosition>)>> {
+        let hash = upcase.map_or_else(|| name_hash(name), |upcase| upcase.name_hash(name));
+        
+        self.reset();
+        let matches_hash = |stream: &StreamExtensionEntry| {
+            stream.name_hash == hash && stream.name_length as usize == name.len()
+        };
+        
+        while let Some((set, position)) = self.next_entry_set(matches_hash)? {
+            let matches = match upcase {
+                Some(upcase) => upcase.eq_ignore_case(&set.name, name),
+                None => names_equal(&set.name, name),
+            };
+            if matches {
+                return Ok(Some((set, position)));
+            }
+        }
+        
+        Ok(None)
+    }
+    
+    /// Create an empty file
+    ///
+    /// No clusters are allocated until the file is written. The name keeps
+    /// the caller's casing, but names are unique ignoring case, so this
+    /// fails with `ExFatError::AlreadyExists` if an entry differing only in
+    /// case exists. A full directory is extended by a cluster.
+    pub fn create_file(&mut self, name: &str) -> Result<ExFatFile<'a>> {
+        self.create_entry(name, file_attributes::ARCHIVE, 0)
+    }
+    
+    /// Create an empty subdirectory
+    ///
+    /// One zeroed cluster is allocated for its entries. Names are checked
+    /// as by `create_file`.
+    pub fn create_directory(&mut self, name: &str) -> Result<ExFatFile<'a>> {
+        self.create_entry(name, file_attributes::DIRECTORY, self.volume.bytes_per_cluster() as u64)
+    }
+    
+    /// Create a file whose clusters are allocated as one contiguous run
+    ///
+    /// The run is reserved in the allocation bitmap and the stream is marked
+    /// `NoFatChain`, so no FAT entries are written. Fails with
+    /// `ExFatError::OutOfResources` rather than falling back to a fragmented
+    /// allocation when no free run is long enough.
+    pub fn create_entry_contiguous(&mut self, name: &str, size: u64) -> Result<ExFatFile<'a>> {
+        self.create_entry(name, file_attributes::ARCHIVE, size)
+    }
+    
+    /// Create an entry with `size` bytes allocated as one contiguous run
+    ///
+    /// The clusters of a directory are zeroed and count as valid data.
+    fn create_entry(&mut self, name: &str, attributes: u16, size: u64) -> Result<ExFatFile<'a>> {
+        self.volume.check_writable()?;
+        let name_units = to_utf16_name(name)?;
+        let mut dir = self.volume.load_directory(self.stream)?;
+        if dir.find(&name_units, self.volume.upcase_table())?.is_some() {
+            return Err(ExFatError::AlreadyExists);
+        }
+        
+        // Extend a full directory until the entry set fits
+        let needed = entries_needed_for_name(name);
+        let slot = loop {
+            if let Some(slot) = dir.find_free_run(needed) {
+                break slot;
+            }
+            self.grow()?;
+            dir = self.volume.load_directory(self.stream)?;
+        };
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let count = u32::try_from(size.div_ceil(bytes_per_cluster)).map_err(|_| ExFatError::OutOfResources)?;
+        
+        let mut file = ExFatFile::new(String::from(name), attributes, 0, size, self.volume);
+        file.valid_data_length = 0;
+        file.timestamps = self.volume.new_timestamps();
+        if count > 0 {
+            file.first_cluster = self.volume.allocate_contiguous(count)?.ok_or(ExFatError::OutOfResources)?;
+            file.flags |= stream_flags::NO_FAT_CHAIN;
+        }
+        
+        if let Err(err) = self.store_new_entry(&mut dir, slot, &mut file) {
+            // Give the run back, so the failed create doesn't leak it
+            if count > 0 {
+                let first = file.first_cluster;
+                if let Ok(Ok(())) = self.volume.with_bitmap(|bitmap| bitmap.set_range(first, count, false)) {
+                    let _ = self.volume.store_bitmap();
+                }
+            }
+            return Err(err);
+        }
+        self.loaded = None;
+        
+        file.entry_position = Some(dir.position(slot, self.stream.contiguous));
+        Ok(file)
+    }
+    
+    /// Write a new entry's set at `slot`, zeroing a directory's clusters
+    /// first
+    ///
+    /// The file's clusters must already be allocated.
+    fn store_new_entry(&self, dir: &mut DirBuffer, slot: usize, file: &mut ExFatFile<'a>) -> Result<()> {
+        if file.is_directory() {
+            let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+            let zeros = vec![0u8; bytes_per_cluster as usize];
+            let count = file.size.div_ceil(bytes_per_cluster) as u32;
+            for cluster in file.first_cluster..file.first_cluster + count {
+                self.volume.write_cluster(cluster, &zeros)?;
+            }
+            file.valid_data_length = file.size;
+        }
+        
+        let entries = EntrySetBuilder::new(file.attributes)
+            .name(&file.name)
+            .first_cluster(file.first_cluster)
+            .size(file.size)
+            .valid_data_length(file.valid_data_length)
+            .contiguous(file.is_contiguous())
+            .timestamps(file.timestamps)
+            .build(self.volume.upcase_table())?;
+        
+        dir.write_entries(slot, &entries);
+        self.volume.commit()?;
+        self.volume.store_directory(dir)
+    }
+    
+    /// Read every raw entry up to and including the end of directory marker
+    ///
+    /// Unlike `read_entry`, deleted and unrecognised entries are returned
+    /// as-is and entry sets are not grouped. The iteration position is left
+    /// unchanged.
+    pub fn raw_entries(&mut self) -> Result<Vec<DirectoryEntry>> {
+        let mut entries = Vec::new();
+        let mut index = 0;
+        
+        while let Some(entry) = self.entry_at(index)? {
+            entries.push(DirectoryEntry::from_bytes(&entry));
+            if EntryType::from(entry[0]) == EntryType::EndOfDirectory {
+                break;
+            }
+            index += 1;
+        }
+        
+        Ok(entries)
+    }
+    
+    /// Check if the directory holds no files or subdirectories
+    ///
+    /// Only in-use File entries count; the allocation bitmap, up-case
+    /// table and volume label entries of the root are ignored. The
+    /// iteration position is left unchanged.
+    pub fn is_empty(&mut self) -> Result<bool> {
+        let mut index = 0;
+        while let Some(entry) = self.entry_at(index)? {
+            match EntryType::from(entry[0]) {
+                EntryType::EndOfDirectory => break,
+                EntryType::File => return Ok(false),
+                _ => index += 1,
+            }
+        }
+        Ok(true)
+    }
+    
+    /// Free trailing clusters that hold no in-use entries
+    ///
+    /// Unused entries after the last in-use entry are rewritten as end of
+    /// directory markers, then every cluster past it is freed and cut from
+    /// the chain. The first cluster is always kept. Other than for the
+    /// root, the directory's own stream extension entry is shortened to
+    /// match, so the handle must come from `from_file`; otherwise this
+    /// fails with `ExFatError::NotFound` before changing anything.
+    pub fn compact(&mut self) -> Result<()> {
+        self.volume.check_writable()?;
+        let is_root = self.entry_position.is_none() && self.stream.first_cluster == self.volume.root_dir_cluster();
+        if self.entry_position.is_none() && !is_root {
+            return Err(ExFatError::NotFound);
+        }
+        
+        let mut dir = self.volume.load_directory(self.stream)?;
+        let used = (0..dir.entry_count())
+            .rposition(|index| dir.entry(index)[0] & 0x80 != 0)
+            .map_or(0, |index| index + 1);
+        for index in used..dir.entry_count() {
+            if dir.entry(index)[0] != EntryType::EndOfDirectory as u8 {
+                dir.write_entries(index, &[[0u8; 32]]);
+            }
+        }
+        
+        let kept = (used * DirectoryEntry::SIZE).div_ceil(dir.bytes_per_cluster).max(1);
+        let freed = dir.clusters.split_off(kept.min(dir.clusters.len()));
+        dir.dirty.truncate(dir.clusters.len());
+        dir.data.truncate(dir.clusters.len() * dir.bytes_per_cluster);
+        self.volume.store_directory(&dir)?;
+        self.loaded = None;
+        
+        if freed.is_empty() {
+            return Ok(());
+        }
+        
+        // Shorten the directory before releasing its clusters
+        if self.entry_position.is_some() {
+            self.set_stream(dir.data.len() as u64, self.stream.contiguous)?;
+            self.max_entries = self.max_entries.min(dir.entry_count());
+        }
+        
+        self.volume.release_tail(&dir.clusters, &freed, self.stream.contiguous)
+    }
+    
+    /// Extend the directory by one zeroed cluster
+    ///
+    /// A contiguous directory is extended in place if the following
+    /// cluster is free, and otherwise converted to a FAT chain. As for
+    /// `compact`, the directory's own stream extension entry is lengthened
+    /// to match, so a handle other than the root's must come from
+    /// `from_file` or `open_dir`. Returns the new cluster.
+    fn grow(&mut self) -> Result<u32> {
+        self.volume.check_writable()?;
+        let is_root = self.entry_position.is_none() && self.stream.first_cluster == self.volume.root_dir_cluster();
+        if self.entry_position.is_none() && !is_root {
+            return Err(ExFatError::NotFound);
+        }
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        let clusters = self.volume.cluster_chain(self.stream.first_cluster, self.stream.contiguous, self.stream.data_length)?;
+        let last = clusters.last().copied().ok_or(ExFatError::Corrupted)?;
+        let data_length = (clusters.len() as u64 + 1) * bytes_per_cluster;
+        if data_length > Self::MAX_DIRECTORY_SIZE {
+            return Err(ExFatError::DirectoryTooLarge);
+        }
+        
+        let mut contiguous = self.stream.contiguous;
+        let cluster = if contiguous && self.volume.allocate_run_at(last + 1, 1)? {
+            last + 1
+        } else {
+            let cluster = self.volume.allocate_chain(1)?;
+            if contiguous {
+                for pair in clusters.windows(2) {
+                    self.volume.set_fat_entry(pair[0], FatEntry::Next(pair[1]))?;
+                }
+                contiguous = false;
+            }
+            self.volume.set_fat_entry(last, FatEntry::Next(cluster))?;
+            cluster
+        };
+        
+        self.volume.write_cluster(cluster, &vec![0u8; bytes_per_cluster as usize])?;
+        self.volume.commit()?;
+        self.loaded = None;
+        
+        if self.entry_position.is_some() {
+            self.set_stream(data_length, contiguous)?;
+            self.max_entries = self.max_entries.max(data_length as usize / DirectoryEntry::SIZE);
+        }
+        Ok(cluster)
+    }
+    
+    /// Rewrite the directory's own stream extension entry
+    ///
+    /// Both lengths are set to `data_length`. The handle must know where
+    /// its entry set lives.
+    fn set_stream(&mut self, data_length: u64, contiguous: bool) -> Result<()> {
+        let position = self.entry_position.ok_or(ExFatError::NotFound)?;
+        let mut entries = self.volume.read_entry_set(position)?;
+        let mut stream = EntrySet::parse(0, &entries)?.stream;
+        stream.data_length = data_length;
+        stream.valid_data_length = data_length;
+        if contiguous {
+            stream.flags |= stream_flags::NO_FAT_CHAIN;
+        } else {
+            stream.flags &= !stream_flags::NO_FAT_CHAIN;
+        }
+        entries[1] = stream.to_bytes();
+        let checksum = entry_set_checksum(&entries);
+        entries[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        self.volume.write_entry_set(position, &entries)?;
+        
+        self.stream.data_length = data_length;
+        self.stream.contiguous = contiguous;
+        Ok(())
+    }
+    
+    /// Reset directory iteration
+    pub fn reset(&mut self) {
+        self.current_entry = 0;
+    }
+    
+    /// Read the next file entry set whose stream entry passes `filter`
+    ///
+    /// Sets rejected by the filter are skipped without assembling their
+    /// names.
+    fn next_entry_set(
+        &mut self,
+        filter: impl Fn(&StreamExtensionEntry) -> bool,
+    ) -> Result<Option<(EntrySet, Option<EntryPosition>)>> {
+        loop {
+            let Some(entry) = self.entry_at(self.current_entry)? else {
+                return Ok(None);
+            };
+            
+            match EntryType::from(entry[0]) {
+                EntryType::EndOfDirectory => return Ok(None),
+                EntryType::File => {
+                    let bytes_per_cluster = self.volume.bytes_per_cluster() as usize;
+                    let position = self.loaded.map(|(_, cluster)| EntryPosition {
+                        cluster,
+                        offset: ((self.current_entry * DirectoryEntry::SIZE) % bytes_per_cluster) as u32,
+                        contiguous: self.stream.contiguous,
+                    });
+                    
+                    let index = self.current_entry;
+                    let next = index + 1 + entry[1] as usize;
+                    let mut entries = vec![entry];
+                    for i in index + 1..next {
+                        let secondary = self.entry_at(i)?.ok_or(ExFatError::InvalidEntrySequence)?;
+                        entries.push(secondary);
+                    }
+                    
+                    // A secondary entry right after the set means the count is too small
+                    if next < self.max_entries {
+                        if let Some(following) = self.entry_at(next)? {
+                            if DirectoryEntry::from_bytes(&following).is_secondary() {
+                                return Err(ExFatError::InvalidEntrySequence);
+                            }
+                        }
+                    }
+                    
+                    EntrySet::check_sequence(&entries)?;
+                    self.current_entry = next;
+                    if !filter(&StreamExtensionEntry::from_bytes(&entries[1])) {
+                        continue;
+                    }
+                    
+                    let set = EntrySet::parse(index, &entries)?;
+                    return Ok(Some((set, position)));
+                }
+                _ if DirectoryEntry::from_bytes(&entry).is_secondary() => {
+                    return Err(ExFatError::InvalidEntrySequence);
+                }
+                _ => self.current_entry += 1,
+            }
+        }
+    }
+    
+    /// Build the file handle for a parsed entry set
+    ///
+    /// The stream's first cluster is checked by `validate_stream`.
+    fn file_from_set(&self, set: EntrySet, position: Option<EntryPosition>) -> Result<ExFatFile<'a>> {
+        self.volume.validate_stream(set.stream.first_cluster, set.stream.data_length)?;
+        let mut file = ExFatFile::new(
+            from_utf16_name(&set.name),
+            set.file.file_attributes,
+            set.stream.first_cluster,
+            set.stream.data_length,
+            self.volume,
+        );
+        file.valid_data_length = set.stream.valid_data_length;
+        file.flags = set.stream.flags;
+        file.timestamps = set.file.timestamps();
+        file.vendor_entries = set.vendor;
+        file.entry_position = position;
+        Ok(file)
+    }
+    
+    /// Get the raw entry at `index`, or `None` past the end of the directory
+    fn entry_at(&mut self, index: usize) -> Result<Option<[u8; 32]>> {
+        // A completely full directory has no end of directory marker
+        let offset = index * DirectoryEntry::SIZE;
+        if self.stream.data_length > 0 && offset as u64 >= self.stream.data_length {
+            return Ok(None);
+        }
+        if index >= self.max_entries {
+            return Err(ExFatError::DirectoryTooLarge);
+        }
+        
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as usize;
+        let cluster_index = offset / bytes_per_cluster;
+        
+        if self.loaded.map(|(loaded, _)| loaded) != Some(cluster_index) {
+            let Some(cluster) = self.cluster_at(cluster_index)? else {
+                return Ok(None);
+            };
+            
+            self.buffer.resize(bytes_per_cluster, 0);
+            self.volume.read_cluster(cluster, &mut self.buffer)?;
+            self.loaded = Some((cluster_index, cluster));
+        }
+        
+        let start = offset % bytes_per_cluster;
+        let mut entry = [0u8; 32];
+        entry.copy_from_slice(&self.buffer[start..start + DirectoryEntry::SIZE]);
+        Ok(Some(entry))
+    }
+    
+    /// Get the cluster number of the `cluster_index`th directory cluster
+    fn cluster_at(&self, cluster_index: usize) -> Result<Option<u32>> {
+        let bytes_per_cluster = self.volume.bytes_per_cluster() as u64;
+        
+        if self.stream.contiguous {
+            if cluster_index as u64 * bytes_per_cluster >= self.stream.data_length {
+                return Ok(None);
+            }
+            let cluster = u32::try_from(self.stream.first_cluster as u64 + cluster_index as u64)
+                .map_err(|_| ExFatError::ClusterOutOfRange)?;
+            if !self.volume.boot_sector.is_valid_cluster(cluster) {
+                return Err(ExFatError::ClusterOutOfRange);
+            }
+            return Ok(Some(cluster));
+        }
+        
+        // Continue from the loaded cluster when walking forward
+        let (mut current, mut cluster) = match self.loaded {
+            Some((loaded, cluster)) if loaded <= cluster_index => (loaded, cluster),
+            _ => (0, self.stream.first_cluster),
+        };
+        
+        if cluster_index >= self.volume.boot_sector.cluster_count as usize {
+            return Err(ExFatError::CyclicChain);
+        }
+        
+        while current < cluster_index {
+            match self.volume.fat_entry(cluster)? {
+                FatEntry::Next(next) if self.volume.boot_sector.is_valid_cluster(next) => cluster = next,
+                FatEntry::Next(_) => return Err(ExFatError::ClusterOutOfRange),
+                FatEntry::EndOfChain | FatEntry::EndOfChainValue(_) => return Ok(None),
+                FatEntry::Free | FatEntry::Bad => return Err(ExFatError::Corrupted),
+            }
+            current += 1;
+        }
+        
+        Ok(Some(cluster))
+    }
+}
+
+#[cfg(all(test, not(target_os = "uefi")))]
+mod tests {
+    use super::*;
+    use crate::block::MemBlockDevice;
+    use crate::time::{DateTime, FixedTimeSource};
+    
+    /// Build a boot sector for a small test volume
+    ///
+    /// 512-byte sectors, one sector per cluster, FAT at sector 24 and the
+    /// cluster heap at sector 32 with 64 clusters; the root is cluster 2.
+    fn test_boot_sector() -> BootSector {
+        let mut boot_sector = BootSector::from_bytes(&[0; 512]);
+        boot_sector.fs_name.copy_from_slice(b"EXFAT   ");
+        boot_sector.boot_signature = 0xAA55;
+        boot_sector.volume_length = 96;
+        boot_sector.fat_offset = 24;
+        boot_sector.fat_length = 8;
+        boot_sector.cluster_heap_offset = 32;
+        boot_sector.cluster_count = 64;
+        boot_sector.root_dir_cluster = 2;
+        boot_sector.bytes_per_sector_shift = 9;
+        boot_sector.sectors_per_cluster_shift = 0;
+        boot_sector.num_fats = 1;
+        boot_sector
+    }
+    
+    /// Write the main and backup boot regions into a test image
+    fn write_boot_regions(device: &mut MemBlockDevice, boot_sector: &BootSector) {
+        let mut region = vec![0u8; boot_sector.boot_region_len()];
+        region[..BootSector::SIZE].copy_from_slice(&boot_sector.to_bytes());
+        let checksum = boot_checksum(&region).to_le_bytes();
+        
+        let sector_len = boot_sector.bytes_per_sector() as usize;
+        for base in [0, BootSector::BACKUP_SECTOR] {
+            device.write_blocks(base, &region).unwrap();
+            let sector: Vec<u8> = checksum.iter().copied().cycle().take(sector_len).collect();
+            device.write_blocks(base + BootSector::CHECKSUM_SECTOR, &sector).unwrap();
+        }
+    }
+    
+    /// Device that keeps only the blocks written to it and reads zeros
+    /// elsewhere, for volumes too large to hold in memory
+    #[derive(Default)]
+    struct SparseDevice {
+        /// Written blocks by LBA
+        blocks: alloc::collections::BTreeMap<u64, Vec<u8>>,
+    }
+    
+    impl BlockDevice for SparseDevice {
+        fn block_size(&self) -> u32 {
+            512
+        }
+        
+        fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+            for (block, chunk) in (lba..).zip(buffer.chunks_mut(512)) {
+                match self.blocks.get(&block) {
+                    Some(data) => chunk.copy_from_slice(data),
+                    None => chunk.fill(0),
+                }
+            }
+            Ok(())
+        }
+        
+        fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<()> {
+            for (block, chunk) in (lba..).zip(buffer.chunks(512)) {
+                self.blocks.insert(block, chunk.to_vec());
+            }
+            Ok(())
+        }
+    }
+    
+    /// RAM-backed device that counts flushes
+    struct FlushCounter {
+        /// Wrapped device
+        device: MemBlockDevice,
+        /// Number of flushes, shared with the test
+        flushes: alloc::rc::Rc<core::cell::Cell<u32>>,
+    }
+    
+    impl BlockDevice for FlushCounter {
+        fn block_size(&self) -> u32 {
+            self.device.block_size()
+        }
+        
+        fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+            self.device.read_blocks(lba, buffer)
+        }
+        
+        fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<()> {
+            self.device.write_blocks(lba, buffer)
+        }
+        
+        fn flush(&mut self) -> Result<()> {
+            self.flushes.set(self.flushes.get() + 1);
+            Ok(())
+        }
+    }
+    
+    /// RAM-backed device that records every read and write as
+    /// `(lba, length)`
+    struct IoLog {
+        /// Wrapped device
+        device: MemBlockDevice,
+        /// Reads issued, shared with the test
+        reads: alloc::rc::Rc<RefCell<Vec<(u64, usize)>>>,
+        /// Writes issued, shared with the test
+        writes: alloc::rc::Rc<RefCell<Vec<(u64, usize)>>>,
+    }
+    
+    impl BlockDevice for IoLog {
+        fn block_size(&self) -> u32 {
+            self.device.block_size()
+        }
+        
+        fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+            self.reads.borrow_mut().push((lba, buffer.len()));
+            self.device.read_blocks(lba, buffer)
+        }
+        
+        fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<()> {
+            self.writes.borrow_mut().push((lba, buffer.len()));
+            self.device.write_blocks(lba, buffer)
+        }
+    }
+    
+    /// RAM-backed device that fails every write to one block
+    struct FailingWrites {
+        /// Wrapped device
+        device: MemBlockDevice,
+        /// Block whose writes fail
+        lba: u64,
+    }
+    
+    impl BlockDevice for FailingWrites {
+        fn block_size(&self) -> u32 {
+            self.device.block_size()
+        }
+        
+        fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+            self.device.read_blocks(lba, buffer)
+        }
+        
+        fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<()> {
+            let blocks = buffer.len() as u64 / 512;
+            if (lba..lba + blocks).contains(&self.lba) {
+                return Err(Status::DEVICE_ERROR.into());
+            }
+            self.device.write_blocks(lba, buffer)
+        }
+    }
+    
+    /// Write a FAT entry directly into a test image
+    fn set_fat(device: &mut MemBlockDevice, cluster: u32, value: u32) {
+        let mut sector = [0u8; 512];
+        let lba = 24 + (cluster as u64 * 4) / 512;
+        let offset = (cluster as usize * 4) % 512;
+        device.read_blocks(lba, &mut sector).unwrap();
+        sector[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        device.write_blocks(lba, &sector).unwrap();
+    }
+    
+    /// Read a FAT entry from disk, bypassing the FAT cache
+    fn disk_fat(volume: &ExFatVolume, cluster: u32) -> u32 {
+        let mut sector = [0u8; 512];
+        volume.dump_region(24 + (cluster as u64 * 4) / 512, 1, &mut sector).unwrap();
+        le_u32(&sector, (cluster as usize * 4) % 512)
+    }
+    
+    /// Build the entry set for a file or directory
+    fn entry_set(name: &str, attributes: u16, first_cluster: u32, size: u64) -> Vec<[u8; 32]> {
+        EntrySetBuilder::new(attributes)
+            .name(name)
+            .first_cluster(first_cluster)
+            .size(size)
+            .timestamps(Timestamps {
+                create: 0x5A21_6C00,
+                modified: 0x5A21_6C01,
+                ..Default::default()
+            })
+            .build(None)
+            .unwrap()
+    }
+    
+    /// Build the allocation bitmap entry
+    fn bitmap_entry(first_cluster: u32, data_length: u64) -> [u8; 32] {
+        let mut entry = [0u8; 32];
+        entry[0] = EntryType::AllocationBitmap as u8;
+        entry[20..24].copy_from_slice(&first_cluster.to_le_bytes());
+        entry[24..32].copy_from_slice(&data_length.to_le_bytes());
+        entry
+    }
+    
+    /// Build a test image with the allocation bitmap in cluster 5
+    ///
+    /// Clusters 2 through 5 are allocated, plus any in `allocated`.
+    fn test_device(allocated: &[u32]) -> MemBlockDevice {
+        let mut device = MemBlockDevice::new(96 * 512, 512);
+        for cluster in 2..=5 {
+            set_fat(&mut device, cluster, 0xFFFF_FFFF);
+        }
+        
+        let mut bitmap = [0u8; 512];
+        for &cluster in [2, 3, 4, 5].iter().chain(allocated) {
+            let index = (cluster - 2) as usize;
+            bitmap[index / 8] |= 1 << (index % 8);
+        }
+        device.write_blocks(35, &bitmap).unwrap();
+        
+        let mut root = [0u8; 512];
+        root[..32].copy_from_slice(&bitmap_entry(5, 8));
+        device.write_blocks(32, &root).unwrap();
+        device
+    }
+    
+    /// Store a compressed up-case table in cluster 6 with its entry at
+    /// root directory slot `index`
+    ///
+    /// The table maps 'a' through 'z' to upper case.
+    fn add_upcase_table(device: &mut MemBlockDevice, index: usize) {
+        let mut table = Vec::new();
+        for unit in [0xFFFF, 0x61].into_iter().chain(0x41..=0x5A) {
+            table.extend_from_slice(&u16::to_le_bytes(unit));
+        }
+        let mut cluster = [0u8; 512];
+        cluster[..table.len()].copy_from_slice(&table);
+        device.write_blocks(36, &cluster).unwrap();
+        set_fat(device, 6, 0xFFFF_FFFF);
+        
+        let mut bitmap = [0u8; 512];
+        device.read_blocks(35, &mut bitmap).unwrap();
+        bitmap[0] |= 1 << 4;
+        device.write_blocks(35, &bitmap).unwrap();
+        
+        let entry = UpCaseTableEntry {
+            entry_type: EntryType::UpCaseTable as u8,
+            reserved1: [0; 3],
+            table_checksum: table_checksum(&table),
+            reserved2: [0; 12],
+            first_cluster: 6,
+            data_length: table.len() as u64,
+        };
+        let mut root = [0u8; 512];
+        device.read_blocks(32, &mut root).unwrap();
+        root[index * 32..index * 32 + 32].copy_from_slice(&entry.to_bytes());
+        device.write_blocks(32, &root).unwrap();
+    }
+    
+    /// Build a volume holding `/docs/` (cluster 3) and `/hello.txt` (cluster 4)
+    fn test_volume() -> ExFatVolume {
+        test_volume_with(test_device(&[]))
+    }
+    
+    /// Add `/docs/` and `/hello.txt` to a test image and mount it
+    fn test_volume_with(device: MemBlockDevice) -> ExFatVolume {
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(1, &entry_set("docs", file_attributes::DIRECTORY, 3, 512));
+        root.write_entries(4, &entry_set("hello.txt", file_attributes::ARCHIVE, 4, 5));
+        volume.store_directory(&root).unwrap();
+        volume
+    }
+    
+    /// Look up an entry set by path
+    fn lookup(volume: &ExFatVolume, path: &str) -> Option<EntrySet> {
+        let (parent, name) = split_path(path).unwrap();
+        let (stream, _) = volume.resolve_directory(parent).ok()?;
+        let name: Vec<u16> = name.encode_utf16().collect();
+        volume.load_directory(stream).unwrap().find(&name, volume.upcase_table()).unwrap()
+    }
+    
+    #[test]
+    fn test_cluster_to_lba() {
+        let mut boot_sector = BootSector::from_bytes(&[0; 512]);
+        boot_sector.cluster_heap_offset = 1024;
+        boot_sector.bytes_per_sector_shift = 9;
+        boot_sector.sectors_per_cluster_shift = 3; // 8 sectors per cluster
+        boot_sector.boot_signature = 0xAA55;
+        boot_sector.fs_name.copy_from_slice(b"EXFAT   ");
+        boot_sector.cluster_count = 16;
+        
+        let volume = ExFatVolume::new(boot_sector, MemBlockDevice::new(0, 512)).unwrap();
+        
+        // Cluster 2 should be at cluster_heap_offset
+        assert_eq!(volume.cluster_to_lba(2), Ok(1024));
+        
+        // Cluster 3 should be at cluster_heap_offset + sectors_per_cluster
+        assert_eq!(volume.cluster_to_lba(3), Ok(1032));
+        
+        // Clusters outside the heap have no LBA
+        for cluster in [0, 1, 18] {
+            assert_eq!(volume.cluster_to_lba(cluster), Err(ExFatError::ClusterOutOfRange));
+        }
+    }
+    
+    #[test]
+    fn test_capacity() {
+        let volume = ExFatVolume::new(test_boot_sector(), MemBlockDevice::new(0, 512)).unwrap();
+        assert_eq!(volume.total_clusters(), 64);
+        assert_eq!(volume.usable_bytes(), 64 * 512);
+        assert_eq!(volume.max_file_size(), 64 * 512);
+        
+        // 32 MiB clusters and 2^24 clusters give a 512 TiB heap
+        let mut boot_sector = test_boot_sector();
+        boot_sector.sectors_per_cluster_shift = 16;
+        boot_sector.cluster_count = 1 << 24;
+        let volume = ExFatVolume::new(boot_sector, MemBlockDevice::new(0, 512)).unwrap();
+        assert_eq!(volume.bytes_per_cluster(), 32 << 20);
+        assert_eq!(volume.usable_bytes(), 1 << 49);
+        assert_eq!(volume.max_file_size(), 1 << 49);
+    }
+    
+    #[test]
+    fn test_recompute_percent_in_use() {
+        // Clusters 2 through 5 are always allocated
+        let allocated: Vec<u32> = (6..34).collect();
+        let volume = test_volume_with(test_device(&allocated));
+        assert_eq!(volume.recompute_percent_in_use(), 50);
+        
+        // 43 of 64 clusters is 67.1875%
+        let allocated: Vec<u32> = (6..45).collect();
+        let volume = test_volume_with(test_device(&allocated));
+        assert_eq!(volume.recompute_percent_in_use(), 67);
+        
+        // No allocation bitmap entry in the root directory
+        let volume = ExFatVolume::new(test_boot_sector(), MemBlockDevice::new(96 * 512, 512)).unwrap();
+        assert_eq!(volume.recompute_percent_in_use(), 0xFF);
+    }
+    
+    #[test]
+    fn test_volume_dirty_flag() {
+        let mut device = test_device(&[]);
+        write_boot_regions(&mut device, &test_boot_sector());
+        
+        let mut volume = ExFatVolume::new(test_boot_sector(), device.clone()).unwrap();
+        assert!(!volume.is_dirty());
+        assert!(!volume.has_media_failure());
+        
+        volume.mark_dirty().unwrap();
+        assert!(volume.is_dirty());
+        let image = device.to_vec();
+        for base in [0, 12 * 512] {
+            let flags = le_u16(&image, base + BootSector::VOLUME_FLAGS_OFFSET);
+            assert_eq!(flags, volume_flags::VOLUME_DIRTY);
+            
+            // The stored checksum still matches the updated region
+            let checksum = le_u32(&image, base + 11 * 512);
+            assert_eq!(boot_checksum(&image[base..base + 11 * 512]), checksum);
+        }
+        
+        volume.mark_clean().unwrap();
+        assert!(!volume.is_dirty());
+        let image = device.to_vec();
+        assert_eq!(le_u16(&image, BootSector::VOLUME_FLAGS_OFFSET), 0);
+        assert_eq!(le_u16(&image, 12 * 512 + BootSector::VOLUME_FLAGS_OFFSET), 0);
+        
+        let mut boot_sector = test_boot_sector();
+        boot_sector.volume_flags = volume_flags::MEDIA_FAILURE;
+        let volume = ExFatVolume::new(boot_sector, MemBlockDevice::new(0, 512)).unwrap();
+        assert!(volume.has_media_failure());
+        assert!(!volume.is_dirty());
+    }
+    
+    #[test]
+    fn test_mount() {
+        let mut device = test_device(&[]);
+        add_upcase_table(&mut device, 7);
+        write_boot_regions(&mut device, &test_boot_sector());
+        test_volume_with(device.clone());
+        
+        let volume = ExFatVolume::mount(device.clone()).unwrap();
+        assert!(!volume.is_read_only());
+        assert_eq!(volume.root_dir_cluster(), 2);
+        assert_eq!(volume.total_clusters(), 64);
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        assert!(dir.find("hello.txt", &UpCaseTable::generate()).unwrap().is_some());
+        
+        // A corrupted main boot region falls back to the backup
+        let mut sector = [0u8; 512];
+        sector[100] = 1;
+        device.write_blocks(3, &sector).unwrap();
+        assert_eq!(volume.verify_boot_region(0), Err(ExFatError::ChecksumMismatch));
+        assert!(ExFatVolume::mount(device.clone()).is_ok());
+        
+        device.write_blocks(BootSector::BACKUP_SECTOR, &[0; 512]).unwrap();
+        assert_eq!(ExFatVolume::mount(device.clone()).err(), Some(ExFatError::ChecksumMismatch));
+        
+        let empty = MemBlockDevice::new(96 * 512, 512);
+        assert_eq!(ExFatVolume::mount(empty).err(), Some(ExFatError::BadSignature));
+        
+        // Clusters larger than 32 MiB
+        let mut device = test_device(&[]);
+        let mut boot_sector = test_boot_sector();
+        boot_sector.sectors_per_cluster_shift = 30;
+        write_boot_regions(&mut device, &boot_sector);
+        assert_eq!(ExFatVolume::mount(device.clone()).err(), Some(ExFatError::BadSignature));
+        boot_sector.sectors_per_cluster_shift = 17;
+        write_boot_regions(&mut device, &boot_sector);
+        assert_eq!(ExFatVolume::mount(device).err(), Some(ExFatError::BadSignature));
+    }
+    
+    #[test]
+    fn test_new_in_memory() {
+        let volume = ExFatVolume::new_in_memory(1024 * 1024, 4096);
+        let metadata = volume.metadata().unwrap();
+        assert_eq!(metadata.bytes_per_cluster, 4096);
+        // The boot regions and FAT take the first cluster
+        assert_eq!(metadata.cluster_count, 252);
+        assert_eq!(metadata.root_dir_cluster, 5);
+        assert_eq!(metadata.free_clusters, 248);
+        assert_eq!(volume.upcase_table().unwrap().upcase(0x00E9), 0x00C9);
+        
+        let mut root = volume.open_dir("/").unwrap();
+        assert!(root.entries().unwrap().is_empty());
+        let mut file = root.create_file("Data.bin").unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        file.write(&data).unwrap();
+        file.flush_metadata().unwrap();
+        
+        let mut file = root.find("DATA.BIN", volume.upcase_table().unwrap()).unwrap().unwrap();
+        assert_eq!(file.read_to_end().unwrap(), data);
+        assert_eq!(volume.free_clusters().unwrap(), 245);
+        volume.delete("/data.bin").unwrap();
+        assert_eq!(volume.free_clusters().unwrap(), 248);
+        
+        // Too small for its own structures, or a bad cluster size
+        let mut device = MemBlockDevice::new(32 * 512, 512);
+        assert_eq!(ExFatVolume::format(&mut device, 32, 512), Err(ExFatError::InvalidParameter));
+        let mut device = MemBlockDevice::new(96 * 512, 512);
+        assert_eq!(ExFatVolume::format(&mut device, 96, 256), Err(ExFatError::InvalidParameter));
+        assert_eq!(ExFatVolume::format(&mut device, 96, 1536), Err(ExFatError::InvalidParameter));
+        ExFatVolume::format(&mut device, 96, 512).unwrap();
+        assert!(ExFatVolume::mount(device).is_ok());
+    }
+    
+    #[test]
+    fn test_reserved_fat_entries() {
+        // The formatter writes the media descriptor and reserved entry
+        let volume = ExFatVolume::new_in_memory(256 * 1024, 512);
+        assert_eq!(volume.check_reserved_fat_entries(), Ok(()));
+        let (lba, _) = volume.fat_entry_location(2).unwrap();
+        let mut sector = [0u8; 512];
+        volume.device.borrow_mut().read_blocks(lba, &mut sector).unwrap();
+        assert_eq!((le_u32(&sector, 0), le_u32(&sector, 4)), (0xFFFF_FFF8, 0xFFFF_FFFF));
+        
+        // Neither is a cluster to read, write or start a chain from
+        for cluster in [0, 1] {
+            assert_eq!(volume.fat_entry(cluster), Err(ExFatError::ClusterOutOfRange));
+            assert_eq!(volume.set_fat_entry(cluster, FatEntry::EndOfChain), Err(ExFatError::ClusterOutOfRange));
+            assert_eq!(volume.chain(cluster).next(), Some(Err(ExFatError::ClusterOutOfRange)));
+        }
+        assert_eq!(volume.check_reserved_fat_entries(), Ok(()));
+        
+        // A test image leaves them zero
+        let volume = test_volume();
+        assert_eq!(volume.check_reserved_fat_entries(), Err(ExFatError::Corrupted));
+    }
+    
+    #[test]
+    fn test_multi_cluster_bitmap() {
+        // 5000 clusters need a 625-byte bitmap, which takes clusters 5 and 9
+        let mut boot_sector = test_boot_sector();
+        boot_sector.cluster_count = 5000;
+        boot_sector.fat_length = boot_sector.required_fat_sectors();
+        boot_sector.cluster_heap_offset = 64;
+        boot_sector.volume_length = 64 + 5000;
+        
+        let mut device = MemBlockDevice::new(64 * 512 + 5000 * 512, 512);
+        for (cluster, value) in [(2, 0xFFFF_FFFF), (5, 9), (9, 0xFFFF_FFFF)] {
+            set_fat(&mut device, cluster, value);
+        }
+        let mut root = [0u8; 512];
+        root[..32].copy_from_slice(&bitmap_entry(5, 625));
+        device.write_blocks(64, &root).unwrap();
+        
+        // Clusters 2, 5 and 9 in the first bitmap cluster, 4100 in the second
+        let mut bits = [0u8; 512];
+        bits[0] = 0b1000_1001;
+        device.write_blocks(67, &bits).unwrap();
+        bits[0] = 0b0000_0100;
+        device.write_blocks(71, &bits).unwrap();
+        // Cluster 6 follows the first bitmap cluster but isn't part of it
+        device.write_blocks(68, &[0xFF; 512]).unwrap();
+        
+        let volume = ExFatVolume::new(boot_sector, device.clone()).unwrap();
+        let bitmap = volume.allocation_bitmap(BitmapId::First).unwrap();
+        assert_eq!(bitmap.as_bytes().len(), 625);
+        assert!(bitmap.is_allocated(9));
+        assert!(bitmap.is_allocated(4100));
+        assert!(!bitmap.is_allocated(4099) && !bitmap.is_allocated(4101));
+        assert_eq!(bitmap.free_cluster_count(), 5000 - 4);
+        
+        // Changes in the second half are written to the second cluster
+        volume.with_bitmap(|bitmap| bitmap.set_allocated(4200, true)).unwrap().unwrap();
+        volume.store_bitmap().unwrap();
+        let image = device.to_vec();
+        assert_eq!(image[71 * 512 + 12], 1 << 6);
+        assert!(image[68 * 512..69 * 512].iter().all(|&byte| byte == 0xFF));
+    }
+    
+    #[test]
+    fn test_load_root_metadata() {
+        // Bitmap, up-case table, label and GUID at the start of the root
+        let mut device = test_device(&[6]);
+        add_upcase_table(&mut device, 1);
+        let mut root = [0u8; 512];
+        device.read_blocks(32, &mut root).unwrap();
+        root[64] = EntryType::VolumeLabel as u8;
+        root[65] = 2;
+        for (i, unit) in "SD".encode_utf16().enumerate() {
+            root[66 + i * 2..68 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        root[96..128].copy_from_slice(&VolumeGuidEntry::new([0x24; 16]).to_bytes());
+        device.write_blocks(32, &root).unwrap();
+        
+        let reads: alloc::rc::Rc<RefCell<Vec<(u64, usize)>>> = Default::default();
+        let log = IoLog { device, reads: reads.clone(), writes: Default::default() };
+        let volume = ExFatVolume::new(test_boot_sector(), log).unwrap();
+        
+        let metadata = volume.load_root_metadata().unwrap();
+        assert_eq!(metadata.bitmap.first_cluster(), 5);
+        assert!(metadata.bitmap.is_allocated(6));
+        assert!(!metadata.bitmap.is_allocated(7));
+        assert_eq!(metadata.upcase.upcase(b'q' as u16), b'Q' as u16);
+        assert_eq!(metadata.label.as_deref(), Some("SD"));
+        assert_eq!(metadata.guid, Some([0x24; 16]));
+        
+        // The root is read once, and nothing is cached on the volume
+        assert_eq!(reads.borrow().iter().filter(|&&(lba, _)| lba == 32).count(), 1);
+        assert!(volume.system_entries().is_none());
+    }
+    
+    #[test]
+    fn test_load_system_entries() {
+        // System entries after a file, in no particular order
+        let mut device = test_device(&[]);
+        let mut root = [0u8; 512];
+        device.read_blocks(32, &mut root).unwrap();
+        root.copy_within(0..32, 9 * 32);
+        root[..32].fill(0);
+        device.write_blocks(32, &root).unwrap();
+        add_upcase_table(&mut device, 7);
+        
+        let mut volume = test_volume_with(device.clone());
+        // The GUID takes the first free slot, ahead of the file entries
+        volume.set_volume_guid(Some([0x42; 16])).unwrap();
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        let mut label = [0u8; 32];
+        label[0] = EntryType::VolumeLabel as u8;
+        label[1] = 4;
+        for (i, unit) in "DATA".encode_utf16().enumerate() {
+            label[2 + i * 2..4 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        root.write_entries(8, &[label]);
+        volume.store_directory(&root).unwrap();
+        assert!(volume.system_entries().is_none());
+        
+        volume.load_system_entries().unwrap();
+        let system = volume.system_entries().unwrap();
+        assert_eq!(system.bitmap, (5, 8));
+        assert_eq!(system.upcase.upcase(b'q' as u16), b'Q' as u16);
+        assert_eq!(system.label.as_deref(), Some("DATA"));
+        assert_eq!(system.guid, Some([0x42; 16]));
+        assert_eq!(volume.metadata().unwrap().label.as_deref(), Some("DATA"));
+        
+        volume.set_volume_guid(None).unwrap();
+        assert_eq!(volume.system_entries().unwrap().guid, None);
+        assert_eq!(volume.free_clusters(), Ok(59));
+        
+        // A corrupted up-case table is rejected
+        let mut corrupted = device.clone();
+        corrupted.write_blocks(36, &[0x61; 512]).unwrap();
+        let mut volume = ExFatVolume::new(test_boot_sector(), corrupted).unwrap();
+        assert_eq!(volume.load_system_entries(), Err(ExFatError::ChecksumMismatch));
+        
+        let mut volume = ExFatVolume::new(test_boot_sector(), test_device(&[])).unwrap();
+        assert_eq!(volume.load_system_entries(), Err(ExFatError::MissingUpCaseTable));
+        
+        let mut empty = MemBlockDevice::new(96 * 512, 512);
+        set_fat(&mut empty, 2, 0xFFFF_FFFF);
+        let mut volume = ExFatVolume::new(test_boot_sector(), empty).unwrap();
+        assert_eq!(volume.load_system_entries(), Err(ExFatError::MissingAllocationBitmap));
+        assert_eq!(volume.free_clusters(), Err(ExFatError::MissingAllocationBitmap));
+    }
+    
+    #[test]
+    fn test_second_allocation_bitmap() {
+        let mut texfat = test_boot_sector();
+        texfat.fat_length = 4;
+        texfat.num_fats = 2;
+        texfat.volume_flags = volume_flags::ACTIVE_FAT;
+        
+        let mut device = test_device(&[7]);
+        add_upcase_table(&mut device, 7);
+        set_fat(&mut device, 7, 0xFFFF_FFFF);
+        device.write_blocks(37, &[0xFF; 512]).unwrap();
+        let volume = test_volume_with(device.clone());
+        assert_eq!(volume.allocation_bitmap(BitmapId::Second).err(), Some(ExFatError::MissingAllocationBitmap));
+        
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        let mut second = bitmap_entry(7, 8);
+        second[1] = 1;
+        root.write_entries(8, &[second]);
+        volume.store_directory(&root).unwrap();
+        write_boot_regions(&mut device, &texfat);
+        let mut fat = [0u8; 512];
+        device.read_blocks(24, &mut fat).unwrap();
+        device.write_blocks(28, &fat).unwrap();
+        
+        // The second bitmap doesn't stop the volume mounting
+        let volume = ExFatVolume::mount_read_only(device.clone()).unwrap();
+        let system = volume.system_entries().unwrap();
+        assert_eq!(system.bitmap, (5, 8));
+        assert_eq!(system.second_bitmap, Some((7, 8)));
+        
+        // With the second FAT active, allocation uses the second bitmap
+        assert_eq!(volume.free_clusters(), Ok(0));
+        let bitmap = volume.allocation_bitmap(BitmapId::Second).unwrap();
+        assert_eq!(bitmap.first_cluster(), 7);
+        assert_eq!(bitmap.free_cluster_count(), 0);
+        let bitmap = volume.allocation_bitmap(BitmapId::First).unwrap();
+        assert_eq!(bitmap.first_cluster(), 5);
+        assert_eq!(bitmap.free_cluster_count(), 58);
+        assert_eq!(volume.load_root_metadata().unwrap().bitmap.first_cluster(), 7);
+        
+        // Otherwise the first bitmap, as on any single-FAT volume
+        let mut volume = ExFatVolume::mount_read_only(device.clone()).unwrap();
+        volume.boot_sector.volume_flags = 0;
+        assert_eq!(volume.boot_sector.active_bitmap(), BitmapId::First);
+        assert_eq!(volume.free_clusters(), Ok(58));
+    }
+    
+    #[test]
+    fn test_parse_directory_bytes() {
+        let mut bytes = Vec::new();
+        bytes.extend(entry_set("docs", file_attributes::DIRECTORY, 3, 512).concat());
+        bytes.extend(bitmap_entry(5, 8));
+        // Stray secondary entries
+        bytes.extend(entry_set("stray", file_attributes::ARCHIVE, 0, 0)[1..].concat());
+        
+        let mut bad_checksum = entry_set("bad.txt", file_attributes::ARCHIVE, 0, 0);
+        bad_checksum[2][2] ^= 1;
+        bytes.extend(bad_checksum.concat());
+        bytes.extend(entry_set("hello.txt", file_attributes::ARCHIVE, 4, 5).concat());
+        
+        let results = parse_directory_bytes(&bytes);
+        assert_eq!(results.len(), 4);
+        let docs = results[0].as_ref().unwrap();
+        assert_eq!((docs.name.as_str(), docs.first_cluster, docs.data_length), ("docs", 3, 512));
+        assert!(docs.is_directory());
+        assert_eq!(docs.timestamps.create, 0x5A21_6C00);
+        assert_eq!(docs.entry_position, None);
+        assert_eq!(results[1], Err(ExFatError::InvalidEntrySequence));
+        assert_eq!(results[2], Err(ExFatError::ChecksumMismatch));
+        assert_eq!(results[3].as_ref().unwrap().name, "hello.txt");
+        
+        // Truncated inside the last set, then inside an entry
+        assert_eq!(parse_directory_bytes(&bytes[..bytes.len() - 32]).last(), Some(&Err(ExFatError::InvalidEntrySequence)));
+        assert_eq!(parse_directory_bytes(&bytes[..40]).len(), 1);
+        
+        // Everything after the end of directory marker is ignored
+        let mut ended = bytes[..96].to_vec();
+        ended.extend([0u8; 32]);
+        ended.extend(&bytes[96..]);
+        assert_eq!(parse_directory_bytes(&ended).len(), 1);
+        assert!(parse_directory_bytes(&[]).is_empty());
+    }
+    
+    #[test]
+    fn test_parse_directory_bytes_arbitrary_input() {
+        let template = entry_set("hello.txt", file_attributes::ARCHIVE, 4, 5).concat();
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        
+        for _ in 0..2000 {
+            let len = (random() % 200) as usize;
+            let mut bytes: Vec<u8> = (0..len).map(|_| random() as u8).collect();
+            // Mutated valid sets reach deeper than pure noise
+            if random() % 2 == 0 {
+                bytes = template.clone();
+                for _ in 0..1 + random() % 4 {
+                    let index = (random() as usize) % bytes.len();
+                    bytes[index] = random() as u8;
+                }
+                bytes.truncate((random() as usize) % (bytes.len() + 1));
+            }
+            
+            for metadata in parse_directory_bytes(&bytes).into_iter().flatten() {
+                assert!(metadata.name.chars().count() <= 255);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_entries() {
+        let volume = test_volume();
+        let docs = DirStream { first_cluster: 3, contiguous: false, data_length: 512 };
+        let mut dir = volume.load_directory(docs).unwrap();
+        dir.write_entries(0, &entry_set("one.txt", file_attributes::ARCHIVE, 0, 0));
+        let mut deleted = entry_set("gone.txt", file_attributes::ARCHIVE, 0, 0);
+        for entry in &mut deleted {
+            entry[0] &= 0x7F;
+        }
+        dir.write_entries(3, &deleted);
+        dir.write_entries(6, &entry_set("two.txt", file_attributes::ARCHIVE, 0, 0));
+        dir.write_entries(9, &entry_set("three", file_attributes::DIRECTORY, 0, 0));
+        // Past the end of directory marker
+        dir.write_entries(13, &entry_set("hidden", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&dir).unwrap();
+        
+        let mut dir = ExFatDirectory::new(3, &volume);
+        dir.read_entry().unwrap();
+        let entries = dir.entries().unwrap();
+        let names: Vec<&str> = entries.iter().map(|file| file.name()).collect();
+        assert_eq!(names, ["one.txt", "two.txt", "three"]);
+        assert!(entries[2].is_directory());
+        assert!(dir.read_entry().unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_create_is_case_insensitive() {
+        let mut device = test_device(&[]);
+        add_upcase_table(&mut device, 7);
+        write_boot_regions(&mut device, &test_boot_sector());
+        test_volume_with(device.clone());
+        let volume = ExFatVolume::mount(device).unwrap();
+        
+        let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        root.create_file("File.txt").unwrap();
+        assert_eq!(root.create_file("FILE.TXT").err(), Some(ExFatError::AlreadyExists));
+        assert_eq!(root.create_directory("file.TXT").err(), Some(ExFatError::AlreadyExists));
+        assert_eq!(root.create_directory("DOCS").err(), Some(ExFatError::AlreadyExists));
+        
+        let sub = root.create_directory("SubDir").unwrap();
+        assert_eq!((sub.first_cluster, sub.size, sub.valid_data_length), (7, 512, 512));
+        assert!(ExFatDirectory::new(7, &volume).is_empty().unwrap());
+        
+        let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let names: Vec<String> = root.entries().unwrap().iter().map(|file| String::from(file.name())).collect();
+        assert_eq!(names, ["docs", "hello.txt", "File.txt", "SubDir"]);
+        assert_eq!(volume.move_entry("/docs", "/FILE.txt"), Err(ExFatError::AlreadyExists));
+        
+        // Duplicates outside ASCII are compared through the volume's table,
+        // which only maps 'a' through 'z'
+        root.create_file("Été.txt").unwrap();
+        assert_eq!(root.create_file("Été.TXT").err(), Some(ExFatError::AlreadyExists));
+        assert_eq!(root.create_directory("Été.Txt").err(), Some(ExFatError::AlreadyExists));
+        root.create_file("été.txt").unwrap();
+        assert_eq!(volume.move_entry("/hello.txt", "/été.TXT"), Err(ExFatError::AlreadyExists));
+    }
+    
+    #[test]
+    fn test_create_with_partial_upcase_table() {
+        let mut device = test_device(&[]);
+        add_upcase_table(&mut device, 7);
+        write_boot_regions(&mut device, &test_boot_sector());
+        test_volume_with(device.clone());
+        let volume = ExFatVolume::mount(device).unwrap();
+        let upcase = volume.upcase_table().unwrap();
+        
+        // The table leaves 'é' alone, unlike the built-in mapping
+        let name: Vec<u16> = "été.txt".encode_utf16().collect();
+        assert_ne!(upcase.name_hash(&name), name_hash(&name));
+        
+        let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        root.create_file("été.txt").unwrap();
+        let file = root.find("éTé.TXT", upcase).unwrap().unwrap();
+        assert_eq!(file.name(), "été.txt");
+        assert!(root.find("ÉTÉ.TXT", upcase).unwrap().is_none());
+        
+        volume.move_entry("/hello.txt", "/héllo.txt").unwrap();
+        assert!(lookup(&volume, "/HéLLO.txt").is_some());
+        
+        volume.delete("/été.txt").unwrap();
+        assert!(lookup(&volume, "/été.txt").is_none());
+    }
+    
+    #[test]
+    fn test_create_emoji_name() {
+        // Chain a second cluster onto the root to fit a 19 entry set
+        let mut device = test_device(&[6]);
+        set_fat(&mut device, 2, 6);
+        set_fat(&mut device, 6, 0xFFFF_FFFF);
+        test_volume_with(device.clone());
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        
+        // 255 code units from 127 surrogate pairs and one BMP char
+        let mut name: String = core::iter::repeat_n('😀', 127).collect();
+        name.push('é');
+        let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        root.create_file(&name).unwrap();
+        
+        let path = ["/", &name].concat();
+        let set = lookup(&volume, &path).unwrap();
+        assert_eq!(set.name.len(), MAX_NAME_LENGTH);
+        let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        assert!(root.entries().unwrap().iter().any(|file| file.name() == name));
+        
+        assert_eq!(volume.rename(&path, &[&name, "x"].concat()), Err(ExFatError::NameTooLong));
+        volume.rename(&path, "😀").unwrap();
+        assert_eq!(from_utf16_name(&lookup(&volume, "/😀").unwrap().name), "😀");
+        assert!(lookup(&volume, &path).is_none());
+    }
+    
+    #[test]
+    fn test_create_grows_directory() {
+        let volume = test_volume();
+        let free = volume.free_clusters().unwrap();
+        
+        // The bitmap, docs and hello.txt leave room for three more sets
+        let mut root = volume.open_dir("/").unwrap();
+        for name in ["a", "b", "c"] {
+            root.create_file(name).unwrap();
+        }
+        assert_eq!(volume.free_clusters().unwrap(), free);
+        
+        root.create_file("d").unwrap();
+        let chain = volume.chain(2).collect::<Result<Vec<u32>>>().unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(volume.free_clusters().unwrap(), free - 1);
+        let position = lookup(&volume, "/d").unwrap();
+        assert_eq!(position.index, 16);
+        assert_eq!(root.entries().unwrap().len(), 6);
+        
+        // docs can't grow in place past hello.txt, so it becomes a chain
+        let mut docs = volume.open_dir("/docs").unwrap();
+        for name in ["1", "2", "3", "4", "5", "6"] {
+            docs.create_file(name).unwrap();
+        }
+        let set = lookup(&volume, "/docs").unwrap();
+        assert_eq!({ set.stream.data_length }, 1024);
+        assert_eq!({ set.stream.valid_data_length }, 1024);
+        assert!(!set.stream.is_contiguous());
+        assert_eq!(volume.chain(3).count(), 2);
+        assert!(lookup(&volume, "/docs/6").is_some());
+        assert_eq!(volume.open_dir("/docs").unwrap().entries().unwrap().len(), 6);
+        
+        // A handle that doesn't know its entry set can't grow
+        let mut docs = ExFatDirectory::new(3, &volume);
+        for name in ["7", "8", "9", "10"] {
+            docs.create_file(name).unwrap();
+        }
+        assert_eq!(docs.create_file("11").err(), Some(ExFatError::NotFound));
+    }
+    
+    #[test]
+    fn test_entries_filtered() {
+        let volume = test_volume();
+        let mut root = volume.open_dir("/").unwrap();
+        root.create_directory("src").unwrap();
+        root.create_file("notes.txt").unwrap();
+        let names = |files: Vec<ExFatFile>| -> Vec<String> {
+            files.into_iter().map(|file| file.name).collect()
+        };
+        
+        assert_eq!(names(root.subdirectories().unwrap()), ["docs", "src"]);
+        assert_eq!(names(root.regular_files().unwrap()), ["hello.txt", "notes.txt"]);
+        let found = root.entries_filtered(|file| file.name().ends_with(".txt")).unwrap();
+        assert_eq!(names(found), ["hello.txt", "notes.txt"]);
+        assert!(root.entries_filtered(|_| false).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_open_dir() {
+        let volume = test_volume();
+        let names = |dir: &mut ExFatDirectory| -> Vec<String> {
+            dir.entries().unwrap().into_iter().map(|file| file.name).collect()
+        };
+        
+        let mut root = volume.open_dir("/").unwrap();
+        assert_eq!(names(&mut root), ["docs", "hello.txt"]);
+        assert_eq!(names(&mut volume.open_dir("").unwrap()), ["docs", "hello.txt"]);
+        assert_eq!(names(&mut volume.open_dir("/docs/..").unwrap()), ["docs", "hello.txt"]);
+        
+        let mut docs = volume.open_dir("/DOCS/").unwrap();
+        assert_eq!(docs.cluster, 3);
+        assert!(names(&mut docs).is_empty());
+        assert_eq!(docs.entry_position.map(|position| position.offset), Some(32));
+        
+        assert_eq!(volume.open_dir("/hello.txt").err(), Some(ExFatError::NotFound));
+        assert_eq!(volume.open_dir("/missing").err(), Some(ExFatError::NotFound));
+        assert_eq!(volume.open_dir("/hello.txt/x").err(), Some(ExFatError::NotFound));
+    }
+    
+    #[test]
+    fn test_open_metadata() {
+        let volume = test_volume();
+        let listing: Vec<FileMetadata> = ExFatDirectory::new(volume.root_dir_cluster(), &volume)
+            .entries()
+            .unwrap()
+            .iter()
+            .map(ExFatFile::metadata)
+            .collect();
+        assert_eq!(listing[1].entry_position, Some(EntryPosition { cluster: 2, offset: 128, contiguous: false }));
+        
+        let mut cluster = [0u8; 512];
+        cluster[..5].copy_from_slice(b"Hello");
+        volume.write_cluster(4, &cluster).unwrap();
+        let mut file = volume.open_metadata(&listing[1]).unwrap();
+        assert_eq!(file.read_to_end().unwrap(), b"Hello");
+        
+        // The handle knows its entry set, so changes can be flushed
+        file.set_hidden(true);
+        file.flush_metadata().unwrap();
+        let set = lookup(&volume, "/hello.txt").unwrap();
+        assert_ne!(set.file.file_attributes & file_attributes::HIDDEN, 0);
+        
+        // Metadata parsed from raw bytes opens for reading only
+        let mut raw = listing[0].clone();
+        raw.entry_position = None;
+        let mut docs = volume.open_metadata(&raw).unwrap();
+        assert!(docs.is_directory());
+        docs.set_archive(true);
+        assert_eq!(docs.flush_metadata(), Err(ExFatError::NotFound));
+        
+        raw.first_cluster = 1000;
+        assert_eq!(volume.open_metadata(&raw).err(), Some(ExFatError::ClusterOutOfRange));
+    }
+    
+    #[test]
+    fn test_write_ordering() {
+        let device = test_device(&[]);
+        test_volume_with(device.clone());
+        let reads = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let writes = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let log = IoLog { device, reads, writes: writes.clone() };
+        let mut volume = ExFatVolume::new(test_boot_sector(), log).unwrap();
+        
+        let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut a = root.create_file("a.bin").unwrap();
+        a.write(&[1; 512]).unwrap();
+        a.flush().unwrap();
+        let mut b = root.create_file("b.bin").unwrap();
+        b.write(&[2; 512]).unwrap();
+        b.flush().unwrap();
+        
+        // Growing a.bin past b.bin turns it into a FAT chain
+        writes.borrow_mut().clear();
+        a.write(&[3; 512]).unwrap();
+        let second = a.next_cluster(a.first_cluster).unwrap();
+        a.flush().unwrap();
+        let data = volume.cluster_to_lba(second).unwrap();
+        assert_eq!(*writes.borrow(), [(35, 512), (data, 512), (24, 512), (32, 512)]);
+        drop(root);
+        
+        // Syncing records the space in use in both boot sectors
+        writes.borrow_mut().clear();
+        volume.sync_all().unwrap();
+        assert_eq!(*writes.borrow(), [(0, 512), (12, 512)]);
+        assert_eq!(volume.boot_sector.percent_in_use, 10);
+    }
+    
+    #[test]
+    fn test_walk() {
+        let mut device = test_device(&[6]);
+        set_fat(&mut device, 6, 0xFFFF_FFFF);
+        let volume = test_volume_with(device);
+        
+        let docs = DirStream { first_cluster: 3, contiguous: false, data_length: 512 };
+        let mut dir = volume.load_directory(docs).unwrap();
+        dir.write_entries(0, &entry_set("sub", file_attributes::DIRECTORY, 6, 512));
+        dir.write_entries(3, &entry_set("a.txt", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&dir).unwrap();
+        
+        let sub = DirStream { first_cluster: 6, contiguous: false, data_length: 512 };
+        let mut dir = volume.load_directory(sub).unwrap();
+        dir.write_entries(0, &entry_set("b.txt", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&dir).unwrap();
+        
+        let mut paths = Vec::new();
+        volume.walk(volume.root_dir_cluster(), &mut |path, file| {
+            paths.push((String::from(path), file.is_directory()));
+        }).unwrap();
+        assert_eq!(paths, [
+            (String::from("/docs"), true),
+            (String::from("/docs/sub"), true),
+            (String::from("/docs/sub/b.txt"), false),
+            (String::from("/docs/a.txt"), false),
+            (String::from("/hello.txt"), false),
+        ]);
+        
+        // From a path, to a bounded depth, with metadata
+        let mut paths = Vec::new();
+        volume.walk_path("/DOCS/./", 0, |path, metadata| {
+            paths.push((String::from(path), metadata.first_cluster));
+        }).unwrap();
+        assert_eq!(paths, [(String::from("/DOCS/sub"), 6), (String::from("/DOCS/a.txt"), 0)]);
+        
+        paths.clear();
+        volume.walk_path("/docs/sub/..", 1, |path, metadata| {
+            paths.push((String::from(path), metadata.first_cluster));
+        }).unwrap();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[1], (String::from("/docs/sub/b.txt"), 0));
+        assert_eq!(volume.walk_path("/hello.txt", 1, |_, _| {}), Err(ExFatError::NotFound));
+        
+        // Pseudo-entries are skipped
+        let mut dir = volume.load_directory(sub).unwrap();
+        let template = entry_set("xx", file_attributes::DIRECTORY, 3, 512);
+        let file = FileEntry::from_bytes(&template[0]);
+        let stream = StreamExtensionEntry::from_bytes(&template[1]);
+        dir.write_entries(3, &build_entry_set(&file, &stream, &[0x2E, 0x2E], None));
+        volume.store_directory(&dir).unwrap();
+        paths.clear();
+        volume.walk_path("/", 8, |path, metadata| {
+            paths.push((String::from(path), metadata.first_cluster));
+        }).unwrap();
+        assert_eq!(paths.len(), 5);
+        
+        // A subdirectory pointing back at its parent is a loop
+        let mut dir = volume.load_directory(sub).unwrap();
+        dir.write_entries(3, &entry_set("loop", file_attributes::DIRECTORY, 3, 512));
+        volume.store_directory(&dir).unwrap();
+        let err = volume.walk(volume.root_dir_cluster(), &mut |_, _| {}).unwrap_err();
+        assert_eq!(err, ExFatError::Corrupted);
+    }
+    
+    #[test]
+    fn test_partition_offset() {
+        let mut boot_sector = test_boot_sector();
+        boot_sector.partition_offset = 64;
+        let mut partition = test_device(&[]);
+        add_upcase_table(&mut partition, 7);
+        write_boot_regions(&mut partition, &boot_sector);
+        test_volume_with(partition.clone());
+        
+        // Embed the partition in a whole-disk image
+        let mut image = vec![0u8; 64 * 512];
+        image.extend_from_slice(&partition.to_vec());
+        let disk = MemBlockDevice::from_vec(image, 512);
+        
+        let mut volume = ExFatVolume::mount_at(disk.clone(), 64).unwrap();
+        assert_eq!(volume.partition_lba_base(), 64);
+        assert_eq!(volume.cluster_to_lba(2), Ok(96));
+        assert_eq!(volume.fat_region(), Ok((88, 8)));
+        assert_eq!(volume.bitmap_region(), Ok((99, 1)));
+        assert_eq!(volume.cluster_heap_region(), Ok((96, 64)));
+        assert_eq!(volume.verify_boot_region(BootSector::BACKUP_SECTOR), Ok(()));
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        assert!(dir.find("hello.txt", &UpCaseTable::generate()).unwrap().is_some());
+        volume.mark_dirty().unwrap();
+        assert_eq!(le_u16(&disk.to_vec(), 64 * 512 + BootSector::VOLUME_FLAGS_OFFSET), volume_flags::VOLUME_DIRTY);
+        
+        // The base defaults to the boot sector's partition offset
+        let volume = ExFatVolume::new(boot_sector, disk).unwrap();
+        assert_eq!(volume.fat_entry(2), Ok(FatEntry::EndOfChain));
+        
+        // A device holding just the partition is mounted at 0 or overridden
+        assert_eq!(ExFatVolume::mount(partition.clone()).unwrap().partition_lba_base(), 0);
+        let mut volume = ExFatVolume::new(boot_sector, partition).unwrap();
+        volume.set_partition_lba_base(0);
+        assert_eq!(volume.cluster_to_lba(2), Ok(32));
+        assert_eq!(volume.fat_entry(2), Ok(FatEntry::EndOfChain));
+    }
+    
+    #[test]
+    fn test_probe() {
+        let mut device = test_device(&[]);
+        write_boot_regions(&mut device, &test_boot_sector());
+        let reads = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let mut log = IoLog {
+            device: device.clone(),
+            reads: reads.clone(),
+            writes: Default::default(),
+        };
+        assert_eq!(ExFatVolume::probe(&mut log), ProbeResult::Valid);
+        assert!(reads.borrow().iter().all(|&(_, len)| len == 512));
+        
+        // The backup region still validates
+        let mut sector = [0u8; 512];
+        sector[100] = 1;
+        device.write_blocks(3, &sector).unwrap();
+        assert_eq!(ExFatVolume::probe(&mut device), ProbeResult::Valid);
+        
+        device.write_blocks(BootSector::BACKUP_SECTOR + 3, &sector).unwrap();
+        assert_eq!(ExFatVolume::probe(&mut device), ProbeResult::ChecksumBad);
+        
+        let mut empty = MemBlockDevice::new(96 * 512, 512);
+        assert_eq!(ExFatVolume::probe(&mut empty), ProbeResult::NotExFat);
+        
+        let mut missing = MemBlockDevice::new(0, 512);
+        assert_eq!(ExFatVolume::probe(&mut missing), ProbeResult::IoError(Status::INVALID_PARAMETER));
+    }
+    
+    #[test]
+    fn test_mount_dirty_volume() {
+        let mut boot_sector = test_boot_sector();
+        boot_sector.volume_flags = volume_flags::VOLUME_DIRTY;
+        let mut device = test_device(&[]);
+        add_upcase_table(&mut device, 1);
+        write_boot_regions(&mut device, &boot_sector);
+        
+        assert_eq!(ExFatVolume::mount(device.clone()).err(), Some(ExFatError::VolumeDirty));
+        
+        // Read-only mounts accept a dirty volume
+        let volume = ExFatVolume::mount_read_only(device).unwrap();
+        assert!(volume.is_dirty());
+    }
+    
+    #[test]
+    fn test_mount_options() {
+        let mut boot_sector = test_boot_sector();
+        boot_sector.volume_flags = volume_flags::VOLUME_DIRTY;
+        let mut device = test_device(&[]);
+        add_upcase_table(&mut device, 7);
+        write_boot_regions(&mut device, &boot_sector);
+        test_volume_with(device.clone());
+        
+        let options = MountOptions::default();
+        assert_eq!(ExFatVolume::mount_options(device.clone(), options).err(), Some(ExFatError::VolumeDirty));
+        let volume = ExFatVolume::mount_options(device.clone(), MountOptions { ignore_dirty: true, ..options }).unwrap();
+        assert!(!volume.is_read_only());
+        assert!(volume.is_dirty());
+        
+        // A volume with a media failure is never mounted read-write
+        boot_sector.volume_flags = volume_flags::MEDIA_FAILURE;
+        write_boot_regions(&mut device, &boot_sector);
+        assert_eq!(ExFatVolume::mount_options(device.clone(), options).err(), Some(ExFatError::WriteProtected));
+        assert_eq!(ExFatVolume::mount(device.clone()).err(), Some(ExFatError::WriteProtected));
+        
+        let volume = ExFatVolume::mount_options(device.clone(), MountOptions { read_only: true, ..options }).unwrap();
+        assert!(volume.is_read_only());
+        assert!(volume.has_media_failure());
+        let mut root = volume.open_dir("/").unwrap();
+        assert_eq!(root.create_file("new.txt").err(), Some(ExFatError::WriteProtected));
+        let mut hello = root.find("hello.txt", &UpCaseTable::generate()).unwrap().unwrap();
+        assert_eq!(hello.write(b"x"), Err(ExFatError::WriteProtected));
+        
+        // Stray bytes in the must-be-zero region only matter when strict
+        boot_sector.volume_flags = 0;
+        boot_sector.must_be_zero[0] = 0xEB;
+        write_boot_regions(&mut device, &boot_sector);
+        assert!(ExFatVolume::mount_options(device.clone(), options).is_ok());
+        let strict = MountOptions { strict: true, ..options };
+        assert_eq!(ExFatVolume::mount_options(device.clone(), strict).err(), Some(ExFatError::BadSignature));
+        boot_sector.must_be_zero[0] = 0;
+        write_boot_regions(&mut device, &boot_sector);
+        assert!(ExFatVolume::mount_options(device, strict).is_ok());
+    }
+    
+    #[test]
+    fn test_mount_read_only() {
+        let mut device = test_device(&[]);
+        add_upcase_table(&mut device, 7);
+        write_boot_regions(&mut device, &test_boot_sector());
+        test_volume_with(device.clone());
+        let before = device.to_vec();
+        
+        let mut volume = ExFatVolume::mount_read_only(device.clone()).unwrap();
+        assert!(volume.is_read_only());
+        assert_eq!(volume.mark_dirty(), Err(ExFatError::WriteProtected));
+        assert!(!volume.is_dirty());
+        
+        // Reads still work
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut hello = dir.find("hello.txt", &UpCaseTable::generate()).unwrap().unwrap();
+        
+        hello.set_hidden(true);
+        assert_eq!(hello.flush_metadata(), Err(ExFatError::WriteProtected));
+        assert_eq!(dir.create_entry_contiguous("new.bin", 512).err(), Some(ExFatError::WriteProtected));
+        assert_eq!(volume.move_entry("/hello.txt", "/docs/hello.txt"), Err(ExFatError::WriteProtected));
+        assert_eq!(volume.set_fat_entry(10, FatEntry::EndOfChain), Err(ExFatError::WriteProtected));
+        assert_eq!(volume.write_cluster(10, &[0; 512]), Err(ExFatError::WriteProtected));
+        
+        volume.unmount().unwrap();
+        assert!(device.to_vec() == before);
+    }
+    
+    #[test]
+    fn test_unmount() {
+        let mut device = test_device(&[]);
+        write_boot_regions(&mut device, &test_boot_sector());
+        let flushes = alloc::rc::Rc::new(core::cell::Cell::new(0));
+        let counter = FlushCounter {
+            device: device.clone(),
+            flushes: flushes.clone(),
+        };
+        
+        let mut volume = ExFatVolume::new(test_boot_sector(), counter).unwrap();
+        volume.mark_dirty().unwrap();
+        
+        // Change the cached bitmap without writing it back
+        volume.with_bitmap(|bitmap| bitmap.set_allocated(20, true)).unwrap().unwrap();
+        assert_eq!(device.to_vec()[35 * 512 + 2], 0);
+        
+        let marked = flushes.get();
+        volume.unmount().unwrap();
+        
+        let image = device.to_vec();
+        assert_eq!(image[35 * 512 + 2], 1 << 2);
+        assert_eq!(le_u16(&image, BootSector::VOLUME_FLAGS_OFFSET), 0);
+        assert_eq!(le_u16(&image, 12 * 512 + BootSector::VOLUME_FLAGS_OFFSET), 0);
+        assert!(flushes.get() > marked);
+    }
+    
+    #[test]
+    fn test_4k_sectors() {
+        let mut boot_sector = test_boot_sector();
+        boot_sector.bytes_per_sector_shift = 12;
+        boot_sector.fat_length = 2;
+        boot_sector.cluster_count = 1100;
+        assert_eq!(boot_sector.boot_region_len(), 11 * 4096);
+        
+        let mut device = MemBlockDevice::new(40 * 4096, 4096);
+        write_boot_regions(&mut device, &boot_sector);
+        let mut image = device.to_vec();
+        // Cluster 5 starts at sector 35
+        image[35 * 4096..36 * 4096].fill(0xA5);
+        // FAT entry 1030 lives in the second FAT sector
+        image[25 * 4096 + 24..25 * 4096 + 28].copy_from_slice(&7u32.to_le_bytes());
+        let mut device = MemBlockDevice::from_vec(image, 4096);
+        
+        let volume = ExFatVolume::new(boot_sector, device.clone()).unwrap();
+        assert_eq!(volume.bytes_per_cluster(), 4096);
+        assert_eq!(volume.cluster_to_lba(5), Ok(35));
+        assert_eq!(volume.fat_entry(1030), Ok(FatEntry::Next(7)));
+        
+        let mut buffer = vec![0u8; 4096];
+        volume.read_cluster(5, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&byte| byte == 0xA5));
+        assert_eq!(volume.read_cluster(5, &mut [0; 512]), Err(ExFatError::BufferTooSmall));
+        
+        // The checksum covers all eleven 4K sectors, not just 11 * 512 bytes
+        assert_eq!(volume.verify_boot_region(0), Ok(()));
+        assert_eq!(volume.verify_boot_region(BootSector::BACKUP_SECTOR), Ok(()));
+        let mut sector = vec![0u8; 4096];
+        sector[100] = 1;
+        device.write_blocks(10, &sector).unwrap();
+        assert_eq!(volume.verify_boot_region(0), Err(ExFatError::ChecksumMismatch));
+        assert_eq!(volume.verify_boot_region(BootSector::BACKUP_SECTOR), Ok(()));
+        
+        // Sector numbers must map directly to device blocks
+        let err = ExFatVolume::new(boot_sector, MemBlockDevice::new(0, 512)).err();
+        assert_eq!(err, Some(ExFatError::InvalidParameter));
+        boot_sector.bytes_per_sector_shift = 13;
+        assert!(!boot_sector.is_valid());
+    }
+    
+    #[test]
+    fn test_regions() {
+        let volume = test_volume();
+        assert_eq!(volume.fat_region(), Ok((24, 8)));
+        assert_eq!(volume.bitmap_region(), Ok((35, 1)));
+        assert_eq!(volume.cluster_heap_region(), Ok((32, 64)));
+        
+        // A heap larger than 2^32 sectors
+        let mut boot_sector = test_boot_sector();
+        boot_sector.sectors_per_cluster_shift = 16;
+        boot_sector.cluster_count = 1 << 24;
+        boot_sector.num_fats = 2;
+        boot_sector.fat_length = 1 << 17;
+        let volume = ExFatVolume::new(boot_sector, MemBlockDevice::new(0, 512)).unwrap();
+        assert_eq!(volume.fat_region(), Ok((24, 1 << 18)));
+        assert_eq!(volume.cluster_heap_region(), Ok((32, 1 << 40)));
+        
+        // Refused at mount, but the field is public
+        boot_sector.sectors_per_cluster_shift = 200;
+        assert!(ExFatVolume::new(boot_sector, MemBlockDevice::new(0, 512)).is_err());
+        let mut volume = ExFatVolume::new(test_boot_sector(), MemBlockDevice::new(0, 512)).unwrap();
+        volume.boot_sector.sectors_per_cluster_shift = 200;
+        assert_eq!(volume.cluster_heap_region(), Err(ExFatError::Corrupted));
+    }
+    
+    #[test]
+    fn test_dump_region() {
+        let mut volume = test_volume();
+        let mut bitmap = [0u8; 512];
+        volume.read_cluster(5, &mut bitmap).unwrap();
+        
+        let mut out = [0u8; 1024];
+        volume.dump_region(35, 1, &mut out).unwrap();
+        assert_eq!(out[..512], bitmap);
+        
+        // The last two sectors of the volume, and nothing past them
+        assert!(volume.dump_region(94, 2, &mut out).is_ok());
+        assert_eq!(volume.dump_region(95, 2, &mut out), Err(ExFatError::InvalidParameter));
+        assert_eq!(volume.dump_region(u64::MAX, 1, &mut out), Err(ExFatError::InvalidParameter));
+        assert_eq!(volume.dump_region(0, 3, &mut out), Err(ExFatError::BufferTooSmall));
+        
+        // Sectors are relative to the partition base
+        volume.set_partition_lba_base(1);
+        volume.dump_region(34, 1, &mut out).unwrap();
+        assert_eq!(out[..512], bitmap);
+    }
+    
+    #[test]
+    fn test_new_rejects_bad_signature() {
+        let mut boot_sector = test_boot_sector();
+        boot_sector.boot_signature = 0;
+        let result = ExFatVolume::new(boot_sector, MemBlockDevice::new(0, 512));
+        assert!(matches!(result, Err(ExFatError::BadSignature)));
+    }
+    
+    #[test]
+    fn test_read_entries() {
+        let volume = test_volume();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        
+        let docs = dir.read_entry().unwrap().unwrap();
+        assert_eq!(docs.name(), "docs");
+        assert!(docs.is_directory());
+        
+        let hello = dir.read_entry().unwrap().unwrap();
+        assert_eq!(hello.name(), "hello.txt");
+        assert_eq!(hello.size(), 5);
+        assert_eq!(hello.first_cluster, 4);
+        
+        assert!(dir.read_entry().unwrap().is_none());
+        
+        dir.reset();
+        assert_eq!(dir.read_entry().unwrap().unwrap().name(), "docs");
+    }
+    
+    #[test]
+    fn test_attribute_helpers() {
+        let volume = test_volume();
+        let mut file = ExFatFile::new(String::from("a"), file_attributes::ARCHIVE, 4, 5, &volume);
+        assert!(file.is_archive());
+        assert!(!file.is_read_only() && !file.is_hidden() && !file.is_system());
+        
+        // Setting an attribute to its current value leaves the entry clean
+        file.set_archive(true);
+        assert!(!file.is_dirty());
+        
+        file.set_read_only(true);
+        file.set_hidden(true);
+        file.set_archive(false);
+        assert!(file.is_read_only() && file.is_hidden() && !file.is_archive());
+        assert_eq!(file.attributes, file_attributes::READ_ONLY | file_attributes::HIDDEN);
+        assert!(file.is_dirty());
+    }
+    
+    #[test]
+    fn test_preallocate_contiguous() {
+        let volume = test_volume();
+        let mut file = ExFatFile::new(String::from("big"), file_attributes::ARCHIVE, 0, 0, &volume);
+        file.preallocate_contiguous(3 * 512).unwrap();
+        
+        assert!(file.is_contiguous());
+        assert_eq!(file.first_cluster, 6);
+        assert_eq!(file.size(), 3 * 512);
+        assert!(file.is_dirty());
+        assert!((6..9).all(|cluster| volume.with_bitmap(|bitmap| bitmap.is_allocated(cluster)).unwrap()));
+        
+        // The FAT is left untouched for a contiguous run
+        assert!((6..9).all(|cluster| volume.fat_entry(cluster) == Ok(FatEntry::Free)));
+        
+        // The bitmap was written back to disk
+        assert!(volume.load_bitmap().unwrap().is_allocated(8));
+    }
+    
+    #[test]
+    fn test_preallocate_falls_back_to_fat_chain() {
+        // Leave only clusters 6, 8 and 10 free
+        let allocated: Vec<u32> = (6..66).filter(|cluster| ![6, 8, 10].contains(cluster)).collect();
+        let volume = test_volume_with(test_device(&allocated));
+        
+        let mut file = ExFatFile::new(String::from("big"), file_attributes::ARCHIVE, 0, 0, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        file.preallocate_contiguous(3 * 512).unwrap();
+        
+        assert!(!file.is_contiguous());
+        assert_eq!(volume.cluster_chain(file.first_cluster, false, 0), Ok(vec![6, 8, 10]));
+        
+        let mut other = ExFatFile::new(String::from("more"), file_attributes::ARCHIVE, 0, 0, &volume);
+        assert_eq!(other.preallocate_contiguous(512), Err(ExFatError::VolumeFull));
+    }
+    
+    #[test]
+    fn test_flush_metadata() {
+        let volume = test_volume();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        dir.read_entry().unwrap();
+        let mut file = dir.read_entry().unwrap().unwrap();
+        assert_eq!(file.entry_position(), Some(EntryPosition { cluster: 2, offset: 128, contiguous: false }));
+        assert_eq!(file.timestamps.create, 0x5A21_6C00);
+        
+        file.set_read_only(true);
+        file.size = 1000;
+        file.valid_data_length = 700;
+        file.set_timestamps(Timestamps { modified: 0x5B00_0000, ..file.timestamps });
+        file.flush_metadata().unwrap();
+        assert!(!file.is_dirty());
+        
+        let set = lookup(&volume, "/hello.txt").unwrap();
+        assert_eq!({ set.file.file_attributes }, file_attributes::ARCHIVE | file_attributes::READ_ONLY);
+        assert_eq!({ set.file.create_timestamp }, 0x5A21_6C00);
+        assert_eq!({ set.file.last_modified_timestamp }, 0x5B00_0000);
+        assert_eq!({ set.stream.data_length }, 1000);
+        assert_eq!({ set.stream.valid_data_length }, 700);
+        
+        let entries = volume.read_entry_set(file.entry_position().unwrap()).unwrap();
+        assert_eq!(le_u16(&entries[0], 2), entry_set_checksum(&entries));
+    }
+    
+    #[test]
+    fn test_flush_metadata_across_clusters() {
+        let mut device = test_device(&[6]);
+        set_fat(&mut device, 2, 6);
+        set_fat(&mut device, 6, 0xFFFF_FFFF);
+        let volume = test_volume_with(device);
+        
+        // Place an entry set so its primary entry ends the first root cluster,
+        // with deleted entries filling the gap before it
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        let mut deleted = [0u8; 32];
+        deleted[0] = 0x05;
+        root.write_entries(7, &[deleted; 8]);
+        root.write_entries(15, &entry_set("split.bin", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&root).unwrap();
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut file = loop {
+            let file = dir.read_entry().unwrap().unwrap();
+            if file.name() == "split.bin" {
+                break file;
+            }
+        };
+        assert_eq!(file.entry_position().unwrap().offset, 480);
+        
+        file.set_hidden(true);
+        file.flush_metadata().unwrap();
+        
+        let set = lookup(&volume, "/split.bin").unwrap();
+        assert_eq!({ set.file.file_attributes }, file_attributes::ARCHIVE | file_attributes::HIDDEN);
+        let entries = volume.read_entry_set(file.entry_position().unwrap()).unwrap();
+        assert_eq!(le_u16(&entries[0], 2), entry_set_checksum(&entries));
+    }
+    
+    #[test]
+    fn test_create_entry_contiguous() {
+        let volume = test_volume();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let file = dir.create_entry_contiguous("swap.bin", 4 * 512).unwrap();
+        
+        assert!(file.is_contiguous());
+        assert_eq!(file.first_cluster, 6);
+        assert!((6..10).all(|cluster| volume.load_bitmap().unwrap().is_allocated(cluster)));
+        assert!((6..10).all(|cluster| volume.fat_entry(cluster) == Ok(FatEntry::Free)));
+        
+        let set = lookup(&volume, "/swap.bin").unwrap();
+        assert!(set.stream.is_contiguous());
+        assert_eq!({ set.stream.first_cluster }, 6);
+        assert_eq!({ set.stream.data_length }, 4 * 512);
+        assert_eq!(file.entry_position(), Some(EntryPosition { cluster: 2, offset: 224, contiguous: false }));
+        
+        let err = dir.create_entry_contiguous("SWAP.BIN", 512).err();
+        assert_eq!(err, Some(ExFatError::AlreadyExists));
+    }
+    
+    #[test]
+    fn test_time_source() {
+        let created = DateTime {
+            year: 2024,
+            month: 5,
+            day: 17,
+            hour: 9,
+            minute: 30,
+            second: 15,
+            hundredths: 0,
+            utc_offset: Some(60),
+        };
+        let modified = DateTime { day: 18, ..created };
+        
+        let mut volume = test_volume();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let file = dir.create_entry_contiguous("zero.bin", 0).unwrap();
+        assert_eq!(file.timestamps, Timestamps::default());
+        
+        volume.set_time_source(FixedTimeSource(created));
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let file = dir.create_entry_contiguous("log.txt", 0).unwrap();
+        assert_eq!(file.timestamps, Timestamps::at(&created).unwrap());
+        assert_eq!(lookup(&volume, "/log.txt").unwrap().file.timestamps(), file.timestamps);
+        
+        // Changing the data stamps the modified time only
+        volume.set_time_source(FixedTimeSource(modified));
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut file = dir.find("log.txt", &UpCaseTable::generate()).unwrap().unwrap();
+        file.preallocate_contiguous(512).unwrap();
+        file.flush_metadata().unwrap();
+        
+        let timestamps = lookup(&volume, "/log.txt").unwrap().file.timestamps();
+        assert_eq!(timestamps.create, Timestamps::at(&created).unwrap().create);
+        assert_eq!(timestamps.modified, Timestamps::at(&modified).unwrap().modified);
+        assert_eq!(timestamps.modified_utc_offset, 0x84);
+    }
+    
+    #[test]
+    fn test_read_unallocated_file() {
+        let volume = test_volume();
+        let mut file = ExFatFile::new(String::from("sparse"), file_attributes::ARCHIVE, 0, 1000, &volume);
+        
+        let mut buffer = [0xFFu8; 2048];
+        assert_eq!(file.read(&mut buffer), Ok(1000));
+        assert!(buffer[..1000].iter().all(|&byte| byte == 0));
+        assert_eq!(buffer[1000], 0xFF);
+        assert_eq!(file.read(&mut buffer), Ok(0));
+    }
+    
+    #[test]
+    fn test_read_contiguous() {
+        let mut device = test_device(&[6, 7, 8, 9]);
+        for (lba, fill) in [(36, 0xA1), (37, 0xA2), (38, 0xA3), (39, 0xA4)] {
+            device.write_blocks(lba, &[fill; 512]).unwrap();
+        }
+        let reads = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let log = IoLog {
+            device,
+            reads: reads.clone(),
+            writes: Default::default(),
+        };
+        let volume = ExFatVolume::new(test_boot_sector(), log).unwrap();
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 6, 4 * 512, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        
+        let mut buffer = vec![0u8; 4 * 512];
+        assert_eq!(file.read(&mut buffer), Ok(4 * 512));
+        assert_eq!(*reads.borrow(), [(36, 4 * 512)]);
+        assert!(buffer[512..1024].iter().all(|&byte| byte == 0xA2));
+        assert!(buffer[1536..].iter().all(|&byte| byte == 0xA4));
+        
+        // Partial clusters at either end are read separately
+        reads.borrow_mut().clear();
+        file.seek(100).unwrap();
+        let mut buffer = vec![0u8; 1500];
+        assert_eq!(file.read(&mut buffer), Ok(1500));
+        assert_eq!(*reads.borrow(), [(36, 512), (37, 1024), (39, 512)]);
+        assert_eq!((buffer[411], buffer[412], buffer[1499]), (0xA1, 0xA2, 0xA4));
+        
+        assert_eq!(volume.read_contiguous(6, 4, &mut [0; 512]), Err(ExFatError::BufferTooSmall));
+        assert_eq!(volume.read_contiguous(64, 4, &mut [0; 4 * 512]), Err(ExFatError::ClusterOutOfRange));
+    }
+    
+    #[test]
+    fn test_bitmap_sector_writes() {
+        let writes = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let log = IoLog {
+            device: test_device(&[]),
+            reads: Default::default(),
+            writes: writes.clone(),
+        };
+        let volume = ExFatVolume::new(test_boot_sector(), log).unwrap();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        
+        // A 40-cluster run is written back as one bitmap sector
+        let file = dir.create_entry_contiguous("big.bin", 40 * 512).unwrap();
+        assert_eq!(file.first_cluster, 6);
+        let bitmap_writes = writes.borrow().iter().filter(|&&(lba, _)| lba == 35).count();
+        assert_eq!(bitmap_writes, 1);
+        
+        // Storing an unchanged bitmap writes nothing
+        writes.borrow_mut().clear();
+        volume.store_bitmap().unwrap();
+        assert!(writes.borrow().is_empty());
+        
+        volume.delete("/big.bin").unwrap();
+        assert!((6..46).all(|cluster| !volume.with_bitmap(|bitmap| bitmap.is_allocated(cluster)).unwrap()));
+        assert_eq!(writes.borrow().iter().filter(|&&(lba, _)| lba == 35).count(), 1);
+    }
+    
+    #[test]
+    fn test_write_allocates_first_cluster() {
+        let volume = test_volume();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut file = dir.create_entry_contiguous("new.txt", 0).unwrap();
+        assert_eq!(file.first_cluster, 0);
+        
+        assert_eq!(file.write(b"hello world"), Ok(11));
+        assert_eq!(file.first_cluster, 6);
+        assert_eq!((file.size, file.valid_data_length), (11, 11));
+        assert!(volume.load_bitmap().unwrap().is_allocated(6));
+        file.flush_metadata().unwrap();
+        
+        let set = lookup(&volume, "/new.txt").unwrap();
+        assert_eq!({ set.stream.first_cluster }, 6);
+        assert_eq!({ set.stream.data_length }, 11);
+        
+        file.seek(0).unwrap();
+        let mut buffer = [0u8; 32];
+        assert_eq!(file.read(&mut buffer), Ok(11));
+        assert_eq!(&buffer[..11], b"hello world");
+    }
+    
+    #[test]
+    fn test_file_flush() {
+        let mut device = test_device(&[]);
+        write_boot_regions(&mut device, &test_boot_sector());
+        let flushes = alloc::rc::Rc::new(core::cell::Cell::new(0));
+        let counter = FlushCounter {
+            device: device.clone(),
+            flushes: flushes.clone(),
+        };
+        let volume = ExFatVolume::new(test_boot_sector(), counter).unwrap();
+        let upcase = UpCaseTable::generate();
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut file = dir.create_entry_contiguous("notes.txt", 0).unwrap();
+        file.write(&[0x5A; 700]).unwrap();
+        assert!(file.is_dirty());
+        file.flush().unwrap();
+        assert!(!file.is_dirty());
+        assert_eq!(flushes.get(), 1);
+        
+        let mut file = dir.find("notes.txt", &upcase).unwrap().unwrap();
+        assert_eq!(file.size(), 700);
+        assert_eq!(file.read_to_end().unwrap(), [0x5A; 700]);
+        
+        // Closing flushes too
+        file.write(b"tail").unwrap();
+        file.close().unwrap();
+        assert_eq!(flushes.get(), 2);
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut file = dir.find("notes.txt", &upcase).unwrap().unwrap();
+        assert_eq!(file.size(), 704);
+        assert_eq!(&file.read_to_end().unwrap()[700..], b"tail");
+    }
+    
+    #[test]
+    fn test_write_grows_file() {
+        let volume = test_volume();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut first = dir.create_entry_contiguous("first", 0).unwrap();
+        first.write(&[0xA1; 512]).unwrap();
+        let mut second = dir.create_entry_contiguous("second", 0).unwrap();
+        second.write(&[0xB2; 10]).unwrap();
+        assert_eq!((first.first_cluster, second.first_cluster), (6, 7));
+        
+        // Cluster 7 is taken, so the contiguous run becomes a FAT chain
+        first.write(&[0xA3; 600]).unwrap();
+        assert!(!first.is_contiguous());
+        assert_eq!(volume.fat_entry(6), Ok(FatEntry::Next(8)));
+        assert_eq!(volume.fat_entry(8), Ok(FatEntry::Next(9)));
+        assert_eq!(volume.fat_entry(9), Ok(FatEntry::EndOfChain));
+        
+        first.seek(0).unwrap();
+        let data = first.read_to_end().unwrap();
+        assert_eq!(data.len(), 1112);
+        assert!(data[..512].iter().all(|&byte| byte == 0xA1));
+        assert!(data[512..].iter().all(|&byte| byte == 0xA3));
+        
+        // Writing past the valid data length zeroes the gap
+        let mut third = dir.create_entry_contiguous("third", 0).unwrap();
+        third.preallocate_contiguous(1024).unwrap();
+        volume.write_cluster(third.first_cluster, &[0xEE; 512]).unwrap();
+        third.seek(600).unwrap();
+        third.write(b"xy").unwrap();
+        assert_eq!(third.valid_data_length, 602);
+        
+        third.seek(0).unwrap();
+        let data = third.read_to_end().unwrap();
+        assert!(data[..600].iter().all(|&byte| byte == 0));
+        assert_eq!(&data[600..602], b"xy");
+    }
+    
+    #[test]
+    fn test_access_and_modify_times() {
+        fn clock() -> DateTime {
+            DateTime {
+                year: 2025,
+                month: 1,
+                day: 2,
+                hour: 3,
+                minute: 4,
+                second: 5,
+                hundredths: 60,
+                utc_offset: None,
+            }
+        }
+        let now = Timestamps::at(&clock()).unwrap();
+        
+        let mut volume = test_volume();
+        volume.set_time_source(clock);
+        let upcase = UpCaseTable::generate();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut hello = dir.find("hello.txt", &upcase).unwrap().unwrap();
+        let before = hello.timestamps;
+        
+        // Reads leave the access time alone by default
+        let mut buffer = [0u8; 5];
+        hello.read(&mut buffer).unwrap();
+        assert_eq!(hello.timestamps, before);
+        assert!(!hello.is_dirty());
+        
+        // Whole-file reads follow the same policy
+        let mut whole = dir.find("hello.txt", &upcase).unwrap().unwrap();
+        whole.read_to_end().unwrap();
+        assert!(!whole.is_dirty());
+        whole.update_atime = true;
+        assert!(whole.read_to_end().unwrap().is_empty());
+        assert!(!whole.is_dirty());
+        whole.seek(0).unwrap();
+        assert_eq!(whole.read_to_end().unwrap().len(), 5);
+        assert_eq!(whole.timestamps.accessed, now.accessed);
+        assert!(whole.is_dirty());
+        
+        hello.update_atime = true;
+        hello.seek(0).unwrap();
+        hello.read(&mut buffer).unwrap();
+        assert_eq!((hello.timestamps.accessed, hello.timestamps.modified), (now.accessed, before.modified));
+        assert!(hello.is_dirty());
+        hello.flush().unwrap();
+        assert_eq!(lookup(&volume, "/hello.txt").unwrap().file.timestamps().accessed, now.accessed);
+        
+        // Writes always update the modified time
+        hello.write(b"!").unwrap();
+        assert_eq!(hello.timestamps.modified, now.modified);
+        assert_eq!(hello.timestamps.modified_10ms, 160);
+        assert_eq!(hello.timestamps.create, before.create);
+        hello.flush().unwrap();
+        assert_eq!(lookup(&volume, "/hello.txt").unwrap().file.timestamps().modified, now.modified);
+    }
+    
+    #[test]
+    fn test_create_entry_contiguous_without_run() {
+        // Leave only clusters 6, 8 and 10 free
+        let allocated: Vec<u32> = (6..66).filter(|cluster| ![6, 8, 10].contains(cluster)).collect();
+        let volume = test_volume_with(test_device(&allocated));
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let err = dir.create_entry_contiguous("swap.bin", 2 * 512).err();
+        assert_eq!(err, Some(ExFatError::OutOfResources));
+        assert!(lookup(&volume, "/swap.bin").is_none());
+        assert!(!volume.load_bitmap().unwrap().is_allocated(6));
+    }
+    
+    #[test]
+    fn test_create_entry_releases_run_on_error() {
+        // Zeroing the new directory's cluster fails
+        let device = test_device(&[]);
+        test_volume_with(device.clone());
+        let volume = ExFatVolume::new(test_boot_sector(), FailingWrites { device, lba: 36 }).unwrap();
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        assert_eq!(dir.create_directory("sub").err(), Some(ExFatError::Io(Status::DEVICE_ERROR)));
+        assert!(lookup(&volume, "/sub").is_none());
+        assert!(!volume.allocation_bitmap(BitmapId::First).unwrap().is_allocated(6));
+        assert!(!volume.load_bitmap().unwrap().is_allocated(6));
+        
+        // The next allocation gets the same run
+        let file = dir.create_entry_contiguous("data.bin", 512).unwrap();
+        assert_eq!(file.first_cluster, 6);
+    }
+    
+    #[test]
+    fn test_cluster_read_iter() {
+        let mut device = test_device(&[6, 7, 9]);
+        set_fat(&mut device, 6, 7);
+        set_fat(&mut device, 7, 9);
+        set_fat(&mut device, 9, 0xFFFF_FFFF);
+        for (lba, fill) in [(36, 0xA1), (37, 0xA2), (39, 0xA3)] {
+            device.write_blocks(lba, &[fill; 512]).unwrap();
+        }
+        let volume = test_volume_with(device);
+        
+        let file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 6, 1280, &volume);
+        let chunks: Vec<Vec<u8>> = file.clusters().collect::<Result<_>>().unwrap();
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), vec![512, 512, 256]);
+        assert!(chunks[0].iter().all(|&b| b == 0xA1));
+        assert!(chunks[1].iter().all(|&b| b == 0xA2));
+        assert!(chunks[2].iter().all(|&b| b == 0xA3));
+        
+        // A contiguous file reads consecutive clusters regardless of the FAT
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 6, 1100, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        let chunks: Vec<Vec<u8>> = file.clusters().collect::<Result<_>>().unwrap();
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), vec![512, 512, 76]);
+        assert!(chunks[2].iter().all(|&b| b == 0x00));
+    }
+    
+    #[test]
+    fn test_fragmentation() {
+        let mut device = test_device(&[10, 11, 12, 20, 21, 30, 40, 41, 42]);
+        for (cluster, next) in [(10, 11), (11, 20), (20, 21), (21, 12), (12, 30), (40, 41), (41, 42)] {
+            set_fat(&mut device, cluster, next);
+        }
+        set_fat(&mut device, 30, 0xFFFF_FFFF);
+        set_fat(&mut device, 42, 0xFFFF_FFFF);
+        let volume = test_volume_with(device);
+        
+        // 10-11, 20-21, 12, 30
+        let report = volume.fragmentation(10, false, 0).unwrap();
+        assert_eq!(report, FragReport { clusters: 6, extents: 4 });
+        assert!(report.is_fragmented());
+        
+        // A NoFatChain stream ignores the FAT
+        let report = volume.fragmentation(10, true, 6 * 512).unwrap();
+        assert_eq!(report, FragReport { clusters: 6, extents: 1 });
+        assert!(!report.is_fragmented());
+        
+        // A chain of consecutive clusters is one extent as well
+        assert_eq!(volume.fragmentation(40, false, 0), Ok(FragReport { clusters: 3, extents: 1 }));
+        assert_eq!(volume.fragmentation(0, false, 0), Ok(FragReport::default()));
+    }
+    
+    #[test]
+    fn test_fragmentation_report() {
+        let mut device = test_device(&[10, 11, 12, 20, 21, 30, 31]);
+        for (cluster, next) in [(10, 20), (20, 11), (11, 21), (21, 12), (30, 31)] {
+            set_fat(&mut device, cluster, next);
+        }
+        set_fat(&mut device, 12, 0xFFFF_FFFF);
+        set_fat(&mut device, 31, 0xFFFF_FFFF);
+        let volume = test_volume_with(device);
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(7, &entry_set("tidy.bin", file_attributes::ARCHIVE, 30, 2 * 512));
+        root.write_entries(10, &entry_set("frag.bin", file_attributes::ARCHIVE, 10, 5 * 512));
+        volume.store_directory(&root).unwrap();
+        
+        let report = volume.fragmentation_report().unwrap();
+        let paths: Vec<&str> = report.files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/frag.bin", "/docs", "/hello.txt", "/tidy.bin"]);
+        
+        // Every link of the five cluster chain is a break
+        let frag = report.files[0].1;
+        assert_eq!((frag.clusters, frag.extents, frag.fragments()), (5, 5, 4));
+        assert_eq!(frag.percent_fragmented(), 100);
+        assert_eq!(report.files[1].1.percent_fragmented(), 0);
+        
+        assert_eq!(report.files[3].1, FragReport { clusters: 2, extents: 1 });
+        
+        // Four of the five links on the volume are breaks
+        assert_eq!((report.clusters, report.fragments), (9, 4));
+        assert_eq!(report.percent_fragmented(), 80);
+    }
+    
+    #[test]
+    fn test_find_file_owning_cluster() {
+        let mut device = test_device(&[6, 10, 11, 12, 20]);
+        set_fat(&mut device, 6, 0xFFFF_FFFF);
+        set_fat(&mut device, 10, 12);
+        set_fat(&mut device, 12, 0xFFFF_FFFF);
+        let volume = test_volume_with(device);
+        
+        let docs = DirStream { first_cluster: 3, contiguous: false, data_length: 512 };
+        let mut dir = volume.load_directory(docs).unwrap();
+        dir.write_entries(0, &entry_set("sub", file_attributes::DIRECTORY, 6, 512));
+        volume.store_directory(&dir).unwrap();
+        
+        let sub = DirStream { first_cluster: 6, contiguous: false, data_length: 512 };
+        let mut dir = volume.load_directory(sub).unwrap();
+        dir.write_entries(0, &entry_set("chain.bin", file_attributes::ARCHIVE, 10, 1024));
+        let run = EntrySetBuilder::new(file_attributes::ARCHIVE)
+            .name("run.bin")
+            .first_cluster(20)
+            .size(512)
+            .contiguous(true)
+            .build(None)
+            .unwrap();
+        dir.write_entries(3, &run);
+        volume.store_directory(&dir).unwrap();
+        
+        let owner = |cluster| volume.find_file_owning_cluster(cluster).unwrap();
+        assert_eq!(owner(2).as_deref(), Some("/"));
+        assert_eq!(owner(4).as_deref(), Some("/hello.txt"));
+        assert_eq!(owner(6).as_deref(), Some("/docs/sub"));
+        assert_eq!(owner(12).as_deref(), Some("/docs/sub/chain.bin"));
+        assert_eq!(owner(20).as_deref(), Some("/docs/sub/run.bin"));
+        
+        // Skipped by the chain, the bitmap, and free space
+        assert_eq!(owner(11), None);
+        assert_eq!(owner(5), None);
+        assert_eq!(owner(40), None);
+        assert_eq!(volume.find_file_owning_cluster(1), Err(ExFatError::ClusterOutOfRange));
+        assert_eq!(volume.find_file_owning_cluster(66), Err(ExFatError::ClusterOutOfRange));
+    }
+    
+    #[test]
+    fn test_repair() {
+        // Cluster 10 is allocated but unused, and 30 and 31 form a loop
+        let mut device = test_device(&[10, 30, 31]);
+        set_fat(&mut device, 20, 21);
+        set_fat(&mut device, 21, 0xFFFF_FFFF);
+        set_fat(&mut device, 30, 31);
+        set_fat(&mut device, 31, 30);
+        for (cluster, value) in [0, 1].into_iter().zip(FatEntry::RESERVED_VALUES) {
+            set_fat(&mut device, cluster, value);
+        }
+        let mut volume = test_volume_with(device);
+        
+        // A chain whose clusters the bitmap marks free
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(7, &entry_set("data.bin", file_attributes::ARCHIVE, 20, 1024));
+        root.write_entries(10, &entry_set("loop.bin", file_attributes::ARCHIVE, 30, 1024));
+        volume.store_directory(&root).unwrap();
+        volume.mark_dirty().unwrap();
+        
+        let report = volume.check().unwrap();
+        assert_eq!(report.cycles, [(String::from("/loop.bin"), 31)]);
+        assert_eq!(report.unallocated, [20, 21]);
+        assert_eq!(report.leaked, [10]);
+        assert!(report.broken_chains.is_empty() && report.cross_linked.is_empty());
+        
+        let result = volume.repair(&report).unwrap();
+        assert_eq!(result.allocated, [20, 21]);
+        assert_eq!(result.freed, [10]);
+        assert_eq!(result.truncated, [(String::from("/loop.bin"), 31)]);
+        assert!(result.marked_clean && !volume.is_dirty());
+        assert_eq!(volume.fat_entry(31), Ok(FatEntry::EndOfChain));
+        assert!(volume.check().unwrap().is_clean());
+        
+        // The corrected bitmap is on disk
+        let mut bitmap = [0u8; 512];
+        volume.dump_region(35, 1, &mut bitmap).unwrap();
+        let allocated = |cluster: u32| bitmap[(cluster - 2) as usize / 8] & (1 << ((cluster - 2) % 8)) != 0;
+        assert!(allocated(20) && allocated(21) && !allocated(10));
+        
+        // A report that no longer matches the volume is refused
+        assert_eq!(volume.repair(&report), Err(ExFatError::InvalidParameter));
+    }
+    
+    #[test]
+    fn test_repair_leaves_broken_chains() {
+        let mut device = test_device(&[10, 20]);
+        set_fat(&mut device, 20, 0xFFFF_FFFF);
+        let mut volume = test_volume_with(device);
+        
+        // Cluster 30 is free in the FAT, so the chain can't be traced
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(7, &entry_set("broken.bin", file_attributes::ARCHIVE, 30, 512));
+        root.write_entries(10, &entry_set("a.bin", file_attributes::ARCHIVE, 20, 512));
+        root.write_entries(13, &entry_set("b.bin", file_attributes::ARCHIVE, 20, 512));
+        volume.store_directory(&root).unwrap();
+        
+        // A first cluster outside the heap, which can't be opened at all
+        let (docs, _) = volume.resolve_directory("/docs").unwrap();
+        let mut dir = volume.load_directory(docs).unwrap();
+        dir.write_entries(0, &entry_set("bad.bin", file_attributes::ARCHIVE, 0xDEAD_BEEF, 512));
+        volume.store_directory(&dir).unwrap();
+        volume.mark_dirty().unwrap();
+        
+        let report = volume.check().unwrap();
+        assert_eq!(report.broken_chains, [
+            (String::from("/broken.bin"), ExFatError::Corrupted),
+            (String::from("/docs/bad.bin"), ExFatError::ClusterOutOfRange),
+        ]);
+        assert_eq!(report.cross_linked, [20]);
+        // The test image leaves FAT entries 0 and 1 zeroed
+        assert!(report.bad_reserved_entries);
+        // Cluster 10 may belong to the broken chain
+        assert!(report.leaked.is_empty());
+        
+        let result = volume.repair(&report).unwrap();
+        assert_eq!(result, RepairResult::default());
+        assert!(volume.is_dirty());
+        assert!(volume.allocation_bitmap(BitmapId::First).unwrap().is_allocated(10));
+    }
+    
+    #[test]
+    fn test_set_size() {
+        let volume = test_volume_with(test_device(&[]));
+        let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let mut file = root.create_file("size.bin").unwrap();
+        file.write(&[0xA5; 100]).unwrap();
+        assert_eq!(volume.free_clusters(), Ok(59));
+        
+        // Growing allocates without writing, so the tail reads as zeros
+        file.set_size(3 * 512 + 10).unwrap();
+        assert_eq!((file.size, file.valid_data_length), (1546, 100));
+        assert_eq!(volume.free_clusters(), Ok(56));
+        file.flush().unwrap();
+        let set = lookup(&volume, "/size.bin").unwrap();
+        assert_eq!(({ set.stream.data_length }, { set.stream.valid_data_length }), (1546, 100));
+        
+        file.seek(0).unwrap();
+        let data = file.read_to_end().unwrap();
+        assert!(data[..100].iter().all(|&b| b == 0xA5));
+        assert!(data[100..].iter().all(|&b| b == 0));
+        
+        // Shrinking frees the tail and clamps the valid data length,
+        // updating the entry straight away
+        file.set_size(600).unwrap();
+        assert_eq!(volume.free_clusters(), Ok(58));
+        file.set_size(50).unwrap();
+        assert_eq!((file.size, file.valid_data_length), (50, 50));
+        assert_eq!(volume.free_clusters(), Ok(59));
+        let set = lookup(&volume, "/size.bin").unwrap();
+        assert_eq!(({ set.stream.data_length }, { set.stream.valid_data_length }), (50, 50));
+        
+        file.set_size(0).unwrap();
+        assert_eq!(file.first_cluster, 0);
+        assert_eq!(volume.free_clusters(), Ok(60));
+        assert_eq!({ lookup(&volume, "/size.bin").unwrap().stream.first_cluster }, 0);
+        
+        // A shrink below the position moves it back to the end
+        file.write(&[0x5A; 1000]).unwrap();
+        file.set_size(10).unwrap();
+        assert_eq!(file.position, 10);
+        assert_eq!(file.read_to_end(), Ok(Vec::new()));
+        file.seek(0).unwrap();
+        assert_eq!(file.read_to_end(), Ok(vec![0x5A; 10]));
+    }
+    
+    #[test]
+    fn test_set_size_chain() {
+        let mut device = test_device(&[10, 11, 12]);
+        set_fat(&mut device, 10, 12);
+        set_fat(&mut device, 12, 11);
+        set_fat(&mut device, 11, 0xFFFF_FFFF);
+        let volume = test_volume_with(device);
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 1300, &volume);
+        file.set_size(512).unwrap();
+        assert_eq!(volume.fat_entry(10), Ok(FatEntry::EndOfChain));
+        assert_eq!(volume.fat_entry(12), Ok(FatEntry::Free));
+        assert_eq!(volume.fat_entry(11), Ok(FatEntry::Free));
+        assert_eq!(volume.free_clusters(), Ok(59));
+        // The cut is on disk, not just in the FAT cache
+        assert_eq!([10, 12, 11].map(|cluster| disk_fat(&volume, cluster)), [0xFFFF_FFFF, 0, 0]);
+        
+        // Growing again links new clusters onto the chain
+        file.set_size(1024).unwrap();
+        assert_eq!(volume.cluster_chain(10, false, 0).unwrap().len(), 2);
+        assert_eq!(file.valid_data_length, 512);
+    }
+    
+    #[test]
+    fn test_partial_cluster_write() {
+        let mut device = test_device(&[10]);
+        set_fat(&mut device, 10, 0xFFFF_FFFF);
+        let mut data = [0u8; 512];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        device.write_blocks(40, &data).unwrap();
+        let volume = test_volume_with(device.clone());
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 512, &volume);
+        file.seek(100).unwrap();
+        assert_eq!(file.write(b"exFA"), Ok(4));
+        assert_eq!((file.position, file.size), (104, 512));
+        
+        // Only the four written bytes change on disk
+        let mut sector = [0u8; 512];
+        device.read_blocks(40, &mut sector).unwrap();
+        assert_eq!(&sector[100..104], b"exFA");
+        assert_eq!(sector[..100], data[..100]);
+        assert_eq!(sector[104..], data[104..]);
+    }
+    
+    #[test]
+    fn test_read_at() {
+        let mut device = test_device(&[10, 11, 12]);
+        set_fat(&mut device, 10, 12);
+        set_fat(&mut device, 12, 11);
+        set_fat(&mut device, 11, 0xFFFF_FFFF);
+        for (lba, fill) in [(40, 0xB1), (42, 0xB2), (41, 0xB3)] {
+            device.write_blocks(lba, &[fill; 512]).unwrap();
+        }
+        let volume = test_volume_with(device);
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 1300, &volume);
+        file.valid_data_length = 1100;
+        file.seek(5).unwrap();
+        
+        // Mid-file, across the jump from cluster 12 back to 11
+        let mut buffer = [0u8; 200];
+        assert_eq!(file.read_at(1000, &mut buffer), Ok(200));
+        assert!(buffer[..24].iter().all(|&b| b == 0xB2));
+        assert!(buffer[24..100].iter().all(|&b| b == 0xB3));
+        assert!(buffer[100..].iter().all(|&b| b == 0));
+        assert_eq!(file.position, 5);
+        
+        assert_eq!(file.read_at(1250, &mut buffer), Ok(50));
+        assert_eq!(file.read_at(1300, &mut buffer), Ok(0));
+        assert_eq!(file.position, 5);
+        
+        // The position-based read is unaffected
+        assert_eq!(file.read(&mut buffer[..10]), Ok(10));
+        assert!(buffer[..10].iter().all(|&b| b == 0xB1));
+        assert_eq!(file.position, 15);
+    }
+    
+    #[test]
+    fn test_validate_first_cluster() {
+        let volume = test_volume();
+        assert_eq!(volume.validate_cluster(2), Ok(()));
+        assert_eq!(volume.validate_cluster(65), Ok(()));
+        for cluster in [0, 1, 66, 0xDEAD_BEEF] {
+            assert_eq!(volume.validate_cluster(cluster), Err(ExFatError::ClusterOutOfRange));
+        }
+        
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(7, &entry_set("wild", file_attributes::DIRECTORY, 0xDEAD_BEEF, 512));
+        root.write_entries(10, &entry_set("hollow.bin", file_attributes::ARCHIVE, 0, 100));
+        volume.store_directory(&root).unwrap();
+        
+        // Opening either entry fails before any cluster is read
+        let upcase = UpCaseTable::generate();
+        let mut dir = volume.open_dir("/").unwrap();
+        assert_eq!(dir.find("wild", &upcase).err(), Some(ExFatError::ClusterOutOfRange));
+        assert_eq!(dir.find("hollow.bin", &upcase).err(), Some(ExFatError::Corrupted));
+        assert_eq!(volume.open_dir("/wild").err(), Some(ExFatError::ClusterOutOfRange));
+        assert_eq!(volume.open_dir("/wild/x").err(), Some(ExFatError::ClusterOutOfRange));
+        assert!(dir.find("hello.txt", &upcase).unwrap().is_some());
+        
+        // An empty file needs no clusters
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(10, &entry_set("empty.bin", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&root).unwrap();
+        assert!(volume.open_dir("/").unwrap().find("empty.bin", &upcase).unwrap().is_some());
+    }
+    
+    #[test]
+    fn test_contiguous_past_heap() {
+        let volume = test_volume();
+        
+        // Clusters 60 through 66, but the heap ends at 65
+        let mut file = ExFatFile::new(String::from("big"), file_attributes::ARCHIVE, 60, 7 * 512, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        let mut buffer = [0u8; 512];
+        assert_eq!(file.read(&mut buffer), Err(ExFatError::ClusterOutOfRange));
+        assert_eq!(file.read_at(0, &mut buffer), Err(ExFatError::ClusterOutOfRange));
+        assert_eq!(volume.cluster_chain(60, true, 7 * 512), Err(ExFatError::ClusterOutOfRange));
+        
+        assert_eq!(volume.check_contiguous(60, 0), Ok(()));
+        assert_eq!(volume.check_contiguous(60, 6 * 512), Ok(()));
+        assert_eq!(volume.check_contiguous(u32::MAX, 512), Err(ExFatError::ClusterOutOfRange));
+        assert_eq!(volume.check_contiguous(10, u64::MAX), Err(ExFatError::ClusterOutOfRange));
+    }
+    
+    #[test]
+    fn test_read_past_valid_data_length() {
+        let mut device = test_device(&[10, 11, 12, 13]);
+        for lba in 40..44 {
+            device.write_blocks(lba, &[0xC1; 512]).unwrap();
+        }
+        let reads: alloc::rc::Rc<RefCell<Vec<(u64, usize)>>> = Default::default();
+        let log = IoLog { device, reads: reads.clone(), writes: Default::default() };
+        let volume = ExFatVolume::new(test_boot_sector(), log).unwrap();
+        
+        // Four contiguous clusters, only the first two of them written
+        let mut file = ExFatFile::new(String::from("half"), file_attributes::ARCHIVE, 10, 2048, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        file.valid_data_length = 1024;
+        
+        // The gap reads as zeros without touching the device
+        let mut buffer = [0xFFu8; 1024];
+        file.seek(1024).unwrap();
+        assert_eq!(file.read(&mut buffer), Ok(1024));
+        assert!(buffer.iter().all(|&b| b == 0));
+        assert_eq!(file.position, 2048);
+        assert!(reads.borrow().is_empty());
+        
+        // A read spanning the boundary fetches only the valid part
+        file.seek(768).unwrap();
+        assert_eq!(file.read(&mut buffer[..512]), Ok(512));
+        assert!(buffer[..256].iter().all(|&b| b == 0xC1));
+        assert!(buffer[256..512].iter().all(|&b| b == 0));
+        assert_eq!(*reads.borrow(), [(41, 512)]);
+    }
+    
+    #[test]
+    fn test_read_to_end() {
+        let mut device = test_device(&[10, 11, 12]);
+        set_fat(&mut device, 10, 12);
+        set_fat(&mut device, 12, 11);
+        set_fat(&mut device, 11, 0xFFFF_FFFF);
+        for (lba, fill) in [(40, 0xB1), (42, 0xB2), (41, 0xB3)] {
+            device.write_blocks(lba, &[fill; 512]).unwrap();
+        }
+        let volume = test_volume_with(device);
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 1300, &volume);
+        file.valid_data_length = 1100;
+        
+        let data = file.read_to_end().unwrap();
+        assert_eq!(data.len(), 1300);
+        assert!(data[..512].iter().all(|&b| b == 0xB1));
+        assert!(data[512..1024].iter().all(|&b| b == 0xB2));
+        assert!(data[1024..1100].iter().all(|&b| b == 0xB3));
+        // Past the valid data length the on-disk bytes are ignored
+        assert!(data[1100..].iter().all(|&b| b == 0));
+        assert_eq!(file.position, 1300);
+        assert_eq!(file.read_to_end(), Ok(Vec::new()));
+        
+        // From mid-file, only the rest is returned
+        file.seek(700).unwrap();
+        let rest = file.read_to_end().unwrap();
+        assert_eq!(rest, data[700..]);
+        file.seek(1200).unwrap();
+        assert_eq!(file.read_to_end().unwrap(), [0; 100]);
+        
+        // A position past the end reads nothing
+        file.position = 2000;
+        assert_eq!(file.read_to_end(), Ok(Vec::new()));
+        assert_eq!(file.position, 1300);
+        
+        // Size larger than the three-cluster chain
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 4 * 512, &volume);
+        assert_eq!(file.read_to_end(), Err(ExFatError::Corrupted));
+        
+        // Contiguous run extending past the cluster heap
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 60, 8 * 512, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        assert_eq!(file.read_to_end(), Err(ExFatError::ClusterOutOfRange));
+        
+        let mut empty = ExFatFile::new(String::from("empty"), file_attributes::ARCHIVE, 0, 0, &volume);
+        assert_eq!(empty.read_to_end(), Ok(Vec::new()));
+    }
+    
+    #[test]
+    fn test_crc32() {
+        let mut device = test_device(&[10, 11, 12]);
+        set_fat(&mut device, 10, 12);
+        set_fat(&mut device, 12, 11);
+        set_fat(&mut device, 11, 0xFFFF_FFFF);
+        for (lba, fill) in [(40, 0xB1), (42, 0xB2), (41, 0xB3)] {
+            device.write_blocks(lba, &[fill; 512]).unwrap();
+        }
+        let volume = test_volume_with(device);
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 1300, &volume);
+        file.valid_data_length = 1100;
+        file.seek(700).unwrap();
+        
+        let mut chunks = Vec::new();
+        file.hash_with(|chunk| chunks.push(chunk.len())).unwrap();
+        assert_eq!(chunks, [512, 512, 76, 200]);
+        
+        // The whole file, zeros past the valid data length included
+        let crc = file.crc32().unwrap();
+        assert_eq!(file.position, 700);
+        file.seek(0).unwrap();
+        assert_eq!(crc, crc32(&file.read_to_end().unwrap()));
+        
+        let empty = ExFatFile::new(String::from("empty"), file_attributes::ARCHIVE, 0, 0, &volume);
+        assert_eq!(empty.crc32(), Ok(0));
+        
+        // No clusters, so the whole size reads as zeros
+        let unallocated = ExFatFile::new(String::from("zeros"), file_attributes::ARCHIVE, 0, 100, &volume);
+        let mut data = [0xFF; 100];
+        assert_eq!(unallocated.read_at(0, &mut data), Ok(100));
+        assert_eq!(unallocated.crc32(), Ok(crc32(&data)));
+        assert_eq!(data, [0; 100]);
+        
+        // Size larger than the three-cluster chain
+        let file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 4 * 512, &volume);
+        assert_eq!(file.crc32(), Err(ExFatError::Corrupted));
+    }
+    
+    #[test]
+    fn test_extents() {
+        let mut device = test_device(&[6, 7, 9, 10]);
+        set_fat(&mut device, 6, 7);
+        set_fat(&mut device, 7, 9);
+        set_fat(&mut device, 9, 10);
+        set_fat(&mut device, 10, 0xFFFF_FFFF);
+        let volume = test_volume_with(device);
+        
+        let file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 6, 4 * 512, &volume);
+        assert_eq!(file.extents(0, 4 * 512), Ok(vec![(36, 1024), (39, 1024)]));
+        assert_eq!(file.extents(512, 700), Ok(vec![(37, 512), (39, 188)]));
+        assert_eq!(file.extents(1024, 100), Ok(vec![(39, 100)]));
+        assert_eq!(file.extents(1024, 0), Ok(Vec::new()));
+        
+        assert_eq!(file.extents(100, 10), Err(ExFatError::InvalidParameter));
+        assert_eq!(file.extents(1536, 513), Err(ExFatError::InvalidParameter));
+        assert_eq!(file.extents(u64::MAX - 511, 1024), Err(ExFatError::InvalidParameter));
+        
+        // A contiguous file is a single span
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 6, 3000, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        assert_eq!(file.extents(512, 2000), Ok(vec![(37, 2000)]));
+        
+        // Size larger than the chain
+        let file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 6, 5 * 512, &volume);
+        assert_eq!(file.extents(0, 5 * 512), Err(ExFatError::Corrupted));
+    }
+    
+    #[test]
+    fn test_exfat_read() {
+        fn read_header(mut source: impl ExfatRead) -> Result<[u8; 4]> {
+            let mut header = [0u8; 4];
+            source.read(&mut header)?;
+            Ok(header)
+        }
+        
+        let mut device = test_device(&[6]);
+        set_fat(&mut device, 6, 0xFFFF_FFFF);
+        device.write_blocks(36, &[0xC1; 512]).unwrap();
+        let volume = test_volume_with(device);
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 6, 300, &volume);
+        assert_eq!(read_header(&mut file), Ok([0xC1; 4]));
+        assert_eq!(read_header(&mut file), Ok([0xC1; 4]));
+        assert_eq!(file.position, 8);
+    }
+    
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_std_read_and_seek() {
+        use std::io::{Read, Seek, SeekFrom};
+        
+        let mut device = test_device(&[10, 11, 12]);
+        set_fat(&mut device, 10, 12);
+        set_fat(&mut device, 12, 11);
+        set_fat(&mut device, 11, 0xFFFF_FFFF);
+        for (lba, fill) in [(40, 0xB1), (42, 0xB2), (41, 0xB3)] {
+            device.write_blocks(lba, &[fill; 512]).unwrap();
+        }
+        let volume = test_volume_with(device);
+        
+        let mut file = ExFatFile::new(String::from("data"), file_attributes::ARCHIVE, 10, 1300, &volume);
+        let mut data = Vec::new();
+        assert_eq!(Read::read_to_end(&mut file, &mut data).unwrap(), 1300);
+        assert!(data[..512].iter().all(|&b| b == 0xB1));
+        assert!(data[512..1024].iter().all(|&b| b == 0xB2));
+        assert!(data[1024..].iter().all(|&b| b == 0xB3));
+        
+        assert_eq!(Seek::seek(&mut file, SeekFrom::End(-300)).unwrap(), 1000);
+        assert_eq!(Seek::seek(&mut file, SeekFrom::Current(20)).unwrap(), 1020);
+        let mut tail = Vec::new();
+        assert_eq!(Read::read_to_end(&mut file, &mut tail).unwrap(), 280);
+        assert_eq!(&tail[..4], &[0xB2; 4]);
+        assert!(tail[4..].iter().all(|&b| b == 0xB3));
+        
+        let error = Seek::seek(&mut file, SeekFrom::Current(-2000)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(Seek::seek(&mut file, SeekFrom::Start(1301)).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(file.position, 1300);
+    }
+    
+    #[test]
+    fn test_read_past_4gib() {
+        const GIB: u64 = 1 << 30;
+        
+        // 1 MiB clusters and an 8 GiB cluster heap
+        let mut boot_sector = test_boot_sector();
+        boot_sector.sectors_per_cluster_shift = 11;
+        boot_sector.cluster_count = 8192;
+        boot_sector.volume_length = 32 + (8192 << 11);
+        
+        // Byte 5 GiB of a file starting at cluster 2 is the start of cluster 5122
+        let lba = 32 + 5 * GIB / 512;
+        let mut device = SparseDevice::default();
+        device.write_blocks(lba - 1, &[0x11; 512]).unwrap();
+        device.write_blocks(lba, &[0x55; 512]).unwrap();
+        let volume = ExFatVolume::new(boot_sector, device).unwrap();
+        assert_eq!(volume.offset_to_cluster(2, true, 5 * GIB), Ok((5122, 0)));
+        assert_eq!(volume.cluster_to_lba(5122), Ok(lba));
+        
+        let mut file = ExFatFile::new(String::from("big"), file_attributes::ARCHIVE, 2, 6 * GIB, &volume);
+        file.flags |= stream_flags::NO_FAT_CHAIN;
+        file.seek(5 * GIB).unwrap();
+        let mut cluster = vec![0u8; 1 << 20];
+        assert_eq!(file.read(&mut cluster), Ok(1 << 20));
+        assert!(cluster[..512].iter().all(|&b| b == 0x55));
+        assert!(cluster[512..].iter().all(|&b| b == 0));
+        assert_eq!(file.position, 5 * GIB + (1 << 20));
+        
+        // Across the boundary into that cluster
+        file.seek(5 * GIB - 4).unwrap();
+        let mut bytes = [0u8; 8];
+        assert_eq!(file.read(&mut bytes), Ok(8));
+        assert_eq!(bytes, [0x11, 0x11, 0x11, 0x11, 0x55, 0x55, 0x55, 0x55]);
+        
+        assert_eq!(file.extents(5 * GIB, 4096), Ok(vec![(lba, 4096)]));
+        assert_eq!(file.seek(6 * GIB + 1), Err(ExFatError::InvalidParameter));
+        
+        // The end of the file reads short, not past the size
+        file.seek(6 * GIB - 10).unwrap();
+        assert_eq!(file.read(&mut bytes), Ok(8));
+        assert_eq!(file.read(&mut bytes), Ok(2));
+        assert_eq!(file.read(&mut bytes), Ok(0));
+    }
+    
+    #[test]
+    fn test_offset_to_cluster() {
+        let mut device = MemBlockDevice::new(96 * 512, 512);
+        set_fat(&mut device, 10, 12);
+        set_fat(&mut device, 12, 11);
+        set_fat(&mut device, 11, 0xFFFF_FFFF);
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        
+        assert_eq!(volume.offset_to_cluster(10, false, 0), Ok((10, 0)));
+        assert_eq!(volume.offset_to_cluster(10, false, 700), Ok((12, 188)));
+        assert_eq!(volume.offset_to_cluster(10, false, 3 * 512 - 1), Ok((11, 511)));
+        assert_eq!(volume.offset_to_cluster(10, false, 3 * 512), Err(ExFatError::InvalidParameter));
+        
+        // Contiguous streams ignore the FAT
+        assert_eq!(volume.offset_to_cluster(10, true, 700), Ok((11, 188)));
+        assert_eq!(volume.offset_to_cluster(10, true, 10 * 512 + 5), Ok((20, 5)));
+        assert_eq!(volume.offset_to_cluster(60, true, 6 * 512), Err(ExFatError::ClusterOutOfRange));
+    }
+    
+    #[test]
+    fn test_free_space() {
+        let volume = test_volume_with(test_device(&[10, 11, 12]));
+        assert_eq!(volume.free_clusters(), Ok(57));
+        assert_eq!(volume.free_bytes(), Ok(57 * 512));
+        
+        let mut file = ExFatFile::new(String::from("big"), file_attributes::ARCHIVE, 0, 0, &volume);
+        file.preallocate_contiguous(2 * 512).unwrap();
+        assert_eq!(volume.free_clusters(), Ok(55));
+    }
+    
+    #[test]
+    fn test_can_allocate() {
+        let volume = test_volume_with(test_device(&[10, 11, 12]));
+        assert_eq!(volume.can_allocate(0), Ok(true));
+        assert_eq!(volume.can_allocate(57 * 512), Ok(true));
+        assert_eq!(volume.can_allocate(57 * 512 + 1), Ok(false));
+        
+        let mut file = ExFatFile::new(String::from("big"), file_attributes::ARCHIVE, 0, 0, &volume);
+        file.write(&[1; 512]).unwrap();
+        assert_eq!(volume.free_clusters(), Ok(56));
+        
+        // One cluster over fails before anything is allocated
+        let data = vec![2u8; 56 * 512];
+        assert_eq!(file.write(&[&data[..], &[2]].concat()), Err(ExFatError::VolumeFull));
+        assert_eq!(volume.free_clusters(), Ok(56));
+        assert_eq!(file.size(), 512);
+        
+        // The rest of the volume, exactly
+        assert_eq!(file.write(&data), Ok(56 * 512));
+        assert_eq!(volume.free_clusters(), Ok(0));
+        assert_eq!(volume.can_allocate(1), Ok(false));
+    }
+    
+    #[test]
+    fn test_volume_metadata() {
+        let mut boot_sector = test_boot_sector();
+        boot_sector.volume_serial = 0x1234_5678;
+        let volume = ExFatVolume::new(boot_sector, test_device(&[10])).unwrap();
+        
+        let metadata = volume.metadata().unwrap();
+        assert_eq!(metadata.bytes_per_sector, boot_sector.bytes_per_sector());
+        assert_eq!(metadata.bytes_per_cluster, boot_sector.bytes_per_cluster());
+        assert_eq!(metadata.cluster_count, { boot_sector.cluster_count });
+        assert_eq!(metadata.root_dir_cluster, { boot_sector.root_dir_cluster });
+        assert_eq!(metadata.volume_serial, 0x1234_5678);
+        assert_eq!(metadata.label, None);
+        assert_eq!(metadata.free_clusters, 59);
+        
+        // A later snapshot sees the allocation; the earlier one is unchanged
+        let mut file = ExFatFile::new(String::from("big"), file_attributes::ARCHIVE, 0, 0, &volume);
+        file.preallocate_contiguous(3 * 512).unwrap();
+        let later = volume.metadata().unwrap();
+        assert_eq!(later.free_clusters, 56);
+        assert_eq!(metadata.free_clusters, 59);
+        assert_eq!(VolumeMetadata { free_clusters: 59, ..later }, metadata);
+    }
+    
+    #[test]
+    fn test_volume_guid_round_trip() {
+        let mut volume = test_volume();
+        assert_eq!(volume.volume_guid(), Ok(None));
+        
+        let guid = *b"0123456789ABCDEF";
+        volume.set_volume_guid(Some(guid)).unwrap();
+        assert_eq!(volume.volume_guid(), Ok(Some(guid)));
+        
+        let root = volume.load_directory(volume.root_stream()).unwrap();
+        let index = root.find_entry(EntryType::VolumeGuid).unwrap();
+        assert!(VolumeGuidEntry::from_bytes(&root.entry(index)).checksum_valid());
+        
+        // Replacing reuses the existing entry
+        let other = *b"FEDCBA9876543210";
+        volume.set_volume_guid(Some(other)).unwrap();
+        assert_eq!(volume.volume_guid(), Ok(Some(other)));
+        let root = volume.load_directory(volume.root_stream()).unwrap();
+        assert_eq!(root.find_entry(EntryType::VolumeGuid), Some(index));
+        
+        volume.set_volume_guid(None).unwrap();
+        assert_eq!(volume.volume_guid(), Ok(None));
+        
+        // A file entry set is still found after the removed GUID entry
+        assert!(lookup(&volume, "/hello.txt").is_some());
+    }
+    
+    #[test]
+    fn test_directory_entry_budget() {
+        let volume = test_volume();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        assert_eq!(dir.max_entries(), 64 * 512 / 32);
+        
+        dir.set_max_entries(4);
+        assert!(dir.read_entry().unwrap().is_some());
+        assert!(matches!(dir.read_entry(), Err(ExFatError::DirectoryTooLarge)));
+    }
+    
+    #[test]
+    fn test_full_directory() {
+        let volume = test_volume();
+        
+        // Four 4-entry sets fill all 16 slots of /docs, leaving no marker
+        let mut docs = volume.open_dir("/docs").unwrap();
+        for name in ["aaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbb", "cccccccccccccccc", "dddddddddddddddd"] {
+            docs.create_file(name).unwrap();
+        }
+        
+        let mut docs = volume.open_dir("/docs").unwrap();
+        assert_eq!(docs.max_entries(), 16);
+        assert_eq!(docs.entries().unwrap().len(), 4);
+        assert_eq!(docs.raw_entries().unwrap().len(), 16);
+        assert!(!docs.is_empty().unwrap());
+        assert!(docs.find("DDDDDDDDDDDDDDDD", &UpCaseTable::generate()).unwrap().is_some());
+        
+        let mut paths = Vec::new();
+        volume.walk(volume.root_dir_cluster(), &mut |path, _| paths.push(String::from(path))).unwrap();
+        assert!(paths.iter().any(|path| path == "/docs/dddddddddddddddd"));
+        
+        volume.delete("/docs/dddddddddddddddd").unwrap();
+        assert!(lookup(&volume, "/docs/dddddddddddddddd").is_none());
+        
+        // A lowered budget still stops short of the end
+        let mut docs = volume.open_dir("/docs").unwrap();
+        docs.set_max_entries(4);
+        assert!(docs.read_entry().unwrap().is_some());
+        assert!(matches!(docs.read_entry(), Err(ExFatError::DirectoryTooLarge)));
+    }
+    
+    #[test]
+    fn test_find_by_name_hash() {
+        let mut device = test_device(&[]);
+        let upcase = UpCaseTable::generate();
+        
+        // Name entries deliberately cut short, so assembling this set fails
+        let mut broken = entry_set("other.txt", file_attributes::ARCHIVE, 0, 0);
+        broken[2][0] = EntryType::VendorExtension as u8;
+        
+        // Same hash and length as the target, but a different name
+        let target: Vec<u16> = "Hello.TXT".encode_utf16().collect();
+        let mut collision = entry_set("xxxxx.xxx", file_attributes::ARCHIVE, 0, 0);
+        collision[1][4..6].copy_from_slice(&upcase.name_hash(&target).to_le_bytes());
+        
+        let mut root = [0u8; 512];
+        for (index, entry) in core::iter::once(bitmap_entry(5, 8))
+            .chain(broken)
+            .chain(collision)
+            .chain(entry_set("hello.txt", file_attributes::ARCHIVE, 4, 5))
+            .enumerate()
+        {
+            root[index * 32..(index + 1) * 32].copy_from_slice(&entry);
+        }
+        device.write_blocks(32, &root).unwrap();
+        
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        
+        let file = dir.find("HELLO.txt", &upcase).unwrap().unwrap();
+        assert_eq!(file.name(), "hello.txt");
+        assert_eq!(file.size(), 5);
+        assert_eq!(file.entry_position().map(|p| p.offset), Some(7 * 32));
+        
+        assert!(dir.find("missing.txt", &upcase).unwrap().is_none());
+        
+        // Without the hash filter the broken set is assembled and rejected
+        dir.reset();
+        assert!(matches!(dir.read_entry(), Err(ExFatError::Corrupted)));
+    }
+    
+    #[test]
+    fn test_lookup_by_name_hash() {
+        let volume = test_volume();
+        let upcase = UpCaseTable::generate();
+        
+        // Same hash and length as "hello.txt", but a different name
+        let target: Vec<u16> = "hello.txt".encode_utf16().collect();
+        let mut collision = entry_set("xxxxx.xxx", file_attributes::ARCHIVE, 0, 0);
+        collision[1][4..6].copy_from_slice(&upcase.name_hash(&target).to_le_bytes());
+        
+        // Right name, wrong hash: skipped without comparing the name
+        let mut stale = entry_set("stale.txt", file_attributes::ARCHIVE, 0, 0);
+        stale[1][4..6].copy_from_slice(&0u16.to_le_bytes());
+        
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(7, &collision);
+        root.write_entries(10, &stale);
+        volume.store_directory(&root).unwrap();
+        
+        let set = lookup(&volume, "/HELLO.TXT").unwrap();
+        assert_eq!(set.index, 4);
+        assert!(lookup(&volume, "/xxxxx.xxx").is_none());
+        assert!(lookup(&volume, "/stale.txt").is_none());
+        assert!(lookup(&volume, "/docs").is_some());
+    }
+    
+    #[test]
+    fn test_vendor_entries_preserved() {
+        let vendor = VendorExtensionEntry {
+            entry_type: EntryType::VendorExtension as u8,
+            flags: 0,
+            vendor_guid: *b"unknown-vendor!!",
+            vendor_defined: [0x5A; 14],
+        };
+        
+        let mut entries = entry_set("tagged.txt", file_attributes::ARCHIVE, 0, 0);
+        entries.push(vendor.to_bytes());
+        entries[0][1] += 1;
+        let checksum = entry_set_checksum(&entries);
+        entries[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        
+        let volume = test_volume();
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(7, &entries);
+        volume.store_directory(&root).unwrap();
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let file = dir.find("tagged.txt", &UpCaseTable::generate()).unwrap().unwrap();
+        assert_eq!(file.vendor_entries, [VendorEntry::Extension(vendor)]);
+        
+        // Renaming keeps the vendor entry in the rebuilt set
+        volume.rename("/tagged.txt", "renamed.txt").unwrap();
+        let set = lookup(&volume, "/renamed.txt").unwrap();
+        assert_eq!(set.vendor, [VendorEntry::Extension(vendor)]);
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let file = dir.find("renamed.txt", &UpCaseTable::generate()).unwrap().unwrap();
+        let raw = volume.read_entry_set(file.entry_position().unwrap()).unwrap();
+        assert_eq!(raw.len(), 4);
+        assert_eq!(le_u16(&raw[0], 2), entry_set_checksum(&raw));
+    }
+    
+    #[test]
+    fn test_raw_entries() {
+        let volume = test_volume();
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        let hello = root.find(&to_utf16_name("hello.txt").unwrap(), None).unwrap().unwrap();
+        root.delete_set(&hello);
+        volume.store_directory(&root).unwrap();
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let entries = dir.raw_entries().unwrap();
+        
+        // Bitmap, docs (3 entries), deleted hello.txt (3 entries), terminator
+        assert_eq!(entries.len(), 8);
+        assert_eq!(entries[0].get_type(), EntryType::AllocationBitmap);
+        assert_eq!(entries[4].entry_type, EntryType::File as u8 & 0x7F);
+        assert!(entries[4..7].iter().all(|entry| !entry.is_in_use()));
+        assert_eq!(entries[7].get_type(), EntryType::EndOfDirectory);
+        
+        // read_entry skips the deleted set
+        assert_eq!(dir.read_entry().unwrap().unwrap().name(), "docs");
+        assert!(dir.read_entry().unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_entry_sequence_validation() {
+        let mount = |set: &[[u8; 32]]| {
+            let mut device = test_device(&[]);
+            let mut root = [0u8; 512];
+            for (index, entry) in core::iter::once(bitmap_entry(5, 8))
+                .chain(set.iter().copied())
+                .chain(entry_set("hello.txt", file_attributes::ARCHIVE, 4, 5))
+                .enumerate()
+            {
+                root[index * 32..(index + 1) * 32].copy_from_slice(&entry);
+            }
+            device.write_blocks(32, &root).unwrap();
+            ExFatVolume::new(test_boot_sector(), device).unwrap()
+        };
+        let check = |set: &[[u8; 32]]| {
+            let volume = mount(set);
+            let root = volume.load_directory(volume.root_stream()).unwrap();
+            assert_eq!(root.find(&to_utf16_name("hello.txt").unwrap(), None).err(), Some(ExFatError::InvalidEntrySequence));
+            
+            let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+            assert_eq!(dir.read_entry().err(), Some(ExFatError::InvalidEntrySequence));
+            assert_eq!(dir.find("hello.txt", &UpCaseTable::generate()).err(), Some(ExFatError::InvalidEntrySequence));
+        };
+        
+        let valid = entry_set("a_name_longer_than_fifteen.txt", file_attributes::ARCHIVE, 0, 0);
+        assert_eq!(valid.len(), 4);
+        assert!(lookup(&mount(&valid), "/hello.txt").is_some());
+        
+        // Claims more entries than precede the next primary entry
+        let mut more = entry_set("a.txt", file_attributes::ARCHIVE, 0, 0);
+        more[0][1] = 3;
+        check(&more);
+        
+        // Claims fewer, leaving a name entry behind
+        let mut fewer = valid.clone();
+        fewer[0][1] = 2;
+        check(&fewer);
+        
+        // Stream extension isn't the first secondary entry
+        let mut swapped = valid.clone();
+        swapped.swap(1, 2);
+        check(&swapped);
+        
+        // Second stream extension
+        let mut doubled = valid.clone();
+        doubled[3] = doubled[1];
+        check(&doubled);
+    }
+    
+    #[test]
+    fn test_multi_cluster_directory() {
+        // Root directory spans clusters 2 and 6
+        let mut device = test_device(&[6]);
+        set_fat(&mut device, 2, 6);
+        set_fat(&mut device, 6, 0xFFFF_FFFF);
+        
+        let mut image = vec![0x05u8; 1024];
+        let mut put = |index: usize, entries: &[[u8; 32]]| {
+            for (i, entry) in entries.iter().enumerate() {
+                image[(index + i) * 32..(index + i + 1) * 32].copy_from_slice(entry);
+            }
+        };
+        put(0, &[bitmap_entry(5, 8)]);
+        put(1, &entry_set("a.txt", file_attributes::ARCHIVE, 0, 0));
+        // Straddles the cluster boundary at entry 16
+        put(14, &entry_set("straddle.txt", file_attributes::ARCHIVE, 0, 0));
+        put(17, &entry_set("b.txt", file_attributes::ARCHIVE, 0, 0));
+        // Terminator mid-cluster, followed by a stale entry set
+        put(20, &[[0u8; 32]]);
+        put(21, &entry_set("ghost.txt", file_attributes::ARCHIVE, 0, 0));
+        device.write_blocks(32, &image[..512]).unwrap();
+        device.write_blocks(36, &image[512..]).unwrap();
+        
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        
+        let names: Vec<String> = core::iter::from_fn(|| dir.read_entry().unwrap())
+            .map(|file| file.name)
+            .collect();
+        assert_eq!(names, ["a.txt", "straddle.txt", "b.txt"]);
+        assert!(dir.read_entry().unwrap().is_none());
+        
+        dir.reset();
+        let positions: Vec<(u32, u32)> = core::iter::from_fn(|| dir.read_entry().unwrap())
+            .map(|file| file.entry_position().map(|p| (p.cluster, p.offset)).unwrap())
+            .collect();
+        assert_eq!(positions, [(2, 32), (2, 448), (6, 32)]);
+    }
+    
+    #[test]
+    fn test_compact_directory() {
+        // /docs/ spans clusters 3, 10 and 11
+        let mut device = test_device(&[10, 11]);
+        set_fat(&mut device, 3, 10);
+        set_fat(&mut device, 10, 11);
+        set_fat(&mut device, 11, 0xFFFF_FFFF);
+        
+        let mut image = vec![0u8; 1536];
+        let mut deleted = entry_set("old.txt", file_attributes::ARCHIVE, 0, 0);
+        for entry in deleted.iter_mut() {
+            entry[0] &= 0x7F;
+        }
+        for (i, entry) in entry_set("a.txt", file_attributes::ARCHIVE, 0, 0).iter().chain(&deleted).enumerate() {
+            image[i * 32..i * 32 + 32].copy_from_slice(entry);
+        }
+        for (i, entry) in deleted.iter().enumerate() {
+            image[512 + i * 32..512 + i * 32 + 32].copy_from_slice(entry);
+        }
+        device.write_blocks(33, &image[..512]).unwrap();
+        device.write_blocks(40, &image[512..1024]).unwrap();
+        device.write_blocks(41, &image[1024..]).unwrap();
+        
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        root.write_entries(1, &entry_set("docs", file_attributes::DIRECTORY, 3, 1536));
+        volume.store_directory(&root).unwrap();
+        let free = volume.free_clusters().unwrap();
+        
+        // Without its entry set the directory can't be shortened
+        assert_eq!(ExFatDirectory::new(3, &volume).compact(), Err(ExFatError::NotFound));
+        
+        let docs = ExFatDirectory::new(volume.root_dir_cluster(), &volume).read_entry().unwrap().unwrap();
+        let mut dir = ExFatDirectory::from_file(&docs).unwrap();
+        dir.compact().unwrap();
+        
+        assert_eq!(volume.fat_entry(3).unwrap(), FatEntry::EndOfChain);
+        assert_eq!(volume.fat_entry(10).unwrap(), FatEntry::Free);
+        assert_eq!(volume.fat_entry(11).unwrap(), FatEntry::Free);
+        assert_eq!([3, 10, 11].map(|cluster| disk_fat(&volume, cluster)), [0xFFFF_FFFF, 0, 0]);
+        assert_eq!(volume.free_clusters().unwrap(), free + 2);
+        let set = lookup(&volume, "/docs").unwrap();
+        assert_eq!((set.stream.data_length, set.stream.valid_data_length), (512, 512));
+        
+        // The deleted set after a.txt is now the end of the directory
+        let raw = ExFatDirectory::from_file(&docs).unwrap().raw_entries().unwrap();
+        assert_eq!(raw.len(), 4);
+        assert_eq!(raw[3].get_type(), EntryType::EndOfDirectory);
+        
+        // An emptied directory keeps its first cluster
+        volume.delete("/docs/a.txt").unwrap();
+        let docs = ExFatDirectory::new(volume.root_dir_cluster(), &volume).read_entry().unwrap().unwrap();
+        ExFatDirectory::from_file(&docs).unwrap().compact().unwrap();
+        assert_eq!({ lookup(&volume, "/docs").unwrap().stream.data_length }, 512);
+        assert_eq!(volume.fat_entry(3).unwrap(), FatEntry::EndOfChain);
+        assert!(ExFatDirectory::from_file(&docs).unwrap().is_empty().unwrap());
+    }
+    
+    #[test]
+    fn test_entry_set_name_split_across_clusters() {
+        // A contiguous two-cluster directory, with no FAT chain to follow
+        let device = test_device(&[10, 11]);
+        let volume = test_volume_with(device.clone());
+        let mut root = volume.load_directory(volume.root_stream()).unwrap();
+        let cont = EntrySetBuilder::new(file_attributes::DIRECTORY)
+            .name("cont")
+            .first_cluster(10)
+            .size(1024)
+            .contiguous(true)
+            .build(None)
+            .unwrap();
+        root.write_entries(7, &cont);
+        volume.store_directory(&root).unwrap();
+        
+        // Three name entries, the last two in the second cluster
+        let name = "a_file_name_needing_three_name_entries.txt";
+        let set = entry_set(name, file_attributes::ARCHIVE, 0, 0);
+        assert_eq!(set.len(), 5);
+        let mut image = vec![0x05u8; 1024];
+        for (i, entry) in set.iter().enumerate() {
+            image[(13 + i) * 32..(14 + i) * 32].copy_from_slice(entry);
+        }
+        image[18 * 32..19 * 32].fill(0);
+        let mut device = device;
+        device.write_blocks(40, &image).unwrap();
+        
+        let cont = ExFatDirectory::new(volume.root_dir_cluster(), &volume)
+            .entries()
+            .unwrap()
+            .into_iter()
+            .find(|file| file.name() == "cont")
+            .unwrap();
+        let mut dir = ExFatDirectory::from_file(&cont).unwrap();
+        let file = dir.read_entry().unwrap().unwrap();
+        assert_eq!(file.name(), name);
+        assert_eq!(file.entry_position(), Some(EntryPosition { cluster: 10, offset: 416, contiguous: true }));
+        assert!(dir.read_entry().unwrap().is_none());
+        
+        let path = ["/cont/", name].concat();
+        assert_eq!(from_utf16_name(&lookup(&volume, &path).unwrap().name), name);
+    }
+    
+    #[test]
+    fn test_directory_streams_by_cluster() {
+        // Root directory spans clusters 2, 6 and 7; /far/ straddles 6 and 7
+        let mut device = test_device(&[6, 7, 8]);
+        set_fat(&mut device, 2, 6);
+        set_fat(&mut device, 6, 7);
+        set_fat(&mut device, 7, 0xFFFF_FFFF);
+        set_fat(&mut device, 8, 0xFFFF_FFFF);
+        
+        let mut image = vec![0x05u8; 1536];
+        let mut put = |index: usize, entries: &[[u8; 32]]| {
+            for (i, entry) in entries.iter().enumerate() {
+                image[(index + i) * 32..(index + i + 1) * 32].copy_from_slice(entry);
+            }
+        };
+        put(0, &[bitmap_entry(5, 8)]);
+        put(1, &entry_set("a.txt", file_attributes::ARCHIVE, 0, 0));
+        put(30, &entry_set("far", file_attributes::DIRECTORY, 8, 512));
+        put(40, &[[0u8; 32]]);
+        device.write_blocks(32, &image[..512]).unwrap();
+        device.write_blocks(36, &image[512..1024]).unwrap();
+        device.write_blocks(37, &image[1024..]).unwrap();
+        
+        let reads = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let writes = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let log = IoLog { device, reads: reads.clone(), writes };
+        let volume = ExFatVolume::new(test_boot_sector(), log).unwrap();
+        let heap_reads = |reads: &[(u64, usize)]| -> Vec<(u64, usize)> {
+            reads.iter().copied().filter(|&(lba, _)| lba >= 32).collect()
+        };
+        
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        let names: Vec<String> = dir.entries().unwrap().iter().map(|file| String::from(file.name())).collect();
+        assert_eq!(names, ["a.txt", "far"]);
+        assert_eq!(heap_reads(&reads.borrow()), [(32, 512), (36, 512), (37, 512)]);
+        
+        // Path lookup walks the directory the same way
+        reads.borrow_mut().clear();
+        let (stream, parents) = volume.resolve_directory("/FAR").unwrap();
+        assert_eq!((stream.first_cluster, parents), (8, vec![2]));
+        assert!(heap_reads(&reads.borrow()).iter().all(|&(_, len)| len == 512));
+    }
+    
+    #[test]
+    fn test_looping_directory_chain() {
+        let mut device = MemBlockDevice::new(96 * 512, 512);
+        set_fat(&mut device, 2, 5);
+        set_fat(&mut device, 5, 2);
+        
+        // Fill both clusters with deleted entries so no terminator is found
+        let deleted = [0x05u8; 512];
+        device.write_blocks(32, &deleted).unwrap();
+        device.write_blocks(35, &deleted).unwrap();
+        
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        let mut dir = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+        assert!(matches!(dir.read_entry(), Err(ExFatError::DirectoryTooLarge)));
+    }
+    
+    #[test]
+    fn test_second_fat_active() {
+        // Shrink the FATs so both fit before the cluster heap
+        let mut boot_sector = test_boot_sector();
+        boot_sector.fat_length = 4;
+        boot_sector.num_fats = 2;
+        boot_sector.volume_flags = volume_flags::ACTIVE_FAT;
+        assert_eq!(boot_sector.active_fat(), 1);
+        assert_eq!(boot_sector.active_fat_offset(), 28);
+        assert_eq!(boot_sector.active_bitmap(), BitmapId::Second);
+        
+        let mut device = MemBlockDevice::new(96 * 512, 512);
+        set_fat(&mut device, 3, 0xFFFF_FFFF);
+        let mut second = [0u8; 512];
+        second[12..16].copy_from_slice(&4u32.to_le_bytes());
+        device.write_blocks(28, &second).unwrap();
+        
+        let volume = ExFatVolume::new(boot_sector, device.clone()).unwrap();
+        assert_eq!(volume.fat_entry_location(3), Ok((28, 12)));
+        assert_eq!(volume.fat_entry(3), Ok(FatEntry::Next(4)));
+        
+        // Writes are held until the FAT is flushed, then reach both FATs
+        volume.set_fat_entry(5, FatEntry::EndOfChain).unwrap();
+        assert_eq!(le_u32(&device.to_vec(), 28 * 512 + 20), 0);
+        volume.flush_fat().unwrap();
+        let image = device.to_vec();
+        assert_eq!(le_u32(&image, 28 * 512 + 20), 0xFFFF_FFFF);
+        assert_eq!(le_u32(&image, 24 * 512 + 20), 0xFFFF_FFFF);
+        assert_eq!(le_u32(&image, 24 * 512 + 12), 4);
+        
+        // The flag is ignored on single-FAT volumes
+        boot_sector.num_fats = 1;
+        assert_eq!(boot_sector.active_fat(), 0);
+        assert_eq!(boot_sector.active_fat_offset(), 24);
+        assert_eq!(boot_sector.active_bitmap(), BitmapId::First);
+    }
+    
+    #[test]
+    fn test_fat_entry_location() {
+        let mut device = test_device(&[]);
+        set_fat(&mut device, 65, 0xFFFF_FFF7);
+        let mut volume = ExFatVolume::new(test_boot_sector(), device.clone()).unwrap();
+        
+        assert_eq!(volume.fat_entry_location(2), Ok((24, 8)));
+        assert_eq!(volume.fat_entry_location(65), Ok((24, 260)));
+        let (lba, offset) = volume.fat_entry_location(65).unwrap();
+        let mut sector = [0u8; 512];
+        device.read_blocks(lba, &mut sector).unwrap();
+        assert_eq!(le_u32(&sector, offset), 0xFFFF_FFF7);
+        assert_eq!(volume.fat_entry(65), Ok(FatEntry::Bad));
+        
+        for cluster in [0, 1, 66] {
+            assert_eq!(volume.fat_entry_location(cluster), Err(ExFatError::ClusterOutOfRange));
+        }
+        
+        volume.set_partition_lba_base(100);
+        assert_eq!(volume.fat_entry_location(2), Ok((124, 8)));
+    }
+    
+    #[test]
+    fn test_fat_cache() {
+        // A chain crossing between the first two FAT sectors and back
+        let mut device = test_device(&[]);
+        for (cluster, next) in [(10, 130), (130, 131), (131, 11), (11, 12)] {
+            set_fat(&mut device, cluster, next);
+        }
+        set_fat(&mut device, 12, 0xFFFF_FFFF);
+        let mut boot_sector = test_boot_sector();
+        boot_sector.cluster_count = 192;
+        
+        let reads = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let writes = alloc::rc::Rc::new(RefCell::new(Vec::new()));
+        let log = IoLog { device: device.clone(), reads: reads.clone(), writes: writes.clone() };
+        let mut volume = ExFatVolume::new(boot_sector, log).unwrap();
+        
+        assert_eq!(volume.cluster_chain(10, false, 0).unwrap(), [10, 130, 131, 11, 12]);
+        assert_eq!(volume.cluster_chain(10, false, 0).unwrap().len(), 5);
+        assert_eq!(*reads.borrow(), [(24, 512), (25, 512)]);
+        
+        // Changes stay in memory until flushed
+        volume.set_fat_entry(12, FatEntry::Next(13)).unwrap();
+        volume.set_fat_entry(13, FatEntry::EndOfChain).unwrap();
+        assert!(writes.borrow().is_empty());
+        volume.flush().unwrap();
+        assert_eq!(*writes.borrow(), [(24, 512)]);
+        assert_eq!(le_u32(&device.to_vec(), 24 * 512 + 13 * 4), 0xFFFF_FFFF);
+        
+        // A full cache writes back the sector it evicts
+        volume.set_fat_cache_capacity(1).unwrap();
+        writes.borrow_mut().clear();
+        volume.set_fat_entry(13, FatEntry::Next(14)).unwrap();
+        volume.set_fat_entry(130, FatEntry::EndOfChain).unwrap();
+        assert_eq!(*writes.borrow(), [(24, 512)]);
+        assert_eq!(volume.fat_entry(13), Ok(FatEntry::Next(14)));
+    }
+    
+    #[test]
+    fn test_end_of_chain_marker_preserved() {
+        let mut device = MemBlockDevice::new(96 * 512, 512);
+        set_fat(&mut device, 3, 4);
+        set_fat(&mut device, 4, 0xFFFF_FFF8);
+        
+        let volume = ExFatVolume::new(test_boot_sector(), device.clone()).unwrap();
+        assert_eq!(volume.cluster_chain(3, false, 0).unwrap(), [3, 4]);
+        
+        // Rewriting the entry read from disk keeps the original marker
+        let entry = volume.fat_entry(4).unwrap();
+        assert_eq!(entry, FatEntry::EndOfChainValue(0xFFFF_FFF8));
+        volume.set_fat_entry(3, FatEntry::Next(4)).unwrap();
+        volume.set_fat_entry(4, entry).unwrap();
+        volume.flush_fat().unwrap();
+        assert_eq!(le_u32(&device.to_vec(), 24 * 512 + 16), 0xFFFF_FFF8);
+    }
+    
+    #[test]
+    fn test_cyclic_chain() {
+        let mut device = MemBlockDevice::new(96 * 512, 512);
+        set_fat(&mut device, 3, 4);
+        set_fat(&mut device, 4, 5);
+        set_fat(&mut device, 5, 3);
+        set_fat(&mut device, 6, 7);
+        set_fat(&mut device, 7, 0xFFFF_FFFF);
+        
+        let volume = ExFatVolume::new(test_boot_sector(), device).unwrap();
+        assert_eq!(volume.cluster_chain(3, false, 0), Err(ExFatError::CyclicChain));
+        assert_eq!(volume.cluster_chain(6, false, 0), Ok(vec![6, 7]));
+    }
+    
+    #[test]
+    fn test_dot_components() {
+        let volume = test_volume();
+        let (docs, _) = volume.resolve_directory("docs").unwrap();
+        let mut dir = volume.load_directory(docs).unwrap();
+        dir.write_entries(0, &entry_set("inner.txt", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&dir).unwrap();
+        
+        assert!(lookup(&volume, "/docs/./inner.txt").is_some());
+        assert!(lookup(&volume, "/./docs/./inner.txt").is_some());
+        assert!(lookup(&volume, "/docs/../hello.txt").is_some());
+        assert!(lookup(&volume, "/docs/../inner.txt").is_none());
+        
+        // `..` at the root stays at the root
+        assert!(lookup(&volume, "/../hello.txt").is_some());
+        assert!(lookup(&volume, "../../docs/inner.txt").is_some());
+        
+        let (stream, ancestors) = volume.resolve_directory("docs/./").unwrap();
+        assert_eq!((stream, ancestors), (docs, vec![2]));
+        let (stream, ancestors) = volume.resolve_directory("docs/..").unwrap();
+        assert_eq!((stream, ancestors), (volume.root_stream(), vec![]));
+    }
+    
+    #[test]
+    fn test_directory_is_empty() {
+        let volume = test_volume();
+        let (docs, _) = volume.resolve_directory("docs").unwrap();
+        assert!(ExFatDirectory::from_stream(docs, &volume).is_empty().unwrap());
+        
+        // The root holds the bitmap entry, which doesn't count
+        let empty_root = ExFatVolume::new(test_boot_sector(), test_device(&[])).unwrap();
+        assert!(ExFatDirectory::new(2, &empty_root).is_empty().unwrap());
+        
+        let mut dir = volume.load_directory(docs).unwrap();
+        dir.write_entries(0, &entry_set("inner.txt", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&dir).unwrap();
+        assert!(!ExFatDirectory::from_stream(docs, &volume).is_empty().unwrap());
+    }
+    
+    #[test]
+    fn test_delete() {
+        let volume = test_volume();
+        let (docs, _) = volume.resolve_directory("docs").unwrap();
+        let mut dir = volume.load_directory(docs).unwrap();
+        dir.write_entries(0, &entry_set("inner.txt", file_attributes::ARCHIVE, 0, 0));
+        volume.store_directory(&dir).unwrap();
+        
+        assert_eq!(volume.delete("/docs"), Err(ExFatError::DirectoryNotEmpty));
+        assert!(lookup(&volume, "/docs").is_some());
+        
+        volume.delete("/docs/inner.txt").unwrap();
+        volume.delete("/docs").unwrap();
+        assert!(lookup(&volume, "/docs").is_none());
+        assert!(!volume.load_bitmap().unwrap().is_allocated(3));
+        assert_eq!(volume.fat_entry(3), Ok(FatEntry::Free));
+        assert_eq!(disk_fat(&volume, 3), 0);
+        
+        volume.delete("/hello.txt").unwrap();
+        assert!(!volume.load_bitmap().unwrap().is_allocated(4));
+        assert_eq!(volume.delete("/hello.txt"), Err(ExFatError::NotFound));
+    }
+    
+    #[test]
+    fn test_move_between_directories() {
+        let volume = test_volume();
+        volume.move_entry("/hello.txt", "/docs/world.txt").unwrap();
+        
+        assert!(lookup(&volume, "/hello.txt").is_none());
+        let moved = lookup(&volume, "/docs/world.txt").unwrap();
+        assert_eq!({ moved.stream.first_cluster }, 4);
+        assert_eq!({ moved.stream.data_length }, 5);
+        assert_eq!({ moved.file.create_timestamp }, 0x5A21_6C00);
+        assert_eq!({ moved.file.last_modified_timestamp }, 0x5A21_6C01);
+    }
+    
+    #[test]
+    fn test_move_within_directory_renames() {
+        let volume = test_volume();
+        volume.move_entry("/hello.txt", "/a_much_longer_name_than_before.txt").unwrap();
+        
+        assert!(lookup(&volume, "/hello.txt").is_none());
+        let renamed = lookup(&volume, "/A_MUCH_LONGER_NAME_THAN_BEFORE.TXT").unwrap();
+        assert_eq!({ renamed.stream.first_cluster }, 4);
+        
+        // Changing only the case of a name is allowed
+        volume.rename("/docs", "DOCS").unwrap();
+        assert!(lookup(&volume, "/DOCS").is_some());
+    }
+    
+    #[test]
+    fn test_move_into_full_directory() {
+        let volume = test_volume();
+        let mut docs = volume.open_dir("/docs").unwrap();
+        for name in ["aaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbb", "cccccccccccccccc", "dddddddddddddddd"] {
+            docs.create_file(name).unwrap();
+        }
+        
+        // /docs grows by a cluster to take the moved entry set
+        volume.move_entry("/hello.txt", "/docs/world.txt").unwrap();
+        assert!(lookup(&volume, "/hello.txt").is_none());
+        assert_eq!({ lookup(&volume, "/docs/world.txt").unwrap().stream.first_cluster }, 4);
+        assert_eq!({ lookup(&volume, "/docs").unwrap().stream.data_length }, 1024);
+        
+        // A longer name within the full second cluster grows it again
+        let mut docs = volume.open_dir("/docs").unwrap();
+        for name in ["eeeeeeeeeeeeeeee", "ffffffffffffffff", "gggggggggggggggg"] {
+            docs.create_file(name).unwrap();
+        }
+        volume.move_entry("/docs/world.txt", "/docs/a_longer_name_for_the_world.txt").unwrap();
+        assert!(lookup(&volume, "/docs/world.txt").is_none());
+        assert!(lookup(&volume, "/docs/a_longer_name_for_the_world.txt").is_some());
+        assert_eq!({ lookup(&volume, "/docs").unwrap().stream.data_length }, 1536);
+        assert_eq!(volume.open_dir("/docs").unwrap().entries().unwrap().len(), 8);
+    }
+    
+    #[test]
+    fn test_move_refuses_existing_destination() {
+        let volume = test_volume();
+        let err = volume.move_entry("/hello.txt", "/DOCS").unwrap_err();
+        assert_eq!(err, ExFatError::AlreadyExists);
+        assert!(lookup(&volume, "/hello.txt").is_some());
+    }
+    
+    #[test]
+    fn test_move_directory_into_itself() {
+        let volume = test_volume();
+        let err = volume.move_entry("/docs/", "/docs/child").unwrap_err();
+        assert_eq!(err, ExFatError::InvalidParameter);
+        
+        let err = volume.move_entry("/missing", "/docs/missing").unwrap_err();
+        assert_eq!(err, ExFatError::NotFound);
     }
 }
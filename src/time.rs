@@ -0,0 +1,284 @@
+//! exFAT timestamps
+//!
+//! This module converts between calendar dates and the packed timestamp
+//! fields stored in file directory entries, and defines the clock used to
+//! stamp new and modified files.
+
+use alloc::format;
+use alloc::string::String;
+use crate::error::{ExFatError, Result};
+
+/// Calendar date and time of a file entry timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    /// Year, 1980 to 2107
+    pub year: u16,
+    /// Month, 1 to 12
+    pub month: u8,
+    /// Day of the month, 1 to 31
+    pub day: u8,
+    /// Hour, 0 to 23
+    pub hour: u8,
+    /// Minute, 0 to 59
+    pub minute: u8,
+    /// Second, 0 to 59
+    pub second: u8,
+    /// Hundredths of a second, 0 to 99
+    pub hundredths: u8,
+    /// Offset from UTC in minutes, if known
+    pub utc_offset: Option<i16>,
+}
+
+impl DateTime {
+    /// First year that can be stored
+    pub const MIN_YEAR: u16 = 1980;
+    
+    /// Last year that can be stored
+    pub const MAX_YEAR: u16 = 1980 + 127;
+    
+    /// Bit set in the UTC offset byte when the offset is valid
+    const UTC_OFFSET_VALID: u8 = 0x80;
+    
+    /// Convert seconds since the Unix epoch to a UTC date and time
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = seconds / 86400;
+        let time = seconds % 86400;
+        
+        // Civil date from a day count, with years starting in March
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = era * 400 + year_of_era + u64::from(month <= 2);
+        
+        Self {
+            year: year.min(u16::MAX as u64) as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            hundredths: 0,
+            utc_offset: Some(0),
+        }
+    }
+    
+    /// Decode a timestamp, its 10ms increment and its UTC offset byte
+    pub fn from_exfat(timestamp: u32, increment_10ms: u8, utc_offset: u8) -> Self {
+        let offset = if (utc_offset & Self::UTC_OFFSET_VALID) != 0 {
+            // Sign-extend the 7-bit count of 15 minute intervals
+            Some((((utc_offset << 1) as i8) >> 1) as i16 * 15)
+        } else {
+            None
+        };
+        
+        Self {
+            year: Self::MIN_YEAR + (timestamp >> 25) as u16,
+            month: ((timestamp >> 21) & 0x0F) as u8,
+            day: ((timestamp >> 16) & 0x1F) as u8,
+            hour: ((timestamp >> 11) & 0x1F) as u8,
+            minute: ((timestamp >> 5) & 0x3F) as u8,
+            second: (timestamp & 0x1F) as u8 * 2 + increment_10ms / 100,
+            hundredths: increment_10ms % 100,
+            utc_offset: offset,
+        }
+    }
+    
+    /// Encode as a timestamp, its 10ms increment and its UTC offset byte
+    ///
+    /// The timestamp stores seconds in units of two; an odd second is
+    /// carried by adding 100 to the 10ms increment. Fails with
+    /// `ExFatError::InvalidParameter` if a field is out of range, including
+    /// years outside 1980 to 2107 and UTC offsets that aren't a multiple
+    /// of 15 minutes.
+    pub fn to_exfat(&self) -> Result<(u32, u8, u8)> {
+        if !(Self::MIN_YEAR..=Self::MAX_YEAR).contains(&self.year)
+            || !(1..=12).contains(&self.month)
+            || !(1..=31).contains(&self.day)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+            || self.hundredths > 99
+        {
+            return Err(ExFatError::InvalidParameter);
+        }
+        
+        let utc_offset = match self.utc_offset {
+            Some(minutes) if minutes % 15 == 0 && (-64 * 15..=63 * 15).contains(&minutes) => {
+                ((minutes / 15) as u8 & 0x7F) | Self::UTC_OFFSET_VALID
+            }
+            Some(_) => return Err(ExFatError::InvalidParameter),
+            None => 0,
+        };
+        
+        let timestamp = ((self.year - Self::MIN_YEAR) as u32) << 25
+            | (self.month as u32) << 21
+            | (self.day as u32) << 16
+            | (self.hour as u32) << 11
+            | (self.minute as u32) << 5
+            | (self.second / 2) as u32;
+        let increment_10ms = (self.second % 2) * 100 + self.hundredths;
+        
+        Ok((timestamp, increment_10ms, utc_offset))
+    }
+    
+    /// Format as ISO 8601, such as `2023-05-01T12:34:56.78+01:00`
+    ///
+    /// The UTC offset is appended when known, and left off otherwise.
+    pub fn to_iso8601(&self) -> String {
+        let mut text = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.hundredths,
+        );
+        if let Some(minutes) = self.utc_offset {
+            let sign = if minutes < 0 { '-' } else { '+' };
+            let minutes = minutes.unsigned_abs();
+            text.push_str(&format!("{sign}{:02}:{:02}", minutes / 60, minutes % 60));
+        }
+        text
+    }
+}
+
+/// Clock used to stamp created and modified files
+///
+/// `no_std` has no clock of its own; on UEFI this is typically implemented
+/// over the runtime services `GetTime` call.
+pub trait TimeSource {
+    /// Get the current date and time
+    fn now(&self) -> DateTime;
+}
+
+/// Any clock function can serve as a time source
+impl<F: Fn() -> DateTime> TimeSource for F {
+    fn now(&self) -> DateTime {
+        self()
+    }
+}
+
+/// Time source that always reports the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedTimeSource(pub DateTime);
+
+impl TimeSource for FixedTimeSource {
+    fn now(&self) -> DateTime {
+        self.0
+    }
+}
+
+/// Time source backed by the host's system clock, in UTC
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemTimeSource;
+
+#[cfg(feature = "std")]
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> DateTime {
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        DateTime {
+            hundredths: (elapsed.subsec_millis() / 10) as u8,
+            ..DateTime::from_unix(elapsed.as_secs())
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "uefi")))]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_round_trip() {
+        let time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 37,
+            second: 41,
+            hundredths: 25,
+            utc_offset: Some(-5 * 60),
+        };
+        
+        let (timestamp, increment_10ms, utc_offset) = time.to_exfat().unwrap();
+        assert_eq!(timestamp >> 25, 44);
+        // 41 seconds is 20 double seconds plus one second in the increment
+        assert_eq!(timestamp & 0x1F, 20);
+        assert_eq!(increment_10ms, 125);
+        assert_eq!(utc_offset, 0x80 | 0x6C);
+        assert_eq!(DateTime::from_exfat(timestamp, increment_10ms, utc_offset), time);
+        
+        let local = DateTime { utc_offset: None, ..time };
+        assert_eq!(local.to_exfat().unwrap().2, 0);
+        assert_eq!(DateTime::from_exfat(timestamp, increment_10ms, 0), local);
+    }
+    
+    #[test]
+    fn test_from_unix() {
+        let epoch = DateTime::from_unix(0);
+        assert_eq!((epoch.year, epoch.month, epoch.day, epoch.hour), (1970, 1, 1, 0));
+        
+        let time = DateTime::from_unix(1_709_213_861);
+        assert_eq!((time.year, time.month, time.day), (2024, 2, 29));
+        assert_eq!((time.hour, time.minute, time.second), (13, 37, 41));
+        assert_eq!(time.utc_offset, Some(0));
+        
+        let time = DateTime::from_unix(951_868_800);
+        assert_eq!((time.year, time.month, time.day), (2000, 3, 1));
+    }
+    
+    #[test]
+    fn test_to_iso8601() {
+        let time = DateTime {
+            year: 2023,
+            month: 5,
+            day: 1,
+            hour: 12,
+            minute: 34,
+            second: 56,
+            hundredths: 78,
+            utc_offset: Some(60),
+        };
+        assert_eq!(time.to_iso8601(), "2023-05-01T12:34:56.78+01:00");
+        
+        let time = DateTime { utc_offset: Some(-(9 * 60 + 30)), hundredths: 5, ..time };
+        assert_eq!(time.to_iso8601(), "2023-05-01T12:34:56.05-09:30");
+        
+        let time = DateTime { utc_offset: None, ..time };
+        assert_eq!(time.to_iso8601(), "2023-05-01T12:34:56.05");
+        
+        assert_eq!(DateTime::from_unix(0).to_iso8601(), "1970-01-01T00:00:00.00+00:00");
+    }
+    
+    #[test]
+    fn test_out_of_range() {
+        let time = DateTime {
+            year: 1980,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+            hundredths: 0,
+            utc_offset: None,
+        };
+        assert_eq!(time.to_exfat(), Ok((0x0021_0000, 0, 0)));
+        assert!(DateTime { year: 2107, ..time }.to_exfat().is_ok());
+        
+        for bad in [
+            DateTime { year: 1979, ..time },
+            DateTime { year: 2108, ..time },
+            DateTime { month: 13, ..time },
+            DateTime { day: 0, ..time },
+            DateTime { second: 60, ..time },
+            DateTime { utc_offset: Some(10), ..time },
+            DateTime { utc_offset: Some(16 * 60), ..time },
+        ] {
+            assert_eq!(bad.to_exfat(), Err(ExFatError::InvalidParameter));
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! exFAT up-case table
+//!
+//! This module implements the up-case table, which maps each UTF-16 code
+//! unit to its upper case form for case-insensitive name comparison and
+//! name hashing.
+
+use alloc::vec::Vec;
+use crate::error::{ExFatError, Result};
+use crate::exfat::{le_u16, name_hash_with, upcase_char};
+
+/// Up-case table
+///
+/// Code units past the end of the table map to themselves.
+#[derive(Debug, Clone)]
+pub struct UpCaseTable {
+    /// Upper case mapping, indexed by code unit
+    table: Vec<u16>,
+}
+
+impl UpCaseTable {
+    /// Create a table from an uncompressed mapping
+    pub fn new(table: Vec<u16>) -> Self {
+        Self { table }
+    }
+    
+    /// Build a full table from the built-in case mapping
+    ///
+    /// Used when the volume's own table is unavailable.
+    pub fn generate() -> Self {
+        Self::new((0..=u16::MAX).map(upcase_char).collect())
+    }
+    
+    /// Parse a table from its on-disk contents
+    ///
+    /// The on-disk table may be compressed: `0xFFFF` followed by a count
+    /// stands for that many identity mappings.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if !bytes.len().is_multiple_of(2) {
+            return Err(ExFatError::Corrupted);
+        }
+        
+        let mut table = Vec::with_capacity(bytes.len() / 2);
+        let mut units = (0..bytes.len()).step_by(2).map(|offset| le_u16(bytes, offset));
+        
+        while let Some(unit) = units.next() {
+            if unit != 0xFFFF {
+                table.push(unit);
+                continue;
+            }
+            
+            let count = units.next().ok_or(ExFatError::Corrupted)? as usize;
+            if table.len() + count > 0x10000 {
+                return Err(ExFatError::Corrupted);
+            }
+            let start = table.len();
+            table.extend((start..start + count).map(|c| c as u16));
+        }
+        
+        Ok(Self::new(table))
+    }
+    
+    /// Serialize the table in its compressed on-disk form
+    ///
+    /// Every run of identity mappings is stored as `0xFFFF` and its length,
+    /// so the result parses back with `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut units = Vec::new();
+        let mut index = 0;
+        while index < self.table.len() {
+            let run = self.table[index..]
+                .iter()
+                .zip(index..)
+                .take(0xFFFF)
+                .take_while(|&(&unit, c)| unit as usize == c)
+                .count();
+            if run == 0 {
+                units.push(self.table[index]);
+                index += 1;
+            } else {
+                units.extend([0xFFFF, run as u16]);
+                index += run;
+            }
+        }
+        
+        units.iter().flat_map(|unit| unit.to_le_bytes()).collect()
+    }
+    
+    /// Up-case a single code unit
+    pub fn upcase(&self, c: u16) -> u16 {
+        self.table.get(c as usize).copied().unwrap_or(c)
+    }
+    
+    /// Compare two UTF-16 names case-insensitively
+    pub fn eq_ignore_case(&self, a: &[u16], b: &[u16]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| self.upcase(x) == self.upcase(y))
+    }
+    
+    /// Compute the name hash stored in the stream extension entry
+    pub fn name_hash(&self, name: &[u16]) -> u16 {
+        name_hash_with(name, |c| self.upcase(c))
+    }
+}
+
+#[cfg(all(test, not(target_os = "uefi")))]
+mod tests {
+    use super::*;
+    use crate::exfat::name_hash;
+    
+    #[test]
+    fn test_generated_table() {
+        let upcase = UpCaseTable::generate();
+        assert_eq!(upcase.upcase(b'a' as u16), b'A' as u16);
+        assert_eq!(upcase.upcase(0x00E9), 0x00C9);
+        
+        let name: Vec<u16> = "Hello.TXT".encode_utf16().collect();
+        let other: Vec<u16> = "hello.txt".encode_utf16().collect();
+        assert!(upcase.eq_ignore_case(&name, &other));
+        assert_eq!(upcase.name_hash(&name), name_hash(&other));
+    }
+    
+    #[test]
+    fn test_from_compressed_bytes() {
+        // Identity for 0x00..0x61, then 'a'..'c' map to 'A'..'C'
+        let mut bytes = Vec::new();
+        for unit in [0xFFFF, 0x61, 0x41, 0x42, 0x43] {
+            bytes.extend_from_slice(&u16::to_le_bytes(unit));
+        }
+        
+        let upcase = UpCaseTable::from_bytes(&bytes).unwrap();
+        assert_eq!(upcase.upcase(0x20), 0x20);
+        assert_eq!(upcase.upcase(b'b' as u16), b'B' as u16);
+        // Past the end of the table
+        assert_eq!(upcase.upcase(b'd' as u16), b'd' as u16);
+        
+        assert!(UpCaseTable::from_bytes(&bytes[..2]).is_err());
+        assert_eq!(upcase.to_bytes(), bytes);
+    }
+    
+    #[test]
+    fn test_to_bytes_round_trip() {
+        let upcase = UpCaseTable::generate();
+        let bytes = upcase.to_bytes();
+        assert!(bytes.len() < 0x10000 * 2);
+        
+        let parsed = UpCaseTable::from_bytes(&bytes).unwrap();
+        assert!((0..=u16::MAX).all(|c| parsed.upcase(c) == upcase.upcase(c)));
+    }
+}
@@ -8,4 +8,7 @@ echo "Running tests for uefi-exfat..."
 # Run tests on host platform
 cargo test --lib --target x86_64-unknown-linux-gnu
 
+# Host-side library, including the disk image integration tests
+cargo test --features std --target x86_64-unknown-linux-gnu
+
 echo "All tests passed!"
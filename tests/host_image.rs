@@ -0,0 +1,173 @@
+//! Host-side integration tests
+//!
+//! These build an exFAT image file on disk and mount it through the
+//! file-backed block device, the way a host tool would.
+
+#![cfg(feature = "std")]
+
+use std::io::Read;
+use std::path::PathBuf;
+use uefi_exfat::*;
+
+/// Bytes per sector and per cluster of the test image
+const SECTOR: usize = 512;
+
+/// Contents of `/readme.txt`
+const README: &[u8] = b"Hello from the host\n";
+
+/// Copy `bytes` into the image at a byte offset
+fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Byte offset of a cluster in the test image
+fn cluster_offset(cluster: usize) -> usize {
+    (32 + cluster - 2) * SECTOR
+}
+
+/// Build a 96-sector image
+///
+/// The FAT is at sector 24 and the cluster heap at sector 32, with one
+/// sector per cluster. The root directory is cluster 2, the allocation
+/// bitmap cluster 3, the up-case table cluster 4, `/readme.txt` cluster 5
+/// and the empty `/docs/` cluster 6.
+fn build_image() -> Vec<u8> {
+    let mut image = vec![0u8; 96 * SECTOR];
+    
+    let mut region = vec![0u8; 11 * SECTOR];
+    put(&mut region, 0, &[0xEB, 0x76, 0x90]);
+    put(&mut region, 3, b"EXFAT   ");
+    put(&mut region, 72, &96u64.to_le_bytes());
+    put(&mut region, 80, &24u32.to_le_bytes());
+    put(&mut region, 84, &8u32.to_le_bytes());
+    put(&mut region, 88, &32u32.to_le_bytes());
+    put(&mut region, 92, &64u32.to_le_bytes());
+    put(&mut region, 96, &2u32.to_le_bytes());
+    put(&mut region, 104, &0x0100u16.to_le_bytes());
+    region[108] = 9;
+    region[110] = 1;
+    put(&mut region, 510, &0xAA55u16.to_le_bytes());
+    let checksum = boot_checksum(&region).to_le_bytes();
+    for base in [0, 12] {
+        put(&mut image, base * SECTOR, &region);
+        for i in 0..SECTOR / 4 {
+            put(&mut image, (base + 11) * SECTOR + i * 4, &checksum);
+        }
+    }
+    
+    put(&mut image, 24 * SECTOR, &0xFFFF_FFF8u32.to_le_bytes());
+    for cluster in 1..=6 {
+        put(&mut image, 24 * SECTOR + cluster * 4, &0xFFFF_FFFFu32.to_le_bytes());
+    }
+    image[cluster_offset(3)] = 0b1_1111;
+    
+    let mut table = Vec::new();
+    for unit in [0xFFFF, 0x61].into_iter().chain(0x41..=0x5A) {
+        table.extend_from_slice(&u16::to_le_bytes(unit));
+    }
+    put(&mut image, cluster_offset(4), &table);
+    put(&mut image, cluster_offset(5), README);
+    
+    let bitmap = AllocationBitmapEntry {
+        entry_type: EntryType::AllocationBitmap as u8,
+        bitmap_flags: 0,
+        reserved: [0; 18],
+        first_cluster: 3,
+        data_length: 8,
+    };
+    let upcase = UpCaseTableEntry {
+        entry_type: EntryType::UpCaseTable as u8,
+        reserved1: [0; 3],
+        table_checksum: table_checksum(&table),
+        reserved2: [0; 12],
+        first_cluster: 4,
+        data_length: table.len() as u64,
+    };
+    let mut volume_label = [0u16; 11];
+    for (unit, c) in volume_label.iter_mut().zip("HOSTIMG".encode_utf16()) {
+        *unit = c;
+    }
+    let label = VolumeLabelEntry {
+        entry_type: EntryType::VolumeLabel as u8,
+        character_count: 7,
+        volume_label,
+        reserved: [0; 8],
+    };
+    
+    let mut entries = vec![bitmap.to_bytes(), upcase.to_bytes(), label.to_bytes()];
+    entries.extend(
+        EntrySetBuilder::new(file_attributes::ARCHIVE)
+            .name("readme.txt")
+            .first_cluster(5)
+            .size(README.len() as u64)
+            .build(None)
+            .unwrap(),
+    );
+    entries.extend(
+        EntrySetBuilder::new(file_attributes::DIRECTORY)
+            .name("docs")
+            .first_cluster(6)
+            .size(SECTOR as u64)
+            .build(None)
+            .unwrap(),
+    );
+    for (i, entry) in entries.iter().enumerate() {
+        put(&mut image, cluster_offset(2) + i * 32, entry);
+    }
+    
+    image
+}
+
+/// Write an image to a file unique to this test
+fn image_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("uefi-exfat-{}-{}.img", name, std::process::id()));
+    std::fs::write(&path, build_image()).unwrap();
+    path
+}
+
+#[test]
+fn test_list_root_directory() {
+    let path = image_file("list");
+    let volume = ExFatVolume::mount(FileBlockDevice::open(&path, 512).unwrap()).unwrap();
+    assert_eq!(volume.system_entries().unwrap().label.as_deref(), Some("HOSTIMG"));
+    
+    let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+    let mut listing = Vec::new();
+    while let Some(file) = root.read_entry().unwrap() {
+        listing.push((String::from(file.name()), file.is_directory(), file.size()));
+    }
+    assert_eq!(listing, [
+        (String::from("readme.txt"), false, README.len() as u64),
+        (String::from("docs"), true, SECTOR as u64),
+    ]);
+    
+    let upcase = &volume.system_entries().unwrap().upcase;
+    let mut readme = root.find("README.TXT", upcase).unwrap().unwrap();
+    let mut text = String::new();
+    readme.read_to_string(&mut text).unwrap();
+    assert_eq!(text.as_bytes(), README);
+    
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_write_through_to_file() {
+    let path = image_file("write");
+    let volume = ExFatVolume::mount(FileBlockDevice::open(&path, 512).unwrap()).unwrap();
+    
+    let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+    let mut file = root.create_entry_contiguous("new.bin", 0).unwrap();
+    file.write(&[0xA5; 700]).unwrap();
+    file.close().unwrap();
+    drop(root);
+    volume.unmount().unwrap();
+    
+    // A fresh mount sees the new file
+    let volume = ExFatVolume::mount(FileBlockDevice::open(&path, 512).unwrap()).unwrap();
+    let mut root = ExFatDirectory::new(volume.root_dir_cluster(), &volume);
+    let upcase = &volume.system_entries().unwrap().upcase;
+    let mut file = root.find("new.bin", upcase).unwrap().unwrap();
+    assert_eq!(file.read_to_end().unwrap(), [0xA5; 700]);
+    
+    std::fs::remove_file(&path).unwrap();
+}